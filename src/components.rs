@@ -1,5 +1,17 @@
+pub mod block_report;
+pub mod block_store;
+pub mod checksum;
 pub mod configs;
 pub mod db;
 pub mod entity;
 pub mod errors;
+pub mod failure_detector;
+pub mod master_registry;
 pub mod packets;
+pub mod placement;
+pub mod raft;
+pub mod rebalancer;
+pub mod replication;
+pub mod scrubber;
+pub mod state_sync;
+pub mod worker_pool;
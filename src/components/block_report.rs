@@ -0,0 +1,220 @@
+use std::collections::{HashMap, HashSet};
+
+use rusqlite::Result;
+
+use crate::components::db::{FileInfoDB, FileState};
+
+// ================================================
+// Definition
+// ================================================
+
+/// A block stored by a Data node as listed in its BlockReport
+#[derive(Clone)]
+pub struct BlockInfo {
+    pub block_id: String,
+    pub size: u64,
+    pub checksum: u32,
+}
+
+/// What Master learnt by comparing a BlockReport against FileInfoDB
+#[derive(Default)]
+pub struct ReconcileSummary {
+    pub n_valid: usize,
+    /// Replicas recorded for the node which the node doesn't hold
+    pub missing: Vec<String>,
+    /// Blocks the node holds which Master doesn't know
+    pub orphaned: Vec<String>,
+    /// Blocks whose size or checksum differ from what Master recorded
+    pub corrupted: Vec<String>,
+}
+
+// ================================================
+// Implementation
+// ================================================
+
+impl std::fmt::Display for ReconcileSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} valid, {} missing, {} orphaned, {} corrupted",
+            self.n_valid,
+            self.missing.len(),
+            self.orphaned.len(),
+            self.corrupted.len()
+        )
+    }
+}
+
+impl ReconcileSummary {
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.orphaned.is_empty() && self.corrupted.is_empty()
+    }
+}
+
+/// Make replicas recorded for `node_id` match what the node reported
+///
+/// Valid copies are recorded as replicas, while missing and corrupted ones are forgotten so that the blocks become
+/// under-replicated and get copied again from a good replica. Blocks still being uploaded are left alone.
+pub fn reconcile(
+    node_id: &String,
+    blocks: &[BlockInfo],
+    file_info: &FileInfoDB,
+    replication_factor: usize,
+) -> Result<ReconcileSummary> {
+    let mut summary = ReconcileSummary::default();
+    let reported: HashMap<&String, &BlockInfo> = blocks.iter().map(|block| (&block.block_id, block)).collect();
+
+    // Replicas Master believes the node holds
+    let mut recorded = HashSet::<String>::new();
+    for replica in file_info.get_replicas_by_node(node_id)? {
+        if reported.contains_key(&replica.block_id) {
+            recorded.insert(replica.block_id);
+        } else {
+            file_info.remove_replica(&replica.block_id, node_id)?;
+            file_info.refresh_block_state(&replica.block_id, replication_factor)?;
+            summary.missing.push(replica.block_id);
+        }
+    }
+
+    // Blocks the node actually holds
+    for block in blocks {
+        let info = match file_info.get_block_info(&block.block_id)?.pop() {
+            Some(info) => info,
+            None => {
+                summary.orphaned.push(block.block_id.clone());
+                continue;
+            }
+        };
+        if info.state == FileState::Pending {
+            continue;
+        }
+
+        let is_recorded = recorded.contains(&block.block_id);
+        if info.size == block.size && info.checksum == block.checksum {
+            summary.n_valid += 1;
+            if is_recorded {
+                continue;
+            }
+            file_info.upsert_replica(&block.block_id, node_id)?;
+        } else {
+            summary.corrupted.push(block.block_id.clone());
+            if !is_recorded {
+                continue;
+            }
+            file_info.remove_replica(&block.block_id, node_id)?;
+        }
+        file_info.refresh_block_state(&block.block_id, replication_factor)?;
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::db::{BlockInfoEntry, DBBackend, FileInfoEntry};
+
+    const NODE_ID: &str = "127.0.0.1:7003";
+
+    /// File 'a' made of blocks of 10 bytes with checksum 1, already stored on `NODE_ID` unless Pending
+    fn file_info(states: &[FileState]) -> (FileInfoDB, Vec<String>) {
+        let file_info = FileInfoDB::initialize("file_info", &DBBackend::InMemory);
+        let mut entry = FileInfoEntry::initialize("a".to_string(), 10 * states.len() as u64, 0, 1);
+        entry.inode = file_info.create_file("a").unwrap().unwrap().0;
+        file_info.upsert(&entry).unwrap();
+
+        let mut block_ids = Vec::<String>::new();
+        for (index, state) in states.iter().enumerate() {
+            let mut block = BlockInfoEntry::initialize(entry.inode, index as u64, 10);
+            block.checksum = 1;
+            block.state = *state;
+            file_info.upsert_block(&block).unwrap();
+            if *state != FileState::Pending {
+                file_info.upsert_replica(&block.block_id, &NODE_ID.to_string()).unwrap();
+            }
+            block_ids.push(block.block_id);
+        }
+
+        (file_info, block_ids)
+    }
+
+    fn block(block_id: &str, size: u64, checksum: u32) -> BlockInfo {
+        BlockInfo {
+            block_id: block_id.to_string(),
+            size,
+            checksum,
+        }
+    }
+
+    fn n_replicas(file_info: &FileInfoDB, block_id: &String) -> usize {
+        file_info.get_replicas(block_id).unwrap().len()
+    }
+
+    #[test]
+    fn matching_report_is_consistent() {
+        let (file_info, block_ids) = file_info(&[FileState::Complete, FileState::Complete]);
+        let blocks: Vec<BlockInfo> = block_ids.iter().map(|id| block(id, 10, 1)).collect();
+
+        let summary = reconcile(&NODE_ID.to_string(), &blocks, &file_info, 1).unwrap();
+
+        assert!(summary.is_consistent());
+        assert_eq!(summary.n_valid, 2);
+    }
+
+    #[test]
+    fn missing_replica_is_forgotten() {
+        let (file_info, block_ids) = file_info(&[FileState::Complete, FileState::Complete]);
+
+        let summary = reconcile(&NODE_ID.to_string(), &[block(&block_ids[0], 10, 1)], &file_info, 1).unwrap();
+
+        assert_eq!(summary.missing, vec![block_ids[1].clone()]);
+        assert_eq!(n_replicas(&file_info, &block_ids[1]), 0);
+        let state = file_info.get_block_info(&block_ids[1]).unwrap()[0].state;
+        assert!(state == FileState::UnderReplicated);
+    }
+
+    #[test]
+    fn unknown_block_is_orphaned() {
+        let (file_info, _) = file_info(&[FileState::Complete]);
+
+        let summary = reconcile(&NODE_ID.to_string(), &[block("999#0", 10, 1)], &file_info, 1).unwrap();
+
+        assert_eq!(summary.orphaned, vec!["999#0".to_string()]);
+    }
+
+    #[test]
+    fn corrupted_replica_is_forgotten() {
+        let (file_info, block_ids) = file_info(&[FileState::Complete]);
+
+        let summary = reconcile(&NODE_ID.to_string(), &[block(&block_ids[0], 10, 2)], &file_info, 1).unwrap();
+
+        assert_eq!(summary.corrupted, block_ids);
+        assert_eq!(n_replicas(&file_info, &block_ids[0]), 0);
+
+        // Reported again once forgotten, still corrupted
+        let summary = reconcile(&NODE_ID.to_string(), &[block(&block_ids[0], 10, 2)], &file_info, 1).unwrap();
+        assert_eq!(summary.corrupted, block_ids);
+    }
+
+    #[test]
+    fn valid_copy_not_recorded_is_recorded() {
+        let (file_info, block_ids) = file_info(&[FileState::Complete]);
+        let node_other = "127.0.0.1:7004".to_string();
+
+        let summary = reconcile(&node_other, &[block(&block_ids[0], 10, 1)], &file_info, 1).unwrap();
+
+        assert!(summary.is_consistent());
+        assert_eq!(n_replicas(&file_info, &block_ids[0]), 2);
+    }
+
+    #[test]
+    fn pending_blocks_are_left_alone() {
+        let (file_info, block_ids) = file_info(&[FileState::Pending]);
+
+        let summary = reconcile(&NODE_ID.to_string(), &[block(&block_ids[0], 3, 7)], &file_info, 1).unwrap();
+
+        assert!(summary.is_consistent());
+        assert_eq!(summary.n_valid, 0);
+        assert_eq!(n_replicas(&file_info, &block_ids[0]), 0);
+    }
+}
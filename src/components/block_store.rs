@@ -0,0 +1,487 @@
+use std::{
+    ffi::CString,
+    fs::{self, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use crate::components::{checksum::crc32c, db::NodeUsage};
+
+// ================================================
+// Definition
+// ================================================
+
+const DIR_BLOCKS: &str = "blocks";
+const DIR_META: &str = "meta";
+const SIZE_META: usize = 20;
+
+/// What a Data node knows about a stored block, kept in a sidecar next to the block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockMeta {
+    pub checksum: u32,
+    pub length: u64,
+    /// Number of times the block has been written, starting from 1
+    pub generation: u64,
+}
+
+/// Storage of blocks on a Data node
+///
+/// Blocks live in `<dir>/blocks/<block_id>` and their metadata in `<dir>/meta/<block_id>`. A block being written is
+/// staged in a hidden temp file and only renamed into place once its last byte arrives, so readers never see a block
+/// half written. Block ids starting with '.' are reserved for those temp files. Block ids of files in directories
+/// contain '/', which is escaped in file names.
+#[derive(Clone)]
+pub struct BlockStore {
+    dir_blocks: PathBuf,
+    dir_meta: PathBuf,
+}
+
+// ================================================
+// Implementation
+// ================================================
+
+impl BlockMeta {
+    fn to_bytes(self) -> [u8; SIZE_META] {
+        let mut bytes = [0u8; SIZE_META];
+        bytes[0..4].copy_from_slice(&self.checksum.to_be_bytes());
+        bytes[4..12].copy_from_slice(&self.length.to_be_bytes());
+        bytes[12..20].copy_from_slice(&self.generation.to_be_bytes());
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> io::Result<BlockMeta> {
+        if bytes.len() != SIZE_META {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Block metadata has {} bytes instead of {}", bytes.len(), SIZE_META),
+            ));
+        }
+
+        Ok(BlockMeta {
+            checksum: u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+            length: u64::from_be_bytes(bytes[4..12].try_into().unwrap()),
+            generation: u64::from_be_bytes(bytes[12..20].try_into().unwrap()),
+        })
+    }
+}
+
+impl BlockStore {
+    /// Directories are only created once the first block is written
+    pub fn new(dir: &Path) -> BlockStore {
+        BlockStore {
+            dir_blocks: dir.join(DIR_BLOCKS),
+            dir_meta: dir.join(DIR_META),
+        }
+    }
+
+    /// Remove temp files left by writes interrupted in a previous run
+    pub fn recover(&self) -> io::Result<usize> {
+        let mut n_removed = 0;
+        for dir in [&self.dir_blocks, &self.dir_meta] {
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            for entry in entries.flatten() {
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    fs::remove_file(entry.path())?;
+                    n_removed += 1;
+                }
+            }
+        }
+
+        Ok(n_removed)
+    }
+
+    /// Write `data` at `offset` of block `block_id` whose final size is `length`
+    ///
+    /// Writing at offset 0 starts the block over, while other offsets continue the write in progress. Once the block
+    /// reaches `length` bytes it replaces the previous version atomically and its metadata is returned.
+    pub fn write(&self, block_id: &str, offset: u64, data: &[u8], length: u64) -> io::Result<Option<BlockMeta>> {
+        if offset.checked_add(data.len() as u64).is_none_or(|end| end > length) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Write of {} bytes at {} goes past the end of block '{}' of {} bytes",
+                    data.len(),
+                    offset,
+                    block_id,
+                    length
+                ),
+            ));
+        }
+
+        let path_staged = self.dir_blocks.join(_temp_name(&_validate(block_id)?));
+        fs::create_dir_all(&self.dir_blocks)?;
+
+        let mut file = match offset {
+            0 => OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&path_staged)?,
+            _ => match OpenOptions::new().write(true).open(&path_staged) {
+                Ok(file) => file,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "No write in progress for block '{}' to continue at {}",
+                            block_id, offset
+                        ),
+                    ));
+                }
+                Err(err) => return Err(err),
+            },
+        };
+        let len_staged = file.metadata()?.len();
+        if offset > len_staged {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Write to block '{}' at {} leaves a gap after {} bytes",
+                    block_id, offset, len_staged
+                ),
+            ));
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+
+        if offset + (data.len() as u64) < length {
+            return Ok(None);
+        }
+        file.sync_all()?;
+        drop(file);
+
+        self._commit(block_id, &path_staged).map(Some)
+    }
+
+    /// Read range [offset, offset + length) of a block. `length` = 0 reads until the end of the block.
+    pub fn read(&self, block_id: &str, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        let mut file = fs::File::open(self.dir_blocks.join(_validate(block_id)?))?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut data = Vec::<u8>::new();
+        match length {
+            0 => file.read_to_end(&mut data)?,
+            _ => file.take(length).read_to_end(&mut data)?,
+        };
+
+        Ok(data)
+    }
+
+    /// Read a whole block and check it against its metadata. A block damaged on disk fails with `InvalidData`.
+    pub fn read_verified(&self, block_id: &str) -> io::Result<Vec<u8>> {
+        let meta = self.get_meta(block_id)?;
+        let data = self.read(block_id, 0, 0)?;
+        if data.len() as u64 != meta.length || crc32c(&data) != meta.checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Block '{}' doesn't match its checksum", block_id),
+            ));
+        }
+
+        Ok(data)
+    }
+
+    pub fn get_meta(&self, block_id: &str) -> io::Result<BlockMeta> {
+        BlockMeta::from_bytes(&fs::read(self.dir_meta.join(_validate(block_id)?))?)
+    }
+
+    /// Remove a block and its metadata. Removing a block which doesn't exist is not an error.
+    pub fn delete(&self, block_id: &str) -> io::Result<()> {
+        let name = _validate(block_id)?;
+        for path in [
+            self.dir_meta.join(&name),
+            self.dir_blocks.join(&name),
+            self.dir_blocks.join(_temp_name(&name)),
+        ] {
+            match fs::remove_file(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ids of every block completely written
+    pub fn list(&self) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.dir_meta) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+
+        let mut block_ids = Vec::<String>::new();
+        for entry in entries.flatten() {
+            if let Ok(name) = entry.file_name().into_string() {
+                if !name.starts_with('.') {
+                    block_ids.push(_unescape(&name));
+                }
+            }
+        }
+        block_ids.sort();
+
+        Ok(block_ids)
+    }
+
+    /// Space taken by blocks and left on the file system holding them. Load is left for the caller to fill.
+    pub fn usage(&self) -> io::Result<NodeUsage> {
+        let mut usage = NodeUsage::default();
+        for block_id in self.list()? {
+            usage.disk_used += self.get_meta(&block_id)?.length;
+            usage.n_blocks += 1;
+        }
+        usage.n_in_flight = match fs::read_dir(&self.dir_blocks) {
+            Ok(entries) => entries
+                .flatten()
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with('.'))
+                .count() as u64,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+
+        // Directories of the store may not be created yet
+        let dir = self
+            .dir_blocks
+            .ancestors()
+            .find(|dir| dir.exists())
+            .unwrap_or(Path::new("."));
+        (usage.disk_total, usage.disk_free) = _get_disk_space(dir)?;
+
+        Ok(usage)
+    }
+
+    /// Move a fully staged block into place, then its metadata with the next generation
+    ///
+    /// If the node stops between both renames, the metadata left describes the previous version and the mismatch is
+    /// caught when the block is checked against it.
+    fn _commit(&self, block_id: &str, path_staged: &Path) -> io::Result<BlockMeta> {
+        let name = _validate(block_id)?;
+        let data = fs::read(path_staged)?;
+        let generation = match self.get_meta(block_id) {
+            Ok(meta) => meta.generation + 1,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 1,
+            Err(err) => {
+                log::warn!(
+                    "Cannot read metadata of block '{}', restart its generation: {}",
+                    block_id,
+                    err
+                );
+                1
+            }
+        };
+        let meta = BlockMeta {
+            checksum: crc32c(&data),
+            length: data.len() as u64,
+            generation,
+        };
+
+        fs::create_dir_all(&self.dir_meta)?;
+        let path_meta_staged = self.dir_meta.join(_temp_name(&name));
+        let mut file = fs::File::create(&path_meta_staged)?;
+        file.write_all(&meta.to_bytes())?;
+        file.sync_all()?;
+
+        fs::rename(path_staged, self.dir_blocks.join(&name))?;
+        fs::rename(path_meta_staged, self.dir_meta.join(&name))?;
+
+        // Renames only survive a crash once the directories holding them are synced
+        _sync_dir(&self.dir_blocks)?;
+        _sync_dir(&self.dir_meta)?;
+
+        Ok(meta)
+    }
+}
+
+/// Reject block ids which would clash with temp files, then give the name of the block's files
+fn _validate(block_id: &str) -> io::Result<String> {
+    if block_id.is_empty() || block_id.starts_with('.') || block_id.contains('\\') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid block id: {}", block_id),
+        ));
+    }
+
+    Ok(block_id.replace('%', "%25").replace('/', "%2F"))
+}
+
+fn _unescape(name: &str) -> String {
+    name.replace("%2F", "/").replace("%25", "%")
+}
+
+fn _temp_name(block_id: &str) -> String {
+    format!(".{}.tmp", block_id)
+}
+
+fn _sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Size and available space of the file system holding `dir`
+#[allow(clippy::unnecessary_cast)] // Field widths of statvfs differ across platforms
+fn _get_disk_space(dir: &Path) -> io::Result<(u64, u64)> {
+    let path =
+        CString::new(dir.as_os_str().as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+    // SAFETY: `path` is NUL-terminated and `stat` is only read once statvfs has filled it
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+
+    Ok((
+        stat.f_blocks as u64 * stat.f_frsize as u64,
+        stat.f_bavail as u64 * stat.f_frsize as u64,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Store in an empty directory of its own, removed when dropped
+    struct TempStore {
+        dir: PathBuf,
+        store: BlockStore,
+    }
+
+    impl TempStore {
+        fn new(name: &str) -> TempStore {
+            let dir = std::env::temp_dir().join(format!("dfs-block-store-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+
+            TempStore {
+                store: BlockStore::new(&dir),
+                dir,
+            }
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn block_is_only_visible_once_complete() {
+        let temp = TempStore::new("complete");
+
+        assert!(temp.store.write("1#0", 0, b"hello ", 11).unwrap().is_none());
+        assert!(temp.store.list().unwrap().is_empty());
+        assert_eq!(
+            temp.store.read("1#0", 0, 0).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        let meta = temp.store.write("1#0", 6, b"world", 11).unwrap().unwrap();
+        assert_eq!(meta.length, 11);
+        assert_eq!(meta.checksum, crc32c(b"hello world"));
+        assert_eq!(meta.generation, 1);
+        assert_eq!(temp.store.list().unwrap(), vec!["1#0".to_string()]);
+        assert_eq!(temp.store.read_verified("1#0").unwrap(), b"hello world");
+        assert_eq!(temp.store.read("1#0", 6, 3).unwrap(), b"wor");
+    }
+
+    #[test]
+    fn rewrite_bumps_generation() {
+        let temp = TempStore::new("rewrite");
+
+        temp.store.write("1#0", 0, b"first", 5).unwrap();
+        let meta = temp.store.write("1#0", 0, b"second", 6).unwrap().unwrap();
+
+        assert_eq!(meta.generation, 2);
+        assert_eq!(temp.store.read_verified("1#0").unwrap(), b"second");
+    }
+
+    #[test]
+    fn write_past_length_is_rejected() {
+        let temp = TempStore::new("past-length");
+
+        let err = temp.store.write("1#0", 0, b"too long", 4).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        temp.store.write("1#0", 0, b"ab", 4).unwrap();
+        let err = temp.store.write("1#0", 2, b"cde", 4).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = temp.store.write("1#0", u64::MAX, b"a", 4).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(temp.store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn write_must_continue_where_staged_data_ends() {
+        let temp = TempStore::new("gap");
+
+        let err = temp.store.write("1#0", 2, b"cd", 4).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        temp.store.write("1#0", 0, b"a", 4).unwrap();
+        let err = temp.store.write("1#0", 2, b"cd", 4).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn recover_removes_partial_writes_only() {
+        let temp = TempStore::new("recover");
+
+        temp.store.write("1#0", 0, b"done", 4).unwrap();
+        temp.store.write("1#1", 0, b"half", 8).unwrap();
+
+        assert_eq!(temp.store.recover().unwrap(), 1);
+        assert_eq!(temp.store.list().unwrap(), vec!["1#0".to_string()]);
+        assert_eq!(
+            temp.store.write("1#1", 4, b"more", 8).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn damaged_block_fails_verification() {
+        let temp = TempStore::new("damaged");
+
+        temp.store.write("1#0", 0, b"intact", 6).unwrap();
+        fs::write(temp.dir.join(DIR_BLOCKS).join("1#0"), b"broken").unwrap();
+
+        assert_eq!(
+            temp.store.read_verified("1#0").unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn block_ids_are_escaped() {
+        let temp = TempStore::new("escaped");
+
+        for block_id in ["", ".hidden", "../up#0", "a\\b"] {
+            assert_eq!(
+                temp.store.write(block_id, 0, b"x", 1).unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+
+        // Ids which look like escapes or hold '..' stay within the store and round-trip as well
+        for block_id in ["dir/sub#0", "100%2F#1", "a/../up#2"] {
+            temp.store.write(block_id, 0, b"x", 1).unwrap();
+        }
+        assert_eq!(_validate("dir/sub#0").unwrap(), "dir%2Fsub#0");
+        assert_eq!(
+            temp.store.list().unwrap(),
+            vec!["100%2F#1".to_string(), "a/../up#2".to_string(), "dir/sub#0".to_string()]
+        );
+        assert!(fs::read_dir(temp.dir.join(DIR_BLOCKS)).unwrap().count() == 3);
+
+        temp.store.delete("dir/sub#0").unwrap();
+        temp.store.delete("dir/sub#0").unwrap();
+        assert_eq!(temp.store.list().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,66 @@
+// ================================================
+// Definition for constants
+// ================================================
+
+const CRC32C_POLY: u32 = 0x82F6_3B78; // reversed Castagnoli polynomial
+const CRC32C_TABLE: [u32; 256] = _build_crc32c_table();
+
+// ================================================
+// Implementation
+// ================================================
+
+/// Compute CRC-32C (Castagnoli) checksum of given bytes
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    crc32c_update(0, bytes)
+}
+
+/// Continue a CRC-32C checksum computed from previous chunks with next chunk of bytes
+pub fn crc32c_update(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in bytes {
+        crc = CRC32C_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+
+    !crc
+}
+
+const fn _build_crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC32C_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32c_matches_known_values() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(&[0u8; 32]), 0x8A91_36AA);
+    }
+
+    #[test]
+    fn crc32c_update_continues_over_chunks() {
+        let bytes = b"The quick brown fox jumps over the lazy dog";
+        let (head, tail) = bytes.split_at(10);
+
+        assert_eq!(crc32c_update(crc32c(head), tail), crc32c(bytes));
+    }
+}
@@ -1,15 +1,44 @@
 use dotenv::dotenv;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+
+use crate::components::{db::DBBackend, packets::DEFAULT_MAX_FRAME_SIZE, placement::PlacementKind};
 
 use std::env;
 use std::str::FromStr;
 
+const DEFAULT_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024 * 1024;
+
 pub struct Configs {
     pub env_ip_dns: Ipv4Addr,
     pub env_port_receiver: u16,
     pub env_port_dns: u16,
     pub interval_heartbeat: u64,
+    pub threshold_suspect: u64,
+    pub threshold_dead: u64,
     pub timeout_channel_wait: u64,
+    pub max_frame_size: usize,
+    pub max_connections: usize,
+    pub dir_data: PathBuf,
+    pub block_size: u64,
+    pub max_file_size: u64,
+    pub replication_factor: usize,
+    pub replication_max_in_flight: usize,
+    pub replication_timeout: u64,
+    pub interval_block_report: u64,
+    pub interval_scrub: u64,
+    pub scrub_bandwidth: u64,
+    pub placement_policy: PlacementKind,
+    pub rebalance_interval: u64,
+    pub rebalance_threshold: f64,
+    pub rebalance_bandwidth: u64,
+    pub rebalance_dry_run: bool,
+    pub rack: String,
+    pub db_backend: DBBackend,
+    pub raft_peers: Vec<SocketAddr>,
+    pub raft_interval_heartbeat: u64,
+    pub raft_timeout_election: u64,
 
     pub args: Vec<String>,
 }
@@ -19,40 +48,150 @@ impl Configs {
         // Read .env file and parse
         dotenv().ok();
 
+        // Parse arguments
+        // TODO: HoangLe [May-02]: Enhance arg parsing
+        let args: Vec<String> = env::args().collect();
+
+        // Clients don't listen, and may be given DNS on the command line
+        let is_client = args.get(1).is_some_and(|mode| mode == "client");
+
         let ip_dns = match env::var("IP_DNS") {
             Ok(value) => Ipv4Addr::from_str(value.parse::<String>().unwrap().as_str())
                 .expect("Cannot parse env 'IP_DNS' to correct IP address format"),
+            Err(_) if is_client => Ipv4Addr::UNSPECIFIED,
             Err(_) => panic!("env 'IP_DNS' not existed"),
         };
         let mut port_receiver = match env::var("PORT_RECEIVER") {
             Ok(value) => value.parse::<u16>().unwrap(),
+            Err(_) if is_client => 0,
             Err(_) => panic!("env 'PORT_RECEIVER' not existed"),
         };
         let port_dns = match env::var("PORT_DNS") {
             Ok(value) => value.parse::<u16>().unwrap(),
+            Err(_) if is_client => 0,
             Err(_) => panic!("env 'PORT_DNS' not existed"),
         };
         let interval_heartbeat = match env::var("HEARTBEAT_INTERVAL_SECOND") {
             Ok(value) => value.parse::<u64>().unwrap(),
+            Err(_) if is_client => 1,
             Err(_) => panic!("env 'HEARTBEAT_INTERVAL_SECOND' not existed"),
         };
+        let threshold_suspect = match env::var("SUSPECT_AFTER_SECOND") {
+            Ok(value) => value.parse::<u64>().unwrap(),
+            Err(_) => 3 * interval_heartbeat,
+        };
+        let threshold_dead = match env::var("DEAD_AFTER_SECOND") {
+            Ok(value) => value.parse::<u64>().unwrap(),
+            Err(_) => 10 * interval_heartbeat,
+        };
         let timeout_channel_wait = match env::var("TIMEOUT_CHANNEL_WAIT") {
             Ok(value) => value.parse::<u64>().unwrap(),
             Err(_) => 1,
         };
+        let block_size = match env::var("BLOCK_SIZE_BYTE") {
+            Ok(value) => match value.parse::<u64>().unwrap() {
+                0 => panic!("env 'BLOCK_SIZE_BYTE' must be positive"),
+                block_size => block_size,
+            },
+            Err(_) => DEFAULT_BLOCK_SIZE,
+        };
+        // Largest file clients may write, which also bounds the number of blocks Master places at once
+        let max_file_size = match env::var("MAX_FILE_SIZE_BYTE") {
+            Ok(value) => value.parse::<u64>().unwrap(),
+            Err(_) => DEFAULT_MAX_FILE_SIZE,
+        };
+        // Factors travel as 1 byte, as those asked by clients
+        let replication_factor = match env::var("REPLICATION_FACTOR") {
+            Ok(value) => match value
+                .parse::<u8>()
+                .expect("env 'REPLICATION_FACTOR' must be at most 255")
+            {
+                0 => panic!("env 'REPLICATION_FACTOR' must be positive"),
+                replication_factor => replication_factor as usize,
+            },
+            Err(_) => 3,
+        };
+        let replication_max_in_flight = match env::var("REPLICATION_MAX_IN_FLIGHT") {
+            Ok(value) => value.parse::<usize>().unwrap(),
+            Err(_) => 2,
+        };
+        let replication_timeout = match env::var("REPLICATION_TIMEOUT_SECOND") {
+            Ok(value) => value.parse::<u64>().unwrap(),
+            Err(_) => 60,
+        };
+        let interval_block_report = match env::var("BLOCK_REPORT_INTERVAL_SECOND") {
+            Ok(value) => value.parse::<u64>().unwrap(),
+            Err(_) => 60,
+        };
+        let interval_scrub = match env::var("SCRUB_INTERVAL_SECOND") {
+            Ok(value) => value.parse::<u64>().unwrap(),
+            Err(_) => 6 * 3600,
+        };
+        let scrub_bandwidth = match env::var("SCRUB_BANDWIDTH_BYTE_PER_SECOND") {
+            Ok(value) => value.parse::<u64>().unwrap(),
+            Err(_) => 10 * 1024 * 1024,
+        };
+        let placement_policy = match env::var("PLACEMENT_POLICY") {
+            Ok(value) => PlacementKind::from_str(value.as_str()).unwrap_or_else(|err| panic!("{}", err)),
+            Err(_) => PlacementKind::RoundRobin,
+        };
+        let rebalance_interval = match env::var("REBALANCE_INTERVAL_SECOND") {
+            Ok(value) => value.parse::<u64>().unwrap(),
+            Err(_) => 600,
+        };
+        // Percentage points a node's disk utilisation may deviate from the cluster mean
+        let rebalance_threshold = match env::var("REBALANCE_THRESHOLD_PERCENT") {
+            Ok(value) => value.parse::<f64>().unwrap(),
+            Err(_) => 10.0,
+        };
+        let rebalance_bandwidth = match env::var("REBALANCE_BANDWIDTH_BYTE_PER_SECOND") {
+            Ok(value) => value.parse::<u64>().unwrap(),
+            Err(_) => 10 * 1024 * 1024,
+        };
+        let rebalance_dry_run = match env::var("REBALANCE_DRY_RUN") {
+            Ok(value) => value.parse::<bool>().unwrap(),
+            Err(_) => false,
+        };
+        // Rack or zone a Data node lies in, used to spread replicas across racks
+        let rack = env::var("RACK").unwrap_or_default();
+        let max_frame_size = match env::var("MAX_FRAME_SIZE_BYTE") {
+            Ok(value) => value.parse::<usize>().unwrap(),
+            Err(_) => DEFAULT_MAX_FRAME_SIZE,
+        };
+        // Connections read at once by thread:Receiver, each on its own thread. Further ones are refused.
+        let max_connections = match env::var("MAX_CONNECTIONS") {
+            Ok(value) => value.parse::<usize>().unwrap(),
+            Err(_) => 256,
+        };
 
-        // Parse arguments
-        // TODO: HoangLe [May-02]: Enhance arg parsing
-        let args: Vec<String> = env::args().collect();
+        // Masters listed here form a Raft group. Empty means a single Master.
+        let raft_peers = match env::var("RAFT_PEERS") {
+            Ok(value) => value
+                .split(',')
+                .filter(|addr| !addr.trim().is_empty())
+                .map(|addr| {
+                    SocketAddr::from_str(addr.trim()).expect("Cannot parse env 'RAFT_PEERS' to list of addresses")
+                })
+                .collect(),
+            Err(_) => vec![],
+        };
+        let raft_interval_heartbeat = match env::var("RAFT_HEARTBEAT_INTERVAL_MS") {
+            Ok(value) => value.parse::<u64>().unwrap(),
+            Err(_) => 200,
+        };
+        let raft_timeout_election = match env::var("RAFT_ELECTION_TIMEOUT_MS") {
+            Ok(value) => value.parse::<u64>().unwrap(),
+            Err(_) => 5 * raft_interval_heartbeat,
+        };
 
-        // Set up logger
+        // Set up logger. Clients only log problems so as not to get in the way of their output.
         if env::var("RUST_LOG").is_err() {
-            env::set_var("RUST_LOG", "info");
+            env::set_var("RUST_LOG", if is_client { "warn" } else { "info" });
         }
         env_logger::init();
 
         // Override some config
-        if args.len() >= 3 {
+        if args.len() >= 3 && !is_client {
             match args[2].parse::<u16>() {
                 Ok(port) => {
                     log::info!("'port' argument specified. Override the default value.");
@@ -64,13 +203,87 @@ impl Configs {
             }
         }
 
+        // Data nodes sharing a host keep their files apart by default
+        let dir_data = match env::var("DIR_DATA") {
+            Ok(value) => PathBuf::from(value),
+            Err(_) => PathBuf::from(format!("data/{}", port_receiver)),
+        };
+
+        // Metadata is kept on disk unless explicitly asked to stay in memory
+        let db_backend = match env::var("DB_PATH") {
+            Ok(value) if value == ":memory:" => DBBackend::InMemory,
+            Ok(value) => DBBackend::File(PathBuf::from(value)),
+            Err(_) => DBBackend::File(PathBuf::from(format!("meta/{}.db", port_receiver))),
+        };
+
         Configs {
             env_ip_dns: ip_dns,
             env_port_receiver: port_receiver,
             env_port_dns: port_dns,
             interval_heartbeat,
+            threshold_suspect,
+            threshold_dead,
             timeout_channel_wait,
+            max_frame_size,
+            max_connections,
+            dir_data,
+            block_size,
+            max_file_size,
+            replication_factor,
+            replication_max_in_flight,
+            replication_timeout,
+            interval_block_report,
+            interval_scrub,
+            scrub_bandwidth,
+            placement_policy,
+            rebalance_interval,
+            rebalance_threshold,
+            rebalance_bandwidth,
+            rebalance_dry_run,
+            rack,
+            db_backend,
+            raft_peers,
+            raft_interval_heartbeat,
+            raft_timeout_election,
             args,
         }
     }
 }
+
+#[cfg(test)]
+impl Default for Configs {
+    /// Defaults of `initialize` for a node listening on 7002, without reading the environment. Metadata stays in memory.
+    fn default() -> Configs {
+        Configs {
+            env_ip_dns: Ipv4Addr::LOCALHOST,
+            env_port_receiver: 7002,
+            env_port_dns: 7001,
+            interval_heartbeat: 1,
+            threshold_suspect: 3,
+            threshold_dead: 10,
+            timeout_channel_wait: 1,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_connections: 256,
+            dir_data: PathBuf::from("data/7002"),
+            block_size: DEFAULT_BLOCK_SIZE,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            replication_factor: 3,
+            replication_max_in_flight: 2,
+            replication_timeout: 60,
+            interval_block_report: 60,
+            interval_scrub: 6 * 3600,
+            scrub_bandwidth: 10 * 1024 * 1024,
+            placement_policy: PlacementKind::RoundRobin,
+            rebalance_interval: 600,
+            rebalance_threshold: 10.0,
+            rebalance_bandwidth: 10 * 1024 * 1024,
+            rebalance_dry_run: false,
+            rack: String::new(),
+            db_backend: DBBackend::InMemory,
+            raft_peers: vec![],
+            raft_interval_heartbeat: 200,
+            raft_timeout_election: 1000,
+            args: vec![],
+        }
+    }
+}
@@ -1,41 +1,249 @@
-use std::{convert::From, net::SocketAddrV4};
+use std::{cell::RefCell, convert::From, fs, net::SocketAddrV4, path::PathBuf, rc::Rc};
 
-use crate::components::entity::node_roles::Role;
+use crate::components::{entity::node_roles::Role, errors::ParseError, packets::MAX_STR_LEN, state_sync::StateChange};
 use chrono::{DateTime, Local};
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, types::Type, Connection, Result, Row};
 use std::{net::Ipv4Addr, str::FromStr};
 
 // ================================================
 // Definitions for DB entry
 // ================================================
 
+/// Inode of the root directory, the only one without parent
+pub const ROOT_INODE: u64 = 1;
+
+#[rustfmt::skip]
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileState {
+    Pending             = 0,
+    Complete            = 1,
+    UnderReplicated     = 2,
+}
+
 pub struct FileInfoEntry {
+    /// Inode of the file in the namespace. 0 until the file is created.
+    pub inode: u64,
+    /// Path of the file from the root directory, names separated by '/'
     pub filename: String,
-    pub is_local: bool,
-    pub path: Option<String>,
+    pub size: u64,
+    pub checksum: u32,
+    pub state: FileState,
+    pub last_updated: Option<DateTime<Local>>,
+    /// Number of replicas wanted for each block. 0 follows the cluster-wide factor.
+    pub replication_factor: usize,
+}
+
+/// Directory or file in the namespace tree. Inodes point to their parent, so moving a directory moves everything
+/// under it at once.
+pub struct InodeEntry {
+    pub inode: u64,
+    pub parent: u64,
+    pub name: String,
+    pub is_dir: bool,
+    /// Factor given to files later created under a directory, 0 if none. Unused for files.
+    pub replication_factor: usize,
+    pub last_updated: Option<DateTime<Local>>,
+}
+
+/// Entry of a directory listing. Directories are always Complete and have no size.
+pub struct DirEntry {
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub state: FileState,
+    pub replication_factor: usize,
+}
+
+/// Part of a file's content placed and replicated on its own
+pub struct BlockInfoEntry {
+    pub block_id: String,
+    /// Inode of the file the block belongs to
+    pub inode: u64,
+    pub index: u64,
+    pub size: u64,
+    /// Unknown (0) until the first replica is stored
+    pub checksum: u32,
+    pub state: FileState,
+    pub last_updated: Option<DateTime<Local>>,
+}
+
+pub struct ReplicaInfoEntry {
+    pub block_id: String,
     pub node_id: String,
     pub last_updated: Option<DateTime<Local>>,
 }
 
+#[rustfmt::skip]
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NodeStatus {
+    Alive               = 0,
+    Suspect             = 1,
+    Dead                = 2,
+}
+
+/// Persistent state of a Master taking part in a Raft group
+pub struct RaftStateEntry {
+    pub term: u64,
+    pub voted_for: Option<String>,
+    pub base_index: u64,
+    pub base_term: u64,
+    pub last_applied: u64,
+}
+
+/// Raft log entry whose changes are kept encoded as in packet StateSync
+pub struct RaftLogEntry {
+    pub index: u64,
+    pub term: u64,
+    pub payload: Vec<u8>,
+}
+
 pub struct NodeInfoEntry {
     pub node_id: String,
     pub ip: Option<Ipv4Addr>,
     pub port: u16,
     pub role: Role,
     pub last_updated: Option<DateTime<Local>>,
+    pub status: NodeStatus,
+    /// Rack or zone label given by the node. Empty if not set.
+    pub rack: String,
+    pub usage: NodeUsage,
+}
+
+/// Disk usage and activity a Data node reports with each HeartbeatAck
+#[derive(Clone, Copy, Default, Debug)]
+pub struct NodeUsage {
+    /// Size of the file system holding the node's blocks
+    pub disk_total: u64,
+    /// Bytes taken by the node's blocks
+    pub disk_used: u64,
+    /// Bytes still available on the file system
+    pub disk_free: u64,
+    pub n_blocks: u64,
+    /// Blocks being written
+    pub n_in_flight: u64,
+    /// Requests served since the previous HeartbeatAck
+    pub load: u64,
 }
 
 // ================================================
 // Implementations
 // ================================================
 
+impl std::fmt::Display for DBBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DBBackend::InMemory => write!(f, "in-memory"),
+            DBBackend::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl TryFrom<u8> for FileState {
+    type Error = ParseError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(FileState::Pending),
+            1 => Ok(FileState::Complete),
+            2 => Ok(FileState::UnderReplicated),
+            _ => Err(ParseError::incorrect_enum_value("FileState", value)),
+        }
+    }
+}
+
+impl From<FileState> for u8 {
+    fn from(value: FileState) -> Self {
+        match value {
+            FileState::Pending => 0,
+            FileState::Complete => 1,
+            FileState::UnderReplicated => 2,
+        }
+    }
+}
+
+impl std::fmt::Display for FileState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            FileState::Pending => "Pending",
+            FileState::Complete => "Complete",
+            FileState::UnderReplicated => "UnderReplicated",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl TryFrom<u8> for NodeStatus {
+    type Error = ParseError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(NodeStatus::Alive),
+            1 => Ok(NodeStatus::Suspect),
+            2 => Ok(NodeStatus::Dead),
+            _ => Err(ParseError::incorrect_enum_value("NodeStatus", value)),
+        }
+    }
+}
+
+impl From<NodeStatus> for u8 {
+    fn from(value: NodeStatus) -> Self {
+        match value {
+            NodeStatus::Alive => 0,
+            NodeStatus::Suspect => 1,
+            NodeStatus::Dead => 2,
+        }
+    }
+}
+
+impl std::fmt::Display for NodeStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            NodeStatus::Alive => "Alive",
+            NodeStatus::Suspect => "Suspect",
+            NodeStatus::Dead => "Dead",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 impl FileInfoEntry {
-    pub fn initialize(filename: String, is_local: bool, path: Option<String>, node_id: String) -> FileInfoEntry {
+    pub fn initialize(filename: String, size: u64, checksum: u32, replication_factor: usize) -> FileInfoEntry {
         FileInfoEntry {
+            inode: 0,
             filename,
-            is_local,
-            path,
-            node_id,
+            size,
+            checksum,
+            state: FileState::Pending,
+            last_updated: None,
+            replication_factor,
+        }
+    }
+}
+
+impl InodeEntry {
+    pub fn initialize(parent: u64, name: String, is_dir: bool) -> InodeEntry {
+        InodeEntry {
+            inode: 0,
+            parent,
+            name,
+            is_dir,
+            replication_factor: 0,
+            last_updated: None,
+        }
+    }
+}
+
+impl BlockInfoEntry {
+    pub fn initialize(inode: u64, index: u64, size: u64) -> BlockInfoEntry {
+        BlockInfoEntry {
+            block_id: _get_block_id(inode, index),
+            inode,
+            index,
+            size,
+            checksum: 0,
+            state: FileState::Pending,
             last_updated: None,
         }
     }
@@ -49,6 +257,9 @@ impl NodeInfoEntry {
             role,
             port,
             last_updated: None,
+            status: NodeStatus::Alive,
+            rack: String::new(),
+            usage: NodeUsage::default(),
         }
     }
 }
@@ -57,17 +268,30 @@ impl NodeInfoEntry {
 // Definitions for DB instance
 // ================================================
 
-pub trait InMemDB<T> {
-    fn create_db(&mut self) -> Result<()>;
-    // fn upsert(&self, ip: Ipv4Addr, port: u16, role: Role) -> Result<()>;
+/// Where a metadata DB keeps its tables
+#[derive(Clone)]
+pub enum DBBackend {
+    InMemory,
+    File(PathBuf),
+}
+
+pub trait MetaDB<T> {
+    fn create_db(&mut self, backend: &DBBackend) -> Result<()>;
 }
 
 pub struct FileInfoDB {
     db_name: &'static str,
-    db_conn: Option<Connection>,
+    db_conn: Option<Rc<Connection>>,
+    journal: RefCell<Vec<StateChange>>,
 }
 
 pub struct NodeInfoDB {
+    db_name: &'static str,
+    db_conn: Option<Rc<Connection>>,
+    journal: RefCell<Vec<StateChange>>,
+}
+
+pub struct RaftLogDB {
     db_name: &'static str,
     db_conn: Option<Connection>,
 }
@@ -76,230 +300,1690 @@ pub struct NodeInfoDB {
 // Implementations
 // ================================================
 
-impl InMemDB<FileInfoEntry> for FileInfoDB {
-    fn create_db(&mut self) -> Result<()> {
-        log::info!("Creating db {}", self.db_name);
+impl MetaDB<FileInfoEntry> for FileInfoDB {
+    fn create_db(&mut self, backend: &DBBackend) -> Result<()> {
+        log::info!("Creating db {} at {}", self.db_name, backend);
 
-        let conn = Connection::open_in_memory()?;
+        let conn = _open_connection(backend)?;
 
+        // Versions before the directory tree kept files by their whole path, which cannot be read any more
+        if _has_column(&conn, self.db_name, "filename")? {
+            return Err(rusqlite::Error::InvalidColumnName(format!(
+                "{}.filename, as files are kept by path. Start from an empty db instead.",
+                self.db_name
+            )));
+        }
+
+        conn.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {}_inode (
+                inode               INTEGER PRIMARY KEY AUTOINCREMENT
+                ,parent             INTEGER NOT NULL
+                ,name               TEXT    NOT NULL
+                ,is_dir             INTEGER NOT NULL
+                ,replication_factor INTEGER NOT NULL DEFAULT 0
+                ,last_updated       TEXT    NOT NULL
+                ,UNIQUE (parent, name)
+            );",
+                &self.db_name
+            )
+            .as_str(),
+            [],
+        )?;
+        conn.execute(
+            format!(
+                "INSERT OR IGNORE INTO {}_inode
+                (inode, parent, name, is_dir, last_updated)
+                VALUES (?1, 0, '', 1, ?2);",
+                &self.db_name
+            )
+            .as_str(),
+            params![ROOT_INODE, Local::now().to_rfc3339()],
+        )?;
         conn.execute(
             format!(
                 "CREATE TABLE IF NOT EXISTS {} (
-                filename        TEXT    PRIMARY KEY
-                ,is_local       BOOLEAN NOT NULL
-                ,path           TEXT
-                ,node           TEXT    NOT NULL
+                inode           INTEGER PRIMARY KEY
+                ,size           INTEGER NOT NULL
+                ,checksum       INTEGER NOT NULL
+                ,state          INTEGER NOT NULL
+                ,last_updated   TEXT    NOT NULL
+                ,replication_factor INTEGER NOT NULL DEFAULT 0
+            );",
+                &self.db_name
+            )
+            .as_str(),
+            [],
+        )?;
+        conn.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {}_block (
+                block_id        TEXT    PRIMARY KEY
+                ,inode          INTEGER NOT NULL
+                ,idx            INTEGER NOT NULL
+                ,size           INTEGER NOT NULL
+                ,checksum       INTEGER NOT NULL
+                ,state          INTEGER NOT NULL
+                ,last_updated   TEXT    NOT NULL
+            );",
+                &self.db_name
+            )
+            .as_str(),
+            [],
+        )?;
+        conn.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {}_block_replica (
+                block_id        TEXT    NOT NULL
+                ,node_id        TEXT    NOT NULL
                 ,last_updated   TEXT    NOT NULL
+                ,PRIMARY KEY (block_id, node_id)
             );",
                 &self.db_name
             )
             .as_str(),
-            // [&self.db_name],
             [],
         )?;
 
-        self.db_conn = Some(conn);
+        self.db_conn = Some(Rc::new(conn));
 
         Ok(())
     }
 }
 
 impl FileInfoDB {
-    pub fn intialize(db_name: &'static str) -> FileInfoDB {
-        let mut db = FileInfoDB { db_name, db_conn: None };
+    pub fn initialize(db_name: &'static str, backend: &DBBackend) -> FileInfoDB {
+        let mut db = FileInfoDB {
+            db_name,
+            db_conn: None,
+            journal: RefCell::new(vec![]),
+        };
 
-        let _ = db.create_db();
+        if let Err(err) = db.create_db(backend) {
+            panic!("Cannot create db {}: {}", db_name, err);
+        }
 
         db
     }
 
-    pub fn upsert(&self, info: &FileInfoEntry) -> Result<()> {
-        log::debug!("Upsert..");
-
-        let current = Local::now();
+    // ================================================
+    // Namespace
+    // ================================================
 
-        match self.db_conn.as_ref().unwrap().execute(
-            format!(
-                "INSERT INTO {}
-                (filename, is_local, path, node, last_updated)
-                VALUES (?1, ?2, ?3, ?4, ?5)
-                ON CONFLICT(filename) DO UPDATE SET
-                    is_local = ?2,
-                    path = ?3,
-                    node = ?4,
-                    last_updated = ?5
-                ;",
-                self.db_name
-            )
-            .as_str(),
-            params![
-                info.filename,
-                info.is_local,
-                info.path,
-                info.node_id,
-                current.to_rfc3339(),
-            ],
-        ) {
-            Ok(size) => log::debug!("Upserted: {}", size),
-            Err(err) => log::error!("{}", err),
+    /// Find the inode at `path`. The empty path is the root directory.
+    pub fn resolve(&self, path: &str) -> Result<Option<InodeEntry>> {
+        let names = match _split_path(path) {
+            Some(names) => names,
+            None => return Ok(None),
         };
 
-        Ok(())
+        let mut entry = self.get_inode(ROOT_INODE)?;
+        for name in names {
+            entry = match entry {
+                Some(entry) if entry.is_dir => self.get_child(entry.inode, name)?,
+                _ => return Ok(None),
+            };
+        }
+
+        Ok(entry)
     }
 
-    pub fn get_file_info(&self, filename: &String) -> Result<Vec<FileInfoEntry>> {
+    pub fn get_inode(&self, inode: u64) -> Result<Option<InodeEntry>> {
         let mut stmt = self
             .db_conn
             .as_ref()
             .unwrap()
-            .prepare(format!("SELECT * FROM {} WHERE filename = ?1;", self.db_name).as_str())?;
-        let rows = stmt.query_map([&filename], |row| {
-            log::debug!("inside: {:?}", row.get::<usize, String>(3));
-
-            Ok(FileInfoEntry {
-                filename: row.get(0)?,
-                is_local: row.get::<usize, i32>(1)? == 1,
-                path: row.get(2)?,
-                node_id: row.get(3)?,
-                last_updated: Some(row.get::<usize, String>(4)?.parse().unwrap()),
-            })
-        })?;
+            .prepare(format!("SELECT * FROM {}_inode WHERE inode = ?1;", self.db_name).as_str())?;
+        let mut rows = stmt.query_map([inode], _parse_inode_info)?;
+
+        rows.next().transpose()
+    }
+
+    pub fn get_child(&self, parent: u64, name: &str) -> Result<Option<InodeEntry>> {
+        let mut stmt = self
+            .db_conn
+            .as_ref()
+            .unwrap()
+            .prepare(format!("SELECT * FROM {}_inode WHERE parent = ?1 AND name = ?2;", self.db_name).as_str())?;
+        let mut rows = stmt.query_map(params![parent, name], _parse_inode_info)?;
+
+        rows.next().transpose()
+    }
+
+    /// Inodes right under directory `inode`, sorted by name
+    pub fn get_children(&self, inode: u64) -> Result<Vec<InodeEntry>> {
+        let mut stmt = self
+            .db_conn
+            .as_ref()
+            .unwrap()
+            .prepare(format!("SELECT * FROM {}_inode WHERE parent = ?1 ORDER BY name;", self.db_name).as_str())?;
+        let rows = stmt.query_map([inode], _parse_inode_info)?;
 
         rows.collect()
     }
-}
 
-impl InMemDB<NodeInfoEntry> for NodeInfoDB {
-    fn create_db(&mut self) -> Result<()> {
-        log::info!("Creating db {}", self.db_name);
+    /// Path from the root directory to `inode`
+    pub fn get_path(&self, inode: u64) -> Result<Option<String>> {
+        let mut names = Vec::<String>::new();
+        let mut current = inode;
+        while current != ROOT_INODE {
+            match self.get_inode(current)? {
+                Some(entry) => {
+                    names.push(entry.name);
+                    current = entry.parent;
+                }
+                None => return Ok(None),
+            }
+        }
+        names.reverse();
 
-        let conn = Connection::open_in_memory()?;
+        Ok(Some(names.join("/")))
+    }
 
+    /// Insert an inode, or overwrite it when replayed. An entry whose inode is 0 gets a number never used before.
+    /// Return the inode number.
+    pub fn insert_inode(&self, entry: &InodeEntry) -> Result<u64> {
+        let conn = self.db_conn.as_ref().unwrap();
         conn.execute(
             format!(
-                "CREATE TABLE IF NOT EXISTS {} (
-                node_id         TEXT    PRIMARY KEY
-                ,ip             TEXT    NOT NULL
-                ,port           INTEGER NOT NULL
-                ,role           INTEGER NOT NULL
-                ,last_updated   TEXT    NOT NULL
-            );",
-                &self.db_name
+                "INSERT INTO {}_inode
+                (inode, parent, name, is_dir, replication_factor, last_updated)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                ON CONFLICT(inode) DO UPDATE SET
+                    parent = ?2,
+                    name = ?3,
+                    is_dir = ?4,
+                    replication_factor = ?5,
+                    last_updated = ?6
+                ;",
+                self.db_name
             )
             .as_str(),
-            [],
+            params![
+                match entry.inode {
+                    0 => None,
+                    inode => Some(inode),
+                },
+                entry.parent,
+                entry.name,
+                entry.is_dir,
+                entry.replication_factor,
+                Local::now().to_rfc3339(),
+            ],
         )?;
+        let inode = match entry.inode {
+            0 => conn.last_insert_rowid() as u64,
+            inode => inode,
+        };
 
-        self.db_conn = Some(conn);
+        self.journal.borrow_mut().push(StateChange::CreateInode {
+            inode,
+            parent: entry.parent,
+            name: entry.name.clone(),
+            is_dir: entry.is_dir,
+        });
 
-        Ok(())
+        Ok(inode)
     }
-}
 
-impl NodeInfoDB {
-    pub fn intialize(db_name: &'static str) -> NodeInfoDB {
-        let mut db = NodeInfoDB { db_name, db_conn: None };
+    /// Give `inode` a new parent and name
+    pub fn move_inode(&self, inode: u64, parent: u64, name: &String) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute(
+            format!(
+                "UPDATE {}_inode SET parent = ?2, name = ?3, last_updated = ?4 WHERE inode = ?1;",
+                self.db_name
+            )
+            .as_str(),
+            params![inode, parent, name, Local::now().to_rfc3339()],
+        )?;
 
-        let _ = db.create_db();
+        self.journal.borrow_mut().push(StateChange::MoveInode {
+            inode,
+            parent,
+            name: name.clone(),
+        });
 
-        db
+        Ok(())
     }
 
-    pub fn upsert(&self, ip: Ipv4Addr, port: u16, role: Role) -> Result<()> {
-        log::debug!("Upsert..");
+    /// Remove `inode` with everything under it: directories, files, their blocks and replicas. Return the removed
+    /// replicas.
+    pub fn remove_inode(&self, inode: u64) -> Result<Vec<ReplicaInfoEntry>> {
+        let conn = self.db_conn.as_ref().unwrap();
+        let tree = format!(
+            "WITH RECURSIVE tree(inode) AS (
+                SELECT ?1
+                UNION ALL
+                SELECT i.inode FROM {}_inode i JOIN tree t ON i.parent = t.inode
+            )",
+            self.db_name
+        );
 
-        let current = Local::now();
+        let replicas: Vec<ReplicaInfoEntry> = conn
+            .prepare(
+                format!(
+                    "{1} SELECT r.* FROM {0}_block_replica r JOIN {0}_block b ON b.block_id = r.block_id
+                    WHERE b.inode IN (SELECT inode FROM tree);",
+                    self.db_name, tree
+                )
+                .as_str(),
+            )?
+            .query_map([inode], _parse_replica_info)?
+            .collect::<Result<_>>()?;
 
-        match self.db_conn.as_ref().unwrap().execute(
+        conn.execute(
             format!(
-                "INSERT INTO {}
-                (node_id, ip, port, role, last_updated)
-                VALUES (?1, ?2, ?3, ?4, ?5)
-                ON CONFLICT(node_id) DO UPDATE SET
-                    ip = ?2,
-                    port = ?3,
-                    role = ?4,
-                    last_updated = ?5
-                ;",
-                self.db_name
+                "{1} DELETE FROM {0}_block_replica
+                WHERE block_id IN (SELECT block_id FROM {0}_block WHERE inode IN (SELECT inode FROM tree));",
+                self.db_name, tree
             )
             .as_str(),
-            params![
-                SocketAddrV4::new(ip, port).to_string(),
-                ip.to_string(),
-                port,
-                u8::from(&role),
-                current.to_rfc3339(),
-            ],
-        ) {
-            Ok(size) => log::info!("Upserted: {}", size),
-            Err(err) => log::error!("{}", err),
-        };
+            [inode],
+        )?;
+        for table in ["_block", "", "_inode"] {
+            conn.execute(
+                format!(
+                    "{2} DELETE FROM {0}{1} WHERE inode IN (SELECT inode FROM tree);",
+                    self.db_name, table, tree
+                )
+                .as_str(),
+                [inode],
+            )?;
+        }
 
-        Ok(())
+        self.journal.borrow_mut().push(StateChange::RemoveInode { inode });
+
+        Ok(replicas)
     }
 
-    pub fn get_node_info(&self, ip: Ipv4Addr, port: u16) -> Result<Vec<NodeInfoEntry>> {
-        let node_id = _get_node_id(&ip, port);
+    /// Create directory `path` and its missing parents. Return its inode, or None if a file is in the way.
+    pub fn make_dir(&self, path: &str) -> Result<Option<u64>> {
+        match _split_path(path) {
+            Some(names) => self._make_dirs(&names),
+            None => Ok(None),
+        }
+    }
 
-        let mut stmt = self
-            .db_conn
-            .as_ref()
-            .unwrap()
-            .prepare(format!("SELECT * FROM {} WHERE node_id = ?1;", self.db_name).as_str())?;
-        let rows = stmt.query_map([&node_id], |row| {
-            log::debug!("inside: {:?}", row.get::<usize, String>(3));
-
-            let ip_str: String = row.get(1)?;
-            let ip = match Ipv4Addr::from_str(ip_str.as_str()) {
-                Ok(ip) => Some(ip),
-                Err(e) => {
-                    log::error!("Cannot parse following to Ipv4: {}: {}", ip_str, e);
-                    None
-                }
+    fn _make_dirs(&self, names: &[&str]) -> Result<Option<u64>> {
+        let mut inode = ROOT_INODE;
+        for name in names {
+            inode = match self.get_child(inode, name)? {
+                Some(child) if child.is_dir => child.inode,
+                Some(_) => return Ok(None),
+                None => self.insert_inode(&InodeEntry::initialize(inode, name.to_string(), true))?,
             };
+        }
 
-            Ok(NodeInfoEntry {
-                node_id: row.get(0)?,
-                ip: ip,
-                port: row.get::<usize, u16>(2)?,
-                role: Role::from(row.get::<usize, u8>(3)?),
-                last_updated: Some(row.get::<usize, String>(4)?.parse().unwrap()),
-            })
-        })?;
+        Ok(Some(inode))
+    }
 
-        rows.collect()
+    /// Create file `path` and its missing parent directories. An existing file is removed and replaced by a new inode,
+    /// so that blocks of the new content never share ids with replicas of the old one. Return the inode with the
+    /// replicas of the replaced file, or None if a directory lies at `path` or a file lies on the way.
+    pub fn create_file(&self, path: &str) -> Result<Option<(u64, Vec<ReplicaInfoEntry>)>> {
+        let names = match _split_path(path) {
+            Some(names) => names,
+            None => return Ok(None),
+        };
+        let (name, dirs) = match names.split_last() {
+            Some(split) => split,
+            None => return Ok(None),
+        };
+        let parent = match self._make_dirs(dirs)? {
+            Some(parent) => parent,
+            None => return Ok(None),
+        };
+
+        let replicas = match self.get_child(parent, name)? {
+            Some(child) if !child.is_dir => self.remove_inode(child.inode)?,
+            Some(_) => return Ok(None),
+            None => vec![],
+        };
+        let inode = self.insert_inode(&InodeEntry::initialize(parent, name.to_string(), false))?;
+
+        Ok(Some((inode, replicas)))
     }
 
-    pub fn get_data_nodes(&self) -> Result<Vec<NodeInfoEntry>> {
-        let mut stmt = self
+    /// Move a file or directory from `src` to `dst`. `dst` must not exist yet and its parent must be a directory
+    /// outside `src`. Return whether it moved.
+    pub fn rename(&self, src: &str, dst: &str) -> Result<bool> {
+        let entry = match self.resolve(src)? {
+            Some(entry) if entry.inode != ROOT_INODE => entry,
+            _ => return Ok(false),
+        };
+        let names = match _split_path(dst) {
+            Some(names) => names,
+            None => return Ok(false),
+        };
+        let (name, dirs) = match names.split_last() {
+            Some(split) => split,
+            None => return Ok(false),
+        };
+        let parent = match self.resolve(&dirs.join("/"))? {
+            Some(parent) if parent.is_dir => parent,
+            _ => return Ok(false),
+        };
+        if self.get_child(parent.inode, name)?.is_some() {
+            return Ok(false);
+        }
+
+        // Paths under the moved entry grow with the new name, and must still fit in packets
+        let dst = names.join("/");
+        let len_max: usize = self
             .db_conn
             .as_ref()
             .unwrap()
-            .prepare(format!("SELECT * FROM {} WHERE role = ?1;", self.db_name).as_str())?;
-        let rows = stmt.query_map([&u8::from(&Role::Data)], |row| {
-            let ip_str: String = row.get(1)?;
-            let ip = match Ipv4Addr::from_str(ip_str.as_str()) {
-                Ok(ip) => Some(ip),
-                Err(e) => {
-                    log::error!("Cannot parse following to Ipv4: {}: {}", ip_str, e);
-                    None
-                }
+            .prepare(format!("{} SELECT MAX(LENGTH(CAST(path AS BLOB))) FROM tree;", self._tree()).as_str())?
+            .query_row(params![entry.inode, dst], |row| row.get(0))?;
+        if len_max > MAX_STR_LEN {
+            log::warn!("Cannot move '{}' to '{}': paths under it would be too long", src, dst);
+            return Ok(false);
+        }
+
+        // A directory cannot be moved under itself
+        let mut ancestor = parent.inode;
+        while ancestor != ROOT_INODE {
+            if ancestor == entry.inode {
+                return Ok(false);
+            }
+            ancestor = match self.get_inode(ancestor)? {
+                Some(inode) => inode.parent,
+                None => return Ok(false),
             };
+        }
 
-            Ok(NodeInfoEntry {
-                node_id: row.get(0)?,
-                ip: ip,
-                port: row.get::<usize, u16>(2)?,
-                role: Role::from(row.get::<usize, u8>(3)?),
-                last_updated: Some(row.get::<usize, String>(4)?.parse().unwrap()),
+        self.move_inode(entry.inode, parent.inode, &name.to_string())?;
+
+        Ok(true)
+    }
+
+    /// Remove `path`, and everything under it if `is_recursive`. Return replicas of the removed blocks so that their
+    /// nodes delete them, or None if nothing lies at `path` or it is a non-empty directory and not `is_recursive`.
+    pub fn delete(&self, path: &str, is_recursive: bool) -> Result<Option<Vec<ReplicaInfoEntry>>> {
+        let entry = match self.resolve(path)? {
+            Some(entry) if entry.inode != ROOT_INODE => entry,
+            _ => return Ok(None),
+        };
+        if entry.is_dir && !is_recursive && !self.get_children(entry.inode)?.is_empty() {
+            return Ok(None);
+        }
+
+        self.remove_inode(entry.inode).map(Some)
+    }
+
+    /// List directory `path`, with its subdirectories if `is_recursive`. A file lists itself. None if nothing lies at
+    /// `path`.
+    pub fn list(&self, path: &str, is_recursive: bool) -> Result<Option<Vec<DirEntry>>> {
+        let entry = match self.resolve(path)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let path = _split_path(path).unwrap_or_default().join("/");
+
+        let mut stmt = self.db_conn.as_ref().unwrap().prepare(
+            format!(
+                "{1} SELECT t.path, i.is_dir, f.size, f.state, i.replication_factor, f.replication_factor
+                FROM tree t
+                JOIN {0}_inode i ON i.inode = t.inode
+                LEFT JOIN {0} f ON f.inode = t.inode
+                WHERE (t.depth > 0 OR i.is_dir = 0) AND (?3 OR t.depth <= 1)
+                ORDER BY t.path;",
+                self.db_name,
+                self._tree()
+            )
+            .as_str(),
+        )?;
+        let rows = stmt.query_map(params![entry.inode, path, is_recursive], |row| {
+            let is_dir: bool = row.get(1)?;
+            Ok(DirEntry {
+                path: row.get(0)?,
+                is_dir,
+                size: row.get::<usize, Option<u64>>(2)?.unwrap_or(0),
+                state: match (is_dir, row.get::<usize, Option<u8>>(3)?) {
+                    (true, _) => FileState::Complete,
+                    (false, Some(_)) => _get_enum(row, 3)?,
+                    (false, None) => FileState::Pending,
+                },
+                replication_factor: match is_dir {
+                    true => row.get(4)?,
+                    false => row.get::<usize, Option<usize>>(5)?.unwrap_or(0),
+                },
             })
         })?;
 
-        rows.collect()
+        rows.collect::<Result<_>>().map(Some)
     }
-}
 
-pub fn _get_node_id(ip: &Ipv4Addr, port: u16) -> String {
-    SocketAddrV4::new(ip.clone(), port).to_string()
+    /// Common table `tree(inode, path, depth)` of inode ?1 whose path is ?2, and of every inode under it
+    fn _tree(&self) -> String {
+        format!(
+            "WITH RECURSIVE tree(inode, path, depth) AS (
+                SELECT ?1, ?2, 0
+                UNION ALL
+                SELECT i.inode, CASE t.path WHEN '' THEN i.name ELSE t.path || '/' || i.name END, t.depth + 1
+                FROM {}_inode i JOIN tree t ON i.parent = t.inode
+            )",
+            self.db_name
+        )
+    }
+
+    // ================================================
+    // Files
+    // ================================================
+
+    /// Insert or overwrite file `info.inode`, which must have been created in the namespace
+    pub fn upsert(&self, info: &FileInfoEntry) -> Result<()> {
+        log::debug!("Upsert..");
+
+        let current = Local::now();
+        let conn = self.db_conn.as_ref().unwrap();
+
+        conn.execute(
+            format!(
+                "INSERT INTO {}
+                (inode, size, checksum, state, last_updated, replication_factor)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                ON CONFLICT(inode) DO UPDATE SET
+                    size = ?2,
+                    checksum = ?3,
+                    state = ?4,
+                    last_updated = ?5,
+                    replication_factor = ?6
+                ;",
+                self.db_name
+            )
+            .as_str(),
+            params![
+                info.inode,
+                info.size,
+                info.checksum,
+                u8::from(info.state),
+                current.to_rfc3339(),
+                info.replication_factor,
+            ],
+        )?;
+
+        self.journal.borrow_mut().push(StateChange::UpsertFile {
+            inode: info.inode,
+            size: info.size,
+            checksum: info.checksum,
+            state: info.state,
+            replication_factor: info.replication_factor,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_state(&self, inode: u64, state: FileState) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute(
+            format!(
+                "UPDATE {} SET state = ?2, last_updated = ?3 WHERE inode = ?1;",
+                self.db_name
+            )
+            .as_str(),
+            params![inode, u8::from(state), Local::now().to_rfc3339()],
+        )?;
+
+        self.journal
+            .borrow_mut()
+            .push(StateChange::UpdateFileState { inode, state });
+
+        Ok(())
+    }
+
+    /// Set the size and checksum of a file written block by block, once its last block is placed
+    pub fn update_size(&self, inode: u64, size: u64, checksum: u32) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute(
+            format!(
+                "UPDATE {} SET size = ?2, checksum = ?3, last_updated = ?4 WHERE inode = ?1;",
+                self.db_name
+            )
+            .as_str(),
+            params![inode, size, checksum, Local::now().to_rfc3339()],
+        )?;
+
+        self.journal
+            .borrow_mut()
+            .push(StateChange::UpdateFileSize { inode, size, checksum });
+
+        Ok(())
+    }
+
+    /// Recompute state of a file from its blocks. The file is as bad as its worst block.
+    pub fn refresh_state(&self, inode: u64) -> Result<Option<FileState>> {
+        let mut stmt = self
+            .db_conn
+            .as_ref()
+            .unwrap()
+            .prepare(format!("SELECT state FROM {} WHERE inode = ?1;", self.db_name).as_str())?;
+        let current: FileState = match stmt.query_map([inode], |row| _get_enum(row, 0))?.next() {
+            Some(state) => state?,
+            None => return Ok(None),
+        };
+        let states: Vec<FileState> = self.get_blocks(inode)?.iter().map(|block| block.state).collect();
+
+        let state = if states.contains(&FileState::Pending) {
+            FileState::Pending
+        } else if states.contains(&FileState::UnderReplicated) {
+            FileState::UnderReplicated
+        } else {
+            FileState::Complete
+        };
+        if state != current {
+            self.update_state(inode, state)?;
+        }
+
+        Ok(Some(state))
+    }
+
+    /// Change the number of replicas wanted for a file. Return whether the file exists.
+    pub fn set_replication_factor(&self, inode: u64, replication_factor: usize) -> Result<bool> {
+        let n_updated = self.db_conn.as_ref().unwrap().execute(
+            format!(
+                "UPDATE {} SET replication_factor = ?2, last_updated = ?3 WHERE inode = ?1;",
+                self.db_name
+            )
+            .as_str(),
+            params![inode, replication_factor, Local::now().to_rfc3339()],
+        )?;
+
+        if n_updated == 0 {
+            return Ok(false);
+        }
+
+        self.journal.borrow_mut().push(StateChange::SetReplicationFactor {
+            inode,
+            replication_factor,
+        });
+
+        Ok(true)
+    }
+
+    /// Number of replicas wanted for a block, from the factor of its file
+    pub fn get_block_replication_factor(&self, block_id: &String, default_replication_factor: usize) -> Result<usize> {
+        let mut stmt = self.db_conn.as_ref().unwrap().prepare(
+            format!(
+                "SELECT f.replication_factor FROM {0} f JOIN {0}_block b ON b.inode = f.inode WHERE b.block_id = ?1;",
+                self.db_name
+            )
+            .as_str(),
+        )?;
+        let replication_factor = stmt
+            .query_map([&block_id], |row| row.get::<usize, usize>(0))?
+            .next()
+            .transpose()?;
+
+        Ok(match replication_factor {
+            Some(0) | None => default_replication_factor,
+            Some(replication_factor) => replication_factor,
+        })
+    }
+
+    /// Blocks which have more replicas than the factor of their file
+    pub fn get_over_replicated_blocks(&self, default_replication_factor: usize) -> Result<Vec<String>> {
+        let mut stmt = self.db_conn.as_ref().unwrap().prepare(
+            format!(
+                "SELECT b.block_id FROM {0}_block b
+                JOIN {0} f ON f.inode = b.inode
+                JOIN {0}_block_replica r ON r.block_id = b.block_id
+                GROUP BY b.block_id
+                HAVING COUNT(*) > (CASE f.replication_factor WHEN 0 THEN ?1 ELSE f.replication_factor END);",
+                self.db_name
+            )
+            .as_str(),
+        )?;
+        let rows = stmt.query_map([default_replication_factor], |row| row.get::<usize, String>(0))?;
+
+        rows.collect()
+    }
+
+    /// Give a replication factor to files later created under directory `inode`. Files already there are left as is.
+    pub fn set_dir_replication_factor(&self, inode: u64, replication_factor: usize) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute(
+            format!(
+                "UPDATE {}_inode SET replication_factor = ?2, last_updated = ?3 WHERE inode = ?1 AND is_dir = 1;",
+                self.db_name
+            )
+            .as_str(),
+            params![inode, replication_factor, Local::now().to_rfc3339()],
+        )?;
+
+        self.journal.borrow_mut().push(StateChange::SetDirReplicationFactor {
+            inode,
+            replication_factor,
+        });
+
+        Ok(())
+    }
+
+    /// Replication factor of the deepest directory containing `filename` which has one
+    pub fn get_dir_replication_factor(&self, filename: &str) -> Result<Option<usize>> {
+        let names = match _split_path(filename) {
+            Some(names) => names,
+            None => return Ok(None),
+        };
+
+        let mut replication_factor = None;
+        let mut inode = ROOT_INODE;
+        for name in names.iter().take(names.len().saturating_sub(1)) {
+            match self.get_child(inode, name)? {
+                Some(child) if child.is_dir => {
+                    if child.replication_factor > 0 {
+                        replication_factor = Some(child.replication_factor);
+                    }
+                    inode = child.inode;
+                }
+                _ => break,
+            }
+        }
+
+        Ok(replication_factor)
+    }
+
+    /// Files under directory `inode`, including those in its subdirectories
+    pub fn get_files_in_dir(&self, inode: u64) -> Result<Vec<FileInfoEntry>> {
+        match self.get_path(inode)? {
+            Some(path) => self._get_files_under(inode, &path, None),
+            None => Ok(vec![]),
+        }
+    }
+
+    pub fn get_file_info(&self, filename: &str) -> Result<Vec<FileInfoEntry>> {
+        let inode = match self.resolve(filename)? {
+            Some(entry) if !entry.is_dir => entry.inode,
+            _ => return Ok(vec![]),
+        };
+
+        let mut stmt = self.db_conn.as_ref().unwrap().prepare(
+            format!(
+                "SELECT inode, ?2, size, checksum, state, last_updated, replication_factor FROM {} WHERE inode = ?1;",
+                self.db_name
+            )
+            .as_str(),
+        )?;
+        let path = _split_path(filename).unwrap_or_default().join("/");
+        let rows = stmt.query_map(params![inode, path], _parse_file_info)?;
+
+        rows.collect()
+    }
+
+    pub fn get_files(&self) -> Result<Vec<FileInfoEntry>> {
+        self._get_files_under(ROOT_INODE, "", None)
+    }
+
+    pub fn get_files_by_state(&self, state: FileState) -> Result<Vec<FileInfoEntry>> {
+        self._get_files_under(ROOT_INODE, "", Some(state))
+    }
+
+    /// Files under directory `inode` whose path is `path`, only those in `state` if given
+    fn _get_files_under(&self, inode: u64, path: &str, state: Option<FileState>) -> Result<Vec<FileInfoEntry>> {
+        let mut stmt = self.db_conn.as_ref().unwrap().prepare(
+            format!(
+                "{1} SELECT f.inode, t.path, f.size, f.checksum, f.state, f.last_updated, f.replication_factor
+                FROM tree t JOIN {0} f ON f.inode = t.inode
+                WHERE ?3 IS NULL OR f.state = ?3;",
+                self.db_name,
+                self._tree()
+            )
+            .as_str(),
+        )?;
+        let rows = stmt.query_map(params![inode, path, state.map(u8::from)], _parse_file_info)?;
+
+        rows.collect()
+    }
+
+    // ================================================
+    // Blocks and replicas
+    // ================================================
+
+    /// Insert or overwrite a block. Its replicas are kept.
+    pub fn upsert_block(&self, info: &BlockInfoEntry) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute(
+            format!(
+                "INSERT INTO {}_block
+                (block_id, inode, idx, size, checksum, state, last_updated)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                ON CONFLICT(block_id) DO UPDATE SET
+                    inode = ?2,
+                    idx = ?3,
+                    size = ?4,
+                    checksum = ?5,
+                    state = ?6,
+                    last_updated = ?7
+                ;",
+                self.db_name
+            )
+            .as_str(),
+            params![
+                info.block_id,
+                info.inode,
+                info.index,
+                info.size,
+                info.checksum,
+                u8::from(info.state),
+                Local::now().to_rfc3339(),
+            ],
+        )?;
+
+        self.journal.borrow_mut().push(StateChange::UpsertBlock {
+            block_id: info.block_id.clone(),
+            inode: info.inode,
+            index: info.index,
+            size: info.size,
+            checksum: info.checksum,
+            state: info.state,
+        });
+
+        Ok(())
+    }
+
+    pub fn update_block_state(&self, block_id: &String, state: FileState) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute(
+            format!(
+                "UPDATE {}_block SET state = ?2, last_updated = ?3 WHERE block_id = ?1;",
+                self.db_name
+            )
+            .as_str(),
+            params![block_id, u8::from(state), Local::now().to_rfc3339()],
+        )?;
+
+        self.journal.borrow_mut().push(StateChange::UpdateBlockState {
+            block_id: block_id.clone(),
+            state,
+        });
+
+        Ok(())
+    }
+
+    /// Recompute state of a block from the number of its replicas, then the state of its file. A block never stored
+    /// stays Pending. `default_replication_factor` applies to files without a factor of their own.
+    pub fn refresh_block_state(
+        &self,
+        block_id: &String,
+        default_replication_factor: usize,
+    ) -> Result<Option<FileState>> {
+        let info = match self.get_block_info(block_id)?.pop() {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        let n_replicas = self.get_replicas(block_id)?.len();
+        let replication_factor = self.get_block_replication_factor(block_id, default_replication_factor)?;
+
+        let state = match n_replicas {
+            0 if info.state == FileState::Pending => FileState::Pending,
+            n if n >= replication_factor => FileState::Complete,
+            _ => FileState::UnderReplicated,
+        };
+        if state != info.state {
+            self.update_block_state(block_id, state)?;
+        }
+        self.refresh_state(info.inode)?;
+
+        Ok(Some(state))
+    }
+
+    pub fn get_block_info(&self, block_id: &String) -> Result<Vec<BlockInfoEntry>> {
+        let mut stmt = self
+            .db_conn
+            .as_ref()
+            .unwrap()
+            .prepare(format!("SELECT * FROM {}_block WHERE block_id = ?1;", self.db_name).as_str())?;
+        let rows = stmt.query_map([&block_id], _parse_block_info)?;
+
+        rows.collect()
+    }
+
+    /// Blocks of a file in the order their content appears
+    pub fn get_blocks(&self, inode: u64) -> Result<Vec<BlockInfoEntry>> {
+        let mut stmt = self
+            .db_conn
+            .as_ref()
+            .unwrap()
+            .prepare(format!("SELECT * FROM {}_block WHERE inode = ?1 ORDER BY idx;", self.db_name).as_str())?;
+        let rows = stmt.query_map([inode], _parse_block_info)?;
+
+        rows.collect()
+    }
+
+    pub fn get_all_blocks(&self) -> Result<Vec<BlockInfoEntry>> {
+        let mut stmt = self
+            .db_conn
+            .as_ref()
+            .unwrap()
+            .prepare(format!("SELECT * FROM {}_block;", self.db_name).as_str())?;
+        let rows = stmt.query_map([], _parse_block_info)?;
+
+        rows.collect()
+    }
+
+    pub fn get_blocks_by_state(&self, state: FileState) -> Result<Vec<BlockInfoEntry>> {
+        let mut stmt = self
+            .db_conn
+            .as_ref()
+            .unwrap()
+            .prepare(format!("SELECT * FROM {}_block WHERE state = ?1;", self.db_name).as_str())?;
+        let rows = stmt.query_map([&u8::from(state)], _parse_block_info)?;
+
+        rows.collect()
+    }
+
+    pub fn upsert_replica(&self, block_id: &String, node_id: &String) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute(
+            format!(
+                "INSERT INTO {}_block_replica
+                (block_id, node_id, last_updated)
+                VALUES (?1, ?2, ?3)
+                ON CONFLICT(block_id, node_id) DO UPDATE SET
+                    last_updated = ?3
+                ;",
+                self.db_name
+            )
+            .as_str(),
+            params![block_id, node_id, Local::now().to_rfc3339()],
+        )?;
+
+        self.journal.borrow_mut().push(StateChange::UpsertReplica {
+            block_id: block_id.clone(),
+            node_id: node_id.clone(),
+        });
+
+        Ok(())
+    }
+
+    pub fn remove_replica(&self, block_id: &String, node_id: &String) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute(
+            format!(
+                "DELETE FROM {}_block_replica WHERE block_id = ?1 AND node_id = ?2;",
+                self.db_name
+            )
+            .as_str(),
+            [block_id, node_id],
+        )?;
+
+        self.journal.borrow_mut().push(StateChange::RemoveReplica {
+            block_id: block_id.clone(),
+            node_id: node_id.clone(),
+        });
+
+        Ok(())
+    }
+
+    pub fn get_replicas(&self, block_id: &String) -> Result<Vec<ReplicaInfoEntry>> {
+        let mut stmt = self
+            .db_conn
+            .as_ref()
+            .unwrap()
+            .prepare(format!("SELECT * FROM {}_block_replica WHERE block_id = ?1;", self.db_name).as_str())?;
+        let rows = stmt.query_map([&block_id], _parse_replica_info)?;
+
+        rows.collect()
+    }
+
+    pub fn get_all_replicas(&self) -> Result<Vec<ReplicaInfoEntry>> {
+        let mut stmt = self
+            .db_conn
+            .as_ref()
+            .unwrap()
+            .prepare(format!("SELECT * FROM {}_block_replica;", self.db_name).as_str())?;
+        let rows = stmt.query_map([], _parse_replica_info)?;
+
+        rows.collect()
+    }
+
+    /// Remove every file, directory, block and replica. The root directory stays.
+    pub fn clear(&self) -> Result<()> {
+        let conn = self.db_conn.as_ref().unwrap();
+        conn.execute(format!("DELETE FROM {};", self.db_name).as_str(), [])?;
+        conn.execute(
+            format!("DELETE FROM {}_inode WHERE inode != ?1;", self.db_name).as_str(),
+            [ROOT_INODE],
+        )?;
+        conn.execute(format!("DELETE FROM {}_block;", self.db_name).as_str(), [])?;
+        conn.execute(format!("DELETE FROM {}_block_replica;", self.db_name).as_str(), [])?;
+
+        Ok(())
+    }
+
+    /// Take changes made since the last call
+    pub fn drain_changes(&self) -> Vec<StateChange> {
+        self.journal.borrow_mut().drain(..).collect()
+    }
+
+    /// Start staging writes to this DB and to DBs sharing its connection, until kept or discarded
+    pub fn stage(&self) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute_batch("SAVEPOINT staged;")
+    }
+
+    pub fn keep_staged(&self) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute_batch("RELEASE staged;")
+    }
+
+    /// Undo staged writes. Their changes are still in the journals.
+    pub fn discard_staged(&self) -> Result<()> {
+        self.db_conn
+            .as_ref()
+            .unwrap()
+            .execute_batch("ROLLBACK TO staged; RELEASE staged;")
+    }
+
+    pub fn get_replicas_by_node(&self, node_id: &String) -> Result<Vec<ReplicaInfoEntry>> {
+        let mut stmt = self
+            .db_conn
+            .as_ref()
+            .unwrap()
+            .prepare(format!("SELECT * FROM {}_block_replica WHERE node_id = ?1;", self.db_name).as_str())?;
+        let rows = stmt.query_map([&node_id], _parse_replica_info)?;
+
+        rows.collect()
+    }
+}
+
+fn _parse_file_info(row: &Row) -> Result<FileInfoEntry> {
+    Ok(FileInfoEntry {
+        inode: row.get(0)?,
+        filename: row.get(1)?,
+        size: row.get(2)?,
+        checksum: row.get(3)?,
+        state: _get_enum(row, 4)?,
+        last_updated: Some(row.get::<usize, String>(5)?.parse().unwrap()),
+        replication_factor: row.get(6)?,
+    })
+}
+
+fn _parse_inode_info(row: &Row) -> Result<InodeEntry> {
+    Ok(InodeEntry {
+        inode: row.get(0)?,
+        parent: row.get(1)?,
+        name: row.get(2)?,
+        is_dir: row.get(3)?,
+        replication_factor: row.get(4)?,
+        last_updated: Some(row.get::<usize, String>(5)?.parse().unwrap()),
+    })
+}
+
+fn _parse_block_info(row: &Row) -> Result<BlockInfoEntry> {
+    Ok(BlockInfoEntry {
+        block_id: row.get(0)?,
+        inode: row.get(1)?,
+        index: row.get(2)?,
+        size: row.get(3)?,
+        checksum: row.get(4)?,
+        state: _get_enum(row, 5)?,
+        last_updated: Some(row.get::<usize, String>(6)?.parse().unwrap()),
+    })
+}
+
+fn _parse_replica_info(row: &Row) -> Result<ReplicaInfoEntry> {
+    Ok(ReplicaInfoEntry {
+        block_id: row.get(0)?,
+        node_id: row.get(1)?,
+        last_updated: Some(row.get::<usize, String>(2)?.parse().unwrap()),
+    })
+}
+
+impl MetaDB<NodeInfoEntry> for NodeInfoDB {
+    fn create_db(&mut self, backend: &DBBackend) -> Result<()> {
+        log::info!("Creating db {} at {}", self.db_name, backend);
+
+        self._create_table(Rc::new(_open_connection(backend)?))
+    }
+}
+
+impl NodeInfoDB {
+    pub fn initialize(db_name: &'static str, backend: &DBBackend) -> NodeInfoDB {
+        let mut db = NodeInfoDB {
+            db_name,
+            db_conn: None,
+            journal: RefCell::new(vec![]),
+        };
+
+        if let Err(err) = db.create_db(backend) {
+            panic!("Cannot create db {}: {}", db_name, err);
+        }
+
+        db
+    }
+
+    /// Keep nodes on the connection of `file_info`, so that changes to both DBs can be staged together
+    pub fn initialize_along(db_name: &'static str, file_info: &FileInfoDB) -> NodeInfoDB {
+        let mut db = NodeInfoDB {
+            db_name,
+            db_conn: None,
+            journal: RefCell::new(vec![]),
+        };
+
+        if let Err(err) = db._create_table(file_info.db_conn.clone().unwrap()) {
+            panic!("Cannot create db {}: {}", db_name, err);
+        }
+
+        db
+    }
+
+    fn _create_table(&mut self, conn: Rc<Connection>) -> Result<()> {
+        conn.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                node_id         TEXT    PRIMARY KEY
+                ,ip             TEXT    NOT NULL
+                ,port           INTEGER NOT NULL
+                ,role           INTEGER NOT NULL
+                ,last_updated   TEXT    NOT NULL
+                ,status         INTEGER NOT NULL DEFAULT 0
+                ,rack           TEXT    NOT NULL DEFAULT ''
+                ,disk_free      INTEGER NOT NULL DEFAULT 0
+                ,load           INTEGER NOT NULL DEFAULT 0
+                ,disk_total     INTEGER NOT NULL DEFAULT 0
+                ,disk_used      INTEGER NOT NULL DEFAULT 0
+                ,n_blocks       INTEGER NOT NULL DEFAULT 0
+                ,n_in_flight    INTEGER NOT NULL DEFAULT 0
+            );",
+                &self.db_name
+            )
+            .as_str(),
+            [],
+        )?;
+        _add_column_if_missing(&conn, self.db_name, "status", "INTEGER NOT NULL DEFAULT 0")?;
+        _add_column_if_missing(&conn, self.db_name, "rack", "TEXT NOT NULL DEFAULT ''")?;
+        _add_column_if_missing(&conn, self.db_name, "disk_free", "INTEGER NOT NULL DEFAULT 0")?;
+        _add_column_if_missing(&conn, self.db_name, "load", "INTEGER NOT NULL DEFAULT 0")?;
+        for column in ["disk_total", "disk_used", "n_blocks", "n_in_flight"] {
+            _add_column_if_missing(&conn, self.db_name, column, "INTEGER NOT NULL DEFAULT 0")?;
+        }
+
+        self.db_conn = Some(conn);
+
+        Ok(())
+    }
+
+    /// Insert or refresh a node that just proved to be reachable, marking it Alive
+    ///
+    /// Nodes answer every heartbeat, so a node already known as Alive with the same role only has its time refreshed
+    /// locally. Only actual changes are journaled.
+    pub fn upsert(&self, ip: Ipv4Addr, port: u16, role: Role) -> Result<()> {
+        let node_id = _get_node_id(&ip, port);
+        let current = Local::now();
+        let conn = self.db_conn.as_ref().unwrap();
+
+        let is_unchanged = self
+            .get_node_info(ip, port)?
+            .pop()
+            .is_some_and(|node| node.status == NodeStatus::Alive && u8::from(&node.role) == u8::from(&role));
+        if is_unchanged {
+            conn.execute(
+                format!("UPDATE {} SET last_updated = ?2 WHERE node_id = ?1;", self.db_name).as_str(),
+                params![node_id, current.to_rfc3339()],
+            )?;
+            return Ok(());
+        }
+
+        conn.execute(
+            format!(
+                "INSERT INTO {}
+                (node_id, ip, port, role, last_updated, status)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                ON CONFLICT(node_id) DO UPDATE SET
+                    ip = ?2,
+                    port = ?3,
+                    role = ?4,
+                    last_updated = ?5,
+                    status = ?6
+                ;",
+                self.db_name
+            )
+            .as_str(),
+            params![
+                node_id,
+                ip.to_string(),
+                port,
+                u8::from(&role),
+                current.to_rfc3339(),
+                u8::from(NodeStatus::Alive),
+            ],
+        )?;
+        log::info!("Node {} registered as {}", node_id, role);
+
+        self.journal
+            .borrow_mut()
+            .push(StateChange::UpsertNode { ip, port, role });
+
+        Ok(())
+    }
+
+    pub fn get_node_info(&self, ip: Ipv4Addr, port: u16) -> Result<Vec<NodeInfoEntry>> {
+        let node_id = _get_node_id(&ip, port);
+
+        let mut stmt = self
+            .db_conn
+            .as_ref()
+            .unwrap()
+            .prepare(format!("SELECT * FROM {} WHERE node_id = ?1;", self.db_name).as_str())?;
+        let rows = stmt.query_map([&node_id], _parse_node_info)?;
+
+        rows.collect()
+    }
+
+    pub fn get_nodes(&self) -> Result<Vec<NodeInfoEntry>> {
+        let mut stmt = self
+            .db_conn
+            .as_ref()
+            .unwrap()
+            .prepare(format!("SELECT * FROM {};", self.db_name).as_str())?;
+        let rows = stmt.query_map([], _parse_node_info)?;
+
+        rows.collect()
+    }
+
+    pub fn get_data_nodes(&self) -> Result<Vec<NodeInfoEntry>> {
+        let mut stmt = self
+            .db_conn
+            .as_ref()
+            .unwrap()
+            .prepare(format!("SELECT * FROM {} WHERE role = ?1;", self.db_name).as_str())?;
+        let rows = stmt.query_map([&u8::from(&Role::Data)], _parse_node_info)?;
+
+        rows.collect()
+    }
+
+    pub fn update_status(&self, node_id: &String, status: NodeStatus) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute(
+            format!("UPDATE {} SET status = ?2 WHERE node_id = ?1;", self.db_name).as_str(),
+            params![node_id, u8::from(status)],
+        )?;
+
+        self.journal.borrow_mut().push(StateChange::UpdateNodeStatus {
+            node_id: node_id.clone(),
+            status,
+        });
+
+        Ok(())
+    }
+
+    /// Record the usage a Data node just reported. Usage is refreshed by every HeartbeatAck, so unlike other changes
+    /// it isn't replicated to other Masters.
+    pub fn update_usage(&self, node_id: &String, usage: &NodeUsage) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute(
+            format!(
+                "UPDATE {} SET
+                    disk_total = ?2,
+                    disk_used = ?3,
+                    disk_free = ?4,
+                    n_blocks = ?5,
+                    n_in_flight = ?6,
+                    load = ?7
+                WHERE node_id = ?1;",
+                self.db_name
+            )
+            .as_str(),
+            params![
+                node_id,
+                usage.disk_total,
+                usage.disk_used,
+                usage.disk_free,
+                usage.n_blocks,
+                usage.n_in_flight,
+                usage.load,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn update_rack(&self, node_id: &String, rack: &String) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute(
+            format!("UPDATE {} SET rack = ?2 WHERE node_id = ?1;", self.db_name).as_str(),
+            params![node_id, rack],
+        )?;
+
+        self.journal.borrow_mut().push(StateChange::UpdateNodeRack {
+            node_id: node_id.clone(),
+            rack: rack.clone(),
+        });
+
+        Ok(())
+    }
+
+    /// Remove every node
+    pub fn clear(&self) -> Result<()> {
+        self.db_conn
+            .as_ref()
+            .unwrap()
+            .execute(format!("DELETE FROM {};", self.db_name).as_str(), [])?;
+
+        Ok(())
+    }
+
+    /// Take changes made since the last call
+    pub fn drain_changes(&self) -> Vec<StateChange> {
+        self.journal.borrow_mut().drain(..).collect()
+    }
+}
+
+impl MetaDB<RaftLogEntry> for RaftLogDB {
+    fn create_db(&mut self, backend: &DBBackend) -> Result<()> {
+        log::info!("Creating db {} at {}", self.db_name, backend);
+
+        let conn = _open_connection(backend)?;
+
+        conn.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                idx             INTEGER PRIMARY KEY
+                ,term           INTEGER NOT NULL
+                ,payload        BLOB    NOT NULL
+            );",
+                &self.db_name
+            )
+            .as_str(),
+            [],
+        )?;
+        conn.execute(
+            format!(
+                "CREATE TABLE IF NOT EXISTS {}_state (
+                id              INTEGER PRIMARY KEY CHECK (id = 0)
+                ,term           INTEGER NOT NULL
+                ,voted_for      TEXT
+                ,base_index     INTEGER NOT NULL
+                ,base_term      INTEGER NOT NULL
+                ,last_applied   INTEGER NOT NULL
+            );",
+                &self.db_name
+            )
+            .as_str(),
+            [],
+        )?;
+
+        self.db_conn = Some(conn);
+
+        Ok(())
+    }
+}
+
+impl RaftLogDB {
+    pub fn initialize(db_name: &'static str, backend: &DBBackend) -> RaftLogDB {
+        let mut db = RaftLogDB { db_name, db_conn: None };
+
+        if let Err(err) = db.create_db(backend) {
+            panic!("Cannot create db {}: {}", db_name, err);
+        }
+
+        db
+    }
+
+    /// Get the persisted state. A fresh DB starts at term 0 with an empty log.
+    pub fn get_state(&self) -> Result<RaftStateEntry> {
+        let mut stmt = self.db_conn.as_ref().unwrap().prepare(
+            format!(
+                "SELECT term, voted_for, base_index, base_term, last_applied FROM {}_state;",
+                self.db_name
+            )
+            .as_str(),
+        )?;
+        let mut rows = stmt.query_map([], |row| {
+            Ok(RaftStateEntry {
+                term: row.get(0)?,
+                voted_for: row.get(1)?,
+                base_index: row.get(2)?,
+                base_term: row.get(3)?,
+                last_applied: row.get(4)?,
+            })
+        })?;
+
+        match rows.next() {
+            Some(state) => state,
+            None => Ok(RaftStateEntry {
+                term: 0,
+                voted_for: None,
+                base_index: 0,
+                base_term: 0,
+                last_applied: 0,
+            }),
+        }
+    }
+
+    pub fn save_state(&self, state: &RaftStateEntry) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute(
+            format!(
+                "INSERT INTO {}_state (id, term, voted_for, base_index, base_term, last_applied)
+                VALUES (0, ?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT(id) DO UPDATE SET
+                    term = excluded.term,
+                    voted_for = excluded.voted_for,
+                    base_index = excluded.base_index,
+                    base_term = excluded.base_term,
+                    last_applied = excluded.last_applied;",
+                self.db_name
+            )
+            .as_str(),
+            params![
+                state.term,
+                state.voted_for,
+                state.base_index,
+                state.base_term,
+                state.last_applied
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn append(&self, entry: &RaftLogEntry) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute(
+            format!(
+                "INSERT OR REPLACE INTO {} (idx, term, payload) VALUES (?1, ?2, ?3);",
+                self.db_name
+            )
+            .as_str(),
+            params![entry.index, entry.term, entry.payload],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_entries(&self) -> Result<Vec<RaftLogEntry>> {
+        let mut stmt = self
+            .db_conn
+            .as_ref()
+            .unwrap()
+            .prepare(format!("SELECT idx, term, payload FROM {} ORDER BY idx;", self.db_name).as_str())?;
+        let rows = stmt.query_map([], |row| {
+            Ok(RaftLogEntry {
+                index: row.get(0)?,
+                term: row.get(1)?,
+                payload: row.get(2)?,
+            })
+        })?;
+
+        rows.collect()
+    }
+
+    /// Remove entries from `index` onwards
+    pub fn truncate_from(&self, index: u64) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute(
+            format!("DELETE FROM {} WHERE idx >= ?1;", self.db_name).as_str(),
+            [index],
+        )?;
+
+        Ok(())
+    }
+
+    /// Remove entries up to `index`, included
+    pub fn compact_until(&self, index: u64) -> Result<()> {
+        self.db_conn.as_ref().unwrap().execute(
+            format!("DELETE FROM {} WHERE idx <= ?1;", self.db_name).as_str(),
+            [index],
+        )?;
+
+        Ok(())
+    }
+}
+
+fn _parse_node_info(row: &Row) -> Result<NodeInfoEntry> {
+    let ip_str: String = row.get(1)?;
+    let ip = match Ipv4Addr::from_str(ip_str.as_str()) {
+        Ok(ip) => Some(ip),
+        Err(e) => {
+            log::error!("Cannot parse following to Ipv4: {}: {}", ip_str, e);
+            None
+        }
+    };
+
+    Ok(NodeInfoEntry {
+        node_id: row.get(0)?,
+        ip,
+        port: row.get::<usize, u16>(2)?,
+        role: _get_enum(row, 3)?,
+        last_updated: Some(row.get::<usize, String>(4)?.parse().unwrap()),
+        status: _get_enum(row, 5)?,
+        rack: row.get(6)?,
+        usage: NodeUsage {
+            disk_free: row.get(7)?,
+            load: row.get(8)?,
+            disk_total: row.get(9)?,
+            disk_used: row.get(10)?,
+            n_blocks: row.get(11)?,
+            n_in_flight: row.get(12)?,
+        },
+    })
+}
+
+/// Open connection to the backend. File-backed DBs use WAL so readers don't block the writer.
+fn _open_connection(backend: &DBBackend) -> Result<Connection> {
+    match backend {
+        DBBackend::InMemory => Connection::open_in_memory(),
+        DBBackend::File(path) => {
+            if let Some(dir) = path.parent() {
+                if let Err(err) = fs::create_dir_all(dir) {
+                    log::error!("Cannot create directory for db at {}: {}", path.display(), err);
+                }
+            }
+
+            let conn = Connection::open(path)?;
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            conn.pragma_update(None, "synchronous", "NORMAL")?;
+
+            Ok(conn)
+        }
+    }
+}
+
+/// Add a column to a table created by an older version
+fn _add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    if !_has_column(conn, table, column)? {
+        log::info!("Adding column '{}' to table {}", column, table);
+        conn.execute(
+            format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, definition).as_str(),
+            [],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Whether `table` exists and has `column`
+fn _has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(format!("PRAGMA table_info({});", table).as_str())?;
+    let is_existed = stmt
+        .query_map([], |row| row.get::<usize, String>(1))?
+        .filter_map(|name| name.ok())
+        .any(|name| name == column);
+
+    Ok(is_existed)
+}
+
+/// Read column `idx` holding an enum stored as its number
+fn _get_enum<T: TryFrom<u8, Error = ParseError>>(row: &Row, idx: usize) -> Result<T> {
+    T::try_from(row.get::<usize, u8>(idx)?)
+        .map_err(|err| rusqlite::Error::FromSqlConversionFailure(idx, Type::Integer, Box::new(err)))
+}
+
+pub fn _get_node_id(ip: &Ipv4Addr, port: u16) -> String {
+    SocketAddrV4::new(*ip, port).to_string()
+}
+
+/// Blocks are named after the inode of their file, so they keep their name when the file moves
+pub fn _get_block_id(inode: u64, index: u64) -> String {
+    format!("{}#{}", inode, index)
+}
+
+/// Names along `path` from the root directory. Empty names from repeated, leading or trailing '/' are skipped. None
+/// if a name is '.' or '..', or if the path is too long to be sent in packets.
+pub fn _split_path(path: &str) -> Option<Vec<&str>> {
+    if path.len() > MAX_STR_LEN {
+        return None;
+    }
+    let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+    match names.iter().any(|name| *name == "." || *name == "..") {
+        true => None,
+        false => Some(names),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_info(paths: &[&str]) -> FileInfoDB {
+        let file_info = FileInfoDB::initialize("file_info", &DBBackend::InMemory);
+        for path in paths {
+            match path.strip_suffix('/') {
+                Some(dir) => file_info.make_dir(dir).unwrap().unwrap(),
+                None => file_info.create_file(path).unwrap().unwrap().0,
+            };
+        }
+
+        file_info
+    }
+
+    fn tree(file_info: &FileInfoDB) -> Vec<String> {
+        let entries = file_info.list("", true).unwrap().unwrap();
+        entries.into_iter().map(|entry| entry.path).collect()
+    }
+
+    #[test]
+    fn rename_moves_whole_subtree() {
+        let file_info = file_info(&["a/b/c", "a/d", "e/"]);
+
+        assert!(file_info.rename("a", "e/f").unwrap());
+
+        assert_eq!(tree(&file_info), vec!["e", "e/f", "e/f/b", "e/f/b/c", "e/f/d"]);
+    }
+
+    #[test]
+    fn rename_rejects_invalid_targets() {
+        let file_info = file_info(&["a/b/c", "d", "e/"]);
+
+        // Existing target, missing parent, file as parent, under itself, root and dot segments
+        assert!(!file_info.rename("d", "e").unwrap());
+        assert!(!file_info.rename("d", "x/d").unwrap());
+        assert!(!file_info.rename("e", "d/e").unwrap());
+        assert!(!file_info.rename("a", "a/b/a").unwrap());
+        assert!(!file_info.rename("", "r").unwrap());
+        assert!(!file_info.rename("d", "e/../d2").unwrap());
+        assert!(!file_info.rename("missing", "f").unwrap());
+
+        assert_eq!(tree(&file_info), vec!["a", "a/b", "a/b/c", "d", "e"]);
+    }
+
+    #[test]
+    fn rename_rejects_paths_too_long_for_packets() {
+        let file_info = file_info(&["a/b"]);
+        let name = "n".repeat(MAX_STR_LEN - 1);
+
+        assert!(!file_info.rename("a", &name).unwrap());
+        assert!(file_info.rename("a", &name[2..]).unwrap());
+    }
+
+    #[test]
+    fn delete_of_non_empty_directory_must_be_recursive() {
+        let file_info = file_info(&["a/b/c", "a/d", "e"]);
+        let (inode, _) = file_info.create_file("a/b/f").unwrap().unwrap();
+        let block = BlockInfoEntry::initialize(inode, 0, 10);
+        file_info.upsert_block(&block).unwrap();
+        file_info
+            .upsert_replica(&block.block_id, &"127.0.0.1:7003".to_string())
+            .unwrap();
+
+        assert!(file_info.delete("a", false).unwrap().is_none());
+        assert!(file_info.delete("missing", true).unwrap().is_none());
+        assert!(file_info.delete("", true).unwrap().is_none());
+
+        let replicas = file_info.delete("a", true).unwrap().unwrap();
+        assert_eq!(replicas.len(), 1);
+        assert_eq!(replicas[0].block_id, block.block_id);
+        assert!(file_info.get_blocks(inode).unwrap().is_empty());
+        assert_eq!(tree(&file_info), vec!["e"]);
+    }
+
+    #[test]
+    fn empty_directory_is_deleted_without_recursion() {
+        let file_info = file_info(&["a/", "b"]);
+
+        assert!(file_info.delete("a", false).unwrap().unwrap().is_empty());
+        assert!(file_info.delete("b", false).unwrap().unwrap().is_empty());
+        assert!(tree(&file_info).is_empty());
+    }
+
+    #[test]
+    fn overwritten_file_gets_new_inode_and_gives_back_replicas() {
+        let file_info = FileInfoDB::initialize("file_info", &DBBackend::InMemory);
+        let (inode, replicas) = file_info.create_file("dir/a").unwrap().unwrap();
+        assert!(replicas.is_empty());
+        let block = BlockInfoEntry::initialize(inode, 0, 10);
+        file_info.upsert_block(&block).unwrap();
+        file_info
+            .upsert_replica(&block.block_id, &"127.0.0.1:7003".to_string())
+            .unwrap();
+
+        let (inode_new, replicas) = file_info.create_file("dir/a").unwrap().unwrap();
+
+        assert!(inode_new > inode);
+        assert_eq!(replicas.len(), 1);
+        assert_eq!(replicas[0].block_id, block.block_id);
+        assert!(file_info.get_blocks(inode).unwrap().is_empty());
+        assert_eq!(file_info.resolve("dir/a").unwrap().unwrap().inode, inode_new);
+    }
+
+    #[test]
+    fn heartbeats_of_known_node_are_not_journaled() {
+        let node_info = NodeInfoDB::initialize("node_info", &DBBackend::InMemory);
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+
+        node_info.upsert(ip, 7003, Role::Data).unwrap();
+        assert_eq!(node_info.drain_changes().len(), 1);
+        node_info.upsert(ip, 7003, Role::Data).unwrap();
+        assert!(node_info.drain_changes().is_empty());
+
+        // Coming back from Dead is a change
+        let node_id = _get_node_id(&ip, 7003);
+        node_info.update_status(&node_id, NodeStatus::Dead).unwrap();
+        node_info.drain_changes();
+        node_info.upsert(ip, 7003, Role::Data).unwrap();
+        assert_eq!(node_info.drain_changes().len(), 1);
+        assert!(node_info.get_node_info(ip, 7003).unwrap()[0].status == NodeStatus::Alive);
+    }
+
+    #[test]
+    fn staged_writes_are_discarded_from_both_dbs() {
+        let file_info = FileInfoDB::initialize("file_info", &DBBackend::InMemory);
+        let node_info = NodeInfoDB::initialize_along("node_info", &file_info);
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+
+        file_info.stage().unwrap();
+        let (inode, _) = file_info.create_file("a").unwrap().unwrap();
+        node_info.upsert(ip, 7003, Role::Data).unwrap();
+        file_info.discard_staged().unwrap();
+
+        assert!(file_info.resolve("a").unwrap().is_none());
+        assert!(node_info.get_nodes().unwrap().is_empty());
+        assert!(!file_info.drain_changes().is_empty());
+        assert_eq!(node_info.drain_changes().len(), 1);
+
+        // Applying the journal afterwards gives the same inode
+        file_info.stage().unwrap();
+        assert_eq!(file_info.create_file("a").unwrap().unwrap().0, inode);
+        node_info.upsert(ip, 7003, Role::Data).unwrap();
+        file_info.keep_staged().unwrap();
+
+        assert_eq!(file_info.resolve("a").unwrap().unwrap().inode, inode);
+        assert_eq!(node_info.get_nodes().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn db_keeping_files_by_path_is_refused() {
+        let path = std::env::temp_dir().join(format!("dfs-db-flat-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch("CREATE TABLE file_info (filename TEXT PRIMARY KEY, size INTEGER NOT NULL);")
+            .unwrap();
+
+        let mut file_info = FileInfoDB {
+            db_name: "file_info",
+            db_conn: None,
+            journal: RefCell::new(vec![]),
+        };
+        assert!(file_info.create_db(&DBBackend::File(path.clone())).is_err());
+
+        let _ = fs::remove_file(&path);
+    }
 }
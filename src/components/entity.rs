@@ -1,3 +1,5 @@
+pub mod cli;
 pub mod client;
+pub mod dfs_client;
 pub mod node_roles;
 pub mod nodes;
@@ -0,0 +1,655 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+};
+
+use crate::components::{
+    configs::Configs,
+    db::DirEntry,
+    entity::client::Client,
+    errors::{ClientError, ClientErrorCode},
+    packets::BlockLocation,
+};
+
+// ================================================
+// Definitions
+// ================================================
+
+/// The operation failed, e.g. the path doesn't exist or Master rejected the request
+pub const EXIT_FAILURE: u8 = 1;
+/// Arguments are missing or wrong
+pub const EXIT_USAGE: u8 = 2;
+/// Neither DNS nor Master could be reached
+pub const EXIT_UNAVAILABLE: u8 = 3;
+
+pub const USAGE: &str = "Usage: dfs client [--dns <ip:port>] [--json] <command> [<args>]
+
+Commands:
+  put [--resume] <local_path> [<path>] [<rf>]       Upload a file. A path ending with '/' keeps the local name.
+  get <path> [<local_path>]                         Download a file
+  ls [-r] [<dir>]                                   List a directory
+  stat <path>                                       Show a file with its blocks, or a directory
+  du [<path>]                                       Show the size of every entry of a directory
+  mkdir <dir>                                       Create a directory and its missing parents
+  mv <path> <new_path>                              Move a file or directory
+  rm [-r] <path>                                    Delete a file or an empty directory, or a whole tree with -r
+  set-replication <path> <replication_factor>       Change the replication factor of a file or directory
+
+Options:
+  --dns <ip:port>  Address of DNS, instead of env 'IP_DNS' and 'PORT_DNS'
+  --json           Print results as JSON
+  -r, --recursive  Apply to a whole tree
+  --resume         Only send the blocks an interrupted upload of the same file didn't store
+
+Exit codes: 0 success, 1 operation failed, 2 wrong usage, 3 cluster unreachable";
+
+/// Subcommands of `dfs client`
+pub enum Command {
+    Help,
+    Put {
+        path_local: PathBuf,
+        path: String,
+        replication_factor: u8,
+        is_resume: bool,
+    },
+    Get {
+        path: String,
+        path_local: PathBuf,
+    },
+    List {
+        path: String,
+        is_recursive: bool,
+    },
+    Stat {
+        path: String,
+    },
+    DiskUsage {
+        path: String,
+    },
+    MakeDir {
+        path: String,
+    },
+    Move {
+        path: String,
+        path_target: String,
+    },
+    Remove {
+        path: String,
+        is_recursive: bool,
+    },
+    SetReplication {
+        path: String,
+        replication_factor: u8,
+    },
+}
+
+/// Command line of `dfs client`
+pub struct Cli {
+    pub command: Command,
+    pub addr_dns: Option<SocketAddr>,
+    pub is_json: bool,
+}
+
+// ================================================
+// Implementations
+// ================================================
+
+impl Cli {
+    /// Parse the arguments following `client`. Options may come anywhere.
+    pub fn parse(args: &[String]) -> Result<Cli, String> {
+        let mut operands = Vec::<&str>::new();
+        let mut addr_dns = None;
+        let mut is_json = false;
+        let mut is_recursive = false;
+        let mut is_resume = false;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--json" => is_json = true,
+                "-r" | "--recursive" => is_recursive = true,
+                "--resume" => is_resume = true,
+                "-h" | "--help" => operands.insert(0, "help"),
+                "--dns" => {
+                    let value = args.next().ok_or("Option '--dns' needs an address")?;
+                    let addr = SocketAddr::from_str(value).map_err(|_| format!("Invalid DNS address '{}'", value))?;
+                    addr_dns = Some(addr);
+                }
+                option if option.starts_with('-') && option.len() > 1 => {
+                    return Err(format!("Unknown option '{}'", option));
+                }
+                operand => operands.push(operand),
+            }
+        }
+
+        let (name, operands) = match operands.split_first() {
+            Some((name, operands)) => (*name, operands),
+            None => return Err("Command must be specified".to_string()),
+        };
+        if is_recursive && !matches!(name, "ls" | "rm" | "help") {
+            return Err(format!("Option '-r' is not supported by '{}'", name));
+        }
+        if is_resume && !matches!(name, "put" | "upload" | "help") {
+            return Err(format!("Option '--resume' is not supported by '{}'", name));
+        }
+
+        let command = match (name, operands) {
+            ("help", _) => Command::Help,
+            ("put" | "upload", [path_local, rest @ ..]) if rest.len() <= 2 => {
+                let name_local = match Path::new(path_local).file_name() {
+                    Some(name_local) => name_local.to_string_lossy().to_string(),
+                    None => return Err(format!("'{}' is not a file", path_local)),
+                };
+                let path = match rest.first() {
+                    Some(path) if path.ends_with('/') => format!("{}{}", path, name_local),
+                    Some(path) => path.to_string(),
+                    None => name_local,
+                };
+                let replication_factor = match rest.get(1) {
+                    Some(value) => _parse_replication_factor(value)?,
+                    None => 0,
+                };
+                Command::Put {
+                    path_local: PathBuf::from(path_local),
+                    path,
+                    replication_factor,
+                    is_resume,
+                }
+            }
+            ("get" | "download", [path, rest @ ..]) if rest.len() <= 1 => {
+                let path_local = match rest.first() {
+                    Some(path_local) => PathBuf::from(path_local),
+                    None => match Path::new(path).file_name() {
+                        Some(name) => PathBuf::from(name),
+                        None => return Err(format!("'{}' is not a file", path)),
+                    },
+                };
+                Command::Get {
+                    path: path.to_string(),
+                    path_local,
+                }
+            }
+            ("ls", [] | [_]) => Command::List {
+                path: operands.first().unwrap_or(&"").to_string(),
+                is_recursive,
+            },
+            ("stat", [path]) => Command::Stat { path: path.to_string() },
+            ("du", [] | [_]) => Command::DiskUsage {
+                path: operands.first().unwrap_or(&"").to_string(),
+            },
+            ("mkdir", [path]) => Command::MakeDir { path: path.to_string() },
+            ("mv", [path, path_target]) => Command::Move {
+                path: path.to_string(),
+                path_target: path_target.to_string(),
+            },
+            ("rm", [path]) => Command::Remove {
+                path: path.to_string(),
+                is_recursive,
+            },
+            ("set-replication", [path, value]) => Command::SetReplication {
+                path: path.to_string(),
+                replication_factor: _parse_replication_factor(value)?,
+            },
+            (
+                "put" | "upload" | "get" | "download" | "ls" | "stat" | "du" | "mkdir" | "mv" | "rm"
+                | "set-replication",
+                _,
+            ) => {
+                return Err(format!("Wrong arguments for '{}'", name));
+            }
+            _ => return Err(format!("Unknown command '{}'", name)),
+        };
+
+        Ok(Cli {
+            command,
+            addr_dns,
+            is_json,
+        })
+    }
+
+    /// Run the command and print its result on stdout, or the error on stderr
+    pub fn run(&self, configs: &Configs) -> ExitCode {
+        if let Command::Help = self.command {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+
+        let client = match self.addr_dns {
+            Some(addr_dns) => Client::new(configs).with_dns(addr_dns),
+            None if configs.env_ip_dns.is_unspecified() || configs.env_port_dns == 0 => {
+                eprintln!("dfs: Address of DNS must be given by env 'IP_DNS' and 'PORT_DNS' or by '--dns'");
+                return ExitCode::from(EXIT_USAGE);
+            }
+            None => Client::new(configs),
+        };
+
+        match self.execute(&client) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                eprintln!("dfs: {}", _describe(&err));
+                match err.error_code {
+                    ClientErrorCode::ConnectionFailed | ClientErrorCode::UnavailableMasterAddress => {
+                        ExitCode::from(EXIT_UNAVAILABLE)
+                    }
+                    _ => ExitCode::from(EXIT_FAILURE),
+                }
+            }
+        }
+    }
+
+    fn execute(&self, client: &Client) -> Result<(), ClientError> {
+        match &self.command {
+            Command::Help => {}
+            Command::Put {
+                path_local,
+                path,
+                replication_factor,
+                is_resume,
+            } => {
+                let result = match is_resume {
+                    true => client.resume_upload(path_local, path),
+                    false => Err(ClientError::not_found(path)),
+                };
+                match result {
+                    Ok(()) => {}
+                    Err(err) if err.is_not_found() => {
+                        if *is_resume {
+                            log::warn!("No interrupted upload of '{}' to resume, uploading it whole", path);
+                        }
+                        client.upload(path_local, path, *replication_factor)?
+                    }
+                    Err(err) => return Err(err),
+                }
+                if self.is_json {
+                    println!("{{\"path\":{}}}", _json_str(path));
+                }
+            }
+            Command::Get { path, path_local } => {
+                // Like cp, a local directory receives the file under its own name
+                let path_local = match path_local.is_dir() {
+                    true => path_local.join(Path::new(path).file_name().unwrap_or_default()),
+                    false => path_local.clone(),
+                };
+                client.download(path, &path_local)?;
+                if self.is_json {
+                    println!(
+                        "{{\"path\":{},\"local_path\":{}}}",
+                        _json_str(path),
+                        _json_str(&path_local.to_string_lossy())
+                    );
+                }
+            }
+            Command::List { path, is_recursive } => {
+                let listing = client.list(path, *is_recursive)?;
+                if self.is_json {
+                    let entries: Vec<String> = listing.iter().map(_json_dir_entry).collect();
+                    println!("[{}]", entries.join(","));
+                } else {
+                    for entry in &listing {
+                        println!("{}", _format_dir_entry(entry));
+                    }
+                }
+            }
+            Command::Stat { path } => {
+                let entry = client.stat(path)?;
+                match entry.is_dir {
+                    true => {
+                        let n_entries = client.list(path, false)?.len();
+                        if self.is_json {
+                            println!(
+                                "{{\"path\":{},\"type\":\"dir\",\"entries\":{},\"replication_factor\":{}}}",
+                                _json_str(&entry.path),
+                                n_entries,
+                                entry.replication_factor
+                            );
+                        } else {
+                            println!("Path:        {}/", entry.path);
+                            println!("Type:        directory");
+                            println!("Entries:     {}", n_entries);
+                            println!("Replication: {}", _format_replication_factor(entry.replication_factor));
+                        }
+                    }
+                    false => {
+                        // Blocks are unknown while no Data node holds them, which stat still reports
+                        let locations = match client.locate(path) {
+                            Ok(locations) => locations,
+                            Err(err) if matches!(err.error_code, ClientErrorCode::UnavailableDataNode) => vec![],
+                            Err(err) => return Err(err),
+                        };
+                        if self.is_json {
+                            let blocks: Vec<String> = locations.iter().map(_json_block_location).collect();
+                            println!(
+                                "{{\"path\":{},\"type\":\"file\",\"size\":{},\"state\":{},\"replication_factor\":{},\"blocks\":[{}]}}",
+                                _json_str(&entry.path),
+                                entry.size,
+                                _json_str(&entry.state.to_string()),
+                                entry.replication_factor,
+                                blocks.join(",")
+                            );
+                        } else {
+                            println!("Path:        {}", entry.path);
+                            println!("Type:        file");
+                            println!("Size:        {} ({})", entry.size, _format_size(entry.size));
+                            println!("State:       {}", entry.state);
+                            println!("Replication: {}", _format_replication_factor(entry.replication_factor));
+                            println!("Blocks:      {}", locations.len());
+                            for location in &locations {
+                                let addr_nodes: Vec<String> =
+                                    location.addr_nodes.iter().map(|addr| addr.to_string()).collect();
+                                println!(
+                                    "  {:<8} offset {:<12} size {:<12} {}",
+                                    location.block_id,
+                                    location.offset,
+                                    location.size,
+                                    addr_nodes.join(",")
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+            Command::DiskUsage { path } => self.disk_usage(client, path)?,
+            Command::MakeDir { path } => {
+                client.make_dir(path)?;
+                if self.is_json {
+                    println!("{{\"path\":{}}}", _json_str(path));
+                }
+            }
+            Command::Move { path, path_target } => {
+                client.rename(path, path_target)?;
+                if self.is_json {
+                    println!("{{\"path\":{},\"target\":{}}}", _json_str(path), _json_str(path_target));
+                }
+            }
+            Command::Remove { path, is_recursive } => {
+                let n_replicas = client.delete(path, *is_recursive)?;
+                if self.is_json {
+                    println!("{{\"path\":{},\"replicas\":{}}}", _json_str(path), n_replicas);
+                }
+            }
+            Command::SetReplication {
+                path,
+                replication_factor,
+            } => {
+                let n_files = client.set_replication(path, *replication_factor)?;
+                if self.is_json {
+                    println!(
+                        "{{\"path\":{},\"replication_factor\":{},\"files\":{}}}",
+                        _json_str(path),
+                        replication_factor,
+                        n_files
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Print the total size of every entry of directory `path`, then the size of the whole directory
+    fn disk_usage(&self, client: &Client, path: &str) -> Result<(), ClientError> {
+        let listing = client.list(path, true)?;
+        let path = path
+            .split('/')
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<&str>>()
+            .join("/");
+        let prefix = match path.is_empty() {
+            true => String::new(),
+            false => format!("{}/", path),
+        };
+
+        let mut sizes = BTreeMap::<String, u64>::new();
+        let (mut n_files, mut n_dirs, mut total) = (0, 0, 0);
+        for entry in &listing {
+            let name = entry.path.strip_prefix(&prefix).unwrap_or(&entry.path);
+            let child = match name.split_once('/') {
+                Some((child, _)) => format!("{}{}", prefix, child),
+                None => entry.path.clone(),
+            };
+            *sizes.entry(child).or_default() += entry.size;
+
+            match entry.is_dir {
+                true => n_dirs += 1,
+                false => n_files += 1,
+            }
+            total += entry.size;
+        }
+
+        if self.is_json {
+            let entries: Vec<String> = sizes
+                .iter()
+                .map(|(child, size)| format!("{{\"path\":{},\"size\":{}}}", _json_str(child), size))
+                .collect();
+            println!(
+                "{{\"path\":{},\"size\":{},\"files\":{},\"dirs\":{},\"entries\":[{}]}}",
+                _json_str(&path),
+                total,
+                n_files,
+                n_dirs,
+                entries.join(",")
+            );
+        } else {
+            for (child, size) in &sizes {
+                println!("{:>8}  {}", _format_size(*size), child);
+            }
+            println!(
+                "{:>8}  total ({} files, {} directories)",
+                _format_size(total),
+                n_files,
+                n_dirs
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn _parse_replication_factor(value: &str) -> Result<u8, String> {
+    value
+        .parse::<u8>()
+        .map_err(|_| format!("Replication factor must be a number below 256, not '{}'", value))
+}
+
+/// Explain an error to someone at a terminal
+fn _describe(err: &ClientError) -> String {
+    let filename = err.filename.as_deref().unwrap_or_default();
+    let detail = err.detail.as_deref().unwrap_or_default();
+    let addr = match err.addr {
+        Some(addr) => addr.to_string(),
+        None => "-".to_string(),
+    };
+
+    match err.error_code {
+        ClientErrorCode::ConnectionFailed => format!("Cannot connect to {}: {}", addr, detail),
+        ClientErrorCode::UnavailableMasterAddress => "No Master is known to DNS".to_string(),
+        ClientErrorCode::UnavailableDataNode => {
+            format!("'{}' doesn't exist or no Data node holding it is available", filename)
+        }
+        ClientErrorCode::RequestRejected => format!("Request on '{}' rejected by {}", filename, addr),
+        ClientErrorCode::NotFound => format!("'{}' doesn't exist", filename),
+        ClientErrorCode::InvalidPath => format!("'{}' is not a valid path", filename),
+        ClientErrorCode::CorruptedData => format!("Data of '{}' from {} is corrupted", filename, addr),
+        ClientErrorCode::LocalIoError => format!("'{}': {}", filename, detail),
+        ClientErrorCode::ParseError => format!("Invalid reply: {}", detail),
+        ClientErrorCode::Default => err.to_string(),
+    }
+}
+
+/// Size in bytes below 1 KiB, otherwise in the largest binary unit with one decimal, e.g. 2.9M
+fn _format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+
+    if size < 1024 {
+        return size.to_string();
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
+fn _format_replication_factor(replication_factor: usize) -> String {
+    match replication_factor {
+        0 => "default".to_string(),
+        replication_factor => replication_factor.to_string(),
+    }
+}
+
+/// One line of `ls`: type, replication factor, size, state and path. Directories end with '/'.
+fn _format_dir_entry(entry: &DirEntry) -> String {
+    let replication_factor = match entry.replication_factor {
+        0 => "-".to_string(),
+        replication_factor => replication_factor.to_string(),
+    };
+
+    match entry.is_dir {
+        true => format!("d {:>3} {:>8} {:<15} {}/", replication_factor, "-", "-", entry.path),
+        false => format!(
+            "- {:>3} {:>8} {:<15} {}",
+            replication_factor,
+            _format_size(entry.size),
+            entry.state.to_string(),
+            entry.path
+        ),
+    }
+}
+
+fn _json_dir_entry(entry: &DirEntry) -> String {
+    format!(
+        "{{\"path\":{},\"type\":\"{}\",\"size\":{},\"state\":{},\"replication_factor\":{}}}",
+        _json_str(&entry.path),
+        if entry.is_dir { "dir" } else { "file" },
+        entry.size,
+        _json_str(&entry.state.to_string()),
+        entry.replication_factor
+    )
+}
+
+fn _json_block_location(location: &BlockLocation) -> String {
+    let addr_nodes: Vec<String> = location
+        .addr_nodes
+        .iter()
+        .map(|addr| _json_str(&addr.to_string()))
+        .collect();
+
+    format!(
+        "{{\"block_id\":{},\"offset\":{},\"size\":{},\"checksum\":{},\"nodes\":[{}]}}",
+        _json_str(&location.block_id),
+        location.offset,
+        location.size,
+        location.checksum,
+        addr_nodes.join(",")
+    )
+}
+
+/// Quote a string for JSON, escaping quotes, backslashes and control characters
+fn _json_str(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Cli, String> {
+        Cli::parse(&args.split_whitespace().map(String::from).collect::<Vec<String>>())
+    }
+
+    #[test]
+    fn put_takes_local_name_and_options_anywhere() {
+        let cli = parse("put --json dir/report.csv backup/ 2 --resume --dns 127.0.0.1:7000").unwrap();
+
+        assert!(cli.is_json);
+        assert_eq!(cli.addr_dns, Some("127.0.0.1:7000".parse().unwrap()));
+        match cli.command {
+            Command::Put {
+                path_local,
+                path,
+                replication_factor,
+                is_resume,
+            } => {
+                assert_eq!(path_local, PathBuf::from("dir/report.csv"));
+                assert_eq!(path, "backup/report.csv");
+                assert_eq!(replication_factor, 2);
+                assert!(is_resume);
+            }
+            _ => panic!("Expected put"),
+        }
+    }
+
+    #[test]
+    fn get_defaults_to_local_name() {
+        match parse("get a/b.txt").unwrap().command {
+            Command::Get { path, path_local } => {
+                assert_eq!(path, "a/b.txt");
+                assert_eq!(path_local, PathBuf::from("b.txt"));
+            }
+            _ => panic!("Expected get"),
+        }
+    }
+
+    #[test]
+    fn recursive_commands() {
+        assert!(matches!(
+            parse("ls -r").unwrap().command,
+            Command::List { path, is_recursive: true } if path.is_empty()
+        ));
+        assert!(matches!(
+            parse("--recursive rm dir").unwrap().command,
+            Command::Remove { path, is_recursive: true } if path == "dir"
+        ));
+        assert!(matches!(parse("-h").unwrap().command, Command::Help));
+    }
+
+    #[test]
+    fn wrong_usage_is_explained() {
+        for (args, err) in [
+            ("", "Command must be specified"),
+            ("cp a b", "Unknown command 'cp'"),
+            ("mv a", "Wrong arguments for 'mv'"),
+            ("stat -x a", "Unknown option '-x'"),
+            ("mkdir -r a", "Option '-r' is not supported by 'mkdir'"),
+            ("get --resume a", "Option '--resume' is not supported by 'get'"),
+            ("ls --dns", "Option '--dns' needs an address"),
+            ("ls --dns dns", "Invalid DNS address 'dns'"),
+            (
+                "set-replication a 256",
+                "Replication factor must be a number below 256, not '256'",
+            ),
+        ] {
+            assert_eq!(parse(args).err().as_deref(), Some(err), "{}", args);
+        }
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(_json_str("plain"), "\"plain\"");
+        assert_eq!(_json_str("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(_json_str("l1\nl2\t\r"), "\"l1\\nl2\\t\\r\"");
+        assert_eq!(_json_str("\u{1}\u{1f}"), "\"\\u0001\\u001f\"");
+        assert_eq!(_json_str("é/日本"), "\"é/日本\"");
+    }
+}
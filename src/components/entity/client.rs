@@ -1,17 +1,33 @@
-// TODO: HoangLe [May-02]: Implement this
-
 use std::{
-    io::Write,
+    borrow::Cow,
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
     net::{IpAddr, SocketAddr, TcpStream},
+    path::Path,
+    thread,
 };
 
-use crate::components::{configs::Configs, packets::Packet};
+use crate::components::{
+    checksum::{crc32c, crc32c_update},
+    configs::Configs,
+    db::{DirEntry, FileState},
+    errors::{ClientError, ParseErrorCode},
+    packets::{BlockLocation, Packet, PacketId, RequestKind, ResumeStatus, MAX_STR_LEN},
+};
 
 // ================================================
 // Definitions
 // ================================================
+
+const SIZE_UPLOAD_CHUNK: usize = 4 * 1024 * 1024;
+
+/// Max number of blocks transferred at once by a client
+const MAX_PARALLEL_BLOCKS: usize = 4;
+
+#[derive(Clone)]
 pub struct Client {
     addr_dns: SocketAddr,
+    max_frame_size: usize,
 }
 
 // ================================================
@@ -19,32 +35,608 @@ pub struct Client {
 // ================================================
 impl Client {
     pub fn new(configs: &Configs) -> Client {
+        Client::from_addr(
+            SocketAddr::new(IpAddr::V4(configs.env_ip_dns), configs.env_port_dns),
+            configs.max_frame_size,
+        )
+    }
+
+    /// Create a client reaching the cluster through DNS at `addr_dns`, without reading any config
+    pub fn from_addr(addr_dns: SocketAddr, max_frame_size: usize) -> Client {
         Client {
-            addr_dns: SocketAddr::new(IpAddr::V4(configs.env_ip_dns), configs.env_port_dns),
-        }
-    }
-
-    pub fn ask_master_ip(&self) {
-        match TcpStream::connect(self.addr_dns) {
-            Ok(mut stream) => {
-                let _ = stream.write_all(Packet::create_ask_ip(self.addr_dns, None).to_bytes().as_slice());
-                match Packet::from_stream(&mut stream) {
-                    Ok(packet_reply) => match packet_reply.addr_sender {
-                        Some(addr_sender) => {
-                            log::info!("Master has address: {}", addr_sender);
-                        }
-                        None => {
-                            log::info!("Address for current Master not available");
-                        }
-                    },
-                    Err(error) => {
-                        log::error!("{}", error);
-                    }
+            addr_dns,
+            max_frame_size,
+        }
+    }
+
+    /// Reach the cluster through DNS at `addr_dns` instead of the configured one
+    pub fn with_dns(mut self, addr_dns: SocketAddr) -> Client {
+        self.addr_dns = addr_dns;
+        self
+    }
+
+    pub fn ask_master_ip(&self) -> Result<SocketAddr, ClientError> {
+        let mut stream = self.connect(self.addr_dns)?;
+        let packet_reply = match self.request(&mut stream, Packet::create_ask_ip(self.addr_dns, None)) {
+            Ok(packet_reply) => packet_reply,
+            Err(err) => {
+                log::info!("Address for current Master not available");
+                return Err(err);
+            }
+        };
+
+        match packet_reply.addr_master {
+            Some(addr_master) => {
+                log::info!("Master has address: {}", addr_master);
+                Ok(addr_master)
+            }
+            None => Err(ClientError::unavailable_master_ip()),
+        }
+    }
+
+    /// Upload a local file to the cluster under `filename`
+    ///
+    /// Master splits the file into blocks and tells which Data nodes should store each of them, then every block is
+    /// sent in chunks to its Data nodes. Several blocks are uploaded at once. The upload succeeds if every block was
+    /// stored by at least one Data node. A `replication_factor` of 0 takes the one of the file's directory or the
+    /// cluster's. The file is read one block at a time, so only the blocks being sent are kept in memory.
+    pub fn upload(&self, path_local: &Path, filename: &str, replication_factor: u8) -> Result<(), ClientError> {
+        let (size, checksum) = _hash_file(path_local).map_err(|err| ClientError::local_io_err(filename, err))?;
+
+        self.upload_with(filename, size, checksum, replication_factor, |location| {
+            _read_block(path_local, location)
+                .map(Cow::Owned)
+                .map_err(|err| ClientError::local_io_err(filename, err))
+        })
+    }
+
+    /// Upload `data` to the cluster under `filename`, replacing the content of an existing file
+    pub fn upload_data(&self, data: &[u8], filename: &str, replication_factor: u8) -> Result<(), ClientError> {
+        self.upload_with(
+            filename,
+            data.len() as u64,
+            crc32c(data),
+            replication_factor,
+            |location| Ok(Cow::Borrowed(_slice_block(data, location))),
+        )
+    }
+
+    /// Upload content of `size` bytes with `checksum`, whose blocks `read_block` gives as they are sent
+    fn upload_with<'a>(
+        &self,
+        filename: &str,
+        size: u64,
+        checksum: u32,
+        replication_factor: u8,
+        read_block: impl Fn(&BlockLocation) -> Result<Cow<'a, [u8]>, ClientError> + Sync,
+    ) -> Result<(), ClientError> {
+        _check_path(filename)?;
+
+        // Ask Master where to write
+        let addr_master = self.ask_master_ip()?;
+        let mut stream = self.connect(addr_master)?;
+        let packet_reply = self.request(
+            &mut stream,
+            Packet::create_request_from_client(
+                addr_master,
+                RequestKind::Upload,
+                filename,
+                size,
+                checksum,
+                replication_factor,
+            ),
+        )?;
+        let locations = packet_reply.locations.unwrap_or_default();
+        if locations.is_empty() {
+            return Err(ClientError::unavailable_data_node(filename));
+        }
+
+        // Stream blocks to Data nodes
+        let n_stored = self.for_each_block(&locations, |location| {
+            self.upload_block(location, &read_block(location)?)
+        })?;
+
+        log::info!(
+            "Uploaded '{}' in {} blocks to {} replicas",
+            filename,
+            locations.len(),
+            n_stored.iter().sum::<usize>()
+        );
+
+        Ok(())
+    }
+
+    /// Finish an interrupted upload of a local file under `filename`
+    ///
+    /// Master keeps track of which blocks of an upload were stored, so only the others are read and sent again. Fail
+    /// with NotFound if no upload of this exact content is left unfinished, in which case the file must be uploaded
+    /// whole, and with UnavailableDataNode if no Data node can take the remaining blocks for now.
+    pub fn resume_upload(&self, path_local: &Path, filename: &str) -> Result<(), ClientError> {
+        let (size, checksum) = _hash_file(path_local).map_err(|err| ClientError::local_io_err(filename, err))?;
+
+        self.resume_with(filename, size, checksum, |location| {
+            _read_block(path_local, location)
+                .map(Cow::Owned)
+                .map_err(|err| ClientError::local_io_err(filename, err))
+        })
+    }
+
+    /// Same as `resume_upload`, with the content in memory
+    pub fn resume_data(&self, data: &[u8], filename: &str) -> Result<(), ClientError> {
+        self.resume_with(filename, data.len() as u64, crc32c(data), |location| {
+            Ok(Cow::Borrowed(_slice_block(data, location)))
+        })
+    }
+
+    fn resume_with<'a>(
+        &self,
+        filename: &str,
+        size: u64,
+        checksum: u32,
+        read_block: impl Fn(&BlockLocation) -> Result<Cow<'a, [u8]>, ClientError> + Sync,
+    ) -> Result<(), ClientError> {
+        _check_path(filename)?;
+
+        let addr_master = self.ask_master_ip()?;
+        let mut stream = self.connect(addr_master)?;
+        let packet_reply = self.request(
+            &mut stream,
+            Packet::create_request_from_client(addr_master, RequestKind::Resume, filename, size, checksum, 0),
+        )?;
+        match packet_reply.resume_status {
+            Some(ResumeStatus::Resumed) => {}
+            Some(ResumeStatus::NotFound) => return Err(ClientError::not_found(filename)),
+            Some(ResumeStatus::UnavailableDataNode) => return Err(ClientError::unavailable_data_node(filename)),
+            Some(ResumeStatus::Rejected) | None => return Err(ClientError::request_rejected(addr_master, filename)),
+        }
+        let locations = packet_reply.locations.unwrap_or_default();
+        let n_persisted = packet_reply.n_blocks_persisted.unwrap_or(0);
+        log::info!(
+            "Resuming '{}': {} of {} blocks already stored",
+            filename,
+            n_persisted,
+            n_persisted as usize + locations.len()
+        );
+
+        let n_stored = self.for_each_block(&locations, |location| {
+            self.upload_block(location, &read_block(location)?)
+        })?;
+
+        log::info!(
+            "Uploaded remaining {} blocks of '{}' to {} replicas",
+            locations.len(),
+            filename,
+            n_stored.iter().sum::<usize>()
+        );
+
+        Ok(())
+    }
+
+    /// Upload a block to each of its Data nodes. Return how many of them stored it.
+    fn upload_block(&self, location: &BlockLocation, block: &[u8]) -> Result<usize, ClientError> {
+        let mut n_stored = 0;
+        for addr_node in &location.addr_nodes {
+            match self.upload_to_node(*addr_node, &location.block_id, block) {
+                Ok(()) => n_stored += 1,
+                Err(err) => log::error!("Cannot upload '{}' to {}: {}", location.block_id, addr_node, err),
+            }
+        }
+        if n_stored == 0 {
+            return Err(ClientError::unavailable_data_node(&location.block_id));
+        }
+
+        Ok(n_stored)
+    }
+
+    fn upload_to_node(&self, addr_node: SocketAddr, block_id: &str, data: &[u8]) -> Result<(), ClientError> {
+        let mut stream = self.connect(addr_node)?;
+
+        // Always send at least one chunk so that empty blocks are created as well
+        let mut offset = 0;
+        loop {
+            let chunk = &data[offset..data.len().min(offset + SIZE_UPLOAD_CHUNK)];
+            let packet_ack = self.request(
+                &mut stream,
+                Packet::create_client_upload(
+                    addr_node,
+                    block_id,
+                    offset as u64,
+                    data.len() as u64,
+                    chunk,
+                    crc32c(chunk),
+                ),
+            )?;
+            if packet_ack.is_success != Some(true) {
+                return Err(ClientError::request_rejected(addr_node, block_id));
+            }
+
+            offset += chunk.len();
+            if offset >= data.len() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start writing file `filename` block by block, replacing an existing file. Return the size blocks must have,
+    /// except the last one.
+    pub(crate) fn create_file(&self, filename: &str, replication_factor: u8) -> Result<u64, ClientError> {
+        let packet_reply = self.request_master(filename, PacketId::ClientRequestAck, |addr_master| {
+            Packet::create_request_from_client(addr_master, RequestKind::Create, filename, 0, 0, replication_factor)
+        })?;
+
+        Ok(packet_reply.length.unwrap_or(0))
+    }
+
+    /// Upload the next block of a file being written to the Data nodes Master chooses for it
+    pub(crate) fn append_block(&self, filename: &str, block: &[u8]) -> Result<(), ClientError> {
+        _check_path(filename)?;
+
+        let addr_master = self.ask_master_ip()?;
+        let mut stream = self.connect(addr_master)?;
+        let packet_reply = self.request(
+            &mut stream,
+            Packet::create_request_from_client(
+                addr_master,
+                RequestKind::Append,
+                filename,
+                block.len() as u64,
+                crc32c(block),
+                0,
+            ),
+        )?;
+        let location = match packet_reply.locations.unwrap_or_default().pop() {
+            Some(location) => location,
+            None => return Err(ClientError::unavailable_data_node(filename)),
+        };
+
+        let n_stored = self.upload_block(&location, block)?;
+        log::debug!("Appended '{}' to {} replicas", location.block_id, n_stored);
+
+        Ok(())
+    }
+
+    /// Finish a file written block by block. `size` and `checksum` are those of the whole content.
+    pub(crate) fn close_file(&self, filename: &str, size: u64, checksum: u32) -> Result<(), ClientError> {
+        self.request_master(filename, PacketId::ClientRequestAck, |addr_master| {
+            Packet::create_request_from_client(addr_master, RequestKind::Close, filename, size, checksum, 0)
+        })?;
+        log::info!("Uploaded '{}' block by block, {} bytes", filename, size);
+
+        Ok(())
+    }
+
+    /// Download file `filename` from the cluster and save it to a local path
+    ///
+    /// Master is asked for the blocks of the file and the Data nodes holding each of them, then every block is
+    /// fetched from the first of its Data nodes that answers with intact data. Several blocks are downloaded at once.
+    pub fn download(&self, filename: &str, path_local: &Path) -> Result<(), ClientError> {
+        let (addr_master, packet_reply) = self.ask_locations(filename)?;
+        let locations = packet_reply.locations.unwrap_or_default();
+
+        let data = self
+            .for_each_block(&locations, |location| self.download_block(location))?
+            .concat();
+        if Some(data.len() as u64) != packet_reply.length || Some(crc32c(&data)) != packet_reply.checksum {
+            return Err(ClientError::corrupted_data(addr_master, filename));
+        }
+
+        fs::write(path_local, data).map_err(|err| ClientError::local_io_err(filename, err))?;
+        log::info!("Downloaded '{}' in {} blocks", filename, locations.len());
+
+        Ok(())
+    }
+
+    /// Return the blocks of file `filename` in order, with the Data nodes holding each of them
+    pub fn locate(&self, filename: &str) -> Result<Vec<BlockLocation>, ClientError> {
+        let (_, packet_reply) = self.ask_locations(filename)?;
+
+        Ok(packet_reply.locations.unwrap_or_default())
+    }
+
+    /// Ask Master where the blocks of a file are. Files always have at least one block, so none means that the file
+    /// doesn't exist or that no Data node holds it.
+    pub(crate) fn ask_locations(&self, filename: &str) -> Result<(SocketAddr, Packet), ClientError> {
+        _check_path(filename)?;
+
+        let addr_master = self.ask_master_ip()?;
+        let mut stream = self.connect(addr_master)?;
+        let packet_reply = self.request(
+            &mut stream,
+            Packet::create_request_download(addr_master, filename, 0, 0),
+        )?;
+        if packet_reply
+            .locations
+            .as_ref()
+            .is_none_or(|locations| locations.is_empty())
+        {
+            return Err(ClientError::unavailable_data_node(filename));
+        }
+
+        Ok((addr_master, packet_reply))
+    }
+
+    /// Change the number of replicas of a file, or of every file under a directory. Files later uploaded to that
+    /// directory get the same factor, and a directory given with a trailing '/' is created if missing. A
+    /// `replication_factor` of 0 goes back to the cluster's.
+    ///
+    /// Master adds or removes replicas in the background. Return the number of files changed.
+    pub fn set_replication(&self, path: &str, replication_factor: u8) -> Result<u64, ClientError> {
+        _check_path(path)?;
+        let addr_master = self.ask_master_ip()?;
+        let mut stream = self.connect(addr_master)?;
+        let packet_reply = self.request(
+            &mut stream,
+            Packet::create_request_from_client(
+                addr_master,
+                RequestKind::SetReplication,
+                path,
+                0,
+                0,
+                replication_factor,
+            ),
+        )?;
+        if packet_reply.packet_id != PacketId::ClientRequestAck || packet_reply.is_success != Some(true) {
+            return Err(ClientError::request_rejected(addr_master, path));
+        }
+
+        let n_files = packet_reply.length.unwrap_or(0);
+        log::info!(
+            "Set replication factor of '{}' to {} on {} files",
+            path,
+            replication_factor,
+            n_files
+        );
+
+        Ok(n_files)
+    }
+
+    /// Create directory `path` and its missing parents
+    pub fn make_dir(&self, path: &str) -> Result<(), ClientError> {
+        self.request_master(path, PacketId::ClientRequestAck, |addr_master| {
+            Packet::create_request_from_client(addr_master, RequestKind::MakeDir, path, 0, 0, 0)
+        })?;
+        log::info!("Created directory '{}'", path);
+
+        Ok(())
+    }
+
+    /// Move a file or directory. `path_target` must not exist yet and its parent must be a directory.
+    pub fn rename(&self, path: &str, path_target: &str) -> Result<(), ClientError> {
+        _check_path(path_target)?;
+        self.request_master(path, PacketId::ClientRequestAck, |addr_master| {
+            Packet::create_request_rename(addr_master, path, path_target)
+        })?;
+        log::info!("Moved '{}' to '{}'", path, path_target);
+
+        Ok(())
+    }
+
+    /// Delete a file or an empty directory, or a directory with everything under it if `is_recursive`. Return the
+    /// number of replicas removed.
+    pub fn delete(&self, path: &str, is_recursive: bool) -> Result<u64, ClientError> {
+        let packet_reply = self.request_master(path, PacketId::ClientRequestAck, |addr_master| {
+            Packet::create_request_tree(addr_master, RequestKind::Delete, path, is_recursive)
+        })?;
+        let n_replicas = packet_reply.length.unwrap_or(0);
+        log::info!("Deleted '{}' and {} replicas", path, n_replicas);
+
+        Ok(n_replicas)
+    }
+
+    /// List the content of directory `path`, with its subdirectories if `is_recursive`. A file lists itself.
+    pub fn list(&self, path: &str, is_recursive: bool) -> Result<Vec<DirEntry>, ClientError> {
+        let packet_reply = self.request_master(path, PacketId::ListAck, |addr_master| {
+            Packet::create_request_tree(addr_master, RequestKind::List, path, is_recursive)
+        })?;
+
+        Ok(packet_reply.listing.unwrap_or_default())
+    }
+
+    /// Get the entry of a file or directory from the listing of its parent. The root has no parent and is made up.
+    pub fn stat(&self, path: &str) -> Result<DirEntry, ClientError> {
+        let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+        let (_, parents) = match names.split_last() {
+            Some(split) => split,
+            None => {
+                return Ok(DirEntry {
+                    path: String::new(),
+                    is_dir: true,
+                    size: 0,
+                    state: FileState::Complete,
+                    replication_factor: 0,
+                })
+            }
+        };
+
+        let path = names.join("/");
+        self.list(&parents.join("/"), false)?
+            .into_iter()
+            .find(|entry| entry.path == path)
+            .ok_or_else(|| ClientError::not_found(&path))
+    }
+
+    /// Send Master a request about `path` and check that it answered with a successful `packet_id`
+    fn request_master(
+        &self,
+        path: &str,
+        packet_id: PacketId,
+        create_packet: impl FnOnce(SocketAddr) -> Packet,
+    ) -> Result<Packet, ClientError> {
+        _check_path(path)?;
+        let addr_master = self.ask_master_ip()?;
+        let mut stream = self.connect(addr_master)?;
+        let packet_reply = self.request(&mut stream, create_packet(addr_master))?;
+        if packet_reply.packet_id != packet_id || packet_reply.is_success != Some(true) {
+            return Err(ClientError::request_rejected(addr_master, path));
+        }
+
+        Ok(packet_reply)
+    }
+
+    /// Fetch a block from one replica, falling back to the next one on failure
+    pub(crate) fn download_block(&self, location: &BlockLocation) -> Result<Vec<u8>, ClientError> {
+        for addr_node in &location.addr_nodes {
+            match self.download_from_node(*addr_node, &location.block_id, 0, 0) {
+                Ok(data) if data.len() as u64 == location.size && crc32c(&data) == location.checksum => {
+                    log::debug!("Downloaded '{}' from {}", location.block_id, addr_node);
+                    return Ok(data);
                 }
+                Ok(_) => log::error!("Replica of '{}' at {} is corrupted", location.block_id, addr_node),
+                Err(err) => log::error!("Cannot download '{}' from {}: {}", location.block_id, addr_node, err),
             }
-            Err(e) => {
-                log::error!("Cannot connect to: {}: {}", self.addr_dns, e);
+        }
+
+        Err(ClientError::unavailable_data_node(&location.block_id))
+    }
+
+    /// Run `f` on every block, a few blocks at a time in parallel, and return the results in block order
+    fn for_each_block<T: Send>(
+        &self,
+        locations: &[BlockLocation],
+        f: impl Fn(&BlockLocation) -> Result<T, ClientError> + Sync,
+    ) -> Result<Vec<T>, ClientError> {
+        let f = &f;
+        let mut results = Vec::<T>::with_capacity(locations.len());
+        for batch in locations.chunks(MAX_PARALLEL_BLOCKS) {
+            let batch_results: Vec<Result<T, ClientError>> = thread::scope(|scope| {
+                let handles: Vec<_> = batch.iter().map(|location| scope.spawn(move || f(location))).collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("Thread transferring a block panicked"))
+                    .collect()
+            });
+            for result in batch_results {
+                results.push(result?);
             }
         }
+
+        Ok(results)
+    }
+
+    /// Fetch range [offset, offset + length) of a block from one replica, falling back to the next one on failure. The
+    /// range is cut to the end of the block. Unlike `download_block`, only the chunks are checked, not the whole block.
+    pub(crate) fn download_range(
+        &self,
+        location: &BlockLocation,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, ClientError> {
+        let size = match length {
+            0 => location.size.saturating_sub(offset),
+            _ => location.size.saturating_sub(offset).min(length),
+        };
+        for addr_node in &location.addr_nodes {
+            match self.download_from_node(*addr_node, &location.block_id, offset, length) {
+                Ok(data) if data.len() as u64 == size => return Ok(data),
+                Ok(_) => log::error!("Replica of '{}' at {} is truncated", location.block_id, addr_node),
+                Err(err) => log::error!("Cannot download '{}' from {}: {}", location.block_id, addr_node, err),
+            }
+        }
+
+        Err(ClientError::unavailable_data_node(&location.block_id))
+    }
+
+    /// Fetch range [offset, offset + length) of a block, or the whole block from `offset` if `length` = 0
+    fn download_from_node(
+        &self,
+        addr_node: SocketAddr,
+        block_id: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, ClientError> {
+        let mut stream = self.connect(addr_node)?;
+        let mut packet_reply = self.request(
+            &mut stream,
+            Packet::create_request_download(addr_node, block_id, offset, length),
+        )?;
+
+        let mut data = Vec::<u8>::new();
+        loop {
+            if packet_reply.packet_id != PacketId::DataNodeSendData {
+                return Err(ClientError::request_rejected(addr_node, block_id));
+            }
+
+            let chunk = packet_reply.data.unwrap();
+            if packet_reply.offset != Some(offset + data.len() as u64)
+                || crc32c(&chunk) != packet_reply.checksum.unwrap()
+            {
+                return Err(ClientError::corrupted_data(addr_node, block_id));
+            }
+            data.extend_from_slice(&chunk);
+
+            if offset + data.len() as u64 >= packet_reply.length.unwrap() {
+                break;
+            }
+            packet_reply = Packet::from_stream(&mut stream, self.max_frame_size)?;
+        }
+
+        Ok(data)
+    }
+
+    fn connect(&self, addr: SocketAddr) -> Result<TcpStream, ClientError> {
+        TcpStream::connect(addr).map_err(|err| {
+            log::error!("Cannot connect to: {}: {}", addr, err);
+            ClientError::connection_failed(addr, err)
+        })
+    }
+
+    /// Send a packet and wait for the reply on the same connection
+    fn request(&self, stream: &mut TcpStream, packet: Packet) -> Result<Packet, ClientError> {
+        let addr_receiver = packet.addr_receiver.unwrap();
+        if let Err(err) = stream.write_all(packet.to_bytes().as_slice()) {
+            return Err(ClientError::connection_failed(addr_receiver, err));
+        }
+
+        match Packet::from_stream(stream, self.max_frame_size) {
+            Ok(packet_reply) => Ok(packet_reply),
+            Err(err) => match err.error_code {
+                ParseErrorCode::UnavailableMasterAddress => Err(ClientError::unavailable_master_ip()),
+                _ => {
+                    log::error!("{}", err);
+                    Err(ClientError::from(err))
+                }
+            },
+        }
+    }
+}
+
+/// Size and checksum of a local file, read in chunks
+fn _hash_file(path_local: &Path) -> io::Result<(u64, u32)> {
+    let mut file = fs::File::open(path_local)?;
+    let mut chunk = vec![0u8; SIZE_UPLOAD_CHUNK];
+    let (mut size, mut checksum) = (0u64, 0u32);
+    loop {
+        match file.read(&mut chunk)? {
+            0 => return Ok((size, checksum)),
+            n_read => {
+                size += n_read as u64;
+                checksum = crc32c_update(checksum, &chunk[..n_read]);
+            }
+        }
+    }
+}
+
+/// Read the range of a local file a block covers. A file which shrank since it was hashed fails.
+fn _read_block(path_local: &Path, location: &BlockLocation) -> io::Result<Vec<u8>> {
+    let mut file = fs::File::open(path_local)?;
+    file.seek(SeekFrom::Start(location.offset))?;
+    let mut block = vec![0u8; location.size as usize];
+    file.read_exact(&mut block)?;
+
+    Ok(block)
+}
+
+fn _slice_block<'a>(data: &'a [u8], location: &BlockLocation) -> &'a [u8] {
+    let offset = location.offset as usize;
+    &data[offset..offset + location.size as usize]
+}
+
+/// Paths are sent with a 2-byte length, so longer ones are refused before building any request
+fn _check_path(path: &str) -> Result<(), ClientError> {
+    match path.len() <= MAX_STR_LEN {
+        true => Ok(()),
+        false => Err(ClientError::invalid_path(&path[..path.floor_char_boundary(64)])),
     }
 }
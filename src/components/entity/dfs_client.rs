@@ -0,0 +1,625 @@
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::SocketAddr,
+    thread,
+};
+
+use crate::components::{
+    checksum::crc32c_update,
+    db::DirEntry,
+    entity::client::Client,
+    errors::ClientError,
+    packets::{BlockLocation, DEFAULT_MAX_FRAME_SIZE},
+};
+
+// ================================================
+// Definitions
+// ================================================
+
+/// Most bytes a reader fetches at once
+const SIZE_WINDOW: u64 = 4 * 1024 * 1024;
+
+/// Access to the cluster for Rust programs
+///
+/// Only the address of DNS is needed. Master is looked up through DNS for every operation, so a Master elected after a
+/// failover is picked up without reconnecting.
+///
+/// ```no_run
+/// use dfs::DfsClient;
+///
+/// let dfs = DfsClient::connect("127.0.0.1:7000".parse().unwrap())?;
+/// dfs.write("reports/today.csv", b"id,value\n1,42\n")?;
+///
+/// let file = dfs.open("reports/today.csv")?;
+/// let mut header = [0u8; 8];
+/// dfs.read_at(&file, 0, &mut header)?;
+/// # Ok::<(), dfs::ClientError>(())
+/// ```
+pub struct DfsClient {
+    client: Client,
+}
+
+/// File opened for reading: its size and where its blocks are when it was opened
+///
+/// Files are written whole, so the snapshot stays valid until the file is written again or deleted.
+pub struct DfsFile {
+    pub path: String,
+    pub size: u64,
+    pub checksum: u32,
+    pub blocks: Vec<BlockLocation>,
+}
+
+/// Sequential reader of a file, which can seek anywhere in it
+///
+/// Data is fetched from Data nodes in windows of at most 4 MiB within a block when a read first reaches them, and only
+/// the last window is kept in memory.
+pub struct DfsReader<'a> {
+    client: &'a dyn Transfer,
+    file: DfsFile,
+    position: u64,
+    /// Offset in the file and content of the last window fetched
+    window: Option<(u64, Vec<u8>)>,
+}
+
+/// Writer of a new file which uploads each block as soon as it is full
+///
+/// At most one block is kept in memory. The file is complete once `close` returns, and `abort` deletes it instead. A
+/// writer dropped without either, e.g. when an error cut the write short, abandons the file, which is deleted in the
+/// background rather than left truncated. A writer dropped while panicking leaves the file unfinished.
+pub struct DfsWriter<'a> {
+    client: &'a dyn Transfer,
+    path: String,
+    block_size: usize,
+    buffer: Vec<u8>,
+    size: u64,
+    checksum: u32,
+    n_blocks: u64,
+    is_closed: bool,
+}
+
+/// What readers and writers ask of the cluster, so that they can be tried against a fake one
+pub(crate) trait Transfer {
+    fn download_range(&self, location: &BlockLocation, offset: u64, length: u64) -> Result<Vec<u8>, ClientError>;
+
+    fn append_block(&self, path: &str, block: &[u8]) -> Result<(), ClientError>;
+
+    fn close_file(&self, path: &str, size: u64, checksum: u32) -> Result<(), ClientError>;
+
+    fn delete_file(&self, path: &str) -> Result<(), ClientError>;
+
+    /// Delete a file without waiting for it to be done
+    fn abandon_file(&self, path: &str);
+}
+
+// ================================================
+// Implementations
+// ================================================
+
+impl Transfer for Client {
+    fn download_range(&self, location: &BlockLocation, offset: u64, length: u64) -> Result<Vec<u8>, ClientError> {
+        Client::download_range(self, location, offset, length)
+    }
+
+    fn append_block(&self, path: &str, block: &[u8]) -> Result<(), ClientError> {
+        Client::append_block(self, path, block)
+    }
+
+    fn close_file(&self, path: &str, size: u64, checksum: u32) -> Result<(), ClientError> {
+        Client::close_file(self, path, size, checksum)
+    }
+
+    fn delete_file(&self, path: &str) -> Result<(), ClientError> {
+        self.delete(path, false).map(|_| ())
+    }
+
+    fn abandon_file(&self, path: &str) {
+        let client = self.clone();
+        let path = path.to_string();
+        thread::spawn(move || {
+            if let Err(err) = client.delete(&path, false) {
+                log::error!("Cannot delete abandoned '{}': {}", path, err);
+            }
+        });
+    }
+}
+
+impl DfsClient {
+    /// Connect to the cluster whose DNS listens at `addr_dns`. Fail if DNS doesn't know any Master.
+    pub fn connect(addr_dns: SocketAddr) -> Result<DfsClient, ClientError> {
+        DfsClient::connect_with(addr_dns, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// Same as `connect`, for clusters configured with another `MAX_FRAME_SIZE_BYTE`
+    pub fn connect_with(addr_dns: SocketAddr, max_frame_size: usize) -> Result<DfsClient, ClientError> {
+        let client = Client::from_addr(addr_dns, max_frame_size);
+        client.ask_master_ip()?;
+
+        Ok(DfsClient { client })
+    }
+
+    /// Create an empty file with its own replication factor, or 0 to keep the one of the file it replaces, else take
+    /// the one of its directory. Missing directories are created and an existing file is emptied.
+    pub fn create(&self, path: &str, replication_factor: u8) -> Result<(), ClientError> {
+        self.client.upload_data(&[], path, replication_factor)
+    }
+
+    /// Replace the content of file `path`, creating it if needed. An existing file keeps its replication factor.
+    pub fn write(&self, path: &str, data: &[u8]) -> Result<(), ClientError> {
+        self.client.upload_data(data, path, 0)
+    }
+
+    /// Same as `write`, but if an earlier write of the same `data` was interrupted, only send the blocks it didn't
+    /// store
+    pub fn resume_write(&self, path: &str, data: &[u8]) -> Result<(), ClientError> {
+        match self.client.resume_data(data, path) {
+            Err(err) if err.is_not_found() => self.write(path, data),
+            result => result,
+        }
+    }
+
+    /// Open file `path` for reading with `read_at`
+    pub fn open(&self, path: &str) -> Result<DfsFile, ClientError> {
+        let (_, packet_reply) = self.client.ask_locations(path)?;
+
+        Ok(DfsFile {
+            path: path.to_string(),
+            size: packet_reply.length.unwrap_or(0),
+            checksum: packet_reply.checksum.unwrap_or(0),
+            blocks: packet_reply.locations.unwrap_or_default(),
+        })
+    }
+
+    /// Read from `offset` of an opened file into `buf` and return the number of bytes read, 0 at the end of the file.
+    /// Only the range asked is fetched, each block from the first replica which answers. Blocks read whole are also
+    /// checked against their checksum.
+    pub fn read_at(&self, file: &DfsFile, offset: u64, buf: &mut [u8]) -> Result<usize, ClientError> {
+        let end = file.size.min(offset.saturating_add(buf.len() as u64));
+
+        let mut n_read = 0;
+        for location in &file.blocks {
+            let block_end = location.offset + location.size;
+            if block_end <= offset || location.offset >= end {
+                continue;
+            }
+
+            let start = offset.max(location.offset);
+            let stop = end.min(block_end);
+            let data = if start == location.offset && stop == block_end {
+                self.client.download_block(location)?
+            } else {
+                self.client
+                    .download_range(location, start - location.offset, stop - start)?
+            };
+            buf[(start - offset) as usize..(stop - offset) as usize].copy_from_slice(&data);
+            n_read += (stop - start) as usize;
+        }
+
+        Ok(n_read)
+    }
+
+    /// Read a whole file
+    pub fn read(&self, path: &str) -> Result<Vec<u8>, ClientError> {
+        let file = self.open(path)?;
+        let mut data = vec![0u8; file.size as usize];
+        let n_read = self.read_at(&file, 0, &mut data)?;
+        data.truncate(n_read);
+
+        Ok(data)
+    }
+
+    /// Open file `path` for streaming reads
+    pub fn reader(&self, path: &str) -> Result<DfsReader<'_>, ClientError> {
+        Ok(DfsReader::new(&self.client, self.open(path)?))
+    }
+
+    /// Create file `path` for streaming writes, replacing an existing file. A `replication_factor` of 0 keeps the one of
+    /// the file replaced, else takes the one of its directory. The previous content stays unreadable until the writer
+    /// is closed.
+    pub fn writer(&self, path: &str, replication_factor: u8) -> Result<DfsWriter<'_>, ClientError> {
+        let block_size = self.client.create_file(path, replication_factor)? as usize;
+        if block_size == 0 {
+            return Err(ClientError::unavailable_data_node(path));
+        }
+
+        Ok(DfsWriter::new(&self.client, path, block_size))
+    }
+
+    /// List directory `path`, with its subdirectories if `is_recursive`. A file lists itself.
+    pub fn list(&self, path: &str, is_recursive: bool) -> Result<Vec<DirEntry>, ClientError> {
+        self.client.list(path, is_recursive)
+    }
+
+    /// Get the size, state and replication factor of a file, or tell that `path` is a directory
+    pub fn stat(&self, path: &str) -> Result<DirEntry, ClientError> {
+        self.client.stat(path)
+    }
+
+    /// Delete a file or an empty directory, or a whole tree if `is_recursive`. Return the number of replicas removed.
+    pub fn delete(&self, path: &str, is_recursive: bool) -> Result<u64, ClientError> {
+        self.client.delete(path, is_recursive)
+    }
+
+    /// Create directory `path` and its missing parents
+    pub fn make_dir(&self, path: &str) -> Result<(), ClientError> {
+        self.client.make_dir(path)
+    }
+
+    /// Move a file or directory to `path_target`, which must not exist yet
+    pub fn rename(&self, path: &str, path_target: &str) -> Result<(), ClientError> {
+        self.client.rename(path, path_target)
+    }
+
+    /// Change the replication factor of a file or of every file under a directory. Return the number of files changed.
+    pub fn set_replication(&self, path: &str, replication_factor: u8) -> Result<u64, ClientError> {
+        self.client.set_replication(path, replication_factor)
+    }
+}
+
+impl DfsFile {
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+}
+
+impl DfsReader<'_> {
+    fn new(client: &dyn Transfer, file: DfsFile) -> DfsReader<'_> {
+        DfsReader {
+            client,
+            file,
+            position: 0,
+            window: None,
+        }
+    }
+
+    /// File being read, as it was when opened
+    pub fn file(&self) -> &DfsFile {
+        &self.file
+    }
+}
+
+impl Read for DfsReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let position = self.position;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self
+            .window
+            .as_ref()
+            .is_none_or(|(start, data)| position < *start || position >= start + data.len() as u64)
+        {
+            let location = match self
+                .file
+                .blocks
+                .iter()
+                .find(|location| position < location.offset + location.size)
+            {
+                Some(location) => location,
+                None => return Ok(0),
+            };
+
+            let offset = position - location.offset;
+            let data = self
+                .client
+                .download_range(location, offset, SIZE_WINDOW.min(location.size - offset))
+                .map_err(io::Error::other)?;
+            self.window = Some((position, data));
+        }
+        let (start, data) = match &self.window {
+            Some(window) => window,
+            None => return Ok(0),
+        };
+
+        let from = (position - start) as usize;
+        let n_read = buf.len().min(data.len() - from);
+        buf[..n_read].copy_from_slice(&data[from..from + n_read]);
+        self.position += n_read as u64;
+
+        Ok(n_read)
+    }
+}
+
+impl Seek for DfsReader<'_> {
+    /// Seeking past the end is allowed, and reads there return nothing
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.file.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot seek before the start of the file",
+            )),
+        }
+    }
+}
+
+impl DfsWriter<'_> {
+    fn new<'a>(client: &'a dyn Transfer, path: &str, block_size: usize) -> DfsWriter<'a> {
+        DfsWriter {
+            client,
+            path: path.to_string(),
+            block_size,
+            buffer: Vec::with_capacity(block_size),
+            size: 0,
+            checksum: 0,
+            n_blocks: 0,
+            is_closed: false,
+        }
+    }
+
+    /// Upload what is left and make the file readable with its full content. If this fails, the file is abandoned.
+    pub fn close(mut self) -> Result<(), ClientError> {
+        // Empty files still have one empty block, as when uploaded whole
+        if !self.buffer.is_empty() || self.n_blocks == 0 {
+            self.append_block()?;
+        }
+
+        self.client.close_file(&self.path, self.size, self.checksum)?;
+        self.is_closed = true;
+
+        Ok(())
+    }
+
+    /// Give up writing and delete the file, waiting for Master to confirm
+    pub fn abort(mut self) -> Result<(), ClientError> {
+        self.is_closed = true;
+        log::info!("Abort writing '{}'", self.path);
+
+        self.client.delete_file(&self.path)
+    }
+
+    fn append_block(&mut self) -> Result<(), ClientError> {
+        self.client.append_block(&self.path, &self.buffer)?;
+
+        self.size += self.buffer.len() as u64;
+        self.checksum = crc32c_update(self.checksum, &self.buffer);
+        self.n_blocks += 1;
+        self.buffer.clear();
+
+        Ok(())
+    }
+}
+
+impl Write for DfsWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n_written = buf.len().min(self.block_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n_written]);
+        if self.buffer.len() == self.block_size {
+            self.append_block().map_err(io::Error::other)?;
+        }
+
+        Ok(n_written)
+    }
+
+    /// Blocks are only sent once full, so that flushing often doesn't split the file into small blocks. Use `close` to
+    /// send the last one.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for DfsWriter<'_> {
+    /// Never waits for the cluster, as it may run while unwinding
+    fn drop(&mut self) {
+        if self.is_closed {
+            return;
+        }
+
+        if thread::panicking() {
+            log::warn!("'{}' dropped while panicking, leave it unfinished", self.path);
+            return;
+        }
+
+        log::warn!("'{}' dropped before being closed, abandon it", self.path);
+        self.client.abandon_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        panic::{self, AssertUnwindSafe},
+    };
+
+    use super::*;
+    use crate::components::checksum::crc32c;
+
+    /// Cluster holding one file in memory, which records what readers and writers ask of it
+    #[derive(Default)]
+    struct FakeCluster {
+        data: Vec<u8>,
+        ranges: RefCell<Vec<(u64, u64)>>,
+        blocks: RefCell<Vec<Vec<u8>>>,
+        closed: RefCell<Option<(u64, u32)>>,
+        deleted: RefCell<Vec<String>>,
+        abandoned: RefCell<Vec<String>>,
+    }
+
+    impl Transfer for FakeCluster {
+        fn download_range(&self, location: &BlockLocation, offset: u64, length: u64) -> Result<Vec<u8>, ClientError> {
+            self.ranges.borrow_mut().push((location.offset + offset, length));
+            let start = (location.offset + offset) as usize;
+            let end = (location.offset + location.size).min(location.offset + offset + length) as usize;
+
+            Ok(self.data[start..end].to_vec())
+        }
+
+        fn append_block(&self, _: &str, block: &[u8]) -> Result<(), ClientError> {
+            self.blocks.borrow_mut().push(block.to_vec());
+            Ok(())
+        }
+
+        fn close_file(&self, _: &str, size: u64, checksum: u32) -> Result<(), ClientError> {
+            *self.closed.borrow_mut() = Some((size, checksum));
+            Ok(())
+        }
+
+        fn delete_file(&self, path: &str) -> Result<(), ClientError> {
+            self.deleted.borrow_mut().push(path.to_string());
+            Ok(())
+        }
+
+        fn abandon_file(&self, path: &str) {
+            self.abandoned.borrow_mut().push(path.to_string());
+        }
+    }
+
+    fn cluster(size: usize) -> FakeCluster {
+        FakeCluster {
+            data: (0..size).map(|i| i as u8).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// File of the whole content of `cluster`, cut in blocks of `block_size` bytes
+    fn file(cluster: &FakeCluster, block_size: u64) -> DfsFile {
+        let size = cluster.data.len() as u64;
+        let blocks = (0..size.div_ceil(block_size))
+            .map(|index| BlockLocation {
+                block_id: format!("1#{}", index),
+                offset: index * block_size,
+                size: block_size.min(size - index * block_size),
+                checksum: 0,
+                addr_nodes: vec![],
+            })
+            .collect();
+
+        DfsFile {
+            path: "f".to_string(),
+            size,
+            checksum: crc32c(&cluster.data),
+            blocks,
+        }
+    }
+
+    #[test]
+    fn reader_reads_across_blocks_in_bounded_windows() {
+        let size_window = SIZE_WINDOW as usize;
+        let cluster = cluster(2 * size_window + 10);
+        let mut reader = DfsReader::new(&cluster, file(&cluster, 2 * SIZE_WINDOW + 4));
+
+        let mut data = Vec::<u8>::new();
+        reader.read_to_end(&mut data).unwrap();
+
+        assert!(data == cluster.data);
+        assert_eq!(
+            *cluster.ranges.borrow(),
+            vec![
+                (0, SIZE_WINDOW),
+                (SIZE_WINDOW, SIZE_WINDOW),
+                (2 * SIZE_WINDOW, 4),
+                (2 * SIZE_WINDOW + 4, 6)
+            ]
+        );
+    }
+
+    #[test]
+    fn reader_seeks_relative_to_end_and_current_position() {
+        let cluster = cluster(10);
+        let mut reader = DfsReader::new(&cluster, file(&cluster, 4));
+
+        assert_eq!(reader.seek(SeekFrom::End(-3)).unwrap(), 7);
+        let mut buf = [0u8; 8];
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 7);
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], &[8, 9]);
+
+        assert_eq!(reader.seek(SeekFrom::Current(-6)).unwrap(), 4);
+        assert_eq!(reader.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], &[4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn reader_rejects_seek_before_start_and_reads_nothing_past_end() {
+        let cluster = cluster(10);
+        let mut reader = DfsReader::new(&cluster, file(&cluster, 4));
+        reader.seek(SeekFrom::Start(2)).unwrap();
+
+        let err = reader.seek(SeekFrom::Current(-3)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(reader.seek(SeekFrom::End(-11)).is_err());
+        assert_eq!(reader.stream_position().unwrap(), 2);
+
+        let mut buf = [0u8; 4];
+        assert_eq!(reader.seek(SeekFrom::End(5)).unwrap(), 15);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), 10);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert!(cluster.ranges.borrow().is_empty());
+    }
+
+    #[test]
+    fn writer_splits_writes_at_block_boundaries() {
+        let cluster = FakeCluster::default();
+        let mut writer = DfsWriter::new(&cluster, "f", 4);
+
+        assert_eq!(writer.write(&[0, 1, 2]).unwrap(), 3);
+        assert_eq!(writer.write(&[3, 4, 5]).unwrap(), 1);
+        writer.write_all(&[4, 5, 6, 7, 8, 9]).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(cluster.blocks.borrow().len(), 2);
+        writer.close().unwrap();
+
+        let blocks = cluster.blocks.borrow();
+        assert_eq!(*blocks, vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]]);
+        assert_eq!(*cluster.closed.borrow(), Some((10, crc32c(&blocks.concat()))));
+        assert!(cluster.abandoned.borrow().is_empty());
+    }
+
+    #[test]
+    fn writer_of_exact_blocks_or_nothing_adds_no_extra_block() {
+        let cluster = FakeCluster::default();
+        let mut writer = DfsWriter::new(&cluster, "f", 4);
+        writer.write_all(&[1; 8]).unwrap();
+        writer.close().unwrap();
+        assert_eq!(cluster.blocks.borrow().len(), 2);
+
+        let cluster = FakeCluster::default();
+        DfsWriter::new(&cluster, "f", 4).close().unwrap();
+        assert_eq!(*cluster.blocks.borrow(), vec![Vec::<u8>::new()]);
+        assert_eq!(*cluster.closed.borrow(), Some((0, 0)));
+    }
+
+    #[test]
+    fn writer_dropped_unclosed_abandons_file_and_abort_deletes_it() {
+        let cluster = FakeCluster::default();
+        let mut writer = DfsWriter::new(&cluster, "f", 4);
+        writer.write_all(&[1; 6]).unwrap();
+        drop(writer);
+        assert_eq!(*cluster.abandoned.borrow(), vec!["f"]);
+        assert!(cluster.closed.borrow().is_none());
+
+        let cluster = FakeCluster::default();
+        DfsWriter::new(&cluster, "f", 4).abort().unwrap();
+        assert_eq!(*cluster.deleted.borrow(), vec!["f"]);
+        assert!(cluster.abandoned.borrow().is_empty());
+    }
+
+    #[test]
+    fn writer_dropped_while_panicking_leaves_file_alone() {
+        let cluster = FakeCluster::default();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _writer = DfsWriter::new(&cluster, "f", 4);
+            panic!("write failed");
+        }));
+
+        assert!(result.is_err());
+        assert!(cluster.abandoned.borrow().is_empty());
+        assert!(cluster.deleted.borrow().is_empty());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::components::errors::ParseError;
+
 // ================================================
 // Definition
 // ================================================
@@ -10,17 +12,21 @@ pub enum Role {
     Master  = 1,
     Data    = 2,
     DNS     = 3,
+    Standby = 4,
 }
 // ================================================
 // Implementations
 // ================================================
-impl From<u8> for Role {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for Role {
+    type Error = ParseError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            1 => Role::Master,
-            2 => Role::Data,
-            3 => Role::DNS,
-            _ => panic!("Error as parsing to enum Role: value = {}", value),
+            1 => Ok(Role::Master),
+            2 => Ok(Role::Data),
+            3 => Ok(Role::DNS),
+            4 => Ok(Role::Standby),
+            _ => Err(ParseError::incorrect_enum_value("Role", value)),
         }
     }
 }
@@ -31,6 +37,7 @@ impl From<&Role> for u8 {
             Role::Master => 1,
             Role::Data => 2,
             Role::DNS => 3,
+            Role::Standby => 4,
             _ => panic!("Error as parsing from enum Role"),
         }
     }
@@ -43,6 +50,7 @@ impl std::fmt::Display for Role {
             Role::Master => "Master",
             Role::Data => "Data",
             Role::DNS => "DNS",
+            Role::Standby => "Standby",
         };
         write!(f, "{}", s)
     }
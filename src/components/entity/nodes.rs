@@ -1,35 +1,73 @@
 use log;
 
 use std::{
-    io::Write,
+    collections::{HashSet, VecDeque},
+    io::{self, Write},
     net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream},
     str::FromStr,
-    sync::mpsc::{channel, Receiver, Sender},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
     thread::{self, JoinHandle},
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::components::{
+    block_report::{self, BlockInfo},
+    block_store::{BlockMeta, BlockStore},
+    checksum::{crc32c, crc32c_update},
     configs::Configs,
-    db::NodeInfoDB,
+    db::{
+        BlockInfoEntry, DBBackend, FileInfoDB, FileInfoEntry, FileState, NodeInfoDB, NodeInfoEntry, NodeStatus,
+        ReplicaInfoEntry,
+    },
     entity::node_roles::Role,
     errors::NodeCreationError,
-    packets::{Packet, PacketId},
+    failure_detector::{FailureDetector, NodeStatusEvent},
+    master_registry::MasterRegistry,
+    packets::{BlockLocation, Packet, PacketId, RequestKind, ResumeStatus},
+    placement::{self, PlacementPolicy},
+    raft::RaftNode,
+    rebalancer::Rebalancer,
+    replication::ReplicationManager,
+    scrubber::Scrubber,
+    state_sync::{StateChange, StateSyncPublisher, StateSyncSubscriber},
+    worker_pool::WorkerPool,
 };
 
 // ================================================
 // Definition
 // ================================================
 
+const SIZE_CHUNK: usize = 4 * 1024 * 1024;
+
+/// Blocks a Data node streams to clients at once, and downloads which may wait for one of them
+const N_WORKERS_DOWNLOAD: usize = 8;
+const MAX_DOWNLOADS_WAITING: usize = 64;
+
+/// Max number of packets Raft leader keeps while waiting for its log to be applied
+const MAX_PACKETS_WAITING: usize = 1024;
+
 pub struct Node {
     configs: Configs,
     role: Role,
 }
 
+/// Place taken by a connection read by thread:Receiver, given back when the reading thread ends
+struct ConnectionSlot(Arc<AtomicUsize>);
+
 // ================================================
 // Implementation
 // ================================================
 
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 impl Node {
     /// Create new node
     pub fn new(configs: Configs, role: Role) -> Node {
@@ -44,6 +82,10 @@ impl Node {
         // Declare different threads for different functions
         // ================================================
 
+        // Data nodes verify their blocks in the background
+        if let Role::Data = self.role {
+            self.create_thread_scrubber(sender_receiver2processor.clone());
+        }
         let thread_receiver = match self.create_thread_receiver(sender_receiver2processor) {
             Ok(handle) => handle,
             Err(err) => {
@@ -74,7 +116,6 @@ impl Node {
         }
         if let Err(err) = thread_sender.join() {
             log::error!("Error as creating thread_sender: {:?}", err);
-            return;
         }
     }
 
@@ -90,8 +131,10 @@ impl Node {
             _ => self.configs.env_port_receiver,
         };
         let addr_node = SocketAddr::from(([127, 0, 0, 1], port));
+        let max_frame_size = self.configs.max_frame_size;
+        let max_connections = self.configs.max_connections;
         Ok(thread::spawn(move || {
-            let listener = match TcpListener::bind(&addr_node) {
+            let listener = match TcpListener::bind(addr_node) {
                 Ok(listener) => listener,
                 Err(_) => {
                     log::error!("Cannot bind to {}", addr_node);
@@ -100,24 +143,27 @@ impl Node {
             };
             log::info!("Server starts at {}", addr_node);
 
+            let n_connections = Arc::new(AtomicUsize::new(0));
             for stream in listener.incoming() {
                 match stream {
-                    Ok(mut stream) => {
-                        let packet = match Packet::from_stream(&mut stream) {
-                            Ok(packet) => packet,
-                            Err(e) => {
-                                log::error!("{}", e);
-                                continue;
-                            }
-                        };
-
-                        // Send to thread Processor
-                        if let Err(err) = sender_receiver2processor.send(packet) {
-                            log::error!(
-                                "Error as sending packet from thread:Receiver -> thread:Processor: err = {}",
-                                err
+                    Ok(stream) => {
+                        // Each connection may carry several packets, so read it on its own thread. Threads are
+                        // limited so that a flood of connections cannot exhaust the node.
+                        if n_connections.fetch_add(1, Ordering::SeqCst) >= max_connections {
+                            n_connections.fetch_sub(1, Ordering::SeqCst);
+                            log::warn!(
+                                "Refuse connection from {:?}: {} connections already open",
+                                stream.peer_addr(),
+                                max_connections
                             );
-                        };
+                            continue;
+                        }
+                        let sender_receiver2processor = sender_receiver2processor.clone();
+                        let slot = ConnectionSlot(Arc::clone(&n_connections));
+                        thread::spawn(move || {
+                            let _slot = slot;
+                            _receive_packets(stream, max_frame_size, sender_receiver2processor);
+                        });
                     }
                     Err(e) => {
                         log::error!("{}", e);
@@ -128,6 +174,18 @@ impl Node {
         }))
     }
 
+    /// Create a thread dedicated for verifying blocks stored, which hands blocks found corrupted to thread:Processor
+    fn create_thread_scrubber(&mut self, sender_receiver2processor: Sender<Packet>) -> JoinHandle<()> {
+        log::info!("Creating thread: Scrubber");
+
+        let addr_current = SocketAddr::from(([127, 0, 0, 1], self.configs.env_port_receiver));
+        Scrubber::new(&self.configs).spawn(
+            BlockStore::new(&self.configs.dir_data),
+            addr_current,
+            sender_receiver2processor,
+        )
+    }
+
     /// Create thread for sending packet
     fn create_thread_sender(
         &mut self,
@@ -145,13 +203,22 @@ impl Node {
                     }
                 };
 
-                // Connect and send
-                let mut stream = match TcpStream::connect(&addr_receiver) {
-                    Ok(stream) => stream,
-                    Err(_) => {
-                        log::error!("Cannot connect to address: {}", addr_receiver);
-                        continue;
-                    }
+                // Reply on the requester's connection if it waits there, otherwise connect and send
+                let mut stream = match packet.stream.as_ref() {
+                    Some(stream) => match stream.try_clone() {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            log::error!("Cannot reuse connection to address: {} : {}", addr_receiver, err);
+                            continue;
+                        }
+                    },
+                    None => match TcpStream::connect(addr_receiver) {
+                        Ok(stream) => stream,
+                        Err(_) => {
+                            log::error!("Cannot connect to address: {}", addr_receiver);
+                            continue;
+                        }
+                    },
                 };
 
                 let a = packet.to_bytes();
@@ -175,14 +242,66 @@ impl Node {
     ) {
         let addr_dns: SocketAddr = SocketAddr::new(IpAddr::V4(self.configs.env_ip_dns), self.configs.env_port_dns);
         let mut addr_master: Option<SocketAddr> = None;
+
+        // For Data nodes to tell whether Master is alive
+        let mut ts_master_seen: Option<Instant> = None;
+        // For DNS to tell whether Master is alive and decide who takes over
+        let mut master_registry = MasterRegistry::new(&self.configs);
+
+        // For Data nodes to report what they store once registered and periodically afterwards
+        let mut ts_block_report: Option<Instant> = None;
+        // For Data nodes to report their load, as requests served between two HeartbeatAcks
+        let mut n_requests: u64 = 0;
         let addr_current = SocketAddr::V4(SocketAddrV4::new(
             Ipv4Addr::new(127, 0, 0, 1),
             self.configs.env_port_receiver,
         ));
 
         // For data management
-        let node_info = NodeInfoDB::intialize("node_info");
-        // let data_info = FileInfoDB::intialize(db_name)
+        // Only Master's metadata needs to survive a restart
+        let db_backend = match self.role {
+            Role::Master | Role::Standby => self.configs.db_backend.clone(),
+            _ => DBBackend::InMemory,
+        };
+        let file_info = FileInfoDB::initialize("file_info", &db_backend);
+        let node_info = NodeInfoDB::initialize_along("node_info", &file_info);
+        let block_store = BlockStore::new(&self.configs.dir_data);
+        let workers_replica = WorkerPool::new(
+            "replica",
+            self.configs.replication_max_in_flight,
+            self.configs.replication_max_in_flight,
+        );
+        let workers_download = WorkerPool::new("download", N_WORKERS_DOWNLOAD, MAX_DOWNLOADS_WAITING);
+
+        let mut failure_detector = FailureDetector::new(&self.configs);
+        let mut replication = ReplicationManager::new(&self.configs);
+        let mut rebalancer = Rebalancer::new(&self.configs);
+        // Files created block by block whose last block isn't placed yet
+        let mut files_writing = HashSet::<u64>::new();
+        let mut placement = placement::create_policy(&self.configs);
+
+        // For replicating metadata to standby Masters
+        let mut state_sync_publisher = StateSyncPublisher::new(&self.configs);
+        let mut state_sync_subscriber = StateSyncSubscriber::new();
+
+        // Masters listed in RAFT_PEERS replicate metadata among themselves and only the elected leader serves
+        let mut raft = match self.role {
+            Role::Master if !self.configs.raft_peers.is_empty() => {
+                Some(RaftNode::new(&self.configs, addr_current, &db_backend))
+            }
+            _ => None,
+        };
+        let mut is_leader = false;
+        // Raft leader stages what it does for a packet and holds the packets it sends meanwhile, until the changes
+        // behind them are committed. Packets received in between wait for the DBs to catch up.
+        let (sender_staged, receiver_staged) = channel::<Packet>();
+        let mut packets_held: Option<(Vec<StateChange>, Vec<Packet>)> = None;
+        let mut packets_waiting = VecDeque::<Packet>::new();
+        let sender_network = sender_processor2sender;
+        let timeout_wait = match raft.as_ref() {
+            Some(raft) => raft.interval_tick(),
+            None => Duration::from_secs(self.configs.timeout_channel_wait),
+        };
 
         // For counter
         let mut last_ts: Option<SystemTime> = None;
@@ -193,19 +312,52 @@ impl Node {
 
         match self.role {
             Role::Master => {
-                // Send its IP to DNS
-                if let Err(err) = sender_processor2sender.send(Packet::create_notify(
-                    addr_dns.clone(),
-                    &self.role,
-                    addr_current.clone(),
-                )) {
-                    log::error!("Error as sending Notify: {}", err);
-                    self.trigger_graceful_shutdown();
+                // Report state recovered from previous run
+                match (node_info.get_data_nodes(), file_info.get_files()) {
+                    (Ok(data_nodes), Ok(files)) => log::info!(
+                        "Recovered {} Data nodes and {} files from {}",
+                        data_nodes.len(),
+                        files.len(),
+                        db_backend
+                    ),
+                    (Err(err), _) | (_, Err(err)) => log::error!("Cannot recover state from {}: {}", db_backend, err),
+                }
+
+                // In a Raft group, only the elected leader takes over and tells DNS
+                if raft.is_none() {
+                    if let Err(err) = replication.enqueue_under_replicated(&file_info) {
+                        log::error!("Cannot schedule re-replication: {}", err);
+                    }
+
+                    // Send its IP to DNS
+                    if let Err(err) =
+                        sender_processor2sender.send(Packet::create_notify(addr_dns, &self.role, addr_current))
+                    {
+                        log::error!("Error as sending Notify: {}", err);
+                        self.trigger_graceful_shutdown();
+                    }
                 }
             }
-            Role::Data => {
-                // Ask Master IP from DNS and notify to current master
+            Role::Data | Role::Standby => {
+                // Writes cut short by the previous run are dropped, Master re-replicates what is missing
+                if let Role::Data = self.role {
+                    match block_store.recover() {
+                        Ok(0) => {}
+                        Ok(n_removed) => log::warn!("Removed {} blocks left partially written", n_removed),
+                        Err(err) => log::error!("Cannot clean up {}: {}", self.configs.dir_data.display(), err),
+                    }
+                }
+
+                // Standby also registers with DNS so that it can be promoted
+                if let Role::Standby = self.role {
+                    _forward_packet(
+                        sender_processor2sender,
+                        Packet::create_notify(addr_dns, &self.role, addr_current),
+                    );
+                }
 
+                // Ask Master IP from DNS and notify to current master
+                ts_master_seen = Some(Instant::now());
                 if let Err(err) =
                     sender_processor2sender.send(Packet::create_ask_ip(addr_dns, Some(self.configs.env_port_receiver)))
                 {
@@ -220,18 +372,37 @@ impl Node {
         // Start processing loop
         // ================================================
         loop {
-            if let Ok(packet) =
-                receiver_receiver2processor.recv_timeout(Duration::from_secs(self.configs.timeout_channel_wait))
-            {
-                log::debug!("Received: {}", packet);
+            let is_waiting = raft
+                .as_ref()
+                .is_some_and(|raft| raft.is_leader() && (!raft.is_settled() || packets_held.is_some()));
+            let packet = if is_waiting || packets_waiting.is_empty() {
+                receiver_receiver2processor.recv_timeout(timeout_wait).ok()
+            } else {
+                packets_waiting.pop_front()
+            };
+            let packet = packet.filter(|packet| {
+                if packet.addr_sender.is_none() {
+                    log::error!("Attribute 'addr_sender' in packet not existed.");
+                }
+                packet.addr_sender.is_some()
+            });
 
-                let addr_sender = match packet.addr_sender {
-                    None => {
-                        log::error!("Attribute 'addr_sender' in packet not existed.");
-                        continue;
+            let is_staging = raft.as_ref().is_some_and(|raft| raft.is_leader())
+                && !is_waiting
+                && packet.as_ref().is_none_or(|packet| !_is_raft_packet(packet))
+                && match file_info.stage() {
+                    Ok(()) => true,
+                    Err(err) => {
+                        log::error!("Cannot stage changes: {}", err);
+                        false
                     }
-                    Some(addr) => addr,
                 };
+            let sender_processor2sender = if is_staging { &sender_staged } else { sender_network };
+
+            if let Some(packet) = packet {
+                log::debug!("Received: {}", packet);
+
+                let addr_sender = packet.addr_sender.unwrap();
 
                 match self.role {
                     Role::Default => {
@@ -242,26 +413,29 @@ impl Node {
                         match packet.packet_id {
                             PacketId::AskIp => {
                                 // Data/Client --AskIp-> DNS
-                                match addr_master {
-                                    None => {
-                                        _forward_packet(
-                                            sender_processor2sender,
-                                            Packet::create_ask_ip_ack(addr_sender, None),
-                                        );
-                                    }
-                                    Some(addr_master) => {
-                                        _forward_packet(
-                                            sender_processor2sender,
-                                            Packet::create_ask_ip_ack(addr_sender, Some(&addr_master)),
-                                        );
-                                    }
-                                };
+                                _forward_packet(
+                                    sender_processor2sender,
+                                    Packet::create_ask_ip_ack(addr_sender, master_registry.get_master().as_ref())
+                                        .with_stream(packet.stream),
+                                );
                             }
-                            PacketId::Notify => {
-                                // Master --Notify-> DNS
-
-                                addr_master = packet.addr_sender;
-                                log::info!("Address Master just notified: {}", &addr_master.as_ref().unwrap());
+                            PacketId::Notify => match packet.role {
+                                Some(Role::Standby) => {
+                                    // Standby --Notify-> DNS
+                                    master_registry.on_notify_standby(addr_sender);
+                                    log::info!("Standby Master just notified: {}", addr_sender);
+                                }
+                                _ => {
+                                    // Master --Notify-> DNS
+                                    if !master_registry.on_notify_master(addr_sender, packet.term.unwrap_or(0)) {
+                                        continue;
+                                    }
+                                    log::info!("Address Master just notified: {}", addr_sender);
+                                }
+                            },
+                            PacketId::HeartbeatAck => {
+                                // Master --HeartbeatAck-> DNS
+                                master_registry.on_heartbeat_ack(packet.node_id.as_ref());
                             }
                             _ => {
                                 log::error!("Unsupported packet type: {}", packet);
@@ -270,15 +444,88 @@ impl Node {
                         };
                     }
                     Role::Master => {
+                        let is_follower = raft.as_ref().is_some_and(|raft| !raft.is_leader());
+                        let is_deferred = raft.as_ref().is_some_and(|raft| raft.is_leader()) && !is_staging;
+
                         match packet.packet_id {
+                            PacketId::RequestVote
+                            | PacketId::RequestVoteAck
+                            | PacketId::AppendEntries
+                            | PacketId::AppendEntriesAck => match raft.as_mut() {
+                                // Master --Raft packets-> Master
+                                Some(raft) => match _handle_raft_packet(raft, packet, &node_info, &file_info) {
+                                    Ok(packets) => {
+                                        for packet in packets {
+                                            _forward_packet(sender_processor2sender, packet);
+                                        }
+                                    }
+                                    Err(err) => log::error!("Cannot handle Raft packet: {}", err),
+                                },
+                                None => log::error!("Raft packet received but RAFT_PEERS not set: {}", packet),
+                            },
+                            PacketId::RequestFromClient if is_follower => {
+                                // Client asked a Master which is no longer leader, so it should ask DNS again
+                                log::warn!("Not leader of Raft group. Reject request from client.");
+                                let filename = packet.filename.unwrap();
+                                let packet_reply = match packet.request_kind.unwrap() {
+                                    RequestKind::Upload | RequestKind::Download | RequestKind::Append => {
+                                        Packet::create_response_node_ip(addr_sender, &filename, 0, 0, &[])
+                                    }
+                                    RequestKind::List => Packet::create_list_ack(addr_sender, &filename, None),
+                                    RequestKind::Resume => Packet::create_resume_ack(
+                                        addr_sender,
+                                        &filename,
+                                        0,
+                                        0,
+                                        ResumeStatus::Rejected,
+                                        (0, &[]),
+                                    ),
+                                    _ => Packet::create_client_request_ack(
+                                        addr_sender,
+                                        &filename,
+                                        0,
+                                        0,
+                                        0,
+                                        false,
+                                        addr_current,
+                                    ),
+                                };
+                                _forward_packet(sender_processor2sender, packet_reply.with_stream(packet.stream));
+                            }
+                            _ if is_follower => {
+                                log::debug!("Not leader of Raft group. Ignore: {}", packet);
+                            }
+                            PacketId::Heartbeat => {
+                                // DNS --Heartbeat-> Master
+                                // Answered even while waiting for the log to be applied, so DNS keeps the leader
+                                _forward_packet(
+                                    sender_processor2sender,
+                                    Packet::create_heartbeat_ack(addr_dns, addr_current),
+                                );
+                            }
+                            _ if is_deferred => {
+                                if packets_waiting.len() < MAX_PACKETS_WAITING {
+                                    packets_waiting.push_back(packet);
+                                } else {
+                                    log::warn!("Too many packets waiting for Raft log to be applied. Drop: {}", packet);
+                                }
+                            }
                             PacketId::HeartbeatAck => {
                                 if let Some(node_id) = packet.node_id {
                                     match SocketAddrV4::from_str(node_id.as_str()) {
                                         Ok(addr) => {
-                                            if let Err(err) =
-                                                node_info.upsert(addr.ip().clone(), addr.port(), Role::Data)
-                                            {
-                                                log::error!("Error as UPSERT: {}", err);
+                                            match failure_detector.record_alive(&node_info, *addr.ip(), addr.port()) {
+                                                Ok(Some(event)) => {
+                                                    _handle_node_status_event(&event, &mut replication, &file_info)
+                                                }
+                                                Ok(None) => {}
+                                                Err(err) => log::error!("Error as UPSERT: {}", err),
+                                            }
+                                            if let Some(usage) = packet.usage {
+                                                log::debug!("Usage of node {}: {:?}", node_id, usage);
+                                                if let Err(err) = node_info.update_usage(&node_id, &usage) {
+                                                    log::error!("Cannot record usage of node {}: {}", node_id, err);
+                                                }
                                             }
                                         }
                                         Err(err) => {
@@ -291,15 +538,513 @@ impl Node {
                                     }
                                 }
                             }
+                            PacketId::SendReplicaAck => {
+                                // Data (target) --SendReplicaAck-> Master
+                                let block_id = packet.filename.unwrap();
+                                let node_id = packet.node_id.unwrap();
+                                let is_recorded = packet.is_success == Some(true)
+                                    && _record_replica(
+                                        &file_info,
+                                        &block_id,
+                                        &node_id,
+                                        packet.length.unwrap(),
+                                        packet.checksum.unwrap(),
+                                        self.configs.replication_factor,
+                                    );
+                                if is_recorded {
+                                    log::info!("Replica of '{}' stored at node {}", block_id, node_id);
+                                } else {
+                                    log::error!("Replica of '{}' could not be stored at node {}", block_id, node_id);
+                                }
+                                replication.on_replica_ack(&block_id, &node_id, is_recorded);
+
+                                // The copy may be part of a move, which leaves the source replica to delete
+                                match rebalancer.on_replica_ack(&block_id, &node_id, is_recorded, &file_info) {
+                                    Ok(Some(packet)) => _forward_packet(sender_processor2sender, packet),
+                                    Ok(None) => {}
+                                    Err(err) => log::error!("Cannot complete move of '{}': {}", block_id, err),
+                                }
+
+                                // A copy may have completed after the factor of its file was lowered
+                                if let Err(err) = replication.enqueue_over_replicated(&file_info) {
+                                    log::error!("Cannot schedule removal of extra replicas: {}", err);
+                                }
+                            }
+                            PacketId::RequestFromClient => match packet.request_kind.unwrap() {
+                                RequestKind::Upload => {
+                                    // Client --RequestFromClient-> Master
+                                    let filename = packet.filename.unwrap();
+                                    let replication_factor =
+                                        _get_replication_factor(&file_info, &filename, packet.replication_factor);
+                                    let mut entry = FileInfoEntry::initialize(
+                                        filename,
+                                        packet.length.unwrap(),
+                                        packet.checksum.unwrap(),
+                                        replication_factor,
+                                    );
+                                    let locations = match _place_blocks(
+                                        &file_info,
+                                        &node_info,
+                                        placement.as_mut(),
+                                        &mut entry,
+                                        self.configs.block_size,
+                                        self.configs.max_file_size,
+                                        if replication_factor == 0 {
+                                            self.configs.replication_factor
+                                        } else {
+                                            replication_factor
+                                        },
+                                        sender_processor2sender,
+                                    ) {
+                                        Ok(locations) => locations,
+                                        Err(err) => {
+                                            log::error!("Cannot place blocks of '{}': {}", entry.filename, err);
+                                            vec![]
+                                        }
+                                    };
+                                    log::info!("Client uploads '{}' in {} blocks", entry.filename, locations.len());
+
+                                    _forward_packet(
+                                        sender_processor2sender,
+                                        Packet::create_response_node_ip(
+                                            addr_sender,
+                                            &entry.filename,
+                                            entry.size,
+                                            entry.checksum,
+                                            &locations,
+                                        )
+                                        .with_stream(packet.stream),
+                                    );
+                                }
+                                RequestKind::Create => {
+                                    // Client --RequestFromClient-> Master
+                                    let filename = packet.filename.unwrap();
+                                    let replication_factor =
+                                        _get_replication_factor(&file_info, &filename, packet.replication_factor);
+                                    let mut entry =
+                                        FileInfoEntry::initialize(filename.clone(), 0, 0, replication_factor);
+                                    let is_success = match _create_file(&file_info, &mut entry, sender_processor2sender)
+                                    {
+                                        Ok(true) => {
+                                            log::info!("Client writes '{}' block by block", filename);
+                                            files_writing.insert(entry.inode);
+                                            true
+                                        }
+                                        Ok(false) => false,
+                                        Err(err) => {
+                                            log::error!("Cannot create file '{}': {}", filename, err);
+                                            false
+                                        }
+                                    };
+
+                                    // Clients fill blocks of the cluster's size before appending them
+                                    _forward_packet(
+                                        sender_processor2sender,
+                                        Packet::create_client_request_ack(
+                                            addr_sender,
+                                            &filename,
+                                            0,
+                                            self.configs.block_size,
+                                            0,
+                                            is_success,
+                                            addr_current,
+                                        )
+                                        .with_stream(packet.stream),
+                                    );
+                                }
+                                RequestKind::Append => {
+                                    // Client --RequestFromClient-> Master
+                                    let filename = packet.filename.unwrap();
+                                    let size = packet.length.unwrap();
+                                    let location = match _append_block(
+                                        &file_info,
+                                        &node_info,
+                                        placement.as_mut(),
+                                        &files_writing,
+                                        &filename,
+                                        size,
+                                        self.configs.block_size,
+                                        self.configs.max_file_size,
+                                        self.configs.replication_factor,
+                                    ) {
+                                        Ok(location) => location,
+                                        Err(err) => {
+                                            log::error!("Cannot append block to '{}': {}", filename, err);
+                                            None
+                                        }
+                                    };
+
+                                    _forward_packet(
+                                        sender_processor2sender,
+                                        Packet::create_response_node_ip(
+                                            addr_sender,
+                                            &filename,
+                                            size,
+                                            0,
+                                            location.as_slice(),
+                                        )
+                                        .with_stream(packet.stream),
+                                    );
+                                }
+                                RequestKind::Close => {
+                                    // Client --RequestFromClient-> Master
+                                    let filename = packet.filename.unwrap();
+                                    let size = packet.length.unwrap();
+                                    let checksum = packet.checksum.unwrap();
+                                    let is_success =
+                                        match _close_file(&file_info, &mut files_writing, &filename, size, checksum) {
+                                            Ok(is_success) => is_success,
+                                            Err(err) => {
+                                                log::error!("Cannot close file '{}': {}", filename, err);
+                                                false
+                                            }
+                                        };
+
+                                    _forward_packet(
+                                        sender_processor2sender,
+                                        Packet::create_client_request_ack(
+                                            addr_sender,
+                                            &filename,
+                                            0,
+                                            size,
+                                            checksum,
+                                            is_success,
+                                            addr_current,
+                                        )
+                                        .with_stream(packet.stream),
+                                    );
+                                }
+                                RequestKind::Resume => {
+                                    // Client --RequestFromClient-> Master
+                                    let filename = packet.filename.unwrap();
+                                    let size = packet.length.unwrap();
+                                    let checksum = packet.checksum.unwrap();
+                                    let (status, n_persisted, locations) = match _resume_upload(
+                                        &file_info,
+                                        &node_info,
+                                        placement.as_mut(),
+                                        &files_writing,
+                                        &filename,
+                                        size,
+                                        checksum,
+                                        self.configs.replication_factor,
+                                    ) {
+                                        Ok(progress) => progress,
+                                        Err(err) => {
+                                            log::error!("Cannot resume upload of '{}': {}", filename, err);
+                                            (ResumeStatus::Rejected, 0, vec![])
+                                        }
+                                    };
+
+                                    _forward_packet(
+                                        sender_processor2sender,
+                                        Packet::create_resume_ack(
+                                            addr_sender,
+                                            &filename,
+                                            size,
+                                            checksum,
+                                            status,
+                                            (n_persisted, &locations),
+                                        )
+                                        .with_stream(packet.stream),
+                                    );
+                                }
+                                RequestKind::Download => {
+                                    // Client --RequestFromClient-> Master
+                                    let filename = packet.filename.unwrap();
+                                    let (info, locations) = match _locate_blocks(&file_info, &filename) {
+                                        Ok(Some((info, locations))) => (Some(info), locations),
+                                        Ok(None) => (None, vec![]),
+                                        Err(err) => {
+                                            log::error!("Cannot retrieve info of file '{}': {}", filename, err);
+                                            (None, vec![])
+                                        }
+                                    };
+                                    log::info!("Client downloads '{}' in {} blocks", filename, locations.len());
+
+                                    _forward_packet(
+                                        sender_processor2sender,
+                                        Packet::create_response_node_ip(
+                                            addr_sender,
+                                            &filename,
+                                            info.as_ref().map_or(0, |info| info.size),
+                                            info.as_ref().map_or(0, |info| info.checksum),
+                                            &locations,
+                                        )
+                                        .with_stream(packet.stream),
+                                    );
+                                }
+                                RequestKind::SetReplication => {
+                                    // Client --RequestFromClient-> Master
+                                    let path = packet.filename.unwrap();
+                                    let replication_factor = packet.replication_factor.unwrap() as usize;
+                                    let n_files = match _set_replication(
+                                        &file_info,
+                                        &path,
+                                        replication_factor,
+                                        self.configs.replication_factor,
+                                    ) {
+                                        Ok(Some(n_files)) => {
+                                            log::info!(
+                                                "Replication factor of '{}' set to {} on {} files",
+                                                path,
+                                                replication_factor,
+                                                n_files
+                                            );
+                                            let result = replication
+                                                .enqueue_under_replicated(&file_info)
+                                                .and_then(|_| replication.enqueue_over_replicated(&file_info));
+                                            if let Err(err) = result {
+                                                log::error!("Cannot schedule replicas of '{}': {}", path, err);
+                                            }
+
+                                            Some(n_files)
+                                        }
+                                        Ok(None) => {
+                                            log::warn!("Cannot set replication factor of '{}': not found", path);
+                                            None
+                                        }
+                                        Err(err) => {
+                                            log::error!("Cannot set replication factor of '{}': {}", path, err);
+                                            None
+                                        }
+                                    };
+                                    _forward_packet(
+                                        sender_processor2sender,
+                                        Packet::create_client_request_ack(
+                                            addr_sender,
+                                            &path,
+                                            0,
+                                            n_files.unwrap_or(0),
+                                            0,
+                                            n_files.is_some(),
+                                            addr_current,
+                                        )
+                                        .with_stream(packet.stream),
+                                    );
+                                }
+                                RequestKind::MakeDir => {
+                                    // Client --RequestFromClient-> Master
+                                    let path = packet.filename.unwrap();
+                                    let is_success = match file_info.make_dir(&path) {
+                                        Ok(Some(_)) => {
+                                            log::info!("Created directory '{}'", path);
+                                            true
+                                        }
+                                        Ok(None) => {
+                                            log::warn!("Cannot create directory '{}': a file is in the way", path);
+                                            false
+                                        }
+                                        Err(err) => {
+                                            log::error!("Cannot create directory '{}': {}", path, err);
+                                            false
+                                        }
+                                    };
+
+                                    _forward_packet(
+                                        sender_processor2sender,
+                                        Packet::create_client_request_ack(
+                                            addr_sender,
+                                            &path,
+                                            0,
+                                            0,
+                                            0,
+                                            is_success,
+                                            addr_current,
+                                        )
+                                        .with_stream(packet.stream),
+                                    );
+                                }
+                                RequestKind::Rename => {
+                                    // Client --RequestFromClient-> Master
+                                    let path = packet.filename.unwrap();
+                                    let path_target = packet.filename_target.unwrap();
+                                    let is_success = match file_info.rename(&path, &path_target) {
+                                        Ok(true) => {
+                                            log::info!("Moved '{}' to '{}'", path, path_target);
+                                            true
+                                        }
+                                        Ok(false) => {
+                                            log::warn!("Cannot move '{}' to '{}'", path, path_target);
+                                            false
+                                        }
+                                        Err(err) => {
+                                            log::error!("Cannot move '{}' to '{}': {}", path, path_target, err);
+                                            false
+                                        }
+                                    };
+
+                                    _forward_packet(
+                                        sender_processor2sender,
+                                        Packet::create_client_request_ack(
+                                            addr_sender,
+                                            &path,
+                                            0,
+                                            0,
+                                            0,
+                                            is_success,
+                                            addr_current,
+                                        )
+                                        .with_stream(packet.stream),
+                                    );
+                                }
+                                RequestKind::Delete => {
+                                    // Client --RequestFromClient-> Master
+                                    let path = packet.filename.unwrap();
+                                    let is_recursive = packet.is_recursive.unwrap();
+                                    let n_replicas = match file_info.delete(&path, is_recursive) {
+                                        Ok(Some(replicas)) => {
+                                            log::info!("Deleted '{}' and {} replicas", path, replicas.len());
+
+                                            _delete_replicas(sender_processor2sender, &replicas);
+
+                                            Some(replicas.len() as u64)
+                                        }
+                                        Ok(None) => {
+                                            log::warn!("Cannot delete '{}': not found or not empty", path);
+                                            None
+                                        }
+                                        Err(err) => {
+                                            log::error!("Cannot delete '{}': {}", path, err);
+                                            None
+                                        }
+                                    };
+
+                                    _forward_packet(
+                                        sender_processor2sender,
+                                        Packet::create_client_request_ack(
+                                            addr_sender,
+                                            &path,
+                                            0,
+                                            n_replicas.unwrap_or(0),
+                                            0,
+                                            n_replicas.is_some(),
+                                            addr_current,
+                                        )
+                                        .with_stream(packet.stream),
+                                    );
+                                }
+                                RequestKind::List => {
+                                    // Client --RequestFromClient-> Master
+                                    let path = packet.filename.unwrap();
+                                    let listing = match file_info.list(&path, packet.is_recursive.unwrap()) {
+                                        Ok(listing) => listing,
+                                        Err(err) => {
+                                            log::error!("Cannot list '{}': {}", path, err);
+                                            None
+                                        }
+                                    };
+
+                                    _forward_packet(
+                                        sender_processor2sender,
+                                        Packet::create_list_ack(addr_sender, &path, listing.as_deref())
+                                            .with_stream(packet.stream),
+                                    );
+                                }
+                            },
+                            PacketId::ClientRequestAck => {
+                                // Data --ClientRequestAck-> Master
+                                let block_id = packet.filename.unwrap();
+                                let node_id = packet.node_id.unwrap();
+                                if packet.is_success == Some(true) {
+                                    _record_replica(
+                                        &file_info,
+                                        &block_id,
+                                        &node_id,
+                                        packet.length.unwrap(),
+                                        packet.checksum.unwrap(),
+                                        self.configs.replication_factor,
+                                    );
+                                }
+                            }
+                            PacketId::BlockReport => {
+                                // Data --BlockReport-> Master
+                                let node_id = packet.node_id.unwrap();
+                                match block_report::reconcile(
+                                    &node_id,
+                                    &packet.blocks.unwrap(),
+                                    &file_info,
+                                    self.configs.replication_factor,
+                                ) {
+                                    Ok(summary) if summary.is_consistent() => {
+                                        log::info!("BlockReport from {}: {}", node_id, summary);
+                                    }
+                                    Ok(summary) => {
+                                        log::warn!("BlockReport from {}: {}", node_id, summary);
+                                        for filename in &summary.missing {
+                                            log::warn!("{} lost replica of '{}'", node_id, filename);
+                                        }
+                                        for filename in &summary.orphaned {
+                                            log::warn!("{} holds unknown file '{}'", node_id, filename);
+                                        }
+                                        for filename in &summary.corrupted {
+                                            log::warn!("{} holds corrupted replica of '{}'", node_id, filename);
+                                        }
+
+                                        // Neither copy is recorded any more, so nothing else frees the node's disk
+                                        _delete_unrecorded_replicas(
+                                            sender_processor2sender,
+                                            &node_id,
+                                            summary.orphaned.iter().chain(&summary.corrupted),
+                                        );
+
+                                        if let Err(err) = replication.enqueue_under_replicated(&file_info) {
+                                            log::error!("Cannot schedule re-replication: {}", err);
+                                        }
+                                    }
+                                    Err(err) => log::error!("Cannot reconcile BlockReport from {}: {}", node_id, err),
+                                }
+
+                                // Replicas whose deletion never reached the node are reported again
+                                if let Err(err) = replication.enqueue_over_replicated(&file_info) {
+                                    log::error!("Cannot schedule removal of extra replicas: {}", err);
+                                }
+                            }
+                            PacketId::CorruptReplica => {
+                                // Data --CorruptReplica-> Master
+                                let block_id = packet.filename.unwrap();
+                                let node_id = packet.node_id.unwrap();
+                                log::warn!("{} dropped corrupted replica of '{}'", node_id, block_id);
+
+                                let result = file_info.remove_replica(&block_id, &node_id).and_then(|_| {
+                                    file_info.refresh_block_state(&block_id, self.configs.replication_factor)
+                                });
+                                match result.and_then(|_| replication.enqueue_under_replicated(&file_info)) {
+                                    Ok(()) => {}
+                                    Err(err) => {
+                                        log::error!("Cannot schedule re-replication of '{}': {}", block_id, err)
+                                    }
+                                }
+                            }
                             PacketId::Notify => {
                                 log::info!("Master receives NOTIFY from: {:?}", packet.addr_sender);
 
                                 match packet.addr_sender {
+                                    Some(addr_sender) if matches!(packet.role, Some(Role::Standby)) => {
+                                        // Standby --Notify-> Master
+                                        match state_sync_publisher.register(addr_sender, &node_info, &file_info) {
+                                            Ok(packets) => {
+                                                for packet in packets {
+                                                    _forward_packet(sender_processor2sender, packet);
+                                                }
+                                            }
+                                            Err(err) => log::error!("Cannot take snapshot for standby: {}", err),
+                                        }
+                                    }
                                     Some(addr_sender) => {
                                         // data_nodes.push(addr_sender);
 
                                         if let IpAddr::V4(ip) = addr_sender.ip() {
-                                            let _ = node_info.upsert(ip, addr_sender.port(), Role::Data);
+                                            match failure_detector.record_alive(&node_info, ip, addr_sender.port()) {
+                                                Ok(Some(event)) => {
+                                                    _handle_node_status_event(&event, &mut replication, &file_info)
+                                                }
+                                                Ok(None) => {}
+                                                Err(err) => log::error!("Error as UPSERT: {}", err),
+                                            }
+                                            if let Some(rack) = packet.rack {
+                                                _record_rack(&node_info, ip, addr_sender.port(), &rack);
+                                            }
 
                                             log::info!("Master added new Data node: {}", addr_sender);
                                         }
@@ -309,35 +1054,291 @@ impl Node {
                                     }
                                 }
                             }
+                            PacketId::StateSyncAck => {
+                                // Standby --StateSyncAck-> Master
+                                state_sync_publisher.on_ack(&packet.node_id.unwrap(), packet.seq.unwrap());
+                            }
                             _ => {
+                                // Falls through, so that changes staged meanwhile are dealt with
                                 log::error!("Unsupported packet type: {}", packet);
-                                continue;
                             }
                         }
                     }
                     Role::Data => match packet.packet_id {
                         PacketId::Heartbeat => {
+                            // Master --Heartbeat-> Data
+                            ts_master_seen = Some(Instant::now());
+
+                            // Another Master took over, e.g. a promoted standby or a newly elected Raft leader
+                            if addr_master != Some(addr_sender) {
+                                log::info!("Master changed from {:?} to {}", addr_master, addr_sender);
+
+                                addr_master = Some(addr_sender);
+                                ts_block_report = Some(Instant::now());
+                                _register_with_master(
+                                    &block_store,
+                                    addr_sender,
+                                    addr_current,
+                                    &self.configs.rack,
+                                    sender_processor2sender,
+                                );
+                            }
+
+                            let packet_ack = match block_store.usage() {
+                                Ok(mut usage) => {
+                                    usage.load = n_requests;
+                                    Packet::create_heartbeat_ack_data(addr_sender, addr_current, &usage)
+                                }
+                                Err(err) => {
+                                    log::error!("Cannot measure usage of blocks: {}", err);
+                                    Packet::create_heartbeat_ack(addr_sender, addr_current)
+                                }
+                            };
+                            n_requests = 0;
+                            _forward_packet(sender_processor2sender, packet_ack);
+                        }
+                        PacketId::RequestSendReplica => {
+                            // Master --RequestSendReplica-> Data (source)
+                            n_requests += 1;
+                            let filename = packet.filename.clone().unwrap();
+                            let offset = packet.offset.unwrap();
+                            let length = packet.length.unwrap();
+                            let addr_target = packet.addr_target.unwrap();
+
+                            // The block is read and sent by a worker, so that thread:Processor isn't held up
+                            let is_submitted = workers_replica.submit({
+                                let block_store = block_store.clone();
+                                let sender = sender_processor2sender.clone();
+                                move || _send_replica(&block_store, packet, addr_master, addr_current, &sender)
+                            });
+                            if !is_submitted {
+                                _report_replica_failed(
+                                    &filename,
+                                    offset,
+                                    length,
+                                    addr_target,
+                                    addr_master,
+                                    sender_processor2sender,
+                                );
+                            }
+                        }
+                        PacketId::SendReplica => {
+                            // Data (source) --SendReplica-> Data (target)
+                            n_requests += 1;
+                            let block_id = packet.filename.unwrap();
+                            let offset = packet.offset.unwrap();
+                            let checksum = packet.checksum.unwrap();
+                            let data = packet.data.unwrap();
+
+                            // Master hears about the replica once the whole block is stored or as soon as it fails
+                            let packet_ack = match _store_chunk(
+                                &block_store,
+                                &block_id,
+                                offset,
+                                &data,
+                                checksum,
+                                packet.length.unwrap(),
+                            ) {
+                                Ok(Some(meta)) => Some((0, meta.length, meta.checksum, true)),
+                                Ok(None) => None,
+                                Err(()) => Some((offset, data.len() as u64, checksum, false)),
+                            };
+                            if let Some((offset, length, checksum, is_success)) = packet_ack {
+                                match addr_master {
+                                    Some(addr_master) => _forward_packet(
+                                        sender_processor2sender,
+                                        Packet::create_send_replica_ack(
+                                            addr_master,
+                                            &block_id,
+                                            offset,
+                                            length,
+                                            checksum,
+                                            is_success,
+                                            addr_current,
+                                        ),
+                                    ),
+                                    None => log::error!("Address of Master not available to send SendReplicaAck"),
+                                }
+                            }
+                        }
+                        PacketId::ClientUpload => {
+                            // Client --ClientUpload-> Data
+                            n_requests += 1;
+                            let block_id = packet.filename.unwrap();
+                            let offset = packet.offset.unwrap();
+                            let checksum = packet.checksum.unwrap();
+                            let data = packet.data.unwrap();
+
+                            let result =
+                                _store_chunk(&block_store, &block_id, offset, &data, checksum, packet.length.unwrap());
                             _forward_packet(
                                 sender_processor2sender,
-                                Packet::create_heartbeat_ack(addr_master.clone().unwrap(), addr_current.clone()),
+                                Packet::create_client_request_ack(
+                                    addr_sender,
+                                    &block_id,
+                                    offset,
+                                    data.len() as u64,
+                                    checksum,
+                                    result.is_ok(),
+                                    addr_current,
+                                )
+                                .with_stream(packet.stream),
+                            );
+
+                            // Let Master know where the block is stored once all of it arrived
+                            if let Ok(Some(meta)) = result {
+                                match addr_master {
+                                    Some(addr_master) => _forward_packet(
+                                        sender_processor2sender,
+                                        Packet::create_client_request_ack(
+                                            addr_master,
+                                            &block_id,
+                                            0,
+                                            meta.length,
+                                            meta.checksum,
+                                            true,
+                                            addr_current,
+                                        ),
+                                    ),
+                                    None => log::error!("Address of Master not available to send ClientRequestAck"),
+                                }
+                            }
+                        }
+                        PacketId::RequestFromClient => match packet.request_kind.unwrap() {
+                            RequestKind::Download => {
+                                // Client --RequestFromClient-> Data
+                                n_requests += 1;
+
+                                // The block is streamed by a worker straight to the client's connection
+                                let block_id = packet.filename.clone().unwrap();
+                                let stream = packet.stream.as_ref().and_then(|stream| stream.try_clone().ok());
+                                let is_submitted = workers_download.submit({
+                                    let block_store = block_store.clone();
+                                    let sender = sender_processor2sender.clone();
+                                    move || {
+                                        _send_block_to_client(&block_store, packet, addr_master, addr_current, &sender)
+                                    }
+                                });
+                                if !is_submitted {
+                                    _forward_packet(
+                                        sender_processor2sender,
+                                        Packet::create_client_request_ack(
+                                            addr_sender,
+                                            &block_id,
+                                            0,
+                                            0,
+                                            0,
+                                            false,
+                                            addr_current,
+                                        )
+                                        .with_stream(stream),
+                                    );
+                                }
+                            }
+                            _ => {
+                                log::error!("Unsupported request kind for Data node: {}", packet);
+                                continue;
+                            }
+                        },
+                        PacketId::CorruptReplica => {
+                            // Scrubber --CorruptReplica-> Data (itself)
+                            if addr_sender != addr_current {
+                                log::error!("Ignore CorruptReplica from {}", addr_sender);
+                                continue;
+                            }
+                            _drop_corrupt_replica(
+                                &block_store,
+                                &packet.filename.unwrap(),
+                                addr_master,
+                                addr_current,
+                                sender_processor2sender,
                             );
                         }
+                        PacketId::DeleteReplica => {
+                            // Master --DeleteReplica-> Data
+                            let block_id = packet.filename.unwrap();
+                            match block_store.delete(&block_id) {
+                                Ok(()) => log::info!("Deleted extra replica of '{}'", block_id),
+                                Err(err) => log::error!("Cannot delete replica of '{}': {}", block_id, err),
+                            }
+                        }
+                        PacketId::AskIpAck => match packet.addr_master {
+                            None => {
+                                log::error!("Received packet not contain address of Master");
+                                continue;
+                            }
+                            Some(addr) => {
+                                log::info!("Register with Master {}", addr);
+
+                                addr_master = Some(addr);
+                                ts_block_report = Some(Instant::now());
+                                _register_with_master(
+                                    &block_store,
+                                    addr,
+                                    addr_current,
+                                    &self.configs.rack,
+                                    sender_processor2sender,
+                                );
+                            }
+                        },
+                        _ => {
+                            log::error!("Unsupported packet type: {}", packet);
+                            continue;
+                        }
+                    },
+                    Role::Standby => match packet.packet_id {
+                        PacketId::StateSync => {
+                            // Master --StateSync-> Standby
+                            match state_sync_subscriber.on_sync(
+                                packet.seq.unwrap(),
+                                packet.is_snapshot.unwrap(),
+                                packet.changes.unwrap(),
+                                &node_info,
+                                &file_info,
+                            ) {
+                                Ok(seq) => match addr_master {
+                                    Some(addr_master) => _forward_packet(
+                                        sender_processor2sender,
+                                        Packet::create_state_sync_ack(addr_master, seq, addr_current),
+                                    ),
+                                    None => log::error!("Address of Master not available to send StateSyncAck"),
+                                },
+                                Err(err) => log::error!("Cannot apply StateSync: {}", err),
+                            }
+                        }
                         PacketId::AskIpAck => match packet.addr_master {
                             None => {
                                 log::error!("Received packet not contain address of Master");
                                 continue;
                             }
                             Some(addr) => {
-                                log::debug!("Addr master: {:?}", addr);
+                                log::info!("Register as standby of Master {}", addr);
 
-                                addr_master = Some(addr.clone());
+                                addr_master = Some(addr);
+                                state_sync_subscriber.on_register();
 
                                 _forward_packet(
                                     sender_processor2sender,
-                                    Packet::create_notify(addr, &self.role, addr_current.clone()),
+                                    Packet::create_notify(addr, &self.role, addr_current),
                                 );
                             }
                         },
+                        PacketId::Promote => {
+                            // DNS --Promote-> Standby
+                            log::warn!("Master {:?} is gone. Take over as Master.", addr_master);
+
+                            self.role = Role::Master;
+                            addr_master = None;
+                            failure_detector.restart();
+                            if let Err(err) = replication.enqueue_under_replicated(&file_info) {
+                                log::error!("Cannot schedule re-replication: {}", err);
+                            }
+
+                            _forward_packet(
+                                sender_processor2sender,
+                                Packet::create_notify(addr_dns, &self.role, addr_current),
+                            );
+                        }
                         _ => {
                             log::error!("Unsupported packet type: {}", packet);
                             continue;
@@ -348,6 +1349,7 @@ impl Node {
 
             // If current node is Master, check timer and send Heartbeat
             match self.role {
+                Role::Master if raft.is_some() && !is_staging => {}
                 Role::Master => {
                     if last_ts.is_none() {
                         last_ts = Some(SystemTime::now());
@@ -374,24 +1376,1146 @@ impl Node {
                                                     log::info!("Send HEARTBEAT to {}", addr);
                                                     _forward_packet(
                                                         sender_processor2sender,
-                                                        Packet::create_heartbeat(addr),
+                                                        Packet::create_heartbeat(addr, addr_current),
                                                     );
                                                 }
                                             }
                                         }
                                     }
-                                }
-                            }
-                            Err(err) => {
-                                log::error!("{}", err);
-                            }
-                        }
-                    }
-                }
+
+                                    // Detect Data nodes which stopped answering
+                                    match failure_detector.detect(&node_info) {
+                                        Ok(events) => {
+                                            for event in &events {
+                                                _handle_node_status_event(event, &mut replication, &file_info);
+                                            }
+                                        }
+                                        Err(err) => log::error!("Cannot run failure detector: {}", err),
+                                    }
+
+                                    // Restore replication factor of files, a few at a time
+                                    match replication.schedule(&file_info, &node_info, placement.as_mut()) {
+                                        Ok(packets) => {
+                                            for packet in packets {
+                                                _forward_packet(sender_processor2sender, packet);
+                                            }
+                                        }
+                                        Err(err) => log::error!("Cannot schedule re-replication: {}", err),
+                                    }
+
+                                    // Move replicas off the fullest Data nodes
+                                    match rebalancer.schedule(&file_info, &node_info, placement.as_mut()) {
+                                        Ok(packets) => {
+                                            for packet in packets {
+                                                _forward_packet(sender_processor2sender, packet);
+                                            }
+                                        }
+                                        Err(err) => log::error!("Cannot schedule rebalancing: {}", err),
+                                    }
+
+                                    // Keep standby Masters in touch even when nothing changes
+                                    state_sync_publisher.expire();
+                                    for packet in state_sync_publisher.publish(&[]) {
+                                        _forward_packet(sender_processor2sender, packet);
+                                    }
+                                }
+                            }
+                            Err(err) => {
+                                log::error!("{}", err);
+                            }
+                        }
+                    }
+                }
+                Role::Standby => {
+                    // Register again if Master stopped streaming its state, e.g. after it restarted
+                    if state_sync_subscriber.is_stale(Duration::from_secs(self.configs.threshold_dead)) {
+                        log::warn!("No StateSync from Master for a while. Ask DNS for Master again.");
+
+                        state_sync_subscriber.on_register();
+                        _forward_packet(
+                            sender_processor2sender,
+                            Packet::create_notify(addr_dns, &self.role, addr_current),
+                        );
+                        _forward_packet(
+                            sender_processor2sender,
+                            Packet::create_ask_ip(addr_dns, Some(self.configs.env_port_receiver)),
+                        );
+                    }
+                }
+                Role::Data => {
+                    // Master stopped sending Heartbeat, so ask DNS which Master to register with. Asking again after
+                    // another window if DNS doesn't know yet.
+                    if _is_master_lost(ts_master_seen, self.configs.threshold_dead) {
+                        log::warn!(
+                            "No Heartbeat from Master {:?} for a while. Ask DNS for Master again.",
+                            addr_master
+                        );
+
+                        ts_master_seen = Some(Instant::now());
+                        _forward_packet(
+                            sender_processor2sender,
+                            Packet::create_ask_ip(addr_dns, Some(self.configs.env_port_receiver)),
+                        );
+                    }
+
+                    // Let Master catch replicas lost or corrupted on disk since the last report
+                    let is_report_due =
+                        ts_block_report.is_some_and(|ts| ts.elapsed().as_secs() >= self.configs.interval_block_report);
+                    if let Some(addr) = addr_master.filter(|_| is_report_due) {
+                        ts_block_report = Some(Instant::now());
+                        _send_block_report(&block_store, addr, addr_current, sender_processor2sender);
+                    }
+                }
+                Role::DNS => {
+                    let is_due = match last_ts {
+                        Some(ts) => ts
+                            .elapsed()
+                            .is_ok_and(|n| n.as_secs() >= self.configs.interval_heartbeat),
+                        None => true,
+                    };
+                    if is_due {
+                        last_ts = Some(SystemTime::now());
+
+                        // Heartbeat to Master, or Promote to a standby once Master stopped answering
+                        if let Some(packet) = master_registry.check(addr_current) {
+                            _forward_packet(sender_processor2sender, packet);
+                        }
+                    }
+                }
                 _ => {
                     last_ts = None;
                 }
             }
+
+            // Changes made while processing are replicated to the Raft group and streamed to standby Masters. Other
+            // roles have nobody to tell.
+            let changes: Vec<_> = node_info
+                .drain_changes()
+                .into_iter()
+                .chain(file_info.drain_changes())
+                .collect();
+            if is_staging {
+                // Staged changes are applied once committed. Writes kept out of the journal, such as the time of a
+                // heartbeat, are dropped along and come back with the next heartbeat.
+                let result = if changes.is_empty() {
+                    file_info.keep_staged()
+                } else {
+                    file_info.discard_staged()
+                };
+                if let Err(err) = result {
+                    log::error!("Cannot end staging of changes: {}", err);
+                }
+            }
+            let packets_staged: Vec<_> = receiver_staged.try_iter().collect();
+            if let Role::Master = self.role {
+                match raft.as_mut() {
+                    Some(raft) if !changes.is_empty() => match raft.propose(changes.clone(), &node_info, &file_info) {
+                        Ok(packets) => {
+                            for packet in packets {
+                                _forward_packet(sender_network, packet);
+                            }
+                            packets_held = Some((changes, packets_staged));
+                        }
+                        Err(err) => {
+                            log::error!(
+                                "Cannot append changes to Raft log. Drop {} packets: {}",
+                                packets_staged.len(),
+                                err
+                            );
+                            // State kept in memory went ahead of the DBs, so take over again from what is applied
+                            is_leader = false;
+                        }
+                    },
+                    Some(_) => {
+                        for packet in packets_staged {
+                            _forward_packet(sender_network, packet);
+                        }
+                    }
+                    None if !changes.is_empty() => {
+                        for packet in state_sync_publisher.publish(&changes) {
+                            _forward_packet(sender_network, packet);
+                        }
+                    }
+                    None => {}
+                }
+            }
+
+            // Keep the Raft group going and take over Data nodes and clients once elected
+            if let Some(raft) = raft.as_mut() {
+                match raft.tick(&node_info, &file_info) {
+                    Ok(packets) => {
+                        for packet in packets {
+                            _forward_packet(sender_network, packet);
+                        }
+                    }
+                    Err(err) => log::error!("Cannot run Raft: {}", err),
+                }
+
+                // Take over once entries of previous terms are applied, so DBs are up to date. State kept in memory
+                // is started afresh from the DBs, since it may follow changes which were staged but never committed.
+                if !raft.is_leader() {
+                    is_leader = false;
+                } else if !is_leader && raft.is_settled() {
+                    is_leader = true;
+                    failure_detector.restart();
+                    files_writing.clear();
+                    placement = placement::create_policy(&self.configs);
+                    rebalancer = Rebalancer::new(&self.configs);
+                    replication = ReplicationManager::new(&self.configs);
+                    if let Err(err) = replication.enqueue_under_replicated(&file_info) {
+                        log::error!("Cannot schedule re-replication: {}", err);
+                    }
+
+                    _forward_packet(
+                        sender_network,
+                        Packet::create_notify_leader(addr_dns, addr_current, raft.term()),
+                    );
+                }
+
+                // Held packets go out once their changes are committed, or never if leadership was lost meanwhile
+                if let Some((changes, packets)) = packets_held.take() {
+                    if !raft.is_leader() {
+                        log::warn!(
+                            "Lost leadership before {} changes were committed. Drop {} packets.",
+                            changes.len(),
+                            packets.len()
+                        );
+                    } else if raft.is_settled() {
+                        for packet in state_sync_publisher.publish(&changes).into_iter().chain(packets) {
+                            _forward_packet(sender_network, packet);
+                        }
+                    } else {
+                        packets_held = Some((changes, packets));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn _is_raft_packet(packet: &Packet) -> bool {
+    matches!(
+        packet.packet_id,
+        PacketId::RequestVote | PacketId::RequestVoteAck | PacketId::AppendEntries | PacketId::AppendEntriesAck
+    )
+}
+
+/// Pass a Raft packet to the Raft node and return its answers
+fn _handle_raft_packet(
+    raft: &mut RaftNode,
+    packet: Packet,
+    node_info: &NodeInfoDB,
+    file_info: &FileInfoDB,
+) -> rusqlite::Result<Vec<Packet>> {
+    let term = packet.term.unwrap();
+    let addr_peer = packet.addr_peer.unwrap();
+
+    match packet.packet_id {
+        PacketId::RequestVote => Ok(vec![raft.on_request_vote(
+            term,
+            addr_peer,
+            packet.index.unwrap(),
+            packet.prev_term.unwrap(),
+        )?]),
+        PacketId::RequestVoteAck => {
+            raft.on_request_vote_ack(term, packet.is_success.unwrap(), addr_peer, node_info, file_info)
+        }
+        PacketId::AppendEntries => Ok(vec![raft.on_append_entries(
+            term,
+            addr_peer,
+            packet.prev_index.unwrap(),
+            packet.prev_term.unwrap(),
+            packet.commit_index.unwrap(),
+            packet.is_snapshot.unwrap(),
+            packet.entries.unwrap(),
+            node_info,
+            file_info,
+        )?]),
+        PacketId::AppendEntriesAck => raft.on_append_entries_ack(
+            term,
+            packet.is_success.unwrap(),
+            packet.index.unwrap(),
+            addr_peer,
+            node_info,
+            file_info,
+        ),
+        _ => Ok(vec![]),
+    }
+}
+
+/// Read packets from one connection until the peer closes it and pass them to thread:Processor
+fn _receive_packets(mut stream: TcpStream, max_frame_size: usize, sender_receiver2processor: Sender<Packet>) {
+    loop {
+        let packet = match Packet::from_stream(&mut stream, max_frame_size) {
+            Ok(packet) => packet,
+            Err(err) if err.is_connection_closed() => break,
+            Err(err) => {
+                log::error!("{}", err);
+                break;
+            }
+        };
+
+        // Send to thread Processor
+        if let Err(err) = sender_receiver2processor.send(packet) {
+            log::error!(
+                "Error as sending packet from thread:Receiver -> thread:Processor: err = {}",
+                err
+            );
+            break;
+        };
+    }
+}
+
+/// Whether Master sent no Heartbeat for `threshold_dead` seconds, or was never seen
+fn _is_master_lost(ts_master_seen: Option<Instant>, threshold_dead: u64) -> bool {
+    ts_master_seen.is_none_or(|ts| ts.elapsed().as_secs() >= threshold_dead)
+}
+
+/// Notify Master `addr_master` of this Data node and report what it stores. Master may have lost track of it, e.g.
+/// after a failover, and rebuilds where replicas are from the report.
+fn _register_with_master(
+    block_store: &BlockStore,
+    addr_master: SocketAddr,
+    addr_current: SocketAddr,
+    rack: &str,
+    sender: &Sender<Packet>,
+) {
+    _forward_packet(sender, Packet::create_notify_data(addr_master, addr_current, rack));
+    _send_block_report(block_store, addr_master, addr_current, sender);
+}
+
+/// Tell Master every block stored locally with its size and checksum so that it reconciles where replicas are. Both
+/// come from the blocks' metadata, so that reporting doesn't read every block. Damage on disk is left for the scrubber
+/// to find.
+fn _send_block_report(
+    block_store: &BlockStore,
+    addr_master: SocketAddr,
+    addr_current: SocketAddr,
+    sender: &Sender<Packet>,
+) {
+    let block_ids = match block_store.list() {
+        Ok(block_ids) => block_ids,
+        Err(err) => {
+            log::error!("Cannot list stored blocks: {}", err);
+            return;
+        }
+    };
+
+    let mut blocks = Vec::<BlockInfo>::new();
+    for block_id in block_ids {
+        match block_store.get_meta(&block_id) {
+            Ok(meta) => blocks.push(BlockInfo {
+                block_id,
+                size: meta.length,
+                checksum: meta.checksum,
+            }),
+            Err(err) => log::error!("Cannot read metadata of '{}' to report it: {}", block_id, err),
+        }
+    }
+
+    log::info!("Report {} blocks to Master {}", blocks.len(), addr_master);
+    _forward_packet(sender, Packet::create_block_report(addr_master, addr_current, &blocks));
+}
+
+/// Read range [offset, offset + length) of a block after checking the whole block against its checksum. `length` = 0
+/// reads until the end of the block. A block damaged on disk is dropped and reported to Master.
+fn _read_block(
+    block_store: &BlockStore,
+    block_id: &str,
+    offset: u64,
+    length: u64,
+    addr_master: Option<SocketAddr>,
+    addr_current: SocketAddr,
+    sender: &Sender<Packet>,
+) -> io::Result<Vec<u8>> {
+    let data = match block_store.read_verified(block_id) {
+        Ok(data) => data,
+        Err(err) => {
+            if err.kind() == io::ErrorKind::InvalidData {
+                _drop_corrupt_replica(block_store, block_id, addr_master, addr_current, sender);
+            }
+            return Err(err);
+        }
+    };
+
+    let start = data.len().min(offset as usize);
+    let end = match length {
+        0 => data.len(),
+        _ => data.len().min(start + length as usize),
+    };
+
+    Ok(data[start..end].to_vec())
+}
+
+/// Stream the range of a block asked by `packet`, a Download request, to the client waiting on its connection. Chunks
+/// are read one at a time so that only one is held in memory.
+///
+/// A block sent whole is checked against its checksum as it is read, while a range of a block is left for the scrubber
+/// to check. A block damaged on disk is dropped and reported to Master, and the client, which checks the block it
+/// assembles, falls back to another replica.
+fn _send_block_to_client(
+    block_store: &BlockStore,
+    packet: Packet,
+    addr_master: Option<SocketAddr>,
+    addr_current: SocketAddr,
+    sender: &Sender<Packet>,
+) {
+    let addr_client = packet.addr_sender.unwrap();
+    let block_id = packet.filename.unwrap();
+    let mut stream = match packet.stream {
+        Some(stream) => stream,
+        None => {
+            log::error!(
+                "Connection of client {} not available to send '{}'",
+                addr_client,
+                block_id
+            );
+            return;
+        }
+    };
+
+    let result = _stream_block(
+        block_store,
+        &block_id,
+        packet.offset.unwrap_or(0),
+        packet.length.unwrap(),
+        addr_client,
+        &mut stream,
+    );
+    if let Err(err) = result {
+        log::error!("Cannot send '{}' to client: {}", block_id, err);
+        if err.kind() == io::ErrorKind::InvalidData {
+            _drop_corrupt_replica(block_store, &block_id, addr_master, addr_current, sender);
+        }
+
+        // Chunks already sent are followed by the failure, which the client cannot mistake for data
+        let packet_ack = Packet::create_client_request_ack(addr_client, &block_id, 0, 0, 0, false, addr_current);
+        if let Err(err) = stream.write_all(packet_ack.to_bytes().as_slice()) {
+            log::error!("Cannot send to address: {} : {}", addr_client, err);
+        }
+    }
+}
+
+/// Send range [offset, offset + length) of a block, cut to the end of the block. `length` = 0 sends until the end.
+fn _stream_block(
+    block_store: &BlockStore,
+    block_id: &str,
+    offset: u64,
+    length: u64,
+    addr_client: SocketAddr,
+    stream: &mut TcpStream,
+) -> io::Result<()> {
+    let meta = block_store.get_meta(block_id)?;
+    let end = match length {
+        0 => meta.length,
+        _ => meta.length.min(offset.saturating_add(length)),
+    };
+    let start = offset.min(end);
+
+    // Always send at least one chunk so that empty ranges are served as well
+    let mut checksum = 0;
+    let mut pos = start;
+    loop {
+        let size_chunk = (end - pos).min(SIZE_CHUNK as u64);
+        let chunk = match size_chunk {
+            0 => vec![],
+            _ => block_store.read(block_id, pos, size_chunk)?,
+        };
+        if (chunk.len() as u64) < size_chunk {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Block '{}' is shorter than its metadata", block_id),
+            ));
+        }
+        checksum = crc32c_update(checksum, &chunk);
+
+        let packet = Packet::create_data_node_send_data(addr_client, block_id, pos, end, &chunk, crc32c(&chunk));
+        stream.write_all(packet.to_bytes().as_slice())?;
+
+        pos += size_chunk;
+        if pos >= end {
+            break;
+        }
+    }
+
+    if start == 0 && end == meta.length && checksum != meta.checksum {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Block '{}' doesn't match its checksum", block_id),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Remove a replica damaged on disk and tell Master so that it copies the block again from a good replica
+fn _drop_corrupt_replica(
+    block_store: &BlockStore,
+    block_id: &str,
+    addr_master: Option<SocketAddr>,
+    addr_current: SocketAddr,
+    sender: &Sender<Packet>,
+) {
+    if let Err(err) = block_store.delete(block_id) {
+        log::error!("Cannot remove corrupted block '{}': {}", block_id, err);
+    }
+
+    match addr_master {
+        Some(addr_master) => _forward_packet(
+            sender,
+            Packet::create_corrupt_replica(addr_master, block_id, addr_current),
+        ),
+        None => log::error!("Address of Master not available to send CorruptReplica"),
+    }
+}
+
+/// Write a chunk received for block `block_id` whose whole size is `length` after checking it against `checksum`.
+/// Return the block's metadata once its last chunk is stored.
+fn _store_chunk(
+    block_store: &BlockStore,
+    block_id: &str,
+    offset: u64,
+    data: &[u8],
+    checksum: u32,
+    length: u64,
+) -> Result<Option<BlockMeta>, ()> {
+    if crc32c(data) != checksum {
+        log::error!(
+            "Chunk of '{}' at {} received with mismatched checksum",
+            block_id,
+            offset
+        );
+        return Err(());
+    }
+
+    match block_store.write(block_id, offset, data, length) {
+        Ok(Some(meta)) => {
+            log::info!(
+                "Stored '{}': {} bytes, generation {}",
+                block_id,
+                meta.length,
+                meta.generation
+            );
+            Ok(Some(meta))
+        }
+        Ok(None) => Ok(None),
+        Err(err) => {
+            log::error!("Cannot write chunk of '{}' at {}: {}", block_id, offset, err);
+            Err(())
+        }
+    }
+}
+
+fn _handle_node_status_event(event: &NodeStatusEvent, replication: &mut ReplicationManager, file_info: &FileInfoDB) {
+    match event.status_new {
+        NodeStatus::Alive => log::info!("{}", event),
+        NodeStatus::Suspect => log::warn!("{}", event),
+        NodeStatus::Dead => {
+            log::warn!("{}", event);
+
+            // Replicas on the dead node are lost, copy them from the remaining ones
+            if let Err(err) = replication.on_node_dead(&event.node_id, file_info) {
+                log::error!("Cannot schedule re-replication for node {}: {}", event.node_id, err);
+            }
+        }
+    }
+}
+
+/// Record that `node_id` holds block `block_id` once it reports the whole block stored, then refresh the block's
+/// state. The first replica stored fixes the block's checksum and later ones must match it. Return whether the replica
+/// was recorded.
+fn _record_replica(
+    file_info: &FileInfoDB,
+    block_id: &String,
+    node_id: &String,
+    length: u64,
+    checksum: u32,
+    replication_factor: usize,
+) -> bool {
+    let mut info = match file_info.get_block_info(block_id) {
+        Ok(mut entries) => match entries.pop() {
+            Some(info) => info,
+            None => {
+                log::error!("Replica acknowledged for unknown block '{}'", block_id);
+                return false;
+            }
+        },
+        Err(err) => {
+            log::error!("Cannot retrieve info of block '{}': {}", block_id, err);
+            return false;
+        }
+    };
+    if length != info.size {
+        log::error!(
+            "Replica of '{}' at node {} has {} bytes instead of {}",
+            block_id,
+            node_id,
+            length,
+            info.size
+        );
+        return false;
+    }
+
+    let is_first = matches!(file_info.get_replicas(block_id), Ok(replicas) if replicas.is_empty());
+    if is_first && info.state == FileState::Pending {
+        info.checksum = checksum;
+        if let Err(err) = file_info.upsert_block(&info) {
+            log::error!("Error as UPSERT block: {}", err);
+            return false;
+        }
+    } else if checksum != info.checksum {
+        log::error!("Replica of '{}' at node {} has mismatched checksum", block_id, node_id);
+        return false;
+    }
+
+    if let Err(err) = file_info.upsert_replica(block_id, node_id) {
+        log::error!("Error as UPSERT replica: {}", err);
+        return false;
+    }
+    match file_info.refresh_block_state(block_id, replication_factor) {
+        Ok(Some(state)) => log::info!("Block '{}' is now {}", block_id, state),
+        Ok(None) => {}
+        Err(err) => log::error!("Cannot refresh state of block '{}': {}", block_id, err),
+    }
+
+    true
+}
+
+/// Ask Data nodes to remove replicas Master no longer records. Data nodes not reachable now report the blocks as
+/// unknown later.
+fn _delete_replicas(sender_processor2sender: &Sender<Packet>, replicas: &[ReplicaInfoEntry]) {
+    for replica in replicas {
+        match SocketAddr::from_str(&replica.node_id) {
+            Ok(addr) => _forward_packet(
+                sender_processor2sender,
+                Packet::create_delete_replica(addr, &replica.block_id),
+            ),
+            Err(err) => log::error!("Cannot parse address of node {}: {}", replica.node_id, err),
+        }
+    }
+}
+
+/// Ask Data node `node_id` to remove the blocks Master holds no record of on it
+fn _delete_unrecorded_replicas<'a>(
+    sender_processor2sender: &Sender<Packet>,
+    node_id: &str,
+    block_ids: impl Iterator<Item = &'a String>,
+) {
+    let addr = match SocketAddr::from_str(node_id) {
+        Ok(addr) => addr,
+        Err(err) => {
+            log::error!("Cannot parse address of node {}: {}", node_id, err);
+            return;
+        }
+    };
+
+    for block_id in block_ids {
+        log::info!("Remove replica of '{}' from {}", block_id, node_id);
+        _forward_packet(sender_processor2sender, Packet::create_delete_replica(addr, block_id));
+    }
+}
+
+/// Keep the rack a Data node tells when registering. Only changes are recorded.
+fn _record_rack(node_info: &NodeInfoDB, ip: Ipv4Addr, port: u16, rack: &String) {
+    match node_info.get_node_info(ip, port) {
+        Ok(mut nodes) => match nodes.pop() {
+            Some(node) if node.rack != *rack => {
+                log::info!("Node {} lies in rack '{}'", node.node_id, rack);
+                if let Err(err) = node_info.update_rack(&node.node_id, rack) {
+                    log::error!("Cannot update rack of node {}: {}", node.node_id, err);
+                }
+            }
+            _ => {}
+        },
+        Err(err) => log::error!("Cannot retrieve info of node {}:{}: {}", ip, port, err),
+    }
+}
+
+/// Change the replication factor of a file, or of a directory and every file under it, then refresh the state of
+/// their blocks. A directory given with a trailing '/' is created if missing. Return the number of files changed, or
+/// None if nothing lies at `path`.
+fn _set_replication(
+    file_info: &FileInfoDB,
+    path: &str,
+    replication_factor: usize,
+    default_replication_factor: usize,
+) -> rusqlite::Result<Option<u64>> {
+    let entry = match path.ends_with('/') {
+        true => file_info.make_dir(path)?.map(|inode| (inode, true)),
+        false => file_info.resolve(path)?.map(|entry| (entry.inode, entry.is_dir)),
+    };
+    let files = match entry {
+        Some((inode, true)) => {
+            file_info.set_dir_replication_factor(inode, replication_factor)?;
+            file_info.get_files_in_dir(inode)?
+        }
+        Some((_, false)) => file_info.get_file_info(path)?,
+        None => return Ok(None),
+    };
+
+    let mut n_files = 0;
+    for file in &files {
+        if !file_info.set_replication_factor(file.inode, replication_factor)? {
+            continue;
+        }
+        for block in file_info.get_blocks(file.inode)? {
+            file_info.refresh_block_state(&block.block_id, default_replication_factor)?;
+        }
+        n_files += 1;
+    }
+
+    Ok(Some(n_files))
+}
+
+/// Record a new file split into blocks of `block_size` bytes, each placed by `placement` on its own set of Data
+/// nodes. Even an empty file has one block. Missing parent directories are created. Nothing is recorded if a block
+/// cannot be placed, the file doesn't fit or a directory lies at the file's path.
+#[allow(clippy::too_many_arguments)]
+fn _place_blocks(
+    file_info: &FileInfoDB,
+    node_info: &NodeInfoDB,
+    placement: &mut dyn PlacementPolicy,
+    entry: &mut FileInfoEntry,
+    block_size: u64,
+    max_file_size: u64,
+    replication_factor: usize,
+    sender_processor2sender: &Sender<Packet>,
+) -> rusqlite::Result<Vec<BlockLocation>> {
+    let mut data_nodes = node_info.get_data_nodes()?;
+    if !_has_room(
+        &data_nodes,
+        &entry.filename,
+        entry.size,
+        entry.size,
+        max_file_size,
+        replication_factor,
+    ) {
+        return Ok(vec![]);
+    }
+
+    let n_blocks = entry.size.div_ceil(block_size).max(1);
+    let mut addrs = Vec::<Vec<SocketAddr>>::new();
+    for index in 0..n_blocks {
+        let size = block_size.min(entry.size - index * block_size);
+        let addr_nodes = placement.choose(&data_nodes, &[], replication_factor, size);
+        if addr_nodes.is_empty() {
+            return Ok(vec![]);
+        }
+        placement::reserve(&mut data_nodes, &addr_nodes, size);
+        addrs.push(addr_nodes);
+    }
+
+    if !_create_file(file_info, entry, sender_processor2sender)? {
+        return Ok(vec![]);
+    }
+    let mut locations = Vec::<BlockLocation>::new();
+    for (index, addr_nodes) in (0..n_blocks).zip(addrs) {
+        let offset = index * block_size;
+        let block = BlockInfoEntry::initialize(entry.inode, index, block_size.min(entry.size - offset));
+        file_info.upsert_block(&block)?;
+
+        locations.push(BlockLocation {
+            block_id: block.block_id,
+            offset,
+            size: block.size,
+            checksum: block.checksum,
+            addr_nodes,
+        });
+    }
+
+    Ok(locations)
+}
+
+/// Tell whether a file may grow to `size` bytes by writing `size_new` more: it must not exceed `max_file_size` and the
+/// new replicas must fit in the space Alive Data nodes reported free. Nodes which didn't report their space yet are
+/// left out, and nothing is refused for lack of space before any node reported it.
+fn _has_room(
+    data_nodes: &[NodeInfoEntry],
+    filename: &str,
+    size: u64,
+    size_new: u64,
+    max_file_size: u64,
+    replication_factor: usize,
+) -> bool {
+    if size > max_file_size {
+        log::warn!(
+            "Refuse '{}' of {} bytes: files have at most {} bytes",
+            filename,
+            size,
+            max_file_size
+        );
+        return false;
+    }
+
+    let nodes_reported: Vec<&NodeInfoEntry> = data_nodes
+        .iter()
+        .filter(|node| node.status == NodeStatus::Alive && node.usage.disk_total > 0)
+        .collect();
+    if nodes_reported.is_empty() {
+        return true;
+    }
+    let disk_free: u64 = nodes_reported.iter().map(|node| node.usage.disk_free).sum();
+    let size_replicas = size_new.saturating_mul(replication_factor as u64);
+    if size_replicas > disk_free {
+        log::warn!(
+            "Refuse '{}': {} bytes of replicas don't fit in the {} bytes free in the cluster",
+            filename,
+            size_replicas,
+            disk_free
+        );
+        return false;
+    }
+
+    true
+}
+
+/// Replication factor of a new file: the one asked by the client, else the one of the file it replaces, else the one of
+/// its directory, else 0 for the cluster's
+fn _get_replication_factor(file_info: &FileInfoDB, filename: &str, requested: Option<u8>) -> usize {
+    match requested {
+        Some(0) | None => {
+            let replication_factor = file_info
+                .get_file_info(filename)
+                .and_then(|mut files| match files.pop() {
+                    Some(file) if file.replication_factor > 0 => Ok(Some(file.replication_factor)),
+                    _ => file_info.get_dir_replication_factor(filename),
+                })
+                .unwrap_or_else(|err| {
+                    log::error!("Cannot retrieve replication factor of '{}': {}", filename, err);
+                    None
+                });
+
+            replication_factor.unwrap_or(0)
+        }
+        Some(replication_factor) => replication_factor as usize,
+    }
+}
+
+/// Create a file without blocks, replacing an existing one whose replicas get deleted, and set the inode of `entry`.
+/// Return false if a directory is in the way.
+fn _create_file(
+    file_info: &FileInfoDB,
+    entry: &mut FileInfoEntry,
+    sender_processor2sender: &Sender<Packet>,
+) -> rusqlite::Result<bool> {
+    entry.inode = match file_info.create_file(&entry.filename)? {
+        Some((inode, replicas)) => {
+            if !replicas.is_empty() {
+                log::info!(
+                    "Replace '{}' and delete its {} replicas",
+                    entry.filename,
+                    replicas.len()
+                );
+                _delete_replicas(sender_processor2sender, &replicas);
+            }
+            inode
+        }
+        None => {
+            log::warn!(
+                "Cannot create file '{}': a directory or file is in the way",
+                entry.filename
+            );
+            return Ok(false);
+        }
+    };
+    file_info.upsert(entry)?;
+
+    Ok(true)
+}
+
+/// Add a block of `size` bytes after the last one of a file being written and choose the Data nodes storing it
+#[allow(clippy::too_many_arguments)]
+fn _append_block(
+    file_info: &FileInfoDB,
+    node_info: &NodeInfoDB,
+    placement: &mut dyn PlacementPolicy,
+    files_writing: &HashSet<u64>,
+    filename: &str,
+    size: u64,
+    block_size: u64,
+    max_file_size: u64,
+    replication_factor: usize,
+) -> rusqlite::Result<Option<BlockLocation>> {
+    let info = match file_info.get_file_info(filename)?.pop() {
+        Some(info) if files_writing.contains(&info.inode) => info,
+        _ => {
+            log::warn!("Cannot append block to '{}': file is not being written", filename);
+            return Ok(None);
+        }
+    };
+    if size > block_size {
+        log::warn!(
+            "Cannot append block of {} bytes to '{}': blocks have at most {} bytes",
+            size,
+            filename,
+            block_size
+        );
+        return Ok(None);
+    }
+
+    let blocks = file_info.get_blocks(info.inode)?;
+    let replication_factor = if info.replication_factor == 0 {
+        replication_factor
+    } else {
+        info.replication_factor
+    };
+    let data_nodes = node_info.get_data_nodes()?;
+    let size_file = blocks.iter().map(|block| block.size).sum::<u64>() + size;
+    if !_has_room(
+        &data_nodes,
+        filename,
+        size_file,
+        size,
+        max_file_size,
+        replication_factor,
+    ) {
+        return Ok(None);
+    }
+    let addr_nodes = placement.choose(&data_nodes, &[], replication_factor, size);
+    if addr_nodes.is_empty() {
+        return Ok(None);
+    }
+
+    let block = BlockInfoEntry::initialize(info.inode, blocks.len() as u64, size);
+    file_info.upsert_block(&block)?;
+
+    Ok(Some(BlockLocation {
+        block_id: block.block_id,
+        offset: blocks.iter().map(|block| block.size).sum(),
+        size,
+        checksum: block.checksum,
+        addr_nodes,
+    }))
+}
+
+/// Finish a file written block by block. Its blocks must add up to `size`. Return whether the file was closed.
+fn _close_file(
+    file_info: &FileInfoDB,
+    files_writing: &mut HashSet<u64>,
+    filename: &str,
+    size: u64,
+    checksum: u32,
+) -> rusqlite::Result<bool> {
+    let info = match file_info.get_file_info(filename)?.pop() {
+        Some(info) if files_writing.contains(&info.inode) => info,
+        _ => {
+            log::warn!("Cannot close '{}': file is not being written", filename);
+            return Ok(false);
+        }
+    };
+    let blocks = file_info.get_blocks(info.inode)?;
+    let n_bytes: u64 = blocks.iter().map(|block| block.size).sum();
+    if blocks.is_empty() || n_bytes != size {
+        log::warn!(
+            "Cannot close '{}': {} blocks hold {} bytes instead of {}",
+            filename,
+            blocks.len(),
+            n_bytes,
+            size
+        );
+        return Ok(false);
+    }
+
+    files_writing.remove(&info.inode);
+    file_info.update_size(info.inode, size, checksum)?;
+    file_info.refresh_state(info.inode)?;
+    log::info!("Client wrote '{}' in {} blocks", filename, blocks.len());
+
+    Ok(true)
+}
+
+/// Find the upload session of `filename` and choose new Data nodes for the blocks no replica was ever stored for
+///
+/// A session is the inode an Upload request gave the file for content of `size` bytes with `checksum`. It ends once
+/// every block has a replica, or when the file is written again and gets a new inode. Sessions live in the metadata,
+/// so they survive a Master failover. Return the number of blocks already stored with the locations of the others.
+#[allow(clippy::too_many_arguments)]
+fn _resume_upload(
+    file_info: &FileInfoDB,
+    node_info: &NodeInfoDB,
+    placement: &mut dyn PlacementPolicy,
+    files_writing: &HashSet<u64>,
+    filename: &str,
+    size: u64,
+    checksum: u32,
+    replication_factor: usize,
+) -> rusqlite::Result<(ResumeStatus, u32, Vec<BlockLocation>)> {
+    let info = match file_info.get_file_info(filename)?.pop() {
+        Some(info)
+            if info.state == FileState::Pending
+                && info.size == size
+                && info.checksum == checksum
+                && !files_writing.contains(&info.inode) =>
+        {
+            info
+        }
+        _ => {
+            log::warn!(
+                "Cannot resume upload of '{}': no unfinished upload of this content",
+                filename
+            );
+            return Ok((ResumeStatus::NotFound, 0, vec![]));
+        }
+    };
+
+    let blocks = file_info.get_blocks(info.inode)?;
+    let replication_factor = if info.replication_factor == 0 {
+        replication_factor
+    } else {
+        info.replication_factor
+    };
+    let mut data_nodes = node_info.get_data_nodes()?;
+
+    let mut locations = Vec::<BlockLocation>::new();
+    let mut offset = 0;
+    for block in &blocks {
+        if block.state == FileState::Pending {
+            let addr_nodes = placement.choose(&data_nodes, &[], replication_factor, block.size);
+            if addr_nodes.is_empty() {
+                log::warn!("Cannot resume upload of '{}': no Data node available", filename);
+                return Ok((ResumeStatus::UnavailableDataNode, 0, vec![]));
+            }
+            placement::reserve(&mut data_nodes, &addr_nodes, block.size);
+            locations.push(BlockLocation {
+                block_id: block.block_id.clone(),
+                offset,
+                size: block.size,
+                checksum: block.checksum,
+                addr_nodes,
+            });
+        }
+        offset += block.size;
+    }
+    let n_persisted = (blocks.len() - locations.len()) as u32;
+    log::info!(
+        "Client resumes upload of '{}': {} of {} blocks already stored",
+        filename,
+        n_persisted,
+        blocks.len()
+    );
+
+    Ok((ResumeStatus::Resumed, n_persisted, locations))
+}
+
+/// Get blocks of a file with the replicas clients can read. Blocks whose upload hasn't completed have none.
+fn _locate_blocks(
+    file_info: &FileInfoDB,
+    filename: &str,
+) -> rusqlite::Result<Option<(FileInfoEntry, Vec<BlockLocation>)>> {
+    let info = match file_info.get_file_info(filename)?.pop() {
+        Some(info) => info,
+        None => return Ok(None),
+    };
+
+    let mut locations = Vec::<BlockLocation>::new();
+    let mut offset = 0;
+    for block in file_info.get_blocks(info.inode)? {
+        let addr_nodes = match block.state {
+            FileState::Pending => vec![],
+            _ => file_info
+                .get_replicas(&block.block_id)?
+                .iter()
+                .filter_map(|replica| SocketAddr::from_str(&replica.node_id).ok())
+                .collect(),
+        };
+        locations.push(BlockLocation {
+            block_id: block.block_id,
+            offset,
+            size: block.size,
+            checksum: block.checksum,
+            addr_nodes,
+        });
+        offset += block.size;
+    }
+
+    Ok(Some((info, locations)))
+}
+
+/// Read the range of a block asked by `packet`, a RequestSendReplica, and send it to the target
+fn _send_replica(
+    block_store: &BlockStore,
+    packet: Packet,
+    addr_master: Option<SocketAddr>,
+    addr_current: SocketAddr,
+    sender: &Sender<Packet>,
+) {
+    let filename = packet.filename.unwrap();
+    let offset = packet.offset.unwrap();
+    let length = packet.length.unwrap();
+    let addr_target = packet.addr_target.unwrap();
+
+    let data = match _read_block(
+        block_store,
+        &filename,
+        offset,
+        length,
+        addr_master,
+        addr_current,
+        sender,
+    ) {
+        Ok(data) => data,
+        Err(err) => {
+            log::error!("Cannot read '{}' to send replica: {}", filename, err);
+            _report_replica_failed(&filename, offset, length, addr_target, addr_master, sender);
+            return;
+        }
+    };
+    log::info!("Send replica of '{}' to {}", filename, addr_target);
+
+    // Chunks go over a single connection so that the target writes them in order
+    let mut packets = Vec::<Packet>::new();
+    let mut pos = 0;
+    loop {
+        let chunk = &data[pos..data.len().min(pos + SIZE_CHUNK)];
+        packets.push(Packet::create_send_replica(
+            addr_target,
+            &filename,
+            offset + pos as u64,
+            offset + data.len() as u64,
+            chunk,
+            crc32c(chunk),
+        ));
+
+        pos += chunk.len();
+        if pos >= data.len() {
+            break;
+        }
+    }
+    _send_packets(addr_target, packets);
+}
+
+/// Report a replica which cannot be sent on behalf of its target, so that Master picks another source
+fn _report_replica_failed(
+    block_id: &str,
+    offset: u64,
+    length: u64,
+    addr_target: SocketAddr,
+    addr_master: Option<SocketAddr>,
+    sender: &Sender<Packet>,
+) {
+    match addr_master {
+        Some(addr_master) => _forward_packet(
+            sender,
+            Packet::create_send_replica_ack(addr_master, block_id, offset, length, 0, false, addr_target),
+        ),
+        None => log::error!("Address of Master not available to send SendReplicaAck"),
+    }
+}
+
+/// Send packets in order over one connection
+fn _send_packets(addr_receiver: SocketAddr, packets: Vec<Packet>) {
+    let mut stream = match TcpStream::connect(addr_receiver) {
+        Ok(stream) => stream,
+        Err(err) => {
+            log::error!("Cannot connect to address: {} : {}", addr_receiver, err);
+            return;
+        }
+    };
+
+    for packet in packets {
+        if let Err(err) = stream.write_all(packet.to_bytes().as_slice()) {
+            log::error!("Cannot send to address: {} : {}", addr_receiver, err);
+            return;
         }
     }
 }
@@ -401,3 +2525,262 @@ fn _forward_packet(sender_processor2sender: &Sender<Packet>, packet: Packet) {
         log::error!("Err as sending from thread:Processor -> thread:Sender: {}", err);
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{db::NodeUsage, placement::RoundRobinPlacement};
+
+    /// Data nodes on ports 7003.. with `disk_free` bytes left out of `disk_total` bytes each
+    fn data_nodes(disk_total: u64, disk_free: &[u64]) -> Vec<NodeInfoEntry> {
+        disk_free
+            .iter()
+            .enumerate()
+            .map(|(i, disk_free)| {
+                let mut node = NodeInfoEntry::initialize(Ipv4Addr::LOCALHOST, 7003 + i as u16, Role::Data);
+                node.usage.disk_total = disk_total;
+                node.usage.disk_free = *disk_free;
+                node
+            })
+            .collect()
+    }
+
+    /// Metadata of a cluster whose Data nodes are Alive with 1000 bytes free each
+    fn cluster(n_nodes: u16) -> (FileInfoDB, NodeInfoDB) {
+        let file_info = FileInfoDB::initialize("file_info", &DBBackend::InMemory);
+        let node_info = NodeInfoDB::initialize_along("node_info", &file_info);
+        for port in 7003..7003 + n_nodes {
+            node_info.upsert(Ipv4Addr::LOCALHOST, port, Role::Data).unwrap();
+            let usage = NodeUsage {
+                disk_total: 1000,
+                disk_free: 1000,
+                ..Default::default()
+            };
+            node_info.update_usage(&format!("127.0.0.1:{}", port), &usage).unwrap();
+        }
+
+        (file_info, node_info)
+    }
+
+    fn place(file_info: &FileInfoDB, node_info: &NodeInfoDB, size: u64, max_file_size: u64) -> Vec<BlockLocation> {
+        let (sender, _) = channel::<Packet>();
+        let mut entry = FileInfoEntry::initialize("d/f".to_string(), size, 0, 2);
+
+        _place_blocks(
+            file_info,
+            node_info,
+            &mut RoundRobinPlacement::new(),
+            &mut entry,
+            100,
+            max_file_size,
+            2,
+            &sender,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn master_is_lost_without_heartbeat_within_window() {
+        assert!(_is_master_lost(None, 10));
+        assert!(!_is_master_lost(Some(Instant::now()), 10));
+        assert!(_is_master_lost(Some(Instant::now() - Duration::from_secs(10)), 10));
+    }
+
+    #[test]
+    fn registering_with_new_master_notifies_it_then_reports_stored_blocks() {
+        let dir = std::env::temp_dir().join(format!("dfs-nodes-register-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let block_store = BlockStore::new(&dir);
+        block_store.write("1#0", 0, b"abc", 3).unwrap();
+        block_store.write("1#1", 0, b"de", 2).unwrap();
+        let addr_master = SocketAddr::from(([127, 0, 0, 1], 7010));
+        let addr_current = SocketAddr::from(([127, 0, 0, 1], 7003));
+        let (sender, receiver) = channel::<Packet>();
+
+        _register_with_master(&block_store, addr_master, addr_current, "rack-a", &sender);
+
+        let packet = receiver.try_recv().unwrap();
+        assert!(packet.packet_id == PacketId::Notify);
+        assert_eq!(packet.addr_receiver, Some(addr_master));
+        assert_eq!(
+            packet.payload,
+            Packet::create_notify_data(addr_master, addr_current, "rack-a").payload
+        );
+
+        let packet = receiver.try_recv().unwrap();
+        assert!(packet.packet_id == PacketId::BlockReport);
+        assert_eq!(packet.addr_receiver, Some(addr_master));
+        let blocks = [
+            BlockInfo {
+                block_id: "1#0".to_string(),
+                size: 3,
+                checksum: crc32c(b"abc"),
+            },
+            BlockInfo {
+                block_id: "1#1".to_string(),
+                size: 2,
+                checksum: crc32c(b"de"),
+            },
+        ];
+        assert_eq!(
+            packet.payload,
+            Packet::create_block_report(addr_master, addr_current, &blocks).payload
+        );
+        assert!(receiver.try_recv().is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn room_is_refused_above_max_file_size() {
+        let nodes = data_nodes(1000, &[1000, 1000]);
+
+        assert!(_has_room(&nodes, "f", 100, 100, 100, 2));
+        assert!(!_has_room(&nodes, "f", 101, 1, 100, 2));
+    }
+
+    #[test]
+    fn room_counts_space_of_alive_nodes_which_reported_it() {
+        let mut nodes = data_nodes(1000, &[300, 300, 300]);
+        assert!(_has_room(&nodes, "f", 450, 450, u64::MAX, 2));
+        assert!(!_has_room(&nodes, "f", 451, 451, u64::MAX, 2));
+
+        // A node which didn't report its space yet doesn't lift the limit
+        nodes.extend(data_nodes(0, &[0]));
+        assert!(!_has_room(&nodes, "f", 451, 451, u64::MAX, 2));
+
+        nodes[0].status = NodeStatus::Dead;
+        assert!(_has_room(&nodes, "f", 300, 300, u64::MAX, 2));
+        assert!(!_has_room(&nodes, "f", 301, 301, u64::MAX, 2));
+
+        // Nothing is known before any node reported
+        assert!(_has_room(&data_nodes(0, &[0, 0]), "f", 10_000, 10_000, u64::MAX, 2));
+    }
+
+    #[test]
+    fn blocks_of_file_of_exact_multiple_of_block_size_are_full() {
+        let (file_info, node_info) = cluster(3);
+
+        let locations = place(&file_info, &node_info, 300, u64::MAX);
+
+        let sizes: Vec<u64> = locations.iter().map(|location| location.size).collect();
+        let offsets: Vec<u64> = locations.iter().map(|location| location.offset).collect();
+        assert_eq!(sizes, vec![100, 100, 100]);
+        assert_eq!(offsets, vec![0, 100, 200]);
+        assert!(locations.iter().all(|location| location.addr_nodes.len() == 2));
+        let inode = file_info.resolve("d/f").unwrap().unwrap().inode;
+        assert_eq!(file_info.get_blocks(inode).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn empty_file_has_one_empty_block() {
+        let (file_info, node_info) = cluster(3);
+
+        let locations = place(&file_info, &node_info, 0, u64::MAX);
+
+        assert_eq!(locations.len(), 1);
+        assert_eq!(locations[0].size, 0);
+        assert_eq!(locations[0].addr_nodes.len(), 2);
+    }
+
+    #[test]
+    fn file_over_max_size_is_not_placed_nor_recorded() {
+        let (file_info, node_info) = cluster(3);
+
+        assert!(place(&file_info, &node_info, 301, 300).is_empty());
+        assert!(file_info.resolve("d/f").unwrap().is_none());
+    }
+
+    #[test]
+    fn replication_factor_of_replaced_file_is_kept() {
+        let file_info = FileInfoDB::initialize("file_info", &DBBackend::InMemory);
+        let inode_dir = file_info.make_dir("d").unwrap().unwrap();
+        file_info.set_dir_replication_factor(inode_dir, 4).unwrap();
+        let mut entry = FileInfoEntry::initialize("d/a".to_string(), 0, 0, 2);
+        entry.inode = file_info.create_file("d/a").unwrap().unwrap().0;
+        file_info.upsert(&entry).unwrap();
+
+        assert_eq!(_get_replication_factor(&file_info, "d/a", Some(0)), 2);
+        assert_eq!(_get_replication_factor(&file_info, "d/a", Some(5)), 5);
+        assert_eq!(_get_replication_factor(&file_info, "d/b", Some(0)), 4);
+        assert_eq!(_get_replication_factor(&file_info, "b", None), 0);
+    }
+
+    fn resume(
+        file_info: &FileInfoDB,
+        node_info: &NodeInfoDB,
+        files_writing: &HashSet<u64>,
+        size: u64,
+        checksum: u32,
+    ) -> (ResumeStatus, u32, Vec<BlockLocation>) {
+        _resume_upload(
+            file_info,
+            node_info,
+            &mut RoundRobinPlacement::new(),
+            files_writing,
+            "d/f",
+            size,
+            checksum,
+            3,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn upload_resumes_from_blocks_not_stored_yet() {
+        let (file_info, node_info) = cluster(3);
+        let blocks = place(&file_info, &node_info, 250, 1000);
+        file_info
+            .update_block_state(&blocks[0].block_id, FileState::Complete)
+            .unwrap();
+
+        let (status, n_persisted, locations) = resume(&file_info, &node_info, &HashSet::new(), 250, 0);
+
+        assert!(status == ResumeStatus::Resumed);
+        assert_eq!(n_persisted, 1);
+        let ranges: Vec<(String, u64, u64)> = locations
+            .iter()
+            .map(|location| (location.block_id.clone(), location.offset, location.size))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![
+                (blocks[1].block_id.clone(), 100, 100),
+                (blocks[2].block_id.clone(), 200, 50)
+            ]
+        );
+        // Factor of the file wins over the one given
+        assert!(locations.iter().all(|location| location.addr_nodes.len() == 2));
+    }
+
+    #[test]
+    fn upload_of_other_content_or_still_written_is_not_resumed() {
+        let (file_info, node_info) = cluster(3);
+        place(&file_info, &node_info, 250, 1000);
+        let inode = file_info.resolve("d/f").unwrap().unwrap().inode;
+
+        assert!(resume(&file_info, &node_info, &HashSet::new(), 250, 1).0 == ResumeStatus::NotFound);
+        assert!(resume(&file_info, &node_info, &HashSet::new(), 251, 0).0 == ResumeStatus::NotFound);
+        assert!(resume(&file_info, &node_info, &HashSet::from([inode]), 250, 0).0 == ResumeStatus::NotFound);
+
+        file_info.update_state(inode, FileState::Complete).unwrap();
+        assert!(resume(&file_info, &node_info, &HashSet::new(), 250, 0).0 == ResumeStatus::NotFound);
+    }
+
+    #[test]
+    fn upload_is_not_resumed_without_data_node_to_take_blocks() {
+        let (file_info, node_info) = cluster(3);
+        place(&file_info, &node_info, 250, 1000);
+        for port in 7003..7006 {
+            node_info
+                .update_status(&format!("127.0.0.1:{}", port), NodeStatus::Dead)
+                .unwrap();
+        }
+
+        let (status, n_persisted, locations) = resume(&file_info, &node_info, &HashSet::new(), 250, 0);
+
+        assert!(status == ResumeStatus::UnavailableDataNode);
+        assert_eq!(n_persisted, 0);
+        assert!(locations.is_empty());
+    }
+}
@@ -1,4 +1,5 @@
 use std::fmt::{Debug, Display};
+use std::net::SocketAddr;
 
 use super::packets::PacketId;
 
@@ -14,6 +15,11 @@ pub enum ParseErrorCode {
     UnavailableMasterAddress,
     IncorrectPayloadSizeAskIPAck,
     StreamReadingError,
+    ConnectionClosed,
+    ExceededMaxFrameSize,
+    IncorrectPayloadFormat,
+    IncorrectRequestKind,
+    IncorrectEnumValue,
 }
 
 pub struct ParseError {
@@ -23,6 +29,9 @@ pub struct ParseError {
     pub header_size: Option<usize>,
     pub payload_size: Option<usize>,
     pub packet_size: Option<usize>,
+    pub max_frame_size: Option<usize>,
+    pub enum_name: Option<&'static str>,
+    pub enum_value: Option<u8>,
 }
 
 impl std::fmt::Display for ParseErrorCode {
@@ -35,6 +44,11 @@ impl std::fmt::Display for ParseErrorCode {
             ParseErrorCode::UnavailableMasterAddress => "UnavailableMasterAddress",
             ParseErrorCode::IncorrectPayloadSizeAskIPAck => "IncorrectPayloadSizeAskIPAck",
             ParseErrorCode::StreamReadingError => "StreamReading",
+            ParseErrorCode::ConnectionClosed => "ConnectionClosed",
+            ParseErrorCode::ExceededMaxFrameSize => "ExceededMaxFrameSize",
+            ParseErrorCode::IncorrectPayloadFormat => "IncorrectPayloadFormat",
+            ParseErrorCode::IncorrectRequestKind => "IncorrectRequestKind",
+            ParseErrorCode::IncorrectEnumValue => "IncorrectEnumValue",
         };
         write!(f, "{}", s)
     }
@@ -44,8 +58,16 @@ impl Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "ParseError{{error_code: {}, packet_id: {:?}, packet_id_value: {:?}, header_size: {:?}, payload_size: {:?}, packet_size: {:?}}}",
-            self.error_code, self.packet_id, self.packet_id_value, self.header_size, self.payload_size, self.packet_size
+            "ParseError{{error_code: {}, packet_id: {:?}, packet_id_value: {:?}, header_size: {:?}, payload_size: {:?}, packet_size: {:?}, max_frame_size: {:?}, enum_name: {:?}, enum_value: {:?}}}",
+            self.error_code,
+            self.packet_id,
+            self.packet_id_value,
+            self.header_size,
+            self.payload_size,
+            self.packet_size,
+            self.max_frame_size,
+            self.enum_name,
+            self.enum_value
         )
     }
 }
@@ -57,6 +79,8 @@ impl Debug for ParseError {
     }
 }
 
+impl std::error::Error for ParseError {}
+
 impl ParseError {
     fn create_instance() -> ParseError {
         ParseError {
@@ -66,6 +90,9 @@ impl ParseError {
             header_size: None,
             payload_size: None,
             packet_size: None,
+            max_frame_size: None,
+            enum_name: None,
+            enum_value: None,
         }
     }
 
@@ -116,6 +143,54 @@ impl ParseError {
         err.error_code = ParseErrorCode::StreamReadingError;
         err
     }
+
+    pub fn connection_closed() -> ParseError {
+        let mut err = ParseError::create_instance();
+        err.error_code = ParseErrorCode::ConnectionClosed;
+        err
+    }
+
+    pub fn exceeded_max_frame_size(packet_id: PacketId, payload_size: usize, max_frame_size: usize) -> ParseError {
+        let mut err = ParseError::create_instance();
+        err.error_code = ParseErrorCode::ExceededMaxFrameSize;
+        err.packet_id = Some(packet_id);
+        err.payload_size = Some(payload_size);
+        err.max_frame_size = Some(max_frame_size);
+
+        err
+    }
+
+    pub fn incorrect_payload_format(packet_id: PacketId, payload_size: usize) -> ParseError {
+        let mut err = ParseError::create_instance();
+        err.error_code = ParseErrorCode::IncorrectPayloadFormat;
+        err.packet_id = Some(packet_id);
+        err.payload_size = Some(payload_size);
+
+        err
+    }
+
+    pub fn incorrect_request_kind(request_kind_value: u8) -> ParseError {
+        let mut err = ParseError::create_instance();
+        err.error_code = ParseErrorCode::IncorrectRequestKind;
+        err.packet_id = Some(PacketId::RequestFromClient);
+        err.packet_id_value = Some(request_kind_value);
+
+        err
+    }
+
+    /// Byte which doesn't stand for any variant of enum `enum_name`, e.g. a state or a role
+    pub fn incorrect_enum_value(enum_name: &'static str, enum_value: u8) -> ParseError {
+        let mut err = ParseError::create_instance();
+        err.error_code = ParseErrorCode::IncorrectEnumValue;
+        err.enum_name = Some(enum_name);
+        err.enum_value = Some(enum_value);
+
+        err
+    }
+
+    pub fn is_connection_closed(&self) -> bool {
+        matches!(self.error_code, ParseErrorCode::ConnectionClosed)
+    }
 }
 
 // ================================================
@@ -147,3 +222,154 @@ impl Debug for NodeCreationError {
         write!(f, "{}", msg)
     }
 }
+
+// ================================================
+// ClientError
+// ================================================
+pub enum ClientErrorCode {
+    Default,
+    ConnectionFailed,
+    UnavailableMasterAddress,
+    UnavailableDataNode,
+    RequestRejected,
+    NotFound,
+    InvalidPath,
+    CorruptedData,
+    LocalIoError,
+    ParseError,
+}
+
+pub struct ClientError {
+    pub error_code: ClientErrorCode,
+    pub addr: Option<SocketAddr>,
+    pub filename: Option<String>,
+    pub detail: Option<String>,
+}
+
+impl std::fmt::Display for ClientErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ClientErrorCode::Default => "Default",
+            ClientErrorCode::ConnectionFailed => "ConnectionFailed",
+            ClientErrorCode::UnavailableMasterAddress => "UnavailableMasterAddress",
+            ClientErrorCode::UnavailableDataNode => "UnavailableDataNode",
+            ClientErrorCode::RequestRejected => "RequestRejected",
+            ClientErrorCode::NotFound => "NotFound",
+            ClientErrorCode::InvalidPath => "InvalidPath",
+            ClientErrorCode::CorruptedData => "CorruptedData",
+            ClientErrorCode::LocalIoError => "LocalIoError",
+            ClientErrorCode::ParseError => "ParseError",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ClientError{{error_code: {}, addr: {:?}, filename: {:?}, detail: {:?}}}",
+            self.error_code, self.addr, self.filename, self.detail
+        )
+    }
+}
+
+impl Debug for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<ParseError> for ClientError {
+    fn from(value: ParseError) -> Self {
+        let mut err = ClientError::create_instance();
+        err.error_code = ClientErrorCode::ParseError;
+        err.detail = Some(value.to_string());
+
+        err
+    }
+}
+
+impl ClientError {
+    fn create_instance() -> ClientError {
+        ClientError {
+            error_code: ClientErrorCode::Default,
+            addr: None,
+            filename: None,
+            detail: None,
+        }
+    }
+
+    pub fn connection_failed(addr: SocketAddr, err: std::io::Error) -> ClientError {
+        let mut err_client = ClientError::create_instance();
+        err_client.error_code = ClientErrorCode::ConnectionFailed;
+        err_client.addr = Some(addr);
+        err_client.detail = Some(err.to_string());
+
+        err_client
+    }
+
+    pub fn unavailable_master_ip() -> ClientError {
+        let mut err = ClientError::create_instance();
+        err.error_code = ClientErrorCode::UnavailableMasterAddress;
+
+        err
+    }
+
+    pub fn unavailable_data_node(filename: &str) -> ClientError {
+        let mut err = ClientError::create_instance();
+        err.error_code = ClientErrorCode::UnavailableDataNode;
+        err.filename = Some(filename.to_string());
+
+        err
+    }
+
+    pub fn request_rejected(addr: SocketAddr, filename: &str) -> ClientError {
+        let mut err = ClientError::create_instance();
+        err.error_code = ClientErrorCode::RequestRejected;
+        err.addr = Some(addr);
+        err.filename = Some(filename.to_string());
+
+        err
+    }
+
+    pub fn not_found(filename: &str) -> ClientError {
+        let mut err = ClientError::create_instance();
+        err.error_code = ClientErrorCode::NotFound;
+        err.filename = Some(filename.to_string());
+
+        err
+    }
+
+    pub fn invalid_path(filename: &str) -> ClientError {
+        let mut err = ClientError::create_instance();
+        err.error_code = ClientErrorCode::InvalidPath;
+        err.filename = Some(filename.to_string());
+
+        err
+    }
+
+    pub fn corrupted_data(addr: SocketAddr, filename: &str) -> ClientError {
+        let mut err = ClientError::create_instance();
+        err.error_code = ClientErrorCode::CorruptedData;
+        err.addr = Some(addr);
+        err.filename = Some(filename.to_string());
+
+        err
+    }
+
+    pub fn local_io_err(filename: &str, err: std::io::Error) -> ClientError {
+        let mut err_client = ClientError::create_instance();
+        err_client.error_code = ClientErrorCode::LocalIoError;
+        err_client.filename = Some(filename.to_string());
+        err_client.detail = Some(err.to_string());
+
+        err_client
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self.error_code, ClientErrorCode::NotFound)
+    }
+}
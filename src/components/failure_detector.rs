@@ -0,0 +1,201 @@
+use std::net::Ipv4Addr;
+
+use chrono::{DateTime, Duration, Local};
+use rusqlite::Result;
+
+use crate::components::{
+    configs::Configs,
+    db::{_get_node_id, NodeInfoDB, NodeStatus},
+    entity::node_roles::Role,
+};
+
+// ================================================
+// Definition
+// ================================================
+
+/// Emitted when a Data node moves from one status to another
+pub struct NodeStatusEvent {
+    pub node_id: String,
+    pub status_old: NodeStatus,
+    pub status_new: NodeStatus,
+}
+
+/// Decide liveness of Data nodes from the time their last HeartbeatAck arrived
+pub struct FailureDetector {
+    threshold_suspect: Duration,
+    threshold_dead: Duration,
+    ts_started: DateTime<Local>,
+}
+
+// ================================================
+// Implementation
+// ================================================
+
+impl std::fmt::Display for NodeStatusEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Node {}: {} -> {}", self.node_id, self.status_old, self.status_new)
+    }
+}
+
+impl FailureDetector {
+    pub fn new(configs: &Configs) -> FailureDetector {
+        FailureDetector {
+            threshold_suspect: Duration::seconds(configs.threshold_suspect as i64),
+            threshold_dead: Duration::seconds(configs.threshold_dead as i64),
+            ts_started: Local::now(),
+        }
+    }
+
+    /// Start detecting from now on, e.g. after taking over as Master. Data nodes get a full window to answer the new
+    /// Master before being suspected.
+    pub fn restart(&mut self) {
+        self.ts_started = Local::now();
+    }
+
+    /// Record that a Data node answered. Returns an event if the node was Suspect or Dead before.
+    pub fn record_alive(&self, node_info: &NodeInfoDB, ip: Ipv4Addr, port: u16) -> Result<Option<NodeStatusEvent>> {
+        let status_old = node_info.get_node_info(ip, port)?.pop().map(|node| node.status);
+        node_info.upsert(ip, port, Role::Data)?;
+
+        Ok(match status_old {
+            Some(status_old) if status_old != NodeStatus::Alive => Some(NodeStatusEvent {
+                node_id: _get_node_id(&ip, port),
+                status_old,
+                status_new: NodeStatus::Alive,
+            }),
+            _ => None,
+        })
+    }
+
+    /// Check every Data node against the thresholds, persist status changes and return them
+    pub fn detect(&self, node_info: &NodeInfoDB) -> Result<Vec<NodeStatusEvent>> {
+        let now = Local::now();
+        let mut events = Vec::<NodeStatusEvent>::new();
+
+        for node in node_info.get_data_nodes()? {
+            let silence = match node.last_updated {
+                Some(last_updated) => now.signed_duration_since(last_updated.max(self.ts_started)),
+                None => continue,
+            };
+            let status_new = if silence >= self.threshold_dead {
+                NodeStatus::Dead
+            } else if silence >= self.threshold_suspect {
+                NodeStatus::Suspect
+            } else {
+                NodeStatus::Alive
+            };
+
+            // A node only comes back to Alive by answering, which `record_alive` handles
+            if status_new == node.status || status_new == NodeStatus::Alive {
+                continue;
+            }
+
+            node_info.update_status(&node.node_id, status_new)?;
+            events.push(NodeStatusEvent {
+                node_id: node.node_id,
+                status_old: node.status,
+                status_new,
+            });
+        }
+
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::components::db::{DBBackend, FileInfoDB};
+
+    /// Detector suspecting nodes silent for 100 ms and declaring them dead after 400 ms
+    fn detector() -> FailureDetector {
+        let mut failure_detector = FailureDetector::new(&Configs::default());
+        failure_detector.threshold_suspect = Duration::milliseconds(100);
+        failure_detector.threshold_dead = Duration::milliseconds(400);
+        failure_detector
+    }
+
+    fn node_info() -> NodeInfoDB {
+        let file_info = FileInfoDB::initialize("file_info", &DBBackend::InMemory);
+        NodeInfoDB::initialize_along("node_info", &file_info)
+    }
+
+    fn status_of(node_info: &NodeInfoDB, port: u16) -> NodeStatus {
+        node_info
+            .get_node_info(Ipv4Addr::LOCALHOST, port)
+            .unwrap()
+            .pop()
+            .unwrap()
+            .status
+    }
+
+    fn transitions(events: &[NodeStatusEvent]) -> Vec<(String, NodeStatus, NodeStatus)> {
+        events
+            .iter()
+            .map(|event| (event.node_id.clone(), event.status_old, event.status_new))
+            .collect()
+    }
+
+    #[test]
+    fn silent_node_goes_suspect_then_dead_and_comes_back_alive_by_answering() {
+        let failure_detector = detector();
+        let node_info = node_info();
+        let node_id = "127.0.0.1:7003".to_string();
+        assert!(failure_detector
+            .record_alive(&node_info, Ipv4Addr::LOCALHOST, 7003)
+            .unwrap()
+            .is_none());
+        assert!(failure_detector.detect(&node_info).unwrap().is_empty());
+
+        thread::sleep(std::time::Duration::from_millis(200));
+        let events = failure_detector.detect(&node_info).unwrap();
+        assert_eq!(
+            transitions(&events),
+            vec![(node_id.clone(), NodeStatus::Alive, NodeStatus::Suspect)]
+        );
+        assert_eq!(status_of(&node_info, 7003), NodeStatus::Suspect);
+        // Events are only emitted on changes
+        assert!(failure_detector.detect(&node_info).unwrap().is_empty());
+
+        thread::sleep(std::time::Duration::from_millis(300));
+        let events = failure_detector.detect(&node_info).unwrap();
+        assert_eq!(
+            transitions(&events),
+            vec![(node_id.clone(), NodeStatus::Suspect, NodeStatus::Dead)]
+        );
+        assert_eq!(status_of(&node_info, 7003), NodeStatus::Dead);
+
+        let event = failure_detector
+            .record_alive(&node_info, Ipv4Addr::LOCALHOST, 7003)
+            .unwrap();
+        assert_eq!(
+            transitions(&event.into_iter().collect::<Vec<_>>()),
+            vec![(node_id, NodeStatus::Dead, NodeStatus::Alive)]
+        );
+        assert_eq!(status_of(&node_info, 7003), NodeStatus::Alive);
+        assert!(failure_detector.detect(&node_info).unwrap().is_empty());
+    }
+
+    #[test]
+    fn restarted_detector_gives_nodes_a_full_window() {
+        let mut failure_detector = detector();
+        let node_info = node_info();
+        failure_detector
+            .record_alive(&node_info, Ipv4Addr::LOCALHOST, 7003)
+            .unwrap();
+
+        thread::sleep(std::time::Duration::from_millis(450));
+        failure_detector.restart();
+        assert!(failure_detector.detect(&node_info).unwrap().is_empty());
+        assert_eq!(status_of(&node_info, 7003), NodeStatus::Alive);
+
+        thread::sleep(std::time::Duration::from_millis(200));
+        let events = failure_detector.detect(&node_info).unwrap();
+        assert_eq!(
+            transitions(&events),
+            vec![("127.0.0.1:7003".to_string(), NodeStatus::Alive, NodeStatus::Suspect)]
+        );
+    }
+}
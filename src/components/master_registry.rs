@@ -0,0 +1,180 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::components::{configs::Configs, packets::Packet};
+
+// ================================================
+// Definition
+// ================================================
+
+/// DNS side: keep track of the active Master and of standby Masters ready to replace it
+///
+/// The Master registered last is the one given to Data nodes and clients asking for it, unless it belongs to an older
+/// Raft term than the current one. DNS checks it with Heartbeat, and once it hasn't answered for `threshold_dead`, the
+/// standby which registered first is promoted in its place.
+pub struct MasterRegistry {
+    threshold_dead: Duration,
+    addr_master: Option<SocketAddr>,
+    term_master: u64,
+    ts_master_seen: Option<Instant>,
+    addr_standbys: Vec<SocketAddr>,
+}
+
+// ================================================
+// Implementation
+// ================================================
+
+impl MasterRegistry {
+    pub fn new(configs: &Configs) -> MasterRegistry {
+        MasterRegistry {
+            threshold_dead: Duration::from_secs(configs.threshold_dead),
+            addr_master: None,
+            term_master: 0,
+            ts_master_seen: None,
+            addr_standbys: Vec::new(),
+        }
+    }
+
+    /// Master to give to those asking for it, if any
+    pub fn get_master(&self) -> Option<SocketAddr> {
+        self.addr_master
+    }
+
+    /// Record a standby Master, which waits to be promoted in registration order
+    pub fn on_notify_standby(&mut self, addr: SocketAddr) {
+        if !self.addr_standbys.contains(&addr) {
+            self.addr_standbys.push(addr);
+        }
+    }
+
+    /// Record the Master which notified. Returns false if it is ignored for belonging to an older term.
+    pub fn on_notify_master(&mut self, addr: SocketAddr, term: u64) -> bool {
+        // A Raft leader from an older term may still believe it leads
+        if term < self.term_master {
+            log::warn!(
+                "Ignore Master {} of term {} older than current term {}",
+                addr,
+                term,
+                self.term_master
+            );
+            return false;
+        }
+
+        self.addr_standbys.retain(|addr_standby| *addr_standby != addr);
+        self.addr_master = Some(addr);
+        self.term_master = term;
+        self.ts_master_seen = Some(Instant::now());
+
+        true
+    }
+
+    /// Record that node `node_id` answered a Heartbeat, which only matters if it is the Master
+    pub fn on_heartbeat_ack(&mut self, node_id: Option<&String>) {
+        if self.addr_master.map(|addr| addr.to_string()).as_ref() == node_id {
+            self.ts_master_seen = Some(Instant::now());
+        }
+    }
+
+    /// Check the Master: return a Heartbeat for it while it answers, otherwise a Promote for the next standby, which
+    /// becomes the Master given out. If no standby is left, no Master is given out until one notifies.
+    pub fn check(&mut self, addr_current: SocketAddr) -> Option<Packet> {
+        let addr = self.addr_master?;
+        let is_master_alive = self.ts_master_seen.is_some_and(|ts| ts.elapsed() < self.threshold_dead);
+        if is_master_alive {
+            return Some(Packet::create_heartbeat(addr, addr_current));
+        }
+
+        log::warn!("Master {} stopped answering", addr);
+
+        // If the promoted standby doesn't answer either, the next one is promoted after another timeout
+        self.addr_master = None;
+        if self.addr_standbys.is_empty() {
+            log::error!("No standby Master to promote");
+            return None;
+        }
+        let addr_standby = self.addr_standbys.remove(0);
+        log::info!("Promote standby {} to Master", addr_standby);
+        self.addr_master = Some(addr_standby);
+        self.ts_master_seen = Some(Instant::now());
+
+        Some(Packet::create_promote(addr_standby))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::packets::PacketId;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// Make the Master look silent for longer than `threshold_dead`
+    fn silence(registry: &mut MasterRegistry) {
+        registry.ts_master_seen = Some(Instant::now() - registry.threshold_dead);
+    }
+
+    #[test]
+    fn silent_master_is_replaced_by_standbys_in_registration_order() {
+        let mut registry = MasterRegistry::new(&Configs::default());
+        let addr_dns = addr(7001);
+        assert!(registry.check(addr_dns).is_none());
+
+        registry.on_notify_standby(addr(7010));
+        registry.on_notify_standby(addr(7011));
+        registry.on_notify_standby(addr(7010));
+        assert!(registry.on_notify_master(addr(7002), 0));
+        assert_eq!(registry.get_master(), Some(addr(7002)));
+
+        let packet = registry.check(addr_dns).unwrap();
+        assert!(packet.packet_id == PacketId::Heartbeat);
+        assert_eq!(packet.addr_receiver, Some(addr(7002)));
+
+        // An answer from another node doesn't keep the Master
+        silence(&mut registry);
+        registry.on_heartbeat_ack(Some(&addr(7003).to_string()));
+        let packet = registry.check(addr_dns).unwrap();
+        assert!(packet.packet_id == PacketId::Promote);
+        assert_eq!(packet.addr_receiver, Some(addr(7010)));
+        assert_eq!(registry.get_master(), Some(addr(7010)));
+
+        // The promoted standby has a full window to answer
+        assert!(registry.check(addr_dns).unwrap().packet_id == PacketId::Heartbeat);
+        silence(&mut registry);
+        assert_eq!(registry.check(addr_dns).unwrap().addr_receiver, Some(addr(7011)));
+
+        silence(&mut registry);
+        assert!(registry.check(addr_dns).is_none());
+        assert_eq!(registry.get_master(), None);
+    }
+
+    #[test]
+    fn answering_master_is_kept() {
+        let mut registry = MasterRegistry::new(&Configs::default());
+        registry.on_notify_standby(addr(7010));
+        registry.on_notify_master(addr(7002), 0);
+
+        silence(&mut registry);
+        registry.on_heartbeat_ack(Some(&addr(7002).to_string()));
+
+        assert!(registry.check(addr(7001)).unwrap().packet_id == PacketId::Heartbeat);
+        assert_eq!(registry.get_master(), Some(addr(7002)));
+    }
+
+    #[test]
+    fn master_of_older_term_is_ignored_and_promoted_standby_leaves_standbys() {
+        let mut registry = MasterRegistry::new(&Configs::default());
+        assert!(registry.on_notify_master(addr(7002), 3));
+        assert!(!registry.on_notify_master(addr(7003), 2));
+        assert_eq!(registry.get_master(), Some(addr(7002)));
+
+        // A standby notifying as Master, e.g. once promoted, isn't promoted again later
+        registry.on_notify_standby(addr(7010));
+        assert!(registry.on_notify_master(addr(7010), 3));
+        silence(&mut registry);
+        assert!(registry.check(addr(7001)).is_none());
+    }
+}
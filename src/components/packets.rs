@@ -2,19 +2,44 @@ use std::convert::From;
 use std::fmt::{self};
 use std::net::SocketAddrV4;
 use std::{
-    io::Read,
+    io::{ErrorKind, Read},
     net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream},
 };
 
-use crate::components::{db::_get_node_id, entity::node_roles::Role, errors::ParseError};
+use crate::components::{
+    block_report::BlockInfo,
+    db::{_get_node_id, DirEntry, FileState, NodeStatus, NodeUsage},
+    entity::node_roles::Role,
+    errors::ParseError,
+    raft::LogEntry,
+    state_sync::StateChange,
+};
 
 // ================================================
 // Definition for enum and constants
 // ================================================
 
-const BYTE_SEP_CHARACTER: u8 = 124; // byte value of character '|'
 const SIZE_HEADER: usize = 5;
-const BUFF_LEN: usize = 1024;
+/// Longest string a payload can carry, as strings are prefixed by a 2-byte length
+pub const MAX_STR_LEN: usize = u16::MAX as usize;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+// Tags of metadata changes carried by StateSync
+const TAG_UPSERT_NODE: u8 = 1;
+const TAG_UPDATE_NODE_STATUS: u8 = 2;
+const TAG_UPSERT_FILE: u8 = 3;
+const TAG_UPDATE_FILE_STATE: u8 = 4;
+const TAG_UPSERT_REPLICA: u8 = 5;
+const TAG_REMOVE_REPLICA: u8 = 6;
+const TAG_UPSERT_BLOCK: u8 = 7;
+const TAG_UPDATE_BLOCK_STATE: u8 = 8;
+const TAG_SET_REPLICATION_FACTOR: u8 = 9;
+const TAG_SET_DIR_REPLICATION_FACTOR: u8 = 10;
+const TAG_UPDATE_NODE_RACK: u8 = 11;
+const TAG_CREATE_INODE: u8 = 12;
+const TAG_MOVE_INODE: u8 = 13;
+const TAG_REMOVE_INODE: u8 = 14;
+const TAG_UPDATE_FILE_SIZE: u8 = 15;
 
 #[rustfmt::skip]
 #[derive(Copy, Clone, PartialEq, Eq)]
@@ -36,6 +61,57 @@ pub enum PacketId {
     StateSync               = 13,
     StateSyncAck            = 14,
     Notify                  = 15,
+    Promote                 = 16,
+    RequestVote             = 17,
+    RequestVoteAck          = 18,
+    AppendEntries           = 19,
+    AppendEntriesAck        = 20,
+    BlockReport             = 21,
+    CorruptReplica          = 22,
+    DeleteReplica           = 23,
+    ListAck                 = 24,
+    ResumeAck               = 25,
+}
+
+/// Kind of request a client sends with RequestFromClient
+#[rustfmt::skip]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum RequestKind {
+    Upload                  = 1,
+    Download                = 2,
+    SetReplication          = 3,
+    MakeDir                 = 4,
+    Rename                  = 5,
+    Delete                  = 6,
+    List                    = 7,
+    Create                  = 8,
+    Append                  = 9,
+    Close                   = 10,
+    Resume                  = 11,
+}
+
+/// Outcome of a Resume request, as Master tells the client with ResumeAck
+#[rustfmt::skip]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum ResumeStatus {
+    /// No upload of this content is left unfinished, so the file must be uploaded whole
+    NotFound                = 0,
+    Resumed                 = 1,
+    /// The upload exists but no Data node can take its remaining blocks now
+    UnavailableDataNode     = 2,
+    /// Master is not the leader of its Raft group
+    Rejected                = 3,
+}
+
+/// Where a block of a file lies in the file and which Data nodes hold it, as Master tells clients
+pub struct BlockLocation {
+    pub block_id: String,
+    pub offset: u64,
+    pub size: u64,
+    pub checksum: u32,
+    pub addr_nodes: Vec<SocketAddr>,
 }
 
 pub struct Packet {
@@ -44,6 +120,9 @@ pub struct Packet {
     pub addr_sender: Option<SocketAddr>,
     pub addr_receiver: Option<SocketAddr>,
 
+    // Connection the packet arrived on, kept when the sender waits for the reply on it
+    pub stream: Option<TcpStream>,
+
     // Attributes dedicated for sending
     pub payload: Option<Vec<u8>>,
 
@@ -51,15 +130,52 @@ pub struct Packet {
     pub addr_master: Option<SocketAddr>,
     pub role: Option<Role>,
     pub node_id: Option<String>,
+    pub filename: Option<String>,
+    pub offset: Option<u64>,
+    pub length: Option<u64>,
+    pub addr_target: Option<SocketAddr>,
+    pub data: Option<Vec<u8>>,
+    pub checksum: Option<u32>,
+    pub is_success: Option<bool>,
+    pub request_kind: Option<RequestKind>,
+    pub locations: Option<Vec<BlockLocation>>,
+    pub seq: Option<u64>,
+    pub is_snapshot: Option<bool>,
+    pub changes: Option<Vec<StateChange>>,
+    pub term: Option<u64>,
+    pub addr_peer: Option<SocketAddr>,
+    pub index: Option<u64>,
+    pub prev_index: Option<u64>,
+    pub prev_term: Option<u64>,
+    pub commit_index: Option<u64>,
+    pub entries: Option<Vec<LogEntry>>,
+    pub blocks: Option<Vec<BlockInfo>>,
+    pub replication_factor: Option<u8>,
+    pub rack: Option<String>,
+    pub usage: Option<NodeUsage>,
+    pub filename_target: Option<String>,
+    pub is_recursive: Option<bool>,
+    pub listing: Option<Vec<DirEntry>>,
+    pub n_blocks_persisted: Option<u32>,
+    pub resume_status: Option<ResumeStatus>,
+}
+
+/// Cursor over a packet's payload used while parsing
+struct PayloadReader<'a> {
+    packet_id: PacketId,
+    payload: &'a [u8],
+    pos: usize,
 }
 
 // ================================================
 // Implementation
 // ================================================
 
-impl From<u8> for PacketId {
-    fn from(value: u8) -> Self {
-        match value {
+impl TryFrom<u8> for PacketId {
+    type Error = ParseError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let packet_id = match value {
             0 => PacketId::Default,
             1 => PacketId::Heartbeat,
             2 => PacketId::HeartbeatAck,
@@ -76,8 +192,19 @@ impl From<u8> for PacketId {
             13 => PacketId::StateSync,
             14 => PacketId::StateSyncAck,
             15 => PacketId::Notify,
-            _ => panic!("Error as parsing to enum PacketId: value = {}", value),
-        }
+            16 => PacketId::Promote,
+            17 => PacketId::RequestVote,
+            18 => PacketId::RequestVoteAck,
+            19 => PacketId::AppendEntries,
+            20 => PacketId::AppendEntriesAck,
+            21 => PacketId::BlockReport,
+            22 => PacketId::CorruptReplica,
+            23 => PacketId::DeleteReplica,
+            24 => PacketId::ListAck,
+            25 => PacketId::ResumeAck,
+            _ => return Err(ParseError::incorrect_packet_id(value)),
+        };
+        Ok(packet_id)
     }
 }
 
@@ -100,6 +227,80 @@ impl From<PacketId> for u8 {
             PacketId::StateSync => 13,
             PacketId::StateSyncAck => 14,
             PacketId::Notify => 15,
+            PacketId::Promote => 16,
+            PacketId::RequestVote => 17,
+            PacketId::RequestVoteAck => 18,
+            PacketId::AppendEntries => 19,
+            PacketId::AppendEntriesAck => 20,
+            PacketId::BlockReport => 21,
+            PacketId::CorruptReplica => 22,
+            PacketId::DeleteReplica => 23,
+            PacketId::ListAck => 24,
+            PacketId::ResumeAck => 25,
+        }
+    }
+}
+
+impl TryFrom<u8> for RequestKind {
+    type Error = ParseError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(RequestKind::Upload),
+            2 => Ok(RequestKind::Download),
+            3 => Ok(RequestKind::SetReplication),
+            4 => Ok(RequestKind::MakeDir),
+            5 => Ok(RequestKind::Rename),
+            6 => Ok(RequestKind::Delete),
+            7 => Ok(RequestKind::List),
+            8 => Ok(RequestKind::Create),
+            9 => Ok(RequestKind::Append),
+            10 => Ok(RequestKind::Close),
+            11 => Ok(RequestKind::Resume),
+            _ => Err(ParseError::incorrect_request_kind(value)),
+        }
+    }
+}
+
+impl From<RequestKind> for u8 {
+    fn from(value: RequestKind) -> Self {
+        match value {
+            RequestKind::Upload => 1,
+            RequestKind::Download => 2,
+            RequestKind::SetReplication => 3,
+            RequestKind::MakeDir => 4,
+            RequestKind::Rename => 5,
+            RequestKind::Delete => 6,
+            RequestKind::List => 7,
+            RequestKind::Create => 8,
+            RequestKind::Append => 9,
+            RequestKind::Close => 10,
+            RequestKind::Resume => 11,
+        }
+    }
+}
+
+impl TryFrom<u8> for ResumeStatus {
+    type Error = ParseError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ResumeStatus::NotFound),
+            1 => Ok(ResumeStatus::Resumed),
+            2 => Ok(ResumeStatus::UnavailableDataNode),
+            3 => Ok(ResumeStatus::Rejected),
+            _ => Err(ParseError::incorrect_enum_value("ResumeStatus", value)),
+        }
+    }
+}
+
+impl From<ResumeStatus> for u8 {
+    fn from(value: ResumeStatus) -> Self {
+        match value {
+            ResumeStatus::NotFound => 0,
+            ResumeStatus::Resumed => 1,
+            ResumeStatus::UnavailableDataNode => 2,
+            ResumeStatus::Rejected => 3,
         }
     }
 }
@@ -123,6 +324,16 @@ impl std::fmt::Display for PacketId {
             PacketId::StateSync => "StateSync",
             PacketId::StateSyncAck => "StateSyncAck",
             PacketId::Notify => "Notify",
+            PacketId::Promote => "Promote",
+            PacketId::RequestVote => "RequestVote",
+            PacketId::RequestVoteAck => "RequestVoteAck",
+            PacketId::AppendEntries => "AppendEntries",
+            PacketId::AppendEntriesAck => "AppendEntriesAck",
+            PacketId::BlockReport => "BlockReport",
+            PacketId::CorruptReplica => "CorruptReplica",
+            PacketId::DeleteReplica => "DeleteReplica",
+            PacketId::ListAck => "ListAck",
+            PacketId::ResumeAck => "ResumeAck",
         };
         write!(f, "{}", s)
     }
@@ -147,6 +358,16 @@ impl std::fmt::Debug for PacketId {
             PacketId::StateSync => "StateSync",
             PacketId::StateSyncAck => "StateSyncAck",
             PacketId::Notify => "Notify",
+            PacketId::Promote => "Promote",
+            PacketId::RequestVote => "RequestVote",
+            PacketId::RequestVoteAck => "RequestVoteAck",
+            PacketId::AppendEntries => "AppendEntries",
+            PacketId::AppendEntriesAck => "AppendEntriesAck",
+            PacketId::BlockReport => "BlockReport",
+            PacketId::CorruptReplica => "CorruptReplica",
+            PacketId::DeleteReplica => "DeleteReplica",
+            PacketId::ListAck => "ListAck",
+            PacketId::ResumeAck => "ResumeAck",
         };
         write!(f, "{}", s)
     }
@@ -159,9 +380,38 @@ impl Default for Packet {
             payload: None,
             addr_sender: None,
             addr_receiver: None,
+            stream: None,
             addr_master: None,
             role: None,
             node_id: None,
+            filename: None,
+            offset: None,
+            length: None,
+            addr_target: None,
+            data: None,
+            checksum: None,
+            is_success: None,
+            request_kind: None,
+            locations: None,
+            seq: None,
+            is_snapshot: None,
+            changes: None,
+            term: None,
+            addr_peer: None,
+            index: None,
+            prev_index: None,
+            prev_term: None,
+            commit_index: None,
+            entries: None,
+            blocks: None,
+            replication_factor: None,
+            rack: None,
+            usage: None,
+            filename_target: None,
+            is_recursive: None,
+            listing: None,
+            n_blocks_persisted: None,
+            resume_status: None,
         }
     }
 }
@@ -196,15 +446,15 @@ impl Packet {
 
         // Add payload size
         let mut payload_size = 0;
-        if self.payload != None {
-            payload_size = self.payload.as_ref().unwrap().len();
+        if let Some(payload) = &self.payload {
+            payload_size = payload.len();
         }
         let bytes_payload_size = (payload_size as u32).to_be_bytes();
         bytes.extend_from_slice(&bytes_payload_size);
 
         // Add payload
-        if self.payload != None {
-            bytes.extend_from_slice(self.payload.as_ref().unwrap());
+        if let Some(payload) = &self.payload {
+            bytes.extend_from_slice(payload);
         }
 
         bytes
@@ -215,49 +465,18 @@ impl Packet {
     // ================================================
 
     /// Create Packet from stream
-    pub fn from_stream(stream: &mut TcpStream) -> Result<Packet, ParseError> {
-        // log::debug!("Receive data from: {}", stream.peer_addr().unwrap());
-
-        // ================================================
-        // Read bytes from stream
-        // ================================================
-        let mut bytes = Vec::<u8>::new();
-        let mut buff: [u8; BUFF_LEN] = [0; BUFF_LEN];
-        loop {
-            let n = stream.read(&mut buff).unwrap();
-            if n == 0 {
-                break;
-            } else {
-                bytes.extend_from_slice(&buff[0..n]);
-
-                if n < BUFF_LEN {
-                    break;
-                }
-            }
-        }
-
-        // ================================================
-        // Parse header
-        // ================================================
-        if bytes.len() < SIZE_HEADER {
-            return Err(ParseError::incorrect_min_header_size(bytes.len()));
-        }
-
-        let packet_id = PacketId::from(bytes[0]);
-        // log::debug!("packet_id = {}", packet_id);
-
-        let payload_size = u32::from_be_bytes(bytes[1..5].try_into().expect("Incorrect length")) as usize;
-        // log::debug!("payload_size = {}", payload_size);
-
-        if bytes.len() != SIZE_HEADER + payload_size {
-            return Err(ParseError::mismatched_packet_size(packet_id, bytes.len(), payload_size));
-        }
+    ///
+    /// Read exactly one frame (5-byte header followed by `payload_size` bytes) from the stream, so the function can be
+    /// called repeatedly on the same connection to receive consecutive packets. Frames whose payload exceeds
+    /// `max_frame_size` are rejected before any payload byte is read.
+    pub fn from_stream(stream: &mut TcpStream, max_frame_size: usize) -> Result<Packet, ParseError> {
+        let (packet_id, payload) = _read_frame(stream, max_frame_size)?;
+        let payload_size = payload.len();
+        let packet_size = SIZE_HEADER + payload_size;
 
         // ================================================
         // Parse payload
         // ================================================
-        let mut payload = Vec::<u8>::new();
-        payload.extend_from_slice(&bytes[5..(5 + payload_size) as usize]);
 
         let mut packet = Packet {
             packet_id,
@@ -266,21 +485,63 @@ impl Packet {
         };
 
         match packet_id {
-            PacketId::Heartbeat => {}
-            PacketId::HeartbeatAck => match String::from_utf8(payload) {
-                Ok(node_id) => packet.node_id = Some(node_id),
-                Err(err) => {
-                    log::error!("Parsing HEARTBEAT_ACK: Cannot parse node_id: {err}");
+            PacketId::Heartbeat => match payload_size {
+                0 => {}
+                2 => {
+                    // Parse port of thread:Receiver of sender so that the receiver can tell which Master is alive
+                    packet.addr_sender.as_mut().unwrap().set_port(u16::from_be_bytes(
+                        payload
+                            .as_slice()
+                            .try_into()
+                            .expect("Cannot parse 2 bytes in payload to port value"),
+                    ));
+                }
+                _ => {
+                    return Err(ParseError::mismatched_packet_size(packet_id, packet_size, payload_size));
                 }
             },
+            PacketId::HeartbeatAck => {
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.node_id = Some(reader.read_str()?);
+
+                // Data nodes also report their usage
+                if reader.has_remaining() {
+                    packet.usage = Some(NodeUsage {
+                        disk_total: reader.read_u64()?,
+                        disk_used: reader.read_u64()?,
+                        disk_free: reader.read_u64()?,
+                        n_blocks: reader.read_u64()?,
+                        n_in_flight: reader.read_u64()?,
+                        load: reader.read_u64()?,
+                    });
+                }
+                reader.finish()?;
+            }
             PacketId::RequestSendReplica => {
-                // TODO: HoangLe [May-02]: Implement this
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.filename = Some(reader.read_str()?);
+                packet.offset = Some(reader.read_u64()?);
+                packet.length = Some(reader.read_u64()?);
+                packet.addr_target = Some(reader.read_addr()?);
+                reader.finish()?;
             }
             PacketId::SendReplica => {
-                // TODO: HoangLe [May-02]: Implement this
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.filename = Some(reader.read_str()?);
+                packet.offset = Some(reader.read_u64()?);
+                packet.length = Some(reader.read_u64()?);
+                packet.checksum = Some(reader.read_u32()?);
+                packet.data = Some(reader.read_chunk(packet.offset.unwrap(), packet.length.unwrap())?);
             }
-            PacketId::SendReplicaAck => {
-                // TODO: HoangLe [May-02]: Implement this
+            PacketId::SendReplicaAck | PacketId::ClientRequestAck => {
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.filename = Some(reader.read_str()?);
+                packet.offset = Some(reader.read_u64()?);
+                packet.length = Some(reader.read_u64()?);
+                packet.checksum = Some(reader.read_u32()?);
+                packet.is_success = Some(reader.read_u8()? == 1);
+                packet.node_id = Some(reader.read_str()?);
+                reader.finish()?;
             }
             PacketId::AskIp => match payload_size {
                 2 => {
@@ -292,9 +553,13 @@ impl Packet {
                             .expect("Cannot parse 2 bytes in payload to port value"),
                     ));
                 }
+                0 => {
+                    // Sender has no thread:Receiver (e.g. Client), so it waits for the reply on this connection
+                    packet.stream = stream.try_clone().ok();
+                }
                 _ => {
                     log::info!("Packet AskIP requires specifying port of thread:Receiver of sender");
-                    return Err(ParseError::mismatched_packet_size(packet_id, bytes.len(), payload_size));
+                    return Err(ParseError::mismatched_packet_size(packet_id, packet_size, payload_size));
                 }
             },
             PacketId::AskIpAck => match payload_size {
@@ -302,13 +567,6 @@ impl Packet {
                     return Err(ParseError::unavailable_master_ip());
                 }
                 6 => {
-                    let mut buff = Vec::with_capacity(payload_size);
-                    if let Err(err) = stream.read_exact(&mut buff) {
-                        log::error!("Err as reading bytes for payload: {}", err);
-                        return Err(ParseError::stream_reading_err());
-                    }
-                    packet.payload = Some(buff);
-
                     // Parse addr's Master from payload
                     let ip_master = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
                     let port_master =
@@ -320,38 +578,178 @@ impl Packet {
                 }
             },
             PacketId::RequestFromClient => {
-                // TODO: HoangLe [May-02]: Implement this
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.request_kind = Some(RequestKind::try_from(reader.read_u8()?)?);
+                packet.filename = Some(reader.read_str()?);
+                packet.length = Some(reader.read_u64()?);
+                packet.checksum = Some(reader.read_u32()?);
+                packet.replication_factor = Some(reader.read_u8()?);
+                match packet.request_kind {
+                    Some(RequestKind::Rename) => packet.filename_target = Some(reader.read_str()?),
+                    Some(RequestKind::Download) => packet.offset = Some(reader.read_u64()?),
+                    Some(RequestKind::Delete) | Some(RequestKind::List) => {
+                        packet.is_recursive = Some(reader.read_u8()? == 1)
+                    }
+                    _ => {}
+                }
+                reader.finish()?;
+
+                packet.stream = stream.try_clone().ok();
             }
             PacketId::ResponseNodeIp => {
-                // TODO: HoangLe [May-02]: Implement this
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.filename = Some(reader.read_str()?);
+                packet.length = Some(reader.read_u64()?);
+                packet.checksum = Some(reader.read_u32()?);
+                packet.locations = Some(reader.read_locations()?);
+                reader.finish()?;
             }
             PacketId::ClientUpload => {
-                // TODO: HoangLe [May-02]: Implement this
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.filename = Some(reader.read_str()?);
+                packet.offset = Some(reader.read_u64()?);
+                packet.length = Some(reader.read_u64()?);
+                packet.checksum = Some(reader.read_u32()?);
+                packet.data = Some(reader.read_chunk(packet.offset.unwrap(), packet.length.unwrap())?);
+
+                packet.stream = stream.try_clone().ok();
             }
             PacketId::DataNodeSendData => {
-                // TODO: HoangLe [May-02]: Implement this
-            }
-            PacketId::ClientRequestAck => {
-                // TODO: HoangLe [May-02]: Implement this
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.filename = Some(reader.read_str()?);
+                packet.offset = Some(reader.read_u64()?);
+                packet.length = Some(reader.read_u64()?);
+                packet.checksum = Some(reader.read_u32()?);
+                packet.data = Some(reader.read_chunk(packet.offset.unwrap(), packet.length.unwrap())?);
             }
             PacketId::StateSync => {
-                // TODO: HoangLe [May-02]: Implement this
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.seq = Some(reader.read_u64()?);
+                packet.is_snapshot = Some(reader.read_u8()? == 1);
+                packet.changes = Some(reader.read_changes()?);
+                reader.finish()?;
+            }
+            PacketId::CorruptReplica => {
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.filename = Some(reader.read_str()?);
+                packet.node_id = Some(reader.read_str()?);
+                reader.finish()?;
+            }
+            PacketId::DeleteReplica => {
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.filename = Some(reader.read_str()?);
+                reader.finish()?;
+            }
+            PacketId::ListAck => {
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.filename = Some(reader.read_str()?);
+                packet.is_success = Some(reader.read_u8()? == 1);
+                let n_entries = reader.read_u32()?;
+                let mut listing = Vec::<DirEntry>::new();
+                for _ in 0..n_entries {
+                    listing.push(DirEntry {
+                        path: reader.read_str()?,
+                        is_dir: reader.read_u8()? == 1,
+                        size: reader.read_u64()?,
+                        state: FileState::try_from(reader.read_u8()?)?,
+                        replication_factor: reader.read_u8()? as usize,
+                    });
+                }
+                packet.listing = Some(listing);
+                reader.finish()?;
+            }
+            PacketId::ResumeAck => {
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.filename = Some(reader.read_str()?);
+                packet.resume_status = Some(ResumeStatus::try_from(reader.read_u8()?)?);
+                packet.length = Some(reader.read_u64()?);
+                packet.checksum = Some(reader.read_u32()?);
+                packet.n_blocks_persisted = Some(reader.read_u32()?);
+                packet.locations = Some(reader.read_locations()?);
+                reader.finish()?;
             }
             PacketId::StateSyncAck => {
-                // TODO: HoangLe [May-02]: Implement this
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.seq = Some(reader.read_u64()?);
+                packet.node_id = Some(reader.read_str()?);
+                reader.finish()?;
+            }
+            PacketId::Promote => {}
+            PacketId::RequestVote => {
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.term = Some(reader.read_u64()?);
+                packet.addr_peer = Some(reader.read_addr()?);
+                packet.index = Some(reader.read_u64()?);
+                packet.prev_term = Some(reader.read_u64()?);
+                reader.finish()?;
+            }
+            PacketId::RequestVoteAck | PacketId::AppendEntriesAck => {
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.term = Some(reader.read_u64()?);
+                packet.is_success = Some(reader.read_u8()? == 1);
+                packet.index = Some(reader.read_u64()?);
+                packet.addr_peer = Some(reader.read_addr()?);
+                reader.finish()?;
+            }
+            PacketId::AppendEntries => {
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.term = Some(reader.read_u64()?);
+                packet.addr_peer = Some(reader.read_addr()?);
+                packet.prev_index = Some(reader.read_u64()?);
+                packet.prev_term = Some(reader.read_u64()?);
+                packet.commit_index = Some(reader.read_u64()?);
+                packet.is_snapshot = Some(reader.read_u8()? == 1);
+                let n_entries = reader.read_u32()?;
+                let mut entries = Vec::<LogEntry>::new();
+                for _ in 0..n_entries {
+                    let term = reader.read_u64()?;
+                    entries.push(LogEntry {
+                        term,
+                        changes: reader.read_changes()?,
+                    });
+                }
+                packet.entries = Some(entries);
+                reader.finish()?;
+            }
+            PacketId::BlockReport => {
+                let mut reader = PayloadReader::new(packet_id, &payload);
+                packet.node_id = Some(reader.read_str()?);
+                let n_blocks = reader.read_u32()?;
+                let mut blocks = Vec::<BlockInfo>::new();
+                for _ in 0..n_blocks {
+                    blocks.push(BlockInfo {
+                        block_id: reader.read_str()?,
+                        size: reader.read_u64()?,
+                        checksum: reader.read_u32()?,
+                    });
+                }
+                packet.blocks = Some(blocks);
+                reader.finish()?;
             }
             PacketId::Notify => match payload_size {
-                3 => {
+                3.. => {
                     // Parse role of sender
-                    packet.role = Some(Role::from(payload[0]));
+                    let role = Role::try_from(payload[0])?;
 
                     // Parse port info from payload
                     packet.addr_sender.as_mut().unwrap().set_port(u16::from_be_bytes(
                         payload[1..3].try_into().expect("Cannot cast last 2 bytes to array"),
                     ));
+
+                    let mut reader = PayloadReader::new(packet_id, &payload[3..]);
+                    match role {
+                        // Leader of a Raft group also tells its term
+                        Role::Master if payload_size == 11 => packet.term = Some(reader.read_u64()?),
+                        // Data node registering with Master also tells its rack
+                        Role::Data if payload_size > 3 => packet.rack = Some(reader.read_str()?),
+                        _ => {}
+                    }
+                    reader.finish()?;
+
+                    packet.role = Some(role);
                 }
                 _ => {
-                    return Err(ParseError::mismatched_packet_size(packet_id, bytes.len(), payload_size));
+                    return Err(ParseError::mismatched_packet_size(packet_id, packet_size, payload_size));
                 }
             },
             _ => return Err(ParseError::incorrect_packet_id(packet_id as u8)),
@@ -359,13 +757,14 @@ impl Packet {
 
         log::debug!("{}", packet);
 
-        return Ok(packet);
+        Ok(packet)
     }
 
-    pub fn create_heartbeat(addr_receiver: SocketAddr) -> Packet {
+    pub fn create_heartbeat(addr_receiver: SocketAddr, addr_current: SocketAddr) -> Packet {
         Packet {
             packet_id: PacketId::Heartbeat,
             addr_receiver: Some(addr_receiver),
+            payload: Some(addr_current.port().to_be_bytes().to_vec()),
             ..Default::default()
         }
     }
@@ -374,7 +773,7 @@ impl Packet {
         let mut payload = Vec::<u8>::new();
         match addr_current {
             SocketAddr::V4(addr) => {
-                payload.extend_from_slice(_get_node_id(addr.ip(), addr.port()).as_bytes());
+                _put_str(&mut payload, &_get_node_id(addr.ip(), addr.port()));
             }
             _ => {
                 log::error!("Creating HEARTBEAT_ACK, but IP of current node isn't IPv4 format.");
@@ -389,15 +788,97 @@ impl Packet {
         }
     }
 
-    // pub fn create_RequestSendReplica() -> Packet {
-    //     // TODO: HoangLe [Apr-28]: Implement this
-    // }
-    // pub fn create_SendReplica() -> Packet {
-    //     // TODO: HoangLe [Apr-28]: Implement this
-    // }
-    // pub fn create_SendReplicaAck() -> Packet {
-    //     // TODO: HoangLe [Apr-28]: Implement this
-    // }
+    /// Data node answers Master's Heartbeat with its disk usage and activity
+    pub fn create_heartbeat_ack_data(addr_receiver: SocketAddr, addr_current: SocketAddr, usage: &NodeUsage) -> Packet {
+        let mut packet = Packet::create_heartbeat_ack(addr_receiver, addr_current);
+        if let Some(payload) = packet.payload.as_mut() {
+            for value in [
+                usage.disk_total,
+                usage.disk_used,
+                usage.disk_free,
+                usage.n_blocks,
+                usage.n_in_flight,
+                usage.load,
+            ] {
+                payload.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+
+        packet
+    }
+
+    /// Master asks a Data node holding `filename` to push the range [offset, offset + length) to `addr_target`.
+    /// `length` = 0 means until the end of the file.
+    pub fn create_request_send_replica(
+        addr_receiver: SocketAddr,
+        filename: &str,
+        offset: u64,
+        length: u64,
+        addr_target: SocketAddr,
+    ) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        _put_str(&mut payload, filename);
+        payload.extend_from_slice(&offset.to_be_bytes());
+        payload.extend_from_slice(&length.to_be_bytes());
+        _put_addr(&mut payload, &addr_target);
+
+        Packet {
+            packet_id: PacketId::RequestSendReplica,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
+
+    /// Data node sends a chunk of a replica starting at `offset`. `length` is the size of the whole replica so the
+    /// target knows when the last chunk arrives.
+    pub fn create_send_replica(
+        addr_receiver: SocketAddr,
+        filename: &str,
+        offset: u64,
+        length: u64,
+        data: &[u8],
+        checksum: u32,
+    ) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        _put_str(&mut payload, filename);
+        payload.extend_from_slice(&offset.to_be_bytes());
+        payload.extend_from_slice(&length.to_be_bytes());
+        payload.extend_from_slice(&checksum.to_be_bytes());
+        payload.extend_from_slice(data);
+
+        Packet {
+            packet_id: PacketId::SendReplica,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
+
+    pub fn create_send_replica_ack(
+        addr_receiver: SocketAddr,
+        filename: &str,
+        offset: u64,
+        length: u64,
+        checksum: u32,
+        is_success: bool,
+        addr_current: SocketAddr,
+    ) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        _put_str(&mut payload, filename);
+        payload.extend_from_slice(&offset.to_be_bytes());
+        payload.extend_from_slice(&length.to_be_bytes());
+        payload.extend_from_slice(&checksum.to_be_bytes());
+        payload.push(is_success as u8);
+        _put_str(&mut payload, &addr_current.to_string());
+
+        Packet {
+            packet_id: PacketId::SendReplicaAck,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
 
     pub fn create_ask_ip(addr_receiver: SocketAddr, port: Option<u16>) -> Packet {
         // Craft payload
@@ -420,46 +901,430 @@ impl Packet {
             addr_receiver: Some(addr_receiver),
             ..Default::default()
         };
-        match addr_master {
-            Some(addr_master) => {
-                if let IpAddr::V4(ip_master) = addr_master.ip() {
-                    let mut payload = ip_master.octets().to_vec();
-                    payload.extend_from_slice(&addr_master.port().to_be_bytes());
-                    packet.payload = Some(payload);
-                }
+        if let Some(addr_master) = addr_master {
+            if let IpAddr::V4(ip_master) = addr_master.ip() {
+                let mut payload = ip_master.octets().to_vec();
+                payload.extend_from_slice(&addr_master.port().to_be_bytes());
+                packet.payload = Some(payload);
             }
-            None => {}
         }
 
         packet
     }
 
-    // pub fn create_RequestFromClient() -> Packet {
-    //     // TODO: HoangLe [Apr-28]: Implement this
-    // }
-    // pub fn create_ResponseNodeIp() -> Packet {
-    //     // TODO: HoangLe [Apr-28]: Implement this
-    // }
-    // pub fn create_ClientUpload() -> Packet {
-    //     // TODO: HoangLe [Apr-28]: Implement this
-    // }
-    // pub fn create_DataNodeSendData() -> Packet {
-    //     // TODO: HoangLe [Apr-28]: Implement this
-    // }
-    // pub fn create_ClientRequestAck() -> Packet {
-    //     // TODO: HoangLe [Apr-28]: Implement this
-    // }
-    // pub fn create_StateSync() -> Packet {
-    //     // TODO: HoangLe [Apr-28]: Implement this
-    // }
-    // pub fn create_StateSyncAck() -> Packet {
-    //     // TODO: HoangLe [Apr-28]: Implement this
-    // }
+    /// Client sends a request about file `filename`. For uploads, `length` and `checksum` describe the whole file.
+    /// `replication_factor` is the number of replicas wanted on upload or set-replication, 0 leaving Master to decide.
+    pub fn create_request_from_client(
+        addr_receiver: SocketAddr,
+        request_kind: RequestKind,
+        filename: &str,
+        length: u64,
+        checksum: u32,
+        replication_factor: u8,
+    ) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        payload.push(u8::from(request_kind));
+        _put_str(&mut payload, filename);
+        payload.extend_from_slice(&length.to_be_bytes());
+        payload.extend_from_slice(&checksum.to_be_bytes());
+        payload.push(replication_factor);
+
+        Packet {
+            packet_id: PacketId::RequestFromClient,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
+
+    /// Client asks to move `filename` to `filename_target`
+    pub fn create_request_rename(addr_receiver: SocketAddr, filename: &str, filename_target: &str) -> Packet {
+        let mut packet = Packet::create_request_from_client(addr_receiver, RequestKind::Rename, filename, 0, 0, 0);
+        _put_str(packet.payload.as_mut().unwrap(), filename_target);
+
+        packet
+    }
+
+    /// Client asks for range [offset, offset + length) of `filename`. `length` = 0 asks until the end.
+    pub fn create_request_download(addr_receiver: SocketAddr, filename: &str, offset: u64, length: u64) -> Packet {
+        let mut packet =
+            Packet::create_request_from_client(addr_receiver, RequestKind::Download, filename, length, 0, 0);
+        packet
+            .payload
+            .as_mut()
+            .unwrap()
+            .extend_from_slice(&offset.to_be_bytes());
+
+        packet
+    }
+
+    /// Client asks to delete or list `filename`, with everything under it if `is_recursive`
+    pub fn create_request_tree(
+        addr_receiver: SocketAddr,
+        request_kind: RequestKind,
+        filename: &str,
+        is_recursive: bool,
+    ) -> Packet {
+        let mut packet = Packet::create_request_from_client(addr_receiver, request_kind, filename, 0, 0, 0);
+        packet.payload.as_mut().unwrap().push(is_recursive as u8);
+
+        packet
+    }
+
+    /// Master answers a client with the entries under `filename`. A failed listing has no entries.
+    pub fn create_list_ack(addr_receiver: SocketAddr, filename: &str, listing: Option<&[DirEntry]>) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        _put_str(&mut payload, filename);
+        payload.push(listing.is_some() as u8);
+        let listing = listing.unwrap_or_default();
+        payload.extend_from_slice(&(listing.len() as u32).to_be_bytes());
+        for entry in listing {
+            _put_str(&mut payload, &entry.path);
+            payload.push(entry.is_dir as u8);
+            payload.extend_from_slice(&entry.size.to_be_bytes());
+            payload.push(u8::from(entry.state));
+            _put_replication_factor(&mut payload, entry.replication_factor);
+        }
+
+        Packet {
+            packet_id: PacketId::ListAck,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
+
+    /// Master answers a client resuming the upload of `filename` with the number of blocks already stored and where to
+    /// send the other ones. Both are only meaningful if `status` is Resumed.
+    pub fn create_resume_ack(
+        addr_receiver: SocketAddr,
+        filename: &str,
+        size: u64,
+        checksum: u32,
+        status: ResumeStatus,
+        (n_blocks_persisted, locations): (u32, &[BlockLocation]),
+    ) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        _put_str(&mut payload, filename);
+        payload.push(u8::from(status));
+        payload.extend_from_slice(&size.to_be_bytes());
+        payload.extend_from_slice(&checksum.to_be_bytes());
+        payload.extend_from_slice(&n_blocks_persisted.to_be_bytes());
+        _put_locations(&mut payload, locations);
+
+        Packet {
+            packet_id: PacketId::ResumeAck,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
+
+    /// Master answers a client with the blocks of file `filename` and the Data nodes to contact for each of them. No
+    /// block means the request cannot be served.
+    pub fn create_response_node_ip(
+        addr_receiver: SocketAddr,
+        filename: &str,
+        size: u64,
+        checksum: u32,
+        locations: &[BlockLocation],
+    ) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        _put_str(&mut payload, filename);
+        payload.extend_from_slice(&size.to_be_bytes());
+        payload.extend_from_slice(&checksum.to_be_bytes());
+        _put_locations(&mut payload, locations);
+
+        Packet {
+            packet_id: PacketId::ResponseNodeIp,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
+
+    /// Client sends a chunk of file content starting at `offset`. `length` is the size of the whole file.
+    pub fn create_client_upload(
+        addr_receiver: SocketAddr,
+        filename: &str,
+        offset: u64,
+        length: u64,
+        data: &[u8],
+        checksum: u32,
+    ) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        _put_str(&mut payload, filename);
+        payload.extend_from_slice(&offset.to_be_bytes());
+        payload.extend_from_slice(&length.to_be_bytes());
+        payload.extend_from_slice(&checksum.to_be_bytes());
+        payload.extend_from_slice(data);
+
+        Packet {
+            packet_id: PacketId::ClientUpload,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
+
+    /// Data node sends a chunk of file content starting at `offset`. `length` is where the range sent ends, the size of
+    /// the whole file when sent whole, so the client knows when the last chunk arrives.
+    pub fn create_data_node_send_data(
+        addr_receiver: SocketAddr,
+        filename: &str,
+        offset: u64,
+        length: u64,
+        data: &[u8],
+        checksum: u32,
+    ) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        _put_str(&mut payload, filename);
+        payload.extend_from_slice(&offset.to_be_bytes());
+        payload.extend_from_slice(&length.to_be_bytes());
+        payload.extend_from_slice(&checksum.to_be_bytes());
+        payload.extend_from_slice(data);
+
+        Packet {
+            packet_id: PacketId::DataNodeSendData,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
+    pub fn create_client_request_ack(
+        addr_receiver: SocketAddr,
+        filename: &str,
+        offset: u64,
+        length: u64,
+        checksum: u32,
+        is_success: bool,
+        addr_current: SocketAddr,
+    ) -> Packet {
+        let mut packet = Packet::create_send_replica_ack(
+            addr_receiver,
+            filename,
+            offset,
+            length,
+            checksum,
+            is_success,
+            addr_current,
+        );
+        packet.packet_id = PacketId::ClientRequestAck;
+
+        packet
+    }
+
+    /// Master sends standby Master the changes numbered `seq`. A snapshot tells the standby to drop what it has first.
+    pub fn create_state_sync(
+        addr_receiver: SocketAddr,
+        seq: u64,
+        is_snapshot: bool,
+        changes: &[StateChange],
+    ) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        payload.extend_from_slice(&seq.to_be_bytes());
+        payload.push(is_snapshot as u8);
+        _put_changes(&mut payload, changes);
+
+        Packet {
+            packet_id: PacketId::StateSync,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
+
+    /// Standby Master acknowledges it applied every StateSync up to `seq`
+    pub fn create_state_sync_ack(addr_receiver: SocketAddr, seq: u64, addr_current: SocketAddr) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        payload.extend_from_slice(&seq.to_be_bytes());
+        _put_str(&mut payload, &addr_current.to_string());
+
+        Packet {
+            packet_id: PacketId::StateSyncAck,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
+
+    /// Send this packet back on the connection the request arrived on, if the requester waits on it
+    pub fn with_stream(mut self, stream: Option<TcpStream>) -> Packet {
+        self.stream = stream;
+
+        self
+    }
+
+    /// Candidate asks a peer Master for its vote in `term`. `last_index` and `last_term` describe the candidate's log.
+    pub fn create_request_vote(
+        addr_receiver: SocketAddr,
+        term: u64,
+        addr_current: SocketAddr,
+        last_index: u64,
+        last_term: u64,
+    ) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        payload.extend_from_slice(&term.to_be_bytes());
+        _put_addr(&mut payload, &addr_current);
+        payload.extend_from_slice(&last_index.to_be_bytes());
+        payload.extend_from_slice(&last_term.to_be_bytes());
+
+        Packet {
+            packet_id: PacketId::RequestVote,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
+
+    pub fn create_request_vote_ack(
+        addr_receiver: SocketAddr,
+        term: u64,
+        is_granted: bool,
+        addr_current: SocketAddr,
+    ) -> Packet {
+        let mut packet = Packet::create_append_entries_ack(addr_receiver, term, is_granted, 0, addr_current);
+        packet.packet_id = PacketId::RequestVoteAck;
+
+        packet
+    }
+
+    /// Leader sends log entries following (`prev_index`, `prev_term`). No entries means a heartbeat. A snapshot
+    /// carries one entry rebuilding the whole metadata up to `prev_index`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_append_entries(
+        addr_receiver: SocketAddr,
+        term: u64,
+        addr_current: SocketAddr,
+        prev_index: u64,
+        prev_term: u64,
+        commit_index: u64,
+        is_snapshot: bool,
+        entries: &[LogEntry],
+    ) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        payload.extend_from_slice(&term.to_be_bytes());
+        _put_addr(&mut payload, &addr_current);
+        payload.extend_from_slice(&prev_index.to_be_bytes());
+        payload.extend_from_slice(&prev_term.to_be_bytes());
+        payload.extend_from_slice(&commit_index.to_be_bytes());
+        payload.push(is_snapshot as u8);
+        payload.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+        for entry in entries {
+            payload.extend_from_slice(&entry.term.to_be_bytes());
+            _put_changes(&mut payload, &entry.changes);
+        }
+
+        Packet {
+            packet_id: PacketId::AppendEntries,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
+
+    /// Follower answers AppendEntries. On success `index` is the last entry matching the leader's log, otherwise it
+    /// hints where the leader should retry from.
+    pub fn create_append_entries_ack(
+        addr_receiver: SocketAddr,
+        term: u64,
+        is_success: bool,
+        index: u64,
+        addr_current: SocketAddr,
+    ) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        payload.extend_from_slice(&term.to_be_bytes());
+        payload.push(is_success as u8);
+        payload.extend_from_slice(&index.to_be_bytes());
+        _put_addr(&mut payload, &addr_current);
+
+        Packet {
+            packet_id: PacketId::AppendEntriesAck,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
+
+    /// Data node lists every block it stores so that Master can reconcile its replicas
+    pub fn create_block_report(addr_receiver: SocketAddr, addr_current: SocketAddr, blocks: &[BlockInfo]) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        _put_str(&mut payload, &addr_current.to_string());
+        payload.extend_from_slice(&(blocks.len() as u32).to_be_bytes());
+        for block in blocks {
+            _put_str(&mut payload, &block.block_id);
+            payload.extend_from_slice(&block.size.to_be_bytes());
+            payload.extend_from_slice(&block.checksum.to_be_bytes());
+        }
+
+        Packet {
+            packet_id: PacketId::BlockReport,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
+
+    /// Data node tells Master that its replica of block `block_id` is damaged and has been dropped
+    pub fn create_corrupt_replica(addr_receiver: SocketAddr, block_id: &str, addr_current: SocketAddr) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        _put_str(&mut payload, block_id);
+        _put_str(&mut payload, &addr_current.to_string());
+
+        Packet {
+            packet_id: PacketId::CorruptReplica,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
+
+    /// Master tells a Data node to drop its replica of block `block_id`, which has more replicas than needed
+    pub fn create_delete_replica(addr_receiver: SocketAddr, block_id: &str) -> Packet {
+        let mut payload = Vec::<u8>::new();
+        _put_str(&mut payload, block_id);
+
+        Packet {
+            packet_id: PacketId::DeleteReplica,
+            addr_receiver: Some(addr_receiver),
+            payload: Some(payload),
+            ..Default::default()
+        }
+    }
+
+    /// Leader of a Raft group of Masters announces itself to DNS
+    pub fn create_notify_leader(addr_receiver: SocketAddr, addr_current: SocketAddr, term: u64) -> Packet {
+        let mut packet = Packet::create_notify(addr_receiver, &Role::Master, addr_current);
+        if let Some(payload) = packet.payload.as_mut() {
+            payload.extend_from_slice(&term.to_be_bytes());
+        }
+
+        packet
+    }
+
+    /// Data node registers with Master, telling the rack it lies in
+    pub fn create_notify_data(addr_receiver: SocketAddr, addr_current: SocketAddr, rack: &str) -> Packet {
+        let mut packet = Packet::create_notify(addr_receiver, &Role::Data, addr_current);
+        if let Some(payload) = packet.payload.as_mut() {
+            _put_str(payload, rack);
+        }
+
+        packet
+    }
+
+    /// DNS tells a standby Master to take over from the Master which stopped answering
+    pub fn create_promote(addr_receiver: SocketAddr) -> Packet {
+        Packet {
+            packet_id: PacketId::Promote,
+            addr_receiver: Some(addr_receiver),
+            ..Default::default()
+        }
+    }
 
     pub fn create_notify(addr_receiver: SocketAddr, role: &Role, addr_current: SocketAddr) -> Packet {
         // Craft payload
         let mut payload = Vec::<u8>::new();
-        payload.push(u8::try_from(role).expect("Cannot parse 'role' to u8 value."));
+        payload.push(u8::from(role));
 
         let port = addr_current.port();
         payload.extend_from_slice(&port.to_be_bytes());
@@ -472,3 +1337,665 @@ impl Packet {
         }
     }
 }
+
+// ================================================
+// Helpers for crafting and parsing payload
+// ================================================
+
+/// Read exactly one frame: a 5-byte header (packet id, then payload size) followed by the payload. Reads may return
+/// any part of the frame, e.g. when it spans TCP segments.
+fn _read_frame(stream: &mut impl Read, max_frame_size: usize) -> Result<(PacketId, Vec<u8>), ParseError> {
+    // ================================================
+    // Read header from stream
+    // ================================================
+    let mut header: [u8; SIZE_HEADER] = [0; SIZE_HEADER];
+    let mut n_read = 0;
+    while n_read < SIZE_HEADER {
+        match stream.read(&mut header[n_read..]) {
+            Ok(0) => break,
+            Ok(n) => n_read += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => {
+                log::error!("Err as reading bytes for header: {}", err);
+                return Err(ParseError::stream_reading_err());
+            }
+        }
+    }
+    match n_read {
+        0 => return Err(ParseError::connection_closed()),
+        SIZE_HEADER => {}
+        _ => return Err(ParseError::incorrect_min_header_size(n_read)),
+    }
+
+    // ================================================
+    // Parse header
+    // ================================================
+    let packet_id = PacketId::try_from(header[0])?;
+    let payload_size = u32::from_be_bytes(header[1..5].try_into().expect("Incorrect length")) as usize;
+
+    if payload_size > max_frame_size {
+        return Err(ParseError::exceeded_max_frame_size(
+            packet_id,
+            payload_size,
+            max_frame_size,
+        ));
+    }
+
+    // ================================================
+    // Read payload from stream
+    // ================================================
+    // Buffer grows as bytes arrive, so a header alone doesn't get `payload_size` bytes allocated
+    let mut payload = Vec::<u8>::new();
+    match stream.take(payload_size as u64).read_to_end(&mut payload) {
+        Ok(n) if n == payload_size => {}
+        Ok(n) => {
+            return Err(ParseError::mismatched_packet_size(
+                packet_id,
+                SIZE_HEADER + n,
+                payload_size,
+            ))
+        }
+        Err(err) => {
+            log::error!("Err as reading bytes for payload: {}", err);
+            return Err(ParseError::stream_reading_err());
+        }
+    }
+
+    Ok((packet_id, payload))
+}
+
+/// Append a string prefixed by its length (2 bytes). Strings come from parsed packets, or from paths checked against
+/// `MAX_STR_LEN` by clients and Master, so a longer one is a bug rather than an input error.
+fn _put_str(payload: &mut Vec<u8>, value: &str) {
+    let len = u16::try_from(value.len()).expect("String too long for a payload");
+    payload.extend_from_slice(&len.to_be_bytes());
+    payload.extend_from_slice(value.as_bytes());
+}
+
+/// Append a replication factor. Factors come from clients as 1 byte and `REPLICATION_FACTOR` is checked at start, so
+/// a larger one is a bug.
+fn _put_replication_factor(payload: &mut Vec<u8>, replication_factor: usize) {
+    payload.push(u8::try_from(replication_factor).expect("Replication factor too large for a payload"));
+}
+
+/// Append an IPv4 address as 4 bytes of IP followed by 2 bytes of port
+fn _put_addr(payload: &mut Vec<u8>, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => payload.extend_from_slice(&ip.octets()),
+        IpAddr::V6(_) => {
+            log::error!("Address {} isn't IPv4 format. Write unspecified address instead.", addr);
+            payload.extend_from_slice(&Ipv4Addr::UNSPECIFIED.octets());
+        }
+    }
+    payload.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Append blocks with the Data nodes holding them, prefixed by their count (4 bytes)
+fn _put_locations(payload: &mut Vec<u8>, locations: &[BlockLocation]) {
+    payload.extend_from_slice(&(locations.len() as u32).to_be_bytes());
+    for location in locations {
+        _put_str(payload, &location.block_id);
+        payload.extend_from_slice(&location.offset.to_be_bytes());
+        payload.extend_from_slice(&location.size.to_be_bytes());
+        payload.extend_from_slice(&location.checksum.to_be_bytes());
+        // Clients only need a few replicas, so extra ones are left out rather than overflowing the count
+        let addr_nodes = &location.addr_nodes[..location.addr_nodes.len().min(u8::MAX as usize)];
+        payload.push(addr_nodes.len() as u8);
+        for addr in addr_nodes {
+            _put_addr(payload, addr);
+        }
+    }
+}
+
+/// Encode metadata changes the way StateSync carries them, e.g. to persist Raft log entries
+pub fn encode_changes(changes: &[StateChange]) -> Vec<u8> {
+    let mut payload = Vec::<u8>::new();
+    _put_changes(&mut payload, changes);
+
+    payload
+}
+
+pub fn decode_changes(payload: &[u8]) -> Result<Vec<StateChange>, ParseError> {
+    let mut reader = PayloadReader::new(PacketId::Default, payload);
+    let changes = reader.read_changes()?;
+    reader.finish()?;
+
+    Ok(changes)
+}
+
+/// Append metadata changes prefixed by their number (4 bytes)
+fn _put_changes(payload: &mut Vec<u8>, changes: &[StateChange]) {
+    payload.extend_from_slice(&(changes.len() as u32).to_be_bytes());
+    for change in changes {
+        _put_change(payload, change);
+    }
+}
+
+/// Append a metadata change as a 1-byte tag followed by its fields
+fn _put_change(payload: &mut Vec<u8>, change: &StateChange) {
+    match change {
+        StateChange::UpsertNode { ip, port, role } => {
+            payload.push(TAG_UPSERT_NODE);
+            _put_addr(payload, &SocketAddr::V4(SocketAddrV4::new(*ip, *port)));
+            payload.push(u8::from(role));
+        }
+        StateChange::UpdateNodeStatus { node_id, status } => {
+            payload.push(TAG_UPDATE_NODE_STATUS);
+            _put_str(payload, node_id);
+            payload.push(u8::from(*status));
+        }
+        StateChange::UpdateNodeRack { node_id, rack } => {
+            payload.push(TAG_UPDATE_NODE_RACK);
+            _put_str(payload, node_id);
+            _put_str(payload, rack);
+        }
+        StateChange::CreateInode {
+            inode,
+            parent,
+            name,
+            is_dir,
+        } => {
+            payload.push(TAG_CREATE_INODE);
+            payload.extend_from_slice(&inode.to_be_bytes());
+            payload.extend_from_slice(&parent.to_be_bytes());
+            _put_str(payload, name);
+            payload.push(*is_dir as u8);
+        }
+        StateChange::MoveInode { inode, parent, name } => {
+            payload.push(TAG_MOVE_INODE);
+            payload.extend_from_slice(&inode.to_be_bytes());
+            payload.extend_from_slice(&parent.to_be_bytes());
+            _put_str(payload, name);
+        }
+        StateChange::RemoveInode { inode } => {
+            payload.push(TAG_REMOVE_INODE);
+            payload.extend_from_slice(&inode.to_be_bytes());
+        }
+        StateChange::UpsertFile {
+            inode,
+            size,
+            checksum,
+            state,
+            replication_factor,
+        } => {
+            payload.push(TAG_UPSERT_FILE);
+            payload.extend_from_slice(&inode.to_be_bytes());
+            payload.extend_from_slice(&size.to_be_bytes());
+            payload.extend_from_slice(&checksum.to_be_bytes());
+            payload.push(u8::from(*state));
+            _put_replication_factor(payload, *replication_factor);
+        }
+        StateChange::UpdateFileState { inode, state } => {
+            payload.push(TAG_UPDATE_FILE_STATE);
+            payload.extend_from_slice(&inode.to_be_bytes());
+            payload.push(u8::from(*state));
+        }
+        StateChange::UpdateFileSize { inode, size, checksum } => {
+            payload.push(TAG_UPDATE_FILE_SIZE);
+            payload.extend_from_slice(&inode.to_be_bytes());
+            payload.extend_from_slice(&size.to_be_bytes());
+            payload.extend_from_slice(&checksum.to_be_bytes());
+        }
+        StateChange::SetReplicationFactor {
+            inode,
+            replication_factor,
+        } => {
+            payload.push(TAG_SET_REPLICATION_FACTOR);
+            payload.extend_from_slice(&inode.to_be_bytes());
+            _put_replication_factor(payload, *replication_factor);
+        }
+        StateChange::SetDirReplicationFactor {
+            inode,
+            replication_factor,
+        } => {
+            payload.push(TAG_SET_DIR_REPLICATION_FACTOR);
+            payload.extend_from_slice(&inode.to_be_bytes());
+            _put_replication_factor(payload, *replication_factor);
+        }
+        StateChange::UpsertBlock {
+            block_id,
+            inode,
+            index,
+            size,
+            checksum,
+            state,
+        } => {
+            payload.push(TAG_UPSERT_BLOCK);
+            _put_str(payload, block_id);
+            payload.extend_from_slice(&inode.to_be_bytes());
+            payload.extend_from_slice(&index.to_be_bytes());
+            payload.extend_from_slice(&size.to_be_bytes());
+            payload.extend_from_slice(&checksum.to_be_bytes());
+            payload.push(u8::from(*state));
+        }
+        StateChange::UpdateBlockState { block_id, state } => {
+            payload.push(TAG_UPDATE_BLOCK_STATE);
+            _put_str(payload, block_id);
+            payload.push(u8::from(*state));
+        }
+        StateChange::UpsertReplica { block_id, node_id } => {
+            payload.push(TAG_UPSERT_REPLICA);
+            _put_str(payload, block_id);
+            _put_str(payload, node_id);
+        }
+        StateChange::RemoveReplica { block_id, node_id } => {
+            payload.push(TAG_REMOVE_REPLICA);
+            _put_str(payload, block_id);
+            _put_str(payload, node_id);
+        }
+    }
+}
+
+impl<'a> PayloadReader<'a> {
+    fn new(packet_id: PacketId, payload: &'a [u8]) -> PayloadReader<'a> {
+        PayloadReader {
+            packet_id,
+            payload,
+            pos: 0,
+        }
+    }
+
+    fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], ParseError> {
+        if self.pos + n > self.payload.len() {
+            return Err(ParseError::incorrect_payload_format(self.packet_id, self.payload.len()));
+        }
+        let bytes = &self.payload[self.pos..self.pos + n];
+        self.pos += n;
+
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, ParseError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, ParseError> {
+        Ok(u16::from_be_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, ParseError> {
+        Ok(u32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, ParseError> {
+        Ok(u64::from_be_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    fn read_str(&mut self) -> Result<String, ParseError> {
+        let len = self.read_u16()? as usize;
+        let bytes = self.read_bytes(len)?;
+
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| ParseError::incorrect_payload_format(self.packet_id, self.payload.len()))
+    }
+
+    fn read_addr(&mut self) -> Result<SocketAddr, ParseError> {
+        let ip = self.read_bytes(4)?;
+        let ip = Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]);
+        let port = self.read_u16()?;
+
+        Ok(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+    }
+
+    fn read_locations(&mut self) -> Result<Vec<BlockLocation>, ParseError> {
+        let n_blocks = self.read_u32()?;
+        let mut locations = Vec::<BlockLocation>::new();
+        for _ in 0..n_blocks {
+            let block_id = self.read_str()?;
+            let offset = self.read_u64()?;
+            let size = self.read_u64()?;
+            let checksum = self.read_u32()?;
+            let n_nodes = self.read_u8()?;
+            let mut addr_nodes = Vec::<SocketAddr>::with_capacity(n_nodes as usize);
+            for _ in 0..n_nodes {
+                addr_nodes.push(self.read_addr()?);
+            }
+            locations.push(BlockLocation {
+                block_id,
+                offset,
+                size,
+                checksum,
+                addr_nodes,
+            });
+        }
+
+        Ok(locations)
+    }
+
+    fn read_changes(&mut self) -> Result<Vec<StateChange>, ParseError> {
+        let n_changes = self.read_u32()?;
+        let mut changes = Vec::<StateChange>::new();
+        for _ in 0..n_changes {
+            changes.push(self.read_change()?);
+        }
+
+        Ok(changes)
+    }
+
+    fn read_change(&mut self) -> Result<StateChange, ParseError> {
+        let change = match self.read_u8()? {
+            TAG_UPSERT_NODE => {
+                let addr = self.read_addr()?;
+                let ip = match addr.ip() {
+                    IpAddr::V4(ip) => ip,
+                    IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+                };
+                StateChange::UpsertNode {
+                    ip,
+                    port: addr.port(),
+                    role: Role::try_from(self.read_u8()?)?,
+                }
+            }
+            TAG_UPDATE_NODE_STATUS => StateChange::UpdateNodeStatus {
+                node_id: self.read_str()?,
+                status: NodeStatus::try_from(self.read_u8()?)?,
+            },
+            TAG_UPDATE_NODE_RACK => StateChange::UpdateNodeRack {
+                node_id: self.read_str()?,
+                rack: self.read_str()?,
+            },
+            TAG_CREATE_INODE => StateChange::CreateInode {
+                inode: self.read_u64()?,
+                parent: self.read_u64()?,
+                name: self.read_str()?,
+                is_dir: self.read_u8()? == 1,
+            },
+            TAG_MOVE_INODE => StateChange::MoveInode {
+                inode: self.read_u64()?,
+                parent: self.read_u64()?,
+                name: self.read_str()?,
+            },
+            TAG_REMOVE_INODE => StateChange::RemoveInode {
+                inode: self.read_u64()?,
+            },
+            TAG_UPSERT_FILE => StateChange::UpsertFile {
+                inode: self.read_u64()?,
+                size: self.read_u64()?,
+                checksum: self.read_u32()?,
+                state: FileState::try_from(self.read_u8()?)?,
+                replication_factor: self.read_u8()? as usize,
+            },
+            TAG_UPDATE_FILE_STATE => StateChange::UpdateFileState {
+                inode: self.read_u64()?,
+                state: FileState::try_from(self.read_u8()?)?,
+            },
+            TAG_UPDATE_FILE_SIZE => StateChange::UpdateFileSize {
+                inode: self.read_u64()?,
+                size: self.read_u64()?,
+                checksum: self.read_u32()?,
+            },
+            TAG_SET_REPLICATION_FACTOR => StateChange::SetReplicationFactor {
+                inode: self.read_u64()?,
+                replication_factor: self.read_u8()? as usize,
+            },
+            TAG_SET_DIR_REPLICATION_FACTOR => StateChange::SetDirReplicationFactor {
+                inode: self.read_u64()?,
+                replication_factor: self.read_u8()? as usize,
+            },
+            TAG_UPSERT_REPLICA => StateChange::UpsertReplica {
+                block_id: self.read_str()?,
+                node_id: self.read_str()?,
+            },
+            TAG_REMOVE_REPLICA => StateChange::RemoveReplica {
+                block_id: self.read_str()?,
+                node_id: self.read_str()?,
+            },
+            TAG_UPSERT_BLOCK => StateChange::UpsertBlock {
+                block_id: self.read_str()?,
+                inode: self.read_u64()?,
+                index: self.read_u64()?,
+                size: self.read_u64()?,
+                checksum: self.read_u32()?,
+                state: FileState::try_from(self.read_u8()?)?,
+            },
+            TAG_UPDATE_BLOCK_STATE => StateChange::UpdateBlockState {
+                block_id: self.read_str()?,
+                state: FileState::try_from(self.read_u8()?)?,
+            },
+            _ => return Err(ParseError::incorrect_payload_format(self.packet_id, self.payload.len())),
+        };
+
+        Ok(change)
+    }
+
+    /// Read the rest of the payload as a chunk of data starting at `offset` of a block of `length` bytes. The chunk
+    /// must lie within the block.
+    fn read_chunk(&mut self, offset: u64, length: u64) -> Result<Vec<u8>, ParseError> {
+        let data = self.read_remaining();
+        if offset.checked_add(data.len() as u64).is_none_or(|end| end > length) {
+            return Err(ParseError::incorrect_payload_format(self.packet_id, self.payload.len()));
+        }
+
+        Ok(data)
+    }
+
+    fn read_remaining(&mut self) -> Vec<u8> {
+        let bytes = self.payload[self.pos..].to_vec();
+        self.pos = self.payload.len();
+
+        bytes
+    }
+
+    fn has_remaining(&self) -> bool {
+        self.pos < self.payload.len()
+    }
+
+    /// Ensure the whole payload has been consumed
+    fn finish(&self) -> Result<(), ParseError> {
+        if self.pos != self.payload.len() {
+            return Err(ParseError::incorrect_payload_format(self.packet_id, self.payload.len()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, net::TcpListener, thread};
+
+    use super::*;
+    use crate::components::errors::ParseErrorCode;
+
+    /// Reader handing out at most `step` bytes per read, like a connection receiving small TCP segments
+    struct SplitReader {
+        data: Vec<u8>,
+        position: usize,
+        step: usize,
+    }
+
+    impl Read for SplitReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.step).min(self.data.len() - self.position);
+            buf[..n].copy_from_slice(&self.data[self.position..self.position + n]);
+            self.position += n;
+            Ok(n)
+        }
+    }
+
+    fn split_reader(data: Vec<u8>, step: usize) -> SplitReader {
+        SplitReader {
+            data,
+            position: 0,
+            step,
+        }
+    }
+
+    fn addr() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 7000))
+    }
+
+    /// Send `bytes` over a loopback connection, in two writes split at `at`, and parse what arrives
+    fn parse(bytes: Vec<u8>, at: usize) -> Result<Packet, ParseError> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr_listener = listener.local_addr().unwrap();
+        let writer = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr_listener).unwrap();
+            let (head, tail) = bytes.split_at(at.min(bytes.len()));
+            stream.write_all(head).unwrap();
+            stream.flush().unwrap();
+            thread::sleep(std::time::Duration::from_millis(20));
+            stream.write_all(tail).unwrap();
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        let packet = Packet::from_stream(&mut stream, DEFAULT_MAX_FRAME_SIZE);
+        writer.join().unwrap();
+
+        packet
+    }
+
+    #[test]
+    fn read_frame_reassembles_split_reads() {
+        let packet = Packet::create_delete_replica(addr(), "12#3");
+        let mut reader = split_reader(packet.to_bytes(), 1);
+
+        let (packet_id, payload) = _read_frame(&mut reader, DEFAULT_MAX_FRAME_SIZE).unwrap();
+        assert!(packet_id == PacketId::DeleteReplica);
+        assert_eq!(payload, packet.payload.unwrap());
+    }
+
+    #[test]
+    fn read_frame_reads_consecutive_frames() {
+        let first = Packet::create_delete_replica(addr(), "1#0");
+        let second = Packet::create_heartbeat(addr(), addr());
+        let mut bytes = first.to_bytes();
+        bytes.extend(second.to_bytes());
+        let mut reader = split_reader(bytes, 3);
+
+        let (packet_id, _) = _read_frame(&mut reader, DEFAULT_MAX_FRAME_SIZE).unwrap();
+        assert!(packet_id == PacketId::DeleteReplica);
+        let (packet_id, payload) = _read_frame(&mut reader, DEFAULT_MAX_FRAME_SIZE).unwrap();
+        assert!(packet_id == PacketId::Heartbeat);
+        assert_eq!(payload, 7000u16.to_be_bytes());
+        let err = _read_frame(&mut reader, DEFAULT_MAX_FRAME_SIZE).unwrap_err();
+        assert!(err.is_connection_closed());
+    }
+
+    #[test]
+    fn read_frame_rejects_frame_above_max_size() {
+        let packet = Packet::create_client_upload(addr(), "1#0", 0, 100, &[7u8; 100], 0);
+        let mut reader = split_reader(packet.to_bytes(), 64);
+
+        let err = _read_frame(&mut reader, 50).unwrap_err();
+        assert!(matches!(err.error_code, ParseErrorCode::ExceededMaxFrameSize));
+        // Only the header was consumed
+        assert_eq!(reader.position, SIZE_HEADER);
+    }
+
+    #[test]
+    fn read_frame_accepts_frame_of_max_size() {
+        let mut bytes = vec![u8::from(PacketId::Heartbeat)];
+        bytes.extend_from_slice(&2u32.to_be_bytes());
+        bytes.extend_from_slice(&[0, 1]);
+
+        assert!(_read_frame(&mut split_reader(bytes, 2), 2).is_ok());
+    }
+
+    #[test]
+    fn read_frame_rejects_truncated_frames() {
+        let bytes = Packet::create_delete_replica(addr(), "1#0").to_bytes();
+
+        let err = _read_frame(&mut split_reader(bytes[..3].to_vec(), 1), DEFAULT_MAX_FRAME_SIZE).unwrap_err();
+        assert!(matches!(err.error_code, ParseErrorCode::IncorrectMinimumHeaderSize));
+        let err = _read_frame(
+            &mut split_reader(bytes[..bytes.len() - 1].to_vec(), 1),
+            DEFAULT_MAX_FRAME_SIZE,
+        )
+        .unwrap_err();
+        assert!(matches!(err.error_code, ParseErrorCode::MismatchedPacketSize));
+    }
+
+    #[test]
+    fn read_frame_rejects_unknown_packet_id() {
+        let bytes = vec![200, 0, 0, 0, 0];
+
+        let err = _read_frame(&mut split_reader(bytes, 5), DEFAULT_MAX_FRAME_SIZE).unwrap_err();
+        assert!(matches!(err.error_code, ParseErrorCode::IncorrectPacketId));
+    }
+
+    #[test]
+    fn from_stream_parses_packet_sent_in_pieces() {
+        let location = BlockLocation {
+            block_id: "5#1".to_string(),
+            offset: 10,
+            size: 20,
+            checksum: 30,
+            addr_nodes: vec![addr(), SocketAddr::from(([10, 0, 0, 2], 7004))],
+        };
+        let bytes =
+            Packet::create_resume_ack(addr(), "a/b", 30, 40, ResumeStatus::Resumed, (1, &[location])).to_bytes();
+
+        let packet = parse(bytes, 7).unwrap();
+        assert!(packet.packet_id == PacketId::ResumeAck);
+        assert_eq!(packet.filename.as_deref(), Some("a/b"));
+        assert_eq!(packet.resume_status, Some(ResumeStatus::Resumed));
+        assert_eq!(packet.n_blocks_persisted, Some(1));
+        let locations = packet.locations.unwrap();
+        assert_eq!(locations.len(), 1);
+        assert_eq!(locations[0].block_id, "5#1");
+        assert_eq!(locations[0].addr_nodes[1], SocketAddr::from(([10, 0, 0, 2], 7004)));
+    }
+
+    #[test]
+    fn download_request_carries_its_range() {
+        let packet = parse(Packet::create_request_download(addr(), "3#2", 100, 50).to_bytes(), 64).unwrap();
+        assert_eq!(packet.request_kind, Some(RequestKind::Download));
+        assert_eq!(packet.filename.as_deref(), Some("3#2"));
+        assert_eq!(packet.offset, Some(100));
+        assert_eq!(packet.length, Some(50));
+    }
+
+    #[test]
+    fn chunk_must_lie_within_declared_length() {
+        let packet = parse(
+            Packet::create_send_replica(addr(), "1#0", 4, 10, &[1; 6], 0).to_bytes(),
+            3,
+        )
+        .unwrap();
+        assert_eq!(packet.data.map(|data| data.len()), Some(6));
+
+        for (offset, length) in [(5, 10), (0, 5), (u64::MAX, 10)] {
+            let bytes = Packet::create_send_replica(addr(), "1#0", offset, length, &[1; 6], 0).to_bytes();
+            let err = parse(bytes, 3).unwrap_err();
+            assert!(matches!(err.error_code, ParseErrorCode::IncorrectPayloadFormat));
+        }
+        let bytes = Packet::create_client_upload(addr(), "1#0", 0, 2, &[1; 3], 0).to_bytes();
+        assert!(parse(bytes, 3).is_err());
+    }
+
+    #[test]
+    fn locations_carry_at_most_255_nodes() {
+        let location = BlockLocation {
+            block_id: "1#0".to_string(),
+            offset: 0,
+            size: 0,
+            checksum: 0,
+            addr_nodes: (0..300).map(|port| SocketAddr::from(([127, 0, 0, 1], port))).collect(),
+        };
+        let bytes = Packet::create_response_node_ip(addr(), "f", 0, 0, &[location]).to_bytes();
+
+        let packet = parse(bytes, 100).unwrap();
+        assert_eq!(packet.locations.unwrap()[0].addr_nodes.len(), 255);
+    }
+
+    #[test]
+    fn unknown_enum_values_are_parse_errors() {
+        let mut bytes = vec![u8::from(PacketId::Notify)];
+        bytes.extend_from_slice(&3u32.to_be_bytes());
+        bytes.extend_from_slice(&[9, 0x1b, 0x58]);
+        let err = parse(bytes, 2).unwrap_err();
+        assert!(matches!(err.error_code, ParseErrorCode::IncorrectEnumValue));
+
+        let changes = encode_changes(&[StateChange::UpdateNodeStatus {
+            node_id: "127.0.0.1:7003".to_string(),
+            status: NodeStatus::Dead,
+        }]);
+        assert!(decode_changes(&changes).is_ok());
+        let mut corrupted = changes.clone();
+        *corrupted.last_mut().unwrap() = 7;
+        match decode_changes(&corrupted) {
+            Err(err) => assert!(matches!(err.error_code, ParseErrorCode::IncorrectEnumValue)),
+            Ok(_) => panic!("Unknown status decoded"),
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::RandomState, HashSet},
+    hash::BuildHasher,
+    net::{SocketAddr, SocketAddrV4},
+    str::FromStr,
+};
+
+use crate::components::{
+    configs::Configs,
+    db::{NodeInfoEntry, NodeStatus},
+};
+
+// ================================================
+// Definition
+// ================================================
+
+/// Name of a placement policy as given in env 'PLACEMENT_POLICY'
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PlacementKind {
+    Random,
+    RoundRobin,
+    LeastUsed,
+    RackAware,
+}
+
+/// Decide which Data nodes receive new replicas of a block
+///
+/// A policy is given every Data node known by Master and the nodes already holding the block, or about to. Only
+/// Alive nodes which don't hold the block and have room for its `size` bytes are chosen. Fewer nodes than asked are
+/// returned if not enough of them fit.
+pub trait PlacementPolicy {
+    fn choose(
+        &mut self,
+        data_nodes: &[NodeInfoEntry],
+        holders: &[String],
+        n_replicas: usize,
+        size: u64,
+    ) -> Vec<SocketAddr>;
+}
+
+/// Pick nodes at random
+pub struct RandomPlacement;
+
+/// Take nodes in turn, starting one node further at each call, so consecutive blocks spread over the cluster
+pub struct RoundRobinPlacement {
+    n_rotation: usize,
+}
+
+/// Prefer nodes with the most free space, then the least loaded ones
+pub struct LeastUsedPlacement;
+
+/// Never put two replicas of a block in the same rack. Nodes without a rack label share one default rack.
+pub struct RackAwarePlacement {
+    round_robin: RoundRobinPlacement,
+}
+
+// ================================================
+// Implementation
+// ================================================
+
+impl FromStr for PlacementKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "random" => Ok(PlacementKind::Random),
+            "round-robin" => Ok(PlacementKind::RoundRobin),
+            "least-used" => Ok(PlacementKind::LeastUsed),
+            "rack-aware" => Ok(PlacementKind::RackAware),
+            _ => Err(format!("Unknown placement policy: {}", value)),
+        }
+    }
+}
+
+impl std::fmt::Display for PlacementKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            PlacementKind::Random => "random",
+            PlacementKind::RoundRobin => "round-robin",
+            PlacementKind::LeastUsed => "least-used",
+            PlacementKind::RackAware => "rack-aware",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Create the policy selected in configs
+pub fn create_policy(configs: &Configs) -> Box<dyn PlacementPolicy> {
+    log::info!("Place replicas with policy '{}'", configs.placement_policy);
+
+    match configs.placement_policy {
+        PlacementKind::Random => Box::new(RandomPlacement),
+        PlacementKind::RoundRobin => Box::new(RoundRobinPlacement::new()),
+        PlacementKind::LeastUsed => Box::new(LeastUsedPlacement),
+        PlacementKind::RackAware => Box::new(RackAwarePlacement {
+            round_robin: RoundRobinPlacement::new(),
+        }),
+    }
+}
+
+impl PlacementPolicy for RandomPlacement {
+    fn choose(
+        &mut self,
+        data_nodes: &[NodeInfoEntry],
+        holders: &[String],
+        n_replicas: usize,
+        size: u64,
+    ) -> Vec<SocketAddr> {
+        let mut candidates = _get_candidates(data_nodes, holders, size);
+        let state = RandomState::new();
+        candidates.sort_by_cached_key(|node| state.hash_one(&node.node_id));
+
+        candidates.iter().take(n_replicas).map(|node| _get_addr(node)).collect()
+    }
+}
+
+impl RoundRobinPlacement {
+    pub fn new() -> RoundRobinPlacement {
+        RoundRobinPlacement { n_rotation: 0 }
+    }
+
+    /// Order candidates starting from the next node in turn
+    fn rotate<'a>(&mut self, data_nodes: &'a [NodeInfoEntry], holders: &[String], size: u64) -> Vec<&'a NodeInfoEntry> {
+        let mut candidates = _get_candidates(data_nodes, holders, size);
+        let n_candidates = candidates.len();
+        if n_candidates > 0 {
+            candidates.rotate_left(self.n_rotation % n_candidates);
+        }
+        self.n_rotation = self.n_rotation.wrapping_add(1);
+
+        candidates
+    }
+}
+
+impl Default for RoundRobinPlacement {
+    fn default() -> Self {
+        RoundRobinPlacement::new()
+    }
+}
+
+impl PlacementPolicy for RoundRobinPlacement {
+    fn choose(
+        &mut self,
+        data_nodes: &[NodeInfoEntry],
+        holders: &[String],
+        n_replicas: usize,
+        size: u64,
+    ) -> Vec<SocketAddr> {
+        self.rotate(data_nodes, holders, size)
+            .iter()
+            .take(n_replicas)
+            .map(|node| _get_addr(node))
+            .collect()
+    }
+}
+
+impl PlacementPolicy for LeastUsedPlacement {
+    fn choose(
+        &mut self,
+        data_nodes: &[NodeInfoEntry],
+        holders: &[String],
+        n_replicas: usize,
+        size: u64,
+    ) -> Vec<SocketAddr> {
+        let mut candidates = _get_candidates(data_nodes, holders, size);
+        candidates.sort_by_key(|node| (Reverse(node.usage.disk_free), node.usage.load));
+
+        candidates.iter().take(n_replicas).map(|node| _get_addr(node)).collect()
+    }
+}
+
+impl PlacementPolicy for RackAwarePlacement {
+    fn choose(
+        &mut self,
+        data_nodes: &[NodeInfoEntry],
+        holders: &[String],
+        n_replicas: usize,
+        size: u64,
+    ) -> Vec<SocketAddr> {
+        let mut racks_used: HashSet<&str> = data_nodes
+            .iter()
+            .filter(|node| holders.contains(&node.node_id))
+            .map(|node| node.rack.as_str())
+            .collect();
+
+        let mut addrs = Vec::<SocketAddr>::new();
+        for node in self.round_robin.rotate(data_nodes, holders, size) {
+            if addrs.len() >= n_replicas {
+                break;
+            }
+            if racks_used.insert(node.rack.as_str()) {
+                addrs.push(_get_addr(node));
+            }
+        }
+
+        addrs
+    }
+}
+
+/// Count `size` bytes as stored on nodes `addrs`, so that later choices made from the same snapshot of `data_nodes`
+/// see them
+pub fn reserve(data_nodes: &mut [NodeInfoEntry], addrs: &[SocketAddr], size: u64) {
+    for node in data_nodes.iter_mut().filter(|node| node.ip.is_some()) {
+        if addrs.contains(&_get_addr(node)) {
+            node.usage.disk_used += size;
+            node.usage.disk_free = node.usage.disk_free.saturating_sub(size);
+        }
+    }
+}
+
+/// Alive nodes which can receive a replica of `size` bytes, in the order Master knows them. Nodes which haven't
+/// reported their disk yet are assumed to have room.
+fn _get_candidates<'a>(data_nodes: &'a [NodeInfoEntry], holders: &[String], size: u64) -> Vec<&'a NodeInfoEntry> {
+    data_nodes
+        .iter()
+        .filter(|node| node.status == NodeStatus::Alive && node.ip.is_some())
+        .filter(|node| !holders.contains(&node.node_id))
+        .filter(|node| node.usage.disk_total == 0 || node.usage.disk_free >= size)
+        .collect()
+}
+
+fn _get_addr(node: &NodeInfoEntry) -> SocketAddr {
+    SocketAddr::V4(SocketAddrV4::new(node.ip.unwrap(), node.port))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::components::{db::NodeUsage, entity::node_roles::Role};
+
+    /// Data nodes on ports 7003.. with `disk_free` bytes left out of 1000
+    fn data_nodes(disk_free: &[u64]) -> Vec<NodeInfoEntry> {
+        disk_free
+            .iter()
+            .enumerate()
+            .map(|(i, disk_free)| {
+                let mut node = NodeInfoEntry::initialize(Ipv4Addr::LOCALHOST, 7003 + i as u16, Role::Data);
+                node.usage.disk_total = 1000;
+                node.usage.disk_free = *disk_free;
+                node.usage.disk_used = 1000 - disk_free;
+                node
+            })
+            .collect()
+    }
+
+    fn ports(addrs: &[SocketAddr]) -> Vec<u16> {
+        addrs.iter().map(|addr| addr.port()).collect()
+    }
+
+    #[test]
+    fn least_used_spreads_blocks_placed_from_one_snapshot() {
+        let mut nodes = data_nodes(&[500, 500, 500, 500]);
+        let mut placement = LeastUsedPlacement;
+
+        let mut ports_chosen = Vec::<u16>::new();
+        for _ in 0..2 {
+            let addrs = placement.choose(&nodes, &[], 2, 100);
+            reserve(&mut nodes, &addrs, 100);
+            ports_chosen.extend(ports(&addrs));
+        }
+        ports_chosen.sort();
+
+        assert_eq!(ports_chosen, vec![7003, 7004, 7005, 7006]);
+        assert!(nodes.iter().all(|node| node.usage.disk_free == 400));
+    }
+
+    #[test]
+    fn nodes_without_room_are_skipped() {
+        let nodes = data_nodes(&[50, 200, 99]);
+
+        assert_eq!(ports(&LeastUsedPlacement.choose(&nodes, &[], 3, 100)), vec![7004]);
+        assert_eq!(
+            ports(&RoundRobinPlacement::new().choose(&nodes, &[], 3, 100)),
+            vec![7004]
+        );
+        assert!(RandomPlacement.choose(&nodes, &[], 1, 300).is_empty());
+    }
+
+    #[test]
+    fn nodes_not_reporting_disk_yet_have_room() {
+        let mut nodes = data_nodes(&[0]);
+        nodes[0].usage = NodeUsage::default();
+
+        assert_eq!(ports(&LeastUsedPlacement.choose(&nodes, &[], 1, 100)), vec![7003]);
+    }
+
+    #[test]
+    fn holders_and_dead_nodes_are_skipped() {
+        let mut nodes = data_nodes(&[500, 500, 500]);
+        nodes[1].status = NodeStatus::Dead;
+
+        let addrs = LeastUsedPlacement.choose(&nodes, &[nodes[0].node_id.clone()], 3, 100);
+
+        assert_eq!(ports(&addrs), vec![7005]);
+    }
+}
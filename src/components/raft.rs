@@ -0,0 +1,950 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    hash::BuildHasher,
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use rusqlite::Result;
+
+use crate::components::{
+    configs::Configs,
+    db::{DBBackend, FileInfoDB, NodeInfoDB, RaftLogDB, RaftLogEntry, RaftStateEntry},
+    packets::{decode_changes, encode_changes, Packet},
+    state_sync::{apply_change, take_snapshot, StateChange},
+};
+
+// ================================================
+// Definition
+// ================================================
+
+/// Max number of log entries carried by one AppendEntries packet
+const MAX_ENTRIES_PER_APPEND: usize = 64;
+
+/// Number of log entries kept before the applied ones are compacted
+const MAX_LOG_ENTRIES: usize = 4096;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RaftRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Metadata changes made by the leader while processing one batch of packets
+#[derive(Clone)]
+pub struct LogEntry {
+    pub term: u64,
+    pub changes: Vec<StateChange>,
+}
+
+/// What the leader knows about the log of a follower
+struct PeerProgress {
+    next_index: u64,
+    match_index: u64,
+}
+
+/// Replicate Master's metadata over a group of Masters with Raft
+///
+/// Only the leader handles Data nodes and clients. Changes it makes while doing so are staged, appended to the log and
+/// applied to the DBs of every Master only once a majority stored them, so the leader holds its replies until then. A
+/// follower whose DBs turn out to contain entries the group dropped rebuilds them, from its own log or from a snapshot
+/// sent by the leader.
+///
+/// Entries older than the last applied one are compacted once the log grows past `MAX_LOG_ENTRIES`. Followers which
+/// lag behind the compacted part receive a snapshot instead.
+pub struct RaftNode {
+    addr_current: SocketAddr,
+    peers: Vec<SocketAddr>,
+    interval_heartbeat: Duration,
+    timeout_election: Duration,
+    storage: RaftLogDB,
+
+    role: RaftRole,
+    term: u64,
+    voted_for: Option<SocketAddr>,
+    leader: Option<SocketAddr>,
+
+    // Entries following (base_index, base_term), which are compacted
+    log: Vec<LogEntry>,
+    base_index: u64,
+    base_term: u64,
+    commit_index: u64,
+    last_applied: u64,
+
+    votes: HashSet<SocketAddr>,
+    progress: HashMap<SocketAddr, PeerProgress>,
+    deadline_election: Instant,
+    last_broadcast: Instant,
+}
+
+// ================================================
+// Implementation
+// ================================================
+
+impl std::fmt::Display for RaftRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            RaftRole::Follower => "Follower",
+            RaftRole::Candidate => "Candidate",
+            RaftRole::Leader => "Leader",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl RaftNode {
+    /// Join the group listed in `configs.raft_peers`, resuming from the term and log persisted in `backend`
+    pub fn new(configs: &Configs, addr_current: SocketAddr, backend: &DBBackend) -> RaftNode {
+        RaftNode::_join(
+            addr_current,
+            &configs.raft_peers,
+            Duration::from_millis(configs.raft_interval_heartbeat),
+            Duration::from_millis(configs.raft_timeout_election),
+            backend,
+        )
+    }
+
+    fn _join(
+        addr_current: SocketAddr,
+        peers: &[SocketAddr],
+        interval_heartbeat: Duration,
+        timeout_election: Duration,
+        backend: &DBBackend,
+    ) -> RaftNode {
+        let storage = RaftLogDB::initialize("raft_log", backend);
+        let state = storage.get_state().expect("Cannot read Raft state");
+
+        let mut log = Vec::<LogEntry>::new();
+        for entry in storage.get_entries().expect("Cannot read Raft log") {
+            match decode_changes(&entry.payload) {
+                Ok(changes) => log.push(LogEntry {
+                    term: entry.term,
+                    changes,
+                }),
+                Err(err) => panic!("Cannot decode Raft log entry {}: {}", entry.index, err),
+            }
+        }
+
+        let raft = RaftNode {
+            addr_current,
+            peers: peers.iter().filter(|addr| **addr != addr_current).cloned().collect(),
+            interval_heartbeat,
+            timeout_election,
+            storage,
+            role: RaftRole::Follower,
+            term: state.term,
+            voted_for: state.voted_for.and_then(|addr| SocketAddr::from_str(&addr).ok()),
+            leader: None,
+            log,
+            base_index: state.base_index,
+            base_term: state.base_term,
+            // Compacted entries are always committed ones
+            commit_index: state.base_index,
+            last_applied: state.last_applied,
+            votes: HashSet::new(),
+            progress: HashMap::new(),
+            deadline_election: Instant::now() + _random_timeout(timeout_election),
+            last_broadcast: Instant::now(),
+        };
+
+        log::info!(
+            "Join Raft group of {} peers at term {} with {} entries after index {}",
+            raft.peers.len(),
+            raft.term,
+            raft.log.len(),
+            raft.base_index
+        );
+
+        raft
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == RaftRole::Leader
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// Whether every entry of the log is committed and applied, so the leader's DBs are up to date
+    pub fn is_settled(&self) -> bool {
+        self.last_applied == self._last_index()
+    }
+
+    /// Wait until something needs to be done, so thread:Processor can wake up in time
+    pub fn interval_tick(&self) -> Duration {
+        self.interval_heartbeat
+    }
+
+    /// Send heartbeats as leader, or start an election if the leader has been silent for too long
+    pub fn tick(&mut self, node_info: &NodeInfoDB, file_info: &FileInfoDB) -> Result<Vec<Packet>> {
+        match self.role {
+            RaftRole::Leader => {
+                if self.last_broadcast.elapsed() >= self.interval_heartbeat {
+                    return self._broadcast(node_info, file_info);
+                }
+
+                Ok(vec![])
+            }
+            RaftRole::Follower | RaftRole::Candidate => {
+                if Instant::now() < self.deadline_election {
+                    return Ok(vec![]);
+                }
+
+                self.term += 1;
+                self.role = RaftRole::Candidate;
+                self.voted_for = Some(self.addr_current);
+                self.leader = None;
+                self.votes = HashSet::from([self.addr_current]);
+                self.deadline_election = Instant::now() + _random_timeout(self.timeout_election);
+                self._persist_state()?;
+
+                log::info!("Start election for term {}", self.term);
+
+                if self._is_majority(self.votes.len()) {
+                    return self._become_leader(node_info, file_info);
+                }
+
+                let (last_index, last_term) = (self._last_index(), self._last_term());
+                Ok(self
+                    .peers
+                    .iter()
+                    .map(|peer| Packet::create_request_vote(*peer, self.term, self.addr_current, last_index, last_term))
+                    .collect())
+            }
+        }
+    }
+
+    /// Append changes the leader staged and replicate them. They're applied once committed.
+    pub fn propose(
+        &mut self,
+        changes: Vec<StateChange>,
+        node_info: &NodeInfoDB,
+        file_info: &FileInfoDB,
+    ) -> Result<Vec<Packet>> {
+        if !self.is_leader() {
+            log::error!("Only leader can propose changes. Drop {} changes.", changes.len());
+            return Ok(vec![]);
+        }
+
+        self._append(LogEntry {
+            term: self.term,
+            changes,
+        })?;
+        self._advance_commit();
+        self._apply_committed(node_info, file_info)?;
+
+        self._broadcast(node_info, file_info)
+    }
+
+    /// Candidate --RequestVote-> Master
+    pub fn on_request_vote(
+        &mut self,
+        term: u64,
+        addr_candidate: SocketAddr,
+        last_index: u64,
+        last_term: u64,
+    ) -> Result<Packet> {
+        if term > self.term {
+            self._step_down(term, None)?;
+        }
+
+        // Only vote for candidates whose log holds everything this node's log holds
+        let is_up_to_date =
+            last_term > self._last_term() || (last_term == self._last_term() && last_index >= self._last_index());
+        let is_granted = term == self.term && self.voted_for.is_none_or(|addr| addr == addr_candidate) && is_up_to_date;
+
+        if is_granted {
+            self.voted_for = Some(addr_candidate);
+            self.deadline_election = Instant::now() + _random_timeout(self.timeout_election);
+            self._persist_state()?;
+        }
+        log::info!(
+            "Vote for {} at term {}: {}",
+            addr_candidate,
+            term,
+            if is_granted { "granted" } else { "rejected" }
+        );
+
+        Ok(Packet::create_request_vote_ack(
+            addr_candidate,
+            self.term,
+            is_granted,
+            self.addr_current,
+        ))
+    }
+
+    /// Master --RequestVoteAck-> Candidate
+    pub fn on_request_vote_ack(
+        &mut self,
+        term: u64,
+        is_granted: bool,
+        addr_voter: SocketAddr,
+        node_info: &NodeInfoDB,
+        file_info: &FileInfoDB,
+    ) -> Result<Vec<Packet>> {
+        if term > self.term {
+            self._step_down(term, None)?;
+            return Ok(vec![]);
+        }
+        if self.role != RaftRole::Candidate || term != self.term || !is_granted {
+            return Ok(vec![]);
+        }
+
+        self.votes.insert(addr_voter);
+        if self._is_majority(self.votes.len()) {
+            return self._become_leader(node_info, file_info);
+        }
+
+        Ok(vec![])
+    }
+
+    /// Leader --AppendEntries-> Follower
+    #[allow(clippy::too_many_arguments)]
+    pub fn on_append_entries(
+        &mut self,
+        term: u64,
+        addr_leader: SocketAddr,
+        prev_index: u64,
+        prev_term: u64,
+        commit_index: u64,
+        is_snapshot: bool,
+        entries: Vec<LogEntry>,
+        node_info: &NodeInfoDB,
+        file_info: &FileInfoDB,
+    ) -> Result<Packet> {
+        if term < self.term {
+            return Ok(Packet::create_append_entries_ack(
+                addr_leader,
+                self.term,
+                false,
+                0,
+                self.addr_current,
+            ));
+        }
+        if term > self.term || self.role != RaftRole::Follower || self.leader != Some(addr_leader) {
+            self._step_down(term, Some(addr_leader))?;
+        }
+        self.deadline_election = Instant::now() + _random_timeout(self.timeout_election);
+
+        if is_snapshot {
+            self._install_snapshot(prev_index, prev_term, commit_index, entries, node_info, file_info)?;
+            return Ok(Packet::create_append_entries_ack(
+                addr_leader,
+                self.term,
+                true,
+                prev_index,
+                self.addr_current,
+            ));
+        }
+
+        // Log must contain the entry preceding the new ones, otherwise the leader retries from the hinted index
+        let hint = match self._term_at(prev_index) {
+            _ if prev_index > self._last_index() => Some(self._last_index()),
+            None => Some(self.base_index),
+            Some(term_prev) if term_prev != prev_term => Some(self.commit_index.max(self.base_index)),
+            Some(_) => None,
+        };
+        if let Some(hint) = hint {
+            return Ok(Packet::create_append_entries_ack(
+                addr_leader,
+                self.term,
+                false,
+                hint,
+                self.addr_current,
+            ));
+        }
+
+        let index_last_new = prev_index + entries.len() as u64;
+        for (i, entry) in entries.into_iter().enumerate() {
+            let index = prev_index + 1 + i as u64;
+            if index <= self._last_index() {
+                if self._term_at(index) == Some(entry.term) {
+                    continue;
+                }
+
+                // Conflicting entries were never committed, drop them and whatever follows
+                self._truncate_from(index)?;
+                if index <= self.last_applied && !self._rebuild(node_info, file_info)? {
+                    return Ok(Packet::create_append_entries_ack(
+                        addr_leader,
+                        self.term,
+                        false,
+                        0,
+                        self.addr_current,
+                    ));
+                }
+            }
+            self._append(entry)?;
+        }
+
+        if commit_index > self.commit_index {
+            self.commit_index = commit_index.min(index_last_new);
+        }
+        self._apply_committed(node_info, file_info)?;
+        self._compact()?;
+
+        Ok(Packet::create_append_entries_ack(
+            addr_leader,
+            self.term,
+            true,
+            index_last_new,
+            self.addr_current,
+        ))
+    }
+
+    /// Follower --AppendEntriesAck-> Leader
+    pub fn on_append_entries_ack(
+        &mut self,
+        term: u64,
+        is_success: bool,
+        index: u64,
+        addr_follower: SocketAddr,
+        node_info: &NodeInfoDB,
+        file_info: &FileInfoDB,
+    ) -> Result<Vec<Packet>> {
+        if term > self.term {
+            self._step_down(term, None)?;
+            return Ok(vec![]);
+        }
+        if !self.is_leader() || term != self.term {
+            return Ok(vec![]);
+        }
+
+        let progress = match self.progress.get_mut(&addr_follower) {
+            Some(progress) => progress,
+            None => {
+                log::warn!("AppendEntriesAck from unknown peer {}", addr_follower);
+                return Ok(vec![]);
+            }
+        };
+
+        if is_success {
+            progress.match_index = progress.match_index.max(index);
+            progress.next_index = progress.next_index.max(progress.match_index + 1);
+            self._advance_commit();
+            self._apply_committed(node_info, file_info)?;
+            self._compact()?;
+
+            Ok(vec![])
+        } else {
+            // Retry right away from where the follower hinted
+            progress.next_index = (index + 1).min(progress.next_index).max(1);
+
+            Ok(vec![self._create_append(addr_follower, node_info, file_info)?])
+        }
+    }
+
+    // ================================================
+    // Private helpers
+    // ================================================
+
+    fn _become_leader(&mut self, node_info: &NodeInfoDB, file_info: &FileInfoDB) -> Result<Vec<Packet>> {
+        log::info!("Become leader at term {}", self.term);
+
+        self.role = RaftRole::Leader;
+        self.leader = Some(self.addr_current);
+
+        // Entries of previous terms only commit along with one of the current term. Until then, they aren't applied
+        // and the leader isn't settled.
+        self._append(LogEntry {
+            term: self.term,
+            changes: vec![],
+        })?;
+
+        let next_index = self._last_index();
+        self.progress = self
+            .peers
+            .iter()
+            .map(|peer| {
+                (
+                    *peer,
+                    PeerProgress {
+                        next_index,
+                        match_index: 0,
+                    },
+                )
+            })
+            .collect();
+        self._advance_commit();
+        self._apply_committed(node_info, file_info)?;
+
+        self._broadcast(node_info, file_info)
+    }
+
+    fn _step_down(&mut self, term: u64, leader: Option<SocketAddr>) -> Result<()> {
+        if self.role == RaftRole::Leader {
+            log::warn!("Step down as leader at term {}", self.term);
+        }
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self._persist_state()?;
+        }
+        if let Some(addr_leader) = leader.filter(|_| self.leader != leader) {
+            log::info!("Follow leader {} at term {}", addr_leader, term);
+        }
+
+        self.role = RaftRole::Follower;
+        self.leader = leader;
+        self.votes.clear();
+        self.progress.clear();
+
+        Ok(())
+    }
+
+    fn _broadcast(&mut self, node_info: &NodeInfoDB, file_info: &FileInfoDB) -> Result<Vec<Packet>> {
+        self.last_broadcast = Instant::now();
+
+        let mut packets = Vec::<Packet>::new();
+        for peer in self.peers.clone() {
+            packets.push(self._create_append(peer, node_info, file_info)?);
+        }
+
+        Ok(packets)
+    }
+
+    /// Create AppendEntries for a peer from its next index, or a snapshot if the entries were compacted
+    fn _create_append(&mut self, peer: SocketAddr, node_info: &NodeInfoDB, file_info: &FileInfoDB) -> Result<Packet> {
+        let next_index = self
+            .progress
+            .get(&peer)
+            .map_or(self._last_index() + 1, |p| p.next_index);
+
+        if next_index <= self.base_index {
+            // Leader's DBs hold every entry up to the last applied one
+            let snapshot = LogEntry {
+                term: self._last_term(),
+                changes: take_snapshot(node_info, file_info)?,
+            };
+            log::info!(
+                "Send snapshot of {} changes at index {} to {}",
+                snapshot.changes.len(),
+                self.last_applied,
+                peer
+            );
+
+            // Assume it arrives, the follower's answer to the next AppendEntries tells otherwise
+            if let Some(progress) = self.progress.get_mut(&peer) {
+                progress.next_index = self.last_applied + 1;
+            }
+
+            return Ok(Packet::create_append_entries(
+                peer,
+                self.term,
+                self.addr_current,
+                self.last_applied,
+                self._last_term(),
+                self.commit_index,
+                true,
+                &[snapshot],
+            ));
+        }
+
+        let prev_index = next_index - 1;
+        let from = (next_index - self.base_index - 1) as usize;
+        let to = self.log.len().min(from + MAX_ENTRIES_PER_APPEND);
+
+        Ok(Packet::create_append_entries(
+            peer,
+            self.term,
+            self.addr_current,
+            prev_index,
+            self._term_at(prev_index).unwrap_or(0),
+            self.commit_index,
+            false,
+            &self.log[from..to],
+        ))
+    }
+
+    fn _install_snapshot(
+        &mut self,
+        index: u64,
+        term: u64,
+        commit_index: u64,
+        mut entries: Vec<LogEntry>,
+        node_info: &NodeInfoDB,
+        file_info: &FileInfoDB,
+    ) -> Result<()> {
+        let changes = entries.pop().map(|entry| entry.changes).unwrap_or_default();
+        log::info!("Install snapshot of {} changes at index {}", changes.len(), index);
+
+        node_info.clear()?;
+        file_info.clear()?;
+        _apply_changes(&changes, node_info, file_info)?;
+
+        self.log.clear();
+        self.storage.truncate_from(0)?;
+        self.base_index = index;
+        self.base_term = term;
+        self.last_applied = index;
+        self.commit_index = commit_index.min(index);
+        self._persist_state()
+    }
+
+    /// Rebuild DBs after applied entries were dropped from the log. Returns false if a snapshot is needed for that.
+    fn _rebuild(&mut self, node_info: &NodeInfoDB, file_info: &FileInfoDB) -> Result<bool> {
+        log::warn!("Applied entries were dropped from the log. Rebuild metadata.");
+
+        node_info.clear()?;
+        file_info.clear()?;
+        self.last_applied = 0;
+
+        if self.base_index > 0 {
+            // Entries before base are gone, so wait for the leader to send everything again
+            self.log.clear();
+            self.storage.truncate_from(0)?;
+            self.base_index = 0;
+            self.base_term = 0;
+            self.commit_index = 0;
+            self._persist_state()?;
+
+            return Ok(false);
+        }
+
+        self._persist_state()?;
+        Ok(true)
+    }
+
+    fn _apply_committed(&mut self, node_info: &NodeInfoDB, file_info: &FileInfoDB) -> Result<()> {
+        if self.last_applied >= self.commit_index {
+            return Ok(());
+        }
+
+        while self.last_applied < self.commit_index {
+            let changes = &self.log[(self.last_applied - self.base_index) as usize].changes;
+            _apply_changes(changes, node_info, file_info)?;
+            self.last_applied += 1;
+        }
+
+        self._persist_state()
+    }
+
+    /// Commit the latest entry of the current term stored by a majority
+    fn _advance_commit(&mut self) {
+        let mut index = self._last_index();
+        while index > self.commit_index && self._term_at(index) == Some(self.term) {
+            let n_stored = 1 + self.progress.values().filter(|p| p.match_index >= index).count();
+            if self._is_majority(n_stored) {
+                log::debug!("Commit up to index {}", index);
+                self.commit_index = index;
+                break;
+            }
+            index -= 1;
+        }
+    }
+
+    fn _compact(&mut self) -> Result<()> {
+        if self.log.len() <= MAX_LOG_ENTRIES {
+            return Ok(());
+        }
+
+        let index = self.commit_index.min(self.last_applied);
+        if index <= self.base_index {
+            return Ok(());
+        }
+
+        self.base_term = self._term_at(index).unwrap_or(self.base_term);
+        self.log.drain(..(index - self.base_index) as usize);
+        self.base_index = index;
+        self.storage.compact_until(index)?;
+        self._persist_state()?;
+
+        log::info!("Compacted Raft log up to index {}", index);
+
+        Ok(())
+    }
+
+    fn _append(&mut self, entry: LogEntry) -> Result<()> {
+        self.storage.append(&RaftLogEntry {
+            index: self._last_index() + 1,
+            term: entry.term,
+            payload: encode_changes(&entry.changes),
+        })?;
+        self.log.push(entry);
+
+        Ok(())
+    }
+
+    fn _truncate_from(&mut self, index: u64) -> Result<()> {
+        self.log.truncate((index - self.base_index - 1) as usize);
+        self.storage.truncate_from(index)
+    }
+
+    fn _persist_state(&self) -> Result<()> {
+        self.storage.save_state(&RaftStateEntry {
+            term: self.term,
+            voted_for: self.voted_for.map(|addr| addr.to_string()),
+            base_index: self.base_index,
+            base_term: self.base_term,
+            last_applied: self.last_applied,
+        })
+    }
+
+    fn _last_index(&self) -> u64 {
+        self.base_index + self.log.len() as u64
+    }
+
+    fn _last_term(&self) -> u64 {
+        self.log.last().map_or(self.base_term, |entry| entry.term)
+    }
+
+    fn _term_at(&self, index: u64) -> Option<u64> {
+        if index == self.base_index {
+            return Some(self.base_term);
+        }
+        if index < self.base_index {
+            return None;
+        }
+
+        self.log
+            .get((index - self.base_index - 1) as usize)
+            .map(|entry| entry.term)
+    }
+
+    fn _is_majority(&self, n: usize) -> bool {
+        // Group is made of peers and the current node
+        2 * n > self.peers.len() + 1
+    }
+}
+
+/// Apply changes coming from the log. They are already in the log, so they're dropped from the DBs' journal.
+fn _apply_changes(changes: &[StateChange], node_info: &NodeInfoDB, file_info: &FileInfoDB) -> Result<()> {
+    for change in changes {
+        apply_change(change, node_info, file_info)?;
+    }
+    node_info.drain_changes();
+    file_info.drain_changes();
+
+    Ok(())
+}
+
+/// Pick a timeout in [timeout, 2 * timeout) so that Masters rarely start elections at the same time
+fn _random_timeout(timeout: Duration) -> Duration {
+    let millis = timeout.as_millis().max(1) as u64;
+    let jitter = RandomState::new().hash_one(Instant::now()) % millis;
+
+    timeout + Duration::from_millis(jitter)
+}
+
+// ================================================
+// Tests
+// ================================================
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::components::entity::node_roles::Role;
+
+    const PORTS: [u16; 3] = [7002, 7012, 7022];
+
+    struct Master {
+        raft: RaftNode,
+        node_info: NodeInfoDB,
+        file_info: FileInfoDB,
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// Master on `port` in the group of `PORTS`, with empty log and DBs
+    fn master(port: u16) -> Master {
+        let peers: Vec<_> = PORTS.iter().map(|port| addr(*port)).collect();
+        let file_info = FileInfoDB::initialize("file_info", &DBBackend::InMemory);
+
+        Master {
+            raft: RaftNode::_join(
+                addr(port),
+                &peers,
+                Duration::from_millis(100),
+                Duration::from_secs(60),
+                &DBBackend::InMemory,
+            ),
+            node_info: NodeInfoDB::initialize_along("node_info", &file_info),
+            file_info,
+        }
+    }
+
+    /// Term, success and index carried by RequestVoteAck or AppendEntriesAck
+    fn ack(packet: &Packet) -> (u64, bool, u64) {
+        let payload = packet.payload.as_ref().unwrap();
+
+        (
+            u64::from_be_bytes(payload[..8].try_into().unwrap()),
+            payload[8] == 1,
+            u64::from_be_bytes(payload[9..17].try_into().unwrap()),
+        )
+    }
+
+    fn entry(term: u64, port: u16) -> LogEntry {
+        LogEntry {
+            term,
+            changes: vec![StateChange::UpsertNode {
+                ip: Ipv4Addr::LOCALHOST,
+                port,
+                role: Role::Data,
+            }],
+        }
+    }
+
+    /// Let `master` start an election and win it with the vote of the 2nd Master
+    fn elect(master: &mut Master) {
+        master.raft.deadline_election = Instant::now();
+        master.raft.tick(&master.node_info, &master.file_info).unwrap();
+        let term = master.raft.term();
+        master
+            .raft
+            .on_request_vote_ack(term, true, addr(PORTS[1]), &master.node_info, &master.file_info)
+            .unwrap();
+    }
+
+    impl Master {
+        fn append_entries(&mut self, term: u64, prev: (u64, u64), commit_index: u64, entries: Vec<LogEntry>) -> Packet {
+            self.raft
+                .on_append_entries(
+                    term,
+                    addr(PORTS[0]),
+                    prev.0,
+                    prev.1,
+                    commit_index,
+                    false,
+                    entries,
+                    &self.node_info,
+                    &self.file_info,
+                )
+                .unwrap()
+        }
+
+        fn ack_append(&mut self, port: u16, index: u64) {
+            let term = self.raft.term();
+            self.raft
+                .on_append_entries_ack(term, true, index, addr(port), &self.node_info, &self.file_info)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn one_vote_per_term() {
+        let mut master = master(PORTS[0]);
+
+        let (term, is_granted, _) = ack(&master.raft.on_request_vote(1, addr(PORTS[1]), 0, 0).unwrap());
+        assert_eq!(term, 1);
+        assert!(is_granted);
+        assert!(!ack(&master.raft.on_request_vote(1, addr(PORTS[2]), 0, 0).unwrap()).1);
+        assert!(ack(&master.raft.on_request_vote(1, addr(PORTS[1]), 0, 0).unwrap()).1);
+
+        // A new term frees the vote
+        assert!(ack(&master.raft.on_request_vote(2, addr(PORTS[2]), 0, 0).unwrap()).1);
+        assert!(!ack(&master.raft.on_request_vote(1, addr(PORTS[1]), 0, 0).unwrap()).1);
+    }
+
+    #[test]
+    fn vote_only_for_log_at_least_as_up_to_date() {
+        let mut master = master(PORTS[1]);
+        let ack_append = master.append_entries(2, (0, 0), 0, vec![entry(1, 7003), entry(2, 7004)]);
+        assert!(ack(&ack_append).1);
+
+        // Older last term, or same last term but shorter log
+        assert!(!ack(&master.raft.on_request_vote(3, addr(PORTS[2]), 5, 1).unwrap()).1);
+        assert!(!ack(&master.raft.on_request_vote(3, addr(PORTS[2]), 1, 2).unwrap()).1);
+        assert!(ack(&master.raft.on_request_vote(3, addr(PORTS[2]), 2, 2).unwrap()).1);
+    }
+
+    #[test]
+    fn leader_applies_changes_once_a_majority_stored_them() {
+        let mut leader = master(PORTS[0]);
+        elect(&mut leader);
+        assert!(leader.raft.is_leader());
+
+        // No-op entry of the new term must commit before the leader serves
+        assert!(!leader.raft.is_settled());
+        leader.ack_append(PORTS[1], 1);
+        assert!(leader.raft.is_settled());
+
+        let changes = entry(0, 7003).changes;
+        leader
+            .raft
+            .propose(changes, &leader.node_info, &leader.file_info)
+            .unwrap();
+        assert!(!leader.raft.is_settled());
+        assert!(leader.node_info.get_nodes().unwrap().is_empty());
+
+        leader.ack_append(PORTS[2], 2);
+        assert!(leader.raft.is_settled());
+        assert_eq!(leader.node_info.get_nodes().unwrap().len(), 1);
+        assert!(leader.node_info.drain_changes().is_empty());
+    }
+
+    #[test]
+    fn entries_of_previous_terms_commit_only_along_with_current_term() {
+        let mut leader = master(PORTS[0]);
+        leader.raft._append(entry(1, 7003)).unwrap();
+        leader.raft.term = 1;
+        elect(&mut leader);
+        assert_eq!(leader.raft.term(), 2);
+
+        // Entry of term 1 stored by a majority isn't committed by itself
+        leader.ack_append(PORTS[1], 1);
+        assert_eq!(leader.raft.commit_index, 0);
+        assert!(leader.node_info.get_nodes().unwrap().is_empty());
+
+        // It is with the no-op entry of term 2
+        leader.ack_append(PORTS[1], 2);
+        assert_eq!(leader.raft.commit_index, 2);
+        assert_eq!(leader.node_info.get_nodes().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn follower_applies_committed_entries_only() {
+        let mut follower = master(PORTS[1]);
+
+        let (_, is_success, index) = ack(&follower.append_entries(1, (0, 0), 0, vec![entry(1, 7003), entry(1, 7004)]));
+        assert!(is_success);
+        assert_eq!(index, 2);
+        assert!(follower.node_info.get_nodes().unwrap().is_empty());
+
+        follower.append_entries(1, (2, 1), 1, vec![]);
+        assert_eq!(follower.node_info.get_nodes().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn follower_replaces_conflicting_entries() {
+        let mut follower = master(PORTS[1]);
+        follower.append_entries(1, (0, 0), 1, vec![entry(1, 7003), entry(1, 7004)]);
+
+        // Entry missing before the new ones: leader is told where to retry from
+        let (_, is_success, hint) = ack(&follower.append_entries(2, (3, 2), 1, vec![entry(2, 7005)]));
+        assert!(!is_success);
+        assert_eq!(hint, 2);
+
+        let (_, is_success, index) = ack(&follower.append_entries(2, (1, 1), 2, vec![entry(2, 7005)]));
+        assert!(is_success);
+        assert_eq!(index, 2);
+        assert_eq!(follower.raft._last_term(), 2);
+
+        let ports: HashSet<u16> = follower
+            .node_info
+            .get_nodes()
+            .unwrap()
+            .iter()
+            .map(|node| node.port)
+            .collect();
+        assert_eq!(ports, HashSet::from([7003, 7005]));
+    }
+
+    #[test]
+    fn stale_leader_is_rejected() {
+        let mut follower = master(PORTS[1]);
+        follower.append_entries(2, (0, 0), 0, vec![]);
+
+        let (term, is_success, _) = ack(&follower.append_entries(1, (0, 0), 0, vec![entry(1, 7003)]));
+        assert_eq!(term, 2);
+        assert!(!is_success);
+        assert_eq!(follower.raft._last_index(), 0);
+    }
+}
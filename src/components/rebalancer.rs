@@ -0,0 +1,530 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use rusqlite::Result;
+
+use crate::components::{
+    configs::Configs,
+    db::{FileInfoDB, FileState, NodeInfoDB, NodeInfoEntry, NodeStatus},
+    packets::Packet,
+    placement::PlacementPolicy,
+};
+
+// ================================================
+// Definition
+// ================================================
+
+/// Even out disk usage of Data nodes by moving replicas from the fullest nodes to the emptiest ones
+///
+/// Every `interval`, nodes whose utilisation is more than `threshold` percent away from the cluster mean are detected
+/// and moves are planned. A move copies a block with RequestSendReplica, then deletes the source replica once the copy
+/// is acknowledged. Moves are issued no faster than `bandwidth` bytes per second. In dry-run, planned moves are only
+/// reported.
+pub struct Rebalancer {
+    /// Factor of files which don't have their own
+    replication_factor: usize,
+    /// Seconds between two plans. 0 disables the rebalancer.
+    interval: Duration,
+    threshold: f64,
+    bandwidth: u64,
+    is_dry_run: bool,
+    max_in_flight: usize,
+    timeout: Duration,
+    /// Bytes which can still be sent. Negative once a large block went over.
+    budget: f64,
+    ts_plan: Option<Instant>,
+    ts_refill: Instant,
+    queue: VecDeque<Move>,
+    in_flight: HashMap<(String, String), (String, Instant)>,
+}
+
+/// One replica to move from `source` to `target`
+struct Move {
+    block_id: String,
+    source: String,
+    target: String,
+    size: u64,
+}
+
+// ================================================
+// Implementation
+// ================================================
+
+impl std::fmt::Display for Move {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "'{}' {} -> {} ({} bytes)",
+            self.block_id, self.source, self.target, self.size
+        )
+    }
+}
+
+impl Rebalancer {
+    pub fn new(configs: &Configs) -> Rebalancer {
+        Rebalancer {
+            replication_factor: configs.replication_factor,
+            interval: Duration::from_secs(configs.rebalance_interval),
+            threshold: configs.rebalance_threshold,
+            bandwidth: configs.rebalance_bandwidth,
+            is_dry_run: configs.rebalance_dry_run,
+            max_in_flight: configs.replication_max_in_flight,
+            timeout: Duration::from_secs(configs.replication_timeout),
+            budget: 0.0,
+            ts_plan: None,
+            ts_refill: Instant::now(),
+            queue: VecDeque::new(),
+            in_flight: HashMap::new(),
+        }
+    }
+
+    /// Release the slot taken by a move. A successful copy removes the replica left at the source, and the returned
+    /// DeleteReplica tells the source to drop its file.
+    pub fn on_replica_ack(
+        &mut self,
+        block_id: &str,
+        node_id: &str,
+        is_success: bool,
+        file_info: &FileInfoDB,
+    ) -> Result<Option<Packet>> {
+        let source = match self.in_flight.remove(&(block_id.to_string(), node_id.to_string())) {
+            Some((source, _)) => source,
+            None => return Ok(None),
+        };
+        if !is_success {
+            log::warn!("Move of '{}' from {} to {} failed", block_id, source, node_id);
+            return Ok(None);
+        }
+
+        let block_id = block_id.to_string();
+        if !file_info
+            .get_replicas(&block_id)?
+            .iter()
+            .any(|replica| replica.node_id == source)
+        {
+            return Ok(None);
+        }
+
+        log::info!("Moved '{}' from {} to {}", block_id, source, node_id);
+        file_info.remove_replica(&block_id, &source)?;
+        file_info.refresh_block_state(&block_id, self.replication_factor)?;
+
+        match SocketAddr::from_str(&source) {
+            Ok(addr) => Ok(Some(Packet::create_delete_replica(addr, &block_id))),
+            Err(err) => {
+                log::error!("Cannot parse address of node {}: {}", source, err);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Plan moves when the previous ones are done and `interval` has passed, then issue RequestSendReplica for planned
+    /// moves as far as the bandwidth allows. Targets are chosen by `placement` among the least used nodes.
+    pub fn schedule(
+        &mut self,
+        file_info: &FileInfoDB,
+        node_info: &NodeInfoDB,
+        placement: &mut dyn PlacementPolicy,
+    ) -> Result<Vec<Packet>> {
+        let mut packets = Vec::<Packet>::new();
+        if self.interval.is_zero() {
+            return Ok(packets);
+        }
+
+        // Moves never acknowledged are given up. Extra replicas they may leave are trimmed by replication.
+        let now = Instant::now();
+        self.in_flight.retain(|(block_id, target), (source, ts)| {
+            let is_expired = now.duration_since(*ts) >= self.timeout;
+            if is_expired {
+                log::warn!("Move of '{}' from {} to {} timed out", block_id, source, target);
+            }
+            !is_expired
+        });
+
+        // Let unused bandwidth pile up for one second at most
+        let bandwidth = self.bandwidth as f64;
+        self.budget = (self.budget + bandwidth * now.duration_since(self.ts_refill).as_secs_f64()).min(bandwidth);
+        self.ts_refill = now;
+
+        let is_due = self
+            .ts_plan
+            .is_none_or(|ts_plan| now.duration_since(ts_plan) >= self.interval);
+        if self.queue.is_empty() && self.in_flight.is_empty() && is_due {
+            self.ts_plan = Some(now);
+            let moves = self.plan(file_info, node_info, placement)?;
+            if self.is_dry_run && !moves.is_empty() {
+                for entry in &moves {
+                    log::info!("Rebalance (dry-run): move {}", entry);
+                }
+                let n_bytes: u64 = moves.iter().map(|entry| entry.size).sum();
+                log::info!(
+                    "Rebalance (dry-run): {} moves, {} bytes, about {} seconds",
+                    moves.len(),
+                    n_bytes,
+                    n_bytes / self.bandwidth.max(1)
+                );
+            } else if !self.is_dry_run {
+                self.queue.extend(moves);
+            }
+        }
+
+        while self.budget > 0.0 && self.in_flight.len() < self.max_in_flight {
+            let entry = match self.queue.pop_front() {
+                Some(entry) => entry,
+                None => break,
+            };
+
+            // Replicas may have changed since the plan
+            let holders: Vec<String> = file_info
+                .get_replicas(&entry.block_id)?
+                .into_iter()
+                .map(|replica| replica.node_id)
+                .collect();
+            if !holders.contains(&entry.source) || holders.contains(&entry.target) {
+                continue;
+            }
+            let (addr_source, addr_target) =
+                match (SocketAddr::from_str(&entry.source), SocketAddr::from_str(&entry.target)) {
+                    (Ok(addr_source), Ok(addr_target)) => (addr_source, addr_target),
+                    _ => {
+                        log::error!("Cannot parse addresses of move {}", entry);
+                        continue;
+                    }
+                };
+
+            log::info!("Rebalance: move {}", entry);
+            packets.push(Packet::create_request_send_replica(
+                addr_source,
+                &entry.block_id,
+                0,
+                0,
+                addr_target,
+            ));
+            self.budget -= entry.size as f64;
+            self.in_flight
+                .insert((entry.block_id, entry.target), (entry.source, Instant::now()));
+        }
+
+        Ok(packets)
+    }
+
+    /// Find nodes beyond the threshold and pick replicas to move until sources come down to the mean. If only one side
+    /// is beyond the threshold, nodes on the other side of the mean make up for it.
+    fn plan(
+        &self,
+        file_info: &FileInfoDB,
+        node_info: &NodeInfoDB,
+        placement: &mut dyn PlacementPolicy,
+    ) -> Result<Vec<Move>> {
+        let mut moves = Vec::<Move>::new();
+
+        let data_nodes: Vec<NodeInfoEntry> = node_info
+            .get_data_nodes()?
+            .into_iter()
+            .filter(|node| node.status == NodeStatus::Alive && node.ip.is_some() && node.usage.disk_total > 0)
+            .collect();
+        let used: u64 = data_nodes.iter().map(|node| node.usage.disk_used).sum();
+        let total: u64 = data_nodes.iter().map(|node| node.usage.disk_total).sum();
+        if data_nodes.len() < 2 || total == 0 {
+            return Ok(moves);
+        }
+        let mean = 100.0 * used as f64 / total as f64;
+
+        let is_over = |node: &NodeInfoEntry| _get_utilisation(node) > mean + self.threshold;
+        let is_under = |node: &NodeInfoEntry| _get_utilisation(node) < mean - self.threshold;
+        for node in &data_nodes {
+            if is_over(node) || is_under(node) {
+                log::info!(
+                    "Node {} uses {:.2}% of its disk, cluster mean is {:.2}%",
+                    node.node_id,
+                    _get_utilisation(node),
+                    mean
+                );
+            }
+        }
+        let has_over = data_nodes.iter().any(is_over);
+        let has_under = data_nodes.iter().any(is_under);
+        if !has_over && !has_under {
+            return Ok(moves);
+        }
+
+        let (mut sources, mut targets): (Vec<NodeInfoEntry>, Vec<NodeInfoEntry>) = data_nodes
+            .into_iter()
+            .filter(|node| {
+                (if has_over {
+                    is_over(node)
+                } else {
+                    _get_utilisation(node) > mean
+                }) || (if has_under {
+                    is_under(node)
+                } else {
+                    _get_utilisation(node) < mean
+                })
+            })
+            .partition(|node| _get_utilisation(node) > mean);
+        sources.sort_by(|a, b| _get_utilisation(b).total_cmp(&_get_utilisation(a)));
+
+        for source in sources.iter_mut() {
+            for replica in file_info.get_replicas_by_node(&source.node_id)? {
+                if _get_utilisation(source) <= mean {
+                    break;
+                }
+                targets.retain(|node| _get_utilisation(node) < mean);
+                if targets.is_empty() {
+                    return Ok(moves);
+                }
+
+                let block = match file_info.get_block_info(&replica.block_id)?.pop() {
+                    Some(block) if block.state == FileState::Complete && block.size > 0 => block,
+                    _ => continue,
+                };
+                if moves.iter().any(|entry| entry.block_id == block.block_id) {
+                    continue;
+                }
+
+                // The source leaves, so only other holders constrain the target
+                let holders: Vec<String> = file_info
+                    .get_replicas(&block.block_id)?
+                    .into_iter()
+                    .map(|replica| replica.node_id)
+                    .filter(|node_id| *node_id != source.node_id)
+                    .collect();
+                let target = match placement.choose(&targets, &holders, 1, block.size).pop() {
+                    Some(addr) => addr.to_string(),
+                    None => continue,
+                };
+
+                source.usage.disk_used = source.usage.disk_used.saturating_sub(block.size);
+                if let Some(node) = targets.iter_mut().find(|node| node.node_id == target) {
+                    node.usage.disk_used += block.size;
+                }
+                moves.push(Move {
+                    block_id: block.block_id,
+                    source: source.node_id.clone(),
+                    target,
+                    size: block.size,
+                });
+            }
+        }
+
+        Ok(moves)
+    }
+}
+
+/// Percentage of the disk taken by blocks of a node. A node which didn't report its disk yet counts as empty, though
+/// `plan` leaves such nodes out.
+fn _get_utilisation(node: &NodeInfoEntry) -> f64 {
+    match node.usage.disk_total {
+        0 => 0.0,
+        disk_total => 100.0 * node.usage.disk_used as f64 / disk_total as f64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::components::{
+        db::{BlockInfoEntry, DBBackend, FileInfoEntry, NodeUsage},
+        entity::node_roles::Role,
+        packets::PacketId,
+        placement::LeastUsedPlacement,
+    };
+
+    const BLOCK_SIZE: u64 = 100;
+
+    fn node_id(port: u16) -> String {
+        format!("127.0.0.1:{}", port)
+    }
+
+    /// Metadata of Data nodes on ports 7003.. with `disk_used` bytes out of 1000 each. Each node holds blocks of 100
+    /// bytes making up its usage, and no block is held twice.
+    fn cluster(disk_used: &[u64]) -> (FileInfoDB, NodeInfoDB) {
+        let file_info = FileInfoDB::initialize("file_info", &DBBackend::InMemory);
+        let node_info = NodeInfoDB::initialize_along("node_info", &file_info);
+
+        let mut entry = FileInfoEntry::initialize("f".to_string(), 0, 0, 1);
+        entry.inode = file_info.create_file("f").unwrap().unwrap().0;
+        file_info.upsert(&entry).unwrap();
+
+        let mut index = 0;
+        for (i, disk_used) in disk_used.iter().enumerate() {
+            let port = 7003 + i as u16;
+            node_info.upsert(Ipv4Addr::LOCALHOST, port, Role::Data).unwrap();
+            let usage = NodeUsage {
+                disk_total: 1000,
+                disk_used: *disk_used,
+                disk_free: 1000 - disk_used,
+                ..Default::default()
+            };
+            node_info.update_usage(&node_id(port), &usage).unwrap();
+
+            for _ in 0..disk_used / BLOCK_SIZE {
+                let block = BlockInfoEntry::initialize(entry.inode, index, BLOCK_SIZE);
+                file_info.upsert_block(&block).unwrap();
+                file_info
+                    .update_block_state(&block.block_id, FileState::Complete)
+                    .unwrap();
+                file_info.upsert_replica(&block.block_id, &node_id(port)).unwrap();
+                index += 1;
+            }
+        }
+
+        (file_info, node_info)
+    }
+
+    fn rebalancer(bandwidth: u64, is_dry_run: bool) -> Rebalancer {
+        Rebalancer::new(&Configs {
+            replication_factor: 1,
+            rebalance_bandwidth: bandwidth,
+            rebalance_dry_run: is_dry_run,
+            replication_max_in_flight: 10,
+            ..Default::default()
+        })
+    }
+
+    fn sources_and_targets(moves: &[Move]) -> Vec<(String, String)> {
+        moves
+            .iter()
+            .map(|entry| (entry.source.clone(), entry.target.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn plan_moves_from_nodes_over_threshold_to_those_under_it() {
+        let (file_info, node_info) = cluster(&[800, 200, 500]);
+
+        let moves = rebalancer(1000, false)
+            .plan(&file_info, &node_info, &mut LeastUsedPlacement)
+            .unwrap();
+
+        // Mean is 50%, so 3 blocks bring the source down to it
+        assert_eq!(sources_and_targets(&moves), vec![(node_id(7003), node_id(7004)); 3]);
+    }
+
+    #[test]
+    fn plan_is_empty_within_threshold() {
+        let (file_info, node_info) = cluster(&[500, 400, 600]);
+
+        let moves = rebalancer(1000, false)
+            .plan(&file_info, &node_info, &mut LeastUsedPlacement)
+            .unwrap();
+
+        assert!(moves.is_empty());
+    }
+
+    #[test]
+    fn plan_moves_to_nodes_below_mean_when_none_is_under_threshold() {
+        let (file_info, node_info) = cluster(&[700, 500, 400]);
+
+        let moves = rebalancer(1000, false)
+            .plan(&file_info, &node_info, &mut LeastUsedPlacement)
+            .unwrap();
+
+        // Mean is 53.3%: only the first node is over, and only the last one is below the mean
+        assert_eq!(sources_and_targets(&moves), vec![(node_id(7003), node_id(7005)); 2]);
+    }
+
+    #[test]
+    fn plan_leaves_out_nodes_without_reported_disk() {
+        let (file_info, node_info) = cluster(&[800, 200]);
+        node_info.upsert(Ipv4Addr::LOCALHOST, 7005, Role::Data).unwrap();
+
+        let moves = rebalancer(1000, false)
+            .plan(&file_info, &node_info, &mut LeastUsedPlacement)
+            .unwrap();
+
+        assert_eq!(sources_and_targets(&moves), vec![(node_id(7003), node_id(7004)); 3]);
+    }
+
+    #[test]
+    fn dry_run_issues_no_move() {
+        let (file_info, node_info) = cluster(&[800, 200, 500]);
+        let mut rebalancer = rebalancer(1000, true);
+        rebalancer.budget = 1000.0;
+
+        let packets = rebalancer
+            .schedule(&file_info, &node_info, &mut LeastUsedPlacement)
+            .unwrap();
+
+        assert!(packets.is_empty());
+        assert!(rebalancer.queue.is_empty());
+    }
+
+    #[test]
+    fn moves_are_issued_within_bandwidth_budget() {
+        let (file_info, node_info) = cluster(&[800, 200, 500]);
+        let mut rebalancer = rebalancer(150, false);
+        rebalancer.budget = 150.0;
+
+        // The budget goes below 0 with the second move
+        let packets = rebalancer
+            .schedule(&file_info, &node_info, &mut LeastUsedPlacement)
+            .unwrap();
+        assert_eq!(packets.len(), 2);
+        assert!(packets
+            .iter()
+            .all(|packet| packet.packet_id == PacketId::RequestSendReplica));
+        assert_eq!(rebalancer.queue.len(), 1);
+
+        // Bandwidth unused for a long time only refills one second worth of bytes
+        rebalancer.ts_refill -= Duration::from_secs(10);
+        let packets = rebalancer
+            .schedule(&file_info, &node_info, &mut LeastUsedPlacement)
+            .unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(rebalancer.budget, 50.0);
+    }
+
+    #[test]
+    fn acked_move_deletes_source_replica_and_expired_move_is_given_up() {
+        let (file_info, node_info) = cluster(&[800, 200, 500]);
+        let mut rebalancer = rebalancer(1000, false);
+        rebalancer.budget = 1000.0;
+        rebalancer
+            .schedule(&file_info, &node_info, &mut LeastUsedPlacement)
+            .unwrap();
+        assert_eq!(rebalancer.in_flight.len(), 3);
+        let mut keys: Vec<(String, String)> = rebalancer.in_flight.keys().cloned().collect();
+        keys.sort();
+
+        // The copy to the target is recorded by replication before the ack reaches the rebalancer
+        let (block_id, target) = &keys[0];
+        file_info.upsert_replica(block_id, target).unwrap();
+        let packet = rebalancer
+            .on_replica_ack(block_id, target, true, &file_info)
+            .unwrap()
+            .unwrap();
+        assert!(packet.packet_id == PacketId::DeleteReplica);
+        assert_eq!(
+            packet.addr_receiver,
+            Some(SocketAddr::from_str(&node_id(7003)).unwrap())
+        );
+        let holders: Vec<String> = file_info
+            .get_replicas(block_id)
+            .unwrap()
+            .into_iter()
+            .map(|replica| replica.node_id)
+            .collect();
+        assert_eq!(holders, vec![node_id(7004)]);
+
+        // Moves never acknowledged free their slot once timed out
+        for (_, ts) in rebalancer.in_flight.values_mut() {
+            *ts -= rebalancer.timeout;
+        }
+        rebalancer
+            .schedule(&file_info, &node_info, &mut LeastUsedPlacement)
+            .unwrap();
+        assert!(rebalancer.in_flight.is_empty());
+        let (block_id, target) = &keys[1];
+        assert!(rebalancer
+            .on_replica_ack(block_id, target, true, &file_info)
+            .unwrap()
+            .is_none());
+    }
+}
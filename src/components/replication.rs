@@ -0,0 +1,424 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use rusqlite::Result;
+
+use crate::components::{
+    configs::Configs,
+    db::{FileInfoDB, FileState, NodeInfoDB, NodeStatus},
+    packets::Packet,
+    placement::PlacementPolicy,
+};
+
+// ================================================
+// Definition
+// ================================================
+
+/// Keep the number of replicas of each block at the replication factor of its file
+///
+/// Blocks needing more replicas wait in a queue. At most `max_in_flight` copies run at once so that recovery doesn't
+/// saturate the cluster, and copies which aren't acknowledged within `timeout` are retried. Blocks with more replicas
+/// than needed, as after their factor was lowered, wait in another queue until extra replicas are deleted.
+pub struct ReplicationManager {
+    /// Factor of files which don't have their own
+    replication_factor: usize,
+    max_in_flight: usize,
+    timeout: Duration,
+    queue: VecDeque<String>,
+    queue_excess: VecDeque<String>,
+    in_flight: HashMap<(String, String), Instant>,
+}
+
+// ================================================
+// Implementation
+// ================================================
+
+impl ReplicationManager {
+    pub fn new(configs: &Configs) -> ReplicationManager {
+        ReplicationManager {
+            replication_factor: configs.replication_factor,
+            max_in_flight: configs.replication_max_in_flight,
+            timeout: Duration::from_secs(configs.replication_timeout),
+            queue: VecDeque::new(),
+            queue_excess: VecDeque::new(),
+            in_flight: HashMap::new(),
+        }
+    }
+
+    /// Forget replicas held by a dead node and queue every block left under-replicated
+    pub fn on_node_dead(&mut self, node_id: &String, file_info: &FileInfoDB) -> Result<()> {
+        for replica in file_info.get_replicas_by_node(node_id)? {
+            file_info.remove_replica(&replica.block_id, node_id)?;
+            file_info.refresh_block_state(&replica.block_id, self.replication_factor)?;
+        }
+
+        self.enqueue_under_replicated(file_info)
+    }
+
+    /// Queue every block whose replicas are fewer than the replication factor
+    pub fn enqueue_under_replicated(&mut self, file_info: &FileInfoDB) -> Result<()> {
+        for block in file_info.get_blocks_by_state(FileState::UnderReplicated)? {
+            if !self.queue.contains(&block.block_id) {
+                self.queue.push_back(block.block_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Queue every block whose replicas are more than the replication factor
+    pub fn enqueue_over_replicated(&mut self, file_info: &FileInfoDB) -> Result<()> {
+        for block_id in file_info.get_over_replicated_blocks(self.replication_factor)? {
+            if !self.queue_excess.contains(&block_id) {
+                self.queue_excess.push_back(block_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Release the slot taken by a copy. Failed copies put the block back in the queue.
+    pub fn on_replica_ack(&mut self, block_id: &str, node_id: &str, is_success: bool) {
+        if self
+            .in_flight
+            .remove(&(block_id.to_string(), node_id.to_string()))
+            .is_none()
+        {
+            return;
+        }
+
+        if !is_success && !self.queue.iter().any(|id| id == block_id) {
+            self.queue.push_back(block_id.to_string());
+        }
+    }
+
+    /// Issue RequestSendReplica for queued blocks while slots are available, and DeleteReplica for extra replicas.
+    /// Targets of copies are chosen by `placement`.
+    pub fn schedule(
+        &mut self,
+        file_info: &FileInfoDB,
+        node_info: &NodeInfoDB,
+        placement: &mut dyn PlacementPolicy,
+    ) -> Result<Vec<Packet>> {
+        let mut packets = self.trim(file_info, node_info)?;
+
+        // Copies never acknowledged are retried
+        let now = Instant::now();
+        let expired: Vec<(String, String)> = self
+            .in_flight
+            .iter()
+            .filter(|(_, ts)| now.duration_since(**ts) >= self.timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for (block_id, node_id) in expired {
+            log::warn!("Replica of '{}' to node {} timed out", block_id, node_id);
+            self.on_replica_ack(&block_id, &node_id, false);
+        }
+
+        let data_nodes = node_info.get_data_nodes()?;
+        let nodes_alive: Vec<&String> = data_nodes
+            .iter()
+            .filter(|node| node.status == NodeStatus::Alive)
+            .map(|node| &node.node_id)
+            .collect();
+
+        let mut n_deferred = 0;
+        while self.in_flight.len() < self.max_in_flight && n_deferred < self.queue.len() {
+            let block_id = match self.queue.pop_front() {
+                Some(block_id) => block_id,
+                None => break,
+            };
+            // The file may have been deleted since
+            let size = match file_info.get_block_info(&block_id)?.pop() {
+                Some(block) => block.size,
+                None => continue,
+            };
+
+            let holders: Vec<String> = file_info
+                .get_replicas(&block_id)?
+                .into_iter()
+                .map(|replica| replica.node_id)
+                .collect();
+            let pending: Vec<String> = self
+                .in_flight
+                .keys()
+                .filter(|(id, _)| *id == block_id)
+                .map(|(_, node_id)| node_id.clone())
+                .collect();
+            let holders_all: Vec<String> = holders.iter().chain(pending.iter()).cloned().collect();
+            let replication_factor = file_info.get_block_replication_factor(&block_id, self.replication_factor)?;
+            if holders.len() + pending.len() >= replication_factor {
+                continue;
+            }
+
+            let source = holders.iter().find(|node_id| nodes_alive.contains(node_id));
+            let target = placement.choose(&data_nodes, &holders_all, 1, size).pop();
+            let (source, addr_target) = match (source, target) {
+                (Some(source), Some(addr_target)) => (source.clone(), addr_target),
+                (None, _) => {
+                    log::error!("No alive node holds '{}'. Cannot re-replicate it.", block_id);
+                    continue;
+                }
+                (_, None) => {
+                    // Wait for another node to join
+                    self.queue.push_back(block_id);
+                    n_deferred += 1;
+                    continue;
+                }
+            };
+            let addr_source = match SocketAddr::from_str(&source) {
+                Ok(addr_source) => addr_source,
+                Err(err) => {
+                    log::error!("Cannot parse address of node {}: {}", source, err);
+                    continue;
+                }
+            };
+            let target = addr_target.to_string();
+
+            log::info!("Re-replicate '{}': {} -> {}", block_id, source, target);
+            packets.push(Packet::create_request_send_replica(
+                addr_source,
+                &block_id,
+                0,
+                0,
+                addr_target,
+            ));
+            self.in_flight.insert((block_id.clone(), target), Instant::now());
+
+            // Further replicas of the same block are scheduled in later rounds
+            if holders.len() + pending.len() + 1 < replication_factor {
+                self.queue.push_back(block_id);
+                n_deferred += 1;
+            }
+        }
+
+        Ok(packets)
+    }
+
+    /// Forget replicas of queued blocks beyond their replication factor and tell their nodes to delete them. Replicas
+    /// on nodes not Alive go first. Blocks being copied wait for the copies to finish.
+    fn trim(&mut self, file_info: &FileInfoDB, node_info: &NodeInfoDB) -> Result<Vec<Packet>> {
+        let mut packets = Vec::<Packet>::new();
+        if self.queue_excess.is_empty() {
+            return Ok(packets);
+        }
+
+        let nodes_alive: Vec<String> = node_info
+            .get_data_nodes()?
+            .into_iter()
+            .filter(|node| node.status == NodeStatus::Alive)
+            .map(|node| node.node_id)
+            .collect();
+
+        for block_id in std::mem::take(&mut self.queue_excess) {
+            if self.in_flight.keys().any(|(id, _)| *id == block_id) {
+                self.queue_excess.push_back(block_id);
+                continue;
+            }
+
+            let replication_factor = file_info
+                .get_block_replication_factor(&block_id, self.replication_factor)?
+                .max(1);
+            let mut holders: Vec<String> = file_info
+                .get_replicas(&block_id)?
+                .into_iter()
+                .map(|replica| replica.node_id)
+                .collect();
+            holders.sort_by_key(|node_id| nodes_alive.contains(node_id));
+
+            let n_excess = holders.len().saturating_sub(replication_factor);
+            for node_id in holders.iter().take(n_excess) {
+                log::info!("Remove extra replica of '{}' from {}", block_id, node_id);
+                file_info.remove_replica(&block_id, node_id)?;
+                match SocketAddr::from_str(node_id) {
+                    Ok(addr) => packets.push(Packet::create_delete_replica(addr, &block_id)),
+                    Err(err) => log::error!("Cannot parse address of node {}: {}", node_id, err),
+                }
+            }
+            if n_excess > 0 {
+                file_info.refresh_block_state(&block_id, self.replication_factor)?;
+            }
+        }
+
+        Ok(packets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::components::{
+        db::{BlockInfoEntry, DBBackend, FileInfoEntry},
+        entity::node_roles::Role,
+        packets::PacketId,
+        placement::RoundRobinPlacement,
+    };
+
+    fn node_id(port: u16) -> String {
+        format!("127.0.0.1:{}", port)
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from_str(&node_id(port)).unwrap()
+    }
+
+    /// Metadata of Alive Data nodes on `ports` and of a file of factor 2 with `n_blocks` blocks, each held by every
+    /// node of `holders`
+    fn cluster(ports: &[u16], n_blocks: u64, holders: &[u16]) -> (FileInfoDB, NodeInfoDB) {
+        let file_info = FileInfoDB::initialize("file_info", &DBBackend::InMemory);
+        let node_info = NodeInfoDB::initialize_along("node_info", &file_info);
+        for port in ports {
+            node_info.upsert(Ipv4Addr::LOCALHOST, *port, Role::Data).unwrap();
+        }
+
+        let mut entry = FileInfoEntry::initialize("f".to_string(), 100 * n_blocks, 0, 2);
+        entry.inode = file_info.create_file("f").unwrap().unwrap().0;
+        file_info.upsert(&entry).unwrap();
+        for index in 0..n_blocks {
+            let block = BlockInfoEntry::initialize(entry.inode, index, 100);
+            file_info.upsert_block(&block).unwrap();
+            for port in holders {
+                file_info.upsert_replica(&block.block_id, &node_id(*port)).unwrap();
+            }
+            file_info.refresh_block_state(&block.block_id, 2).unwrap();
+        }
+
+        (file_info, node_info)
+    }
+
+    fn manager(max_in_flight: usize) -> ReplicationManager {
+        ReplicationManager::new(&Configs {
+            replication_factor: 2,
+            replication_max_in_flight: max_in_flight,
+            ..Default::default()
+        })
+    }
+
+    fn kill(node_info: &NodeInfoDB, port: u16) {
+        node_info.update_status(&node_id(port), NodeStatus::Dead).unwrap();
+    }
+
+    fn holders(file_info: &FileInfoDB, block_id: &str) -> Vec<String> {
+        let mut holders: Vec<String> = file_info
+            .get_replicas(&block_id.to_string())
+            .unwrap()
+            .into_iter()
+            .map(|replica| replica.node_id)
+            .collect();
+        holders.sort();
+        holders
+    }
+
+    #[test]
+    fn blocks_of_dead_node_are_copied_up_to_max_in_flight() {
+        let (file_info, node_info) = cluster(&[7003, 7004, 7005], 3, &[7003, 7004]);
+        let mut replication = manager(2);
+        let mut placement = RoundRobinPlacement::new();
+
+        kill(&node_info, 7004);
+        replication.on_node_dead(&node_id(7004), &file_info).unwrap();
+        assert_eq!(replication.queue.len(), 3);
+        assert!(file_info.get_replicas_by_node(&node_id(7004)).unwrap().is_empty());
+
+        let packets = replication.schedule(&file_info, &node_info, &mut placement).unwrap();
+        assert_eq!(packets.len(), 2);
+        for packet in &packets {
+            assert!(packet.packet_id == PacketId::RequestSendReplica);
+            assert_eq!(packet.addr_receiver, Some(addr(7003)));
+        }
+        assert!(replication.in_flight.keys().all(|(_, target)| *target == node_id(7005)));
+
+        // No slot is free until a copy is acknowledged
+        assert!(replication
+            .schedule(&file_info, &node_info, &mut placement)
+            .unwrap()
+            .is_empty());
+
+        let (block_id, target) = replication.in_flight.keys().next().cloned().unwrap();
+        file_info.upsert_replica(&block_id, &target).unwrap();
+        replication.on_replica_ack(&block_id, &target, true);
+        let packets = replication.schedule(&file_info, &node_info, &mut placement).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert!(replication.queue.is_empty());
+    }
+
+    #[test]
+    fn failed_or_timed_out_copies_are_retried() {
+        let (file_info, node_info) = cluster(&[7003, 7004, 7005], 1, &[7003, 7004]);
+        let mut replication = manager(2);
+        let mut placement = RoundRobinPlacement::new();
+        kill(&node_info, 7004);
+        replication.on_node_dead(&node_id(7004), &file_info).unwrap();
+
+        let packets = replication.schedule(&file_info, &node_info, &mut placement).unwrap();
+        assert_eq!(packets.len(), 1);
+        let (block_id, target) = replication.in_flight.keys().next().cloned().unwrap();
+
+        replication.on_replica_ack(&block_id, &target, false);
+        assert!(replication.in_flight.is_empty());
+        assert_eq!(replication.queue, vec![block_id.clone()]);
+        let packets = replication.schedule(&file_info, &node_info, &mut placement).unwrap();
+        assert_eq!(packets.len(), 1);
+
+        for ts in replication.in_flight.values_mut() {
+            *ts -= replication.timeout;
+        }
+        let packets = replication.schedule(&file_info, &node_info, &mut placement).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(replication.in_flight.len(), 1);
+        assert!(replication.in_flight.contains_key(&(block_id, node_id(7005))));
+    }
+
+    #[test]
+    fn block_without_target_waits_for_a_node_to_join() {
+        let (file_info, node_info) = cluster(&[7003, 7004], 1, &[7003, 7004]);
+        let mut replication = manager(2);
+        let mut placement = RoundRobinPlacement::new();
+        kill(&node_info, 7004);
+        replication.on_node_dead(&node_id(7004), &file_info).unwrap();
+
+        assert!(replication
+            .schedule(&file_info, &node_info, &mut placement)
+            .unwrap()
+            .is_empty());
+        assert_eq!(replication.queue.len(), 1);
+
+        node_info.upsert(Ipv4Addr::LOCALHOST, 7005, Role::Data).unwrap();
+        let packets = replication.schedule(&file_info, &node_info, &mut placement).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert!(replication.queue.is_empty());
+    }
+
+    #[test]
+    fn extra_replicas_on_nodes_not_alive_are_trimmed_first() {
+        let (file_info, node_info) = cluster(&[7003, 7004, 7005, 7006], 1, &[7003, 7004, 7005, 7006]);
+        let mut replication = manager(2);
+        let mut placement = RoundRobinPlacement::new();
+        node_info.update_status(&node_id(7004), NodeStatus::Suspect).unwrap();
+        kill(&node_info, 7006);
+
+        replication.enqueue_over_replicated(&file_info).unwrap();
+        let packets = replication.schedule(&file_info, &node_info, &mut placement).unwrap();
+
+        let mut addrs: Vec<SocketAddr> = packets
+            .iter()
+            .map(|packet| {
+                assert!(packet.packet_id == PacketId::DeleteReplica);
+                packet.addr_receiver.unwrap()
+            })
+            .collect();
+        addrs.sort();
+        assert_eq!(addrs, vec![addr(7004), addr(7006)]);
+        let block_id = file_info.get_replicas_by_node(&node_id(7003)).unwrap()[0]
+            .block_id
+            .clone();
+        assert_eq!(holders(&file_info, &block_id), vec![node_id(7003), node_id(7005)]);
+        assert!(replication.queue_excess.is_empty());
+    }
+}
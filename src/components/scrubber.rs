@@ -0,0 +1,191 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    sync::mpsc::Sender,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::components::{
+    block_store::BlockStore,
+    configs::Configs,
+    packets::{Packet, PacketId},
+};
+
+// ================================================
+// Definition
+// ================================================
+
+/// Time to wait before looking again whether a pass is due
+const INTERVAL_IDLE: Duration = Duration::from_secs(1);
+
+/// Re-read blocks stored by a Data node in the background to find those damaged on disk
+///
+/// A pass over all stored blocks starts when the scrubber starts and then every `interval`. Blocks are verified against
+/// the checksum kept in their metadata one at a time, pausing after each one so that no more than `bandwidth` bytes
+/// are read per second.
+pub struct Scrubber {
+    interval: Duration,
+    bandwidth: u64,
+    queue: VecDeque<String>,
+    ts_pass: Option<Instant>,
+}
+
+// ================================================
+// Implementation
+// ================================================
+
+impl Scrubber {
+    pub fn new(configs: &Configs) -> Scrubber {
+        Scrubber {
+            interval: Duration::from_secs(configs.interval_scrub),
+            bandwidth: configs.scrub_bandwidth.max(1),
+            queue: VecDeque::new(),
+            ts_pass: None,
+        }
+    }
+
+    /// Scrub on a thread of its own. Each block found corrupted is handed to thread:Processor through `sender` as a
+    /// CorruptReplica from `addr_current`, which drops the replica and reports it to Master. The thread stops once
+    /// thread:Processor is gone.
+    pub fn spawn(
+        mut self,
+        block_store: BlockStore,
+        addr_current: SocketAddr,
+        sender: Sender<Packet>,
+    ) -> JoinHandle<()> {
+        thread::spawn(move || loop {
+            match self.scrub_next(&block_store) {
+                None => thread::sleep(INTERVAL_IDLE),
+                Some(Ok(n_bytes)) => thread::sleep(Duration::from_secs_f64(n_bytes as f64 / self.bandwidth as f64)),
+                Some(Err(block_id)) => {
+                    // Fields are filled as thread:Receiver does when parsing the packet
+                    let packet = Packet {
+                        packet_id: PacketId::CorruptReplica,
+                        addr_sender: Some(addr_current),
+                        filename: Some(block_id),
+                        node_id: Some(addr_current.to_string()),
+                        ..Default::default()
+                    };
+                    if sender.send(packet).is_err() {
+                        return;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Verify the next block of the current pass, starting a pass if one is due. Return the number of bytes read, or
+    /// the id of the block if it is corrupted. Return None if no pass is running.
+    pub fn scrub_next(&mut self, block_store: &BlockStore) -> Option<Result<u64, String>> {
+        if self.queue.is_empty() {
+            if self.ts_pass.is_some_and(|ts| ts.elapsed() < self.interval) {
+                return None;
+            }
+
+            match block_store.list() {
+                Ok(block_ids) => {
+                    log::debug!("Start scrubbing {} blocks", block_ids.len());
+                    self.ts_pass = Some(Instant::now());
+                    self.queue = block_ids.into();
+                }
+                Err(err) => {
+                    log::error!("Cannot list blocks to scrub: {}", err);
+                    return None;
+                }
+            }
+        }
+
+        let block_id = self.queue.pop_front()?;
+        match block_store.read_verified(&block_id) {
+            Ok(data) => Some(Ok(data.len() as u64)),
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                log::warn!("Scrubber found block '{}' corrupted: {}", block_id, err);
+                Some(Err(block_id))
+            }
+            // Removed since the pass started
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Some(Ok(0)),
+            Err(err) => {
+                log::error!("Cannot scrub block '{}': {}", block_id, err);
+                Some(Ok(0))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf, process, sync::mpsc::channel};
+
+    use super::*;
+
+    /// Empty directory for a store, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = env::temp_dir().join(format!("dfs-scrubber-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn scrubber(interval: u64) -> Scrubber {
+        Scrubber::new(&Configs {
+            interval_scrub: interval,
+            scrub_bandwidth: 1024 * 1024 * 1024,
+            ..Default::default()
+        })
+    }
+
+    /// Store holding blocks "1#0", "1#1" and "1#2", with the data of "1#0" and the metadata of "1#2" damaged
+    fn damaged_store(dir: &TempDir) -> BlockStore {
+        let block_store = BlockStore::new(&dir.0);
+        for block_id in ["1#0", "1#1", "1#2"] {
+            block_store.write(block_id, 0, b"abcdef", 6).unwrap();
+        }
+        fs::write(dir.0.join("blocks").join("1#0"), b"abcdeX").unwrap();
+        fs::write(dir.0.join("meta").join("1#2"), b"short").unwrap();
+
+        block_store
+    }
+
+    #[test]
+    fn pass_reports_blocks_with_corrupt_data_or_metadata() {
+        let dir = TempDir::new("pass");
+        let block_store = damaged_store(&dir);
+        let mut scrubber = scrubber(3600);
+
+        let results: Vec<_> = (0..3).map(|_| scrubber.scrub_next(&block_store).unwrap()).collect();
+        assert_eq!(results, vec![Err("1#0".to_string()), Ok(6), Err("1#2".to_string())]);
+
+        // The next pass waits for the interval
+        assert_eq!(scrubber.scrub_next(&block_store), None);
+    }
+
+    #[test]
+    fn spawned_scrubber_hands_corrupt_blocks_to_processor() {
+        let dir = TempDir::new("spawn");
+        let block_store = damaged_store(&dir);
+        let addr_current = SocketAddr::from(([127, 0, 0, 1], 7003));
+        let (sender, receiver) = channel::<Packet>();
+
+        scrubber(3600).spawn(block_store, addr_current, sender);
+
+        let mut block_ids = Vec::<String>::new();
+        for _ in 0..2 {
+            let packet = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert!(packet.packet_id == PacketId::CorruptReplica);
+            assert_eq!(packet.addr_sender, Some(addr_current));
+            block_ids.push(packet.filename.unwrap());
+        }
+        assert_eq!(block_ids, vec!["1#0", "1#2"]);
+    }
+}
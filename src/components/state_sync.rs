@@ -0,0 +1,620 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use rusqlite::Result;
+
+use crate::components::{
+    configs::Configs,
+    db::{BlockInfoEntry, FileInfoDB, FileInfoEntry, FileState, InodeEntry, NodeInfoDB, NodeStatus, ROOT_INODE},
+    entity::node_roles::Role,
+    packets::Packet,
+};
+
+// ================================================
+// Definition
+// ================================================
+
+/// Max number of changes carried by one StateSync packet
+const MAX_CHANGES_PER_SYNC: usize = 1024;
+
+/// Max number of out-of-order StateSync packets a standby keeps while waiting for the missing ones
+const MAX_BUFFERED_SYNCS: usize = 256;
+
+/// One mutation of Master's metadata, replayed by standby Masters in the same order
+#[derive(Clone)]
+pub enum StateChange {
+    UpsertNode {
+        ip: Ipv4Addr,
+        port: u16,
+        role: Role,
+    },
+    UpdateNodeStatus {
+        node_id: String,
+        status: NodeStatus,
+    },
+    UpdateNodeRack {
+        node_id: String,
+        rack: String,
+    },
+    CreateInode {
+        inode: u64,
+        parent: u64,
+        name: String,
+        is_dir: bool,
+    },
+    MoveInode {
+        inode: u64,
+        parent: u64,
+        name: String,
+    },
+    RemoveInode {
+        inode: u64,
+    },
+    UpsertFile {
+        inode: u64,
+        size: u64,
+        checksum: u32,
+        state: FileState,
+        replication_factor: usize,
+    },
+    UpdateFileState {
+        inode: u64,
+        state: FileState,
+    },
+    UpdateFileSize {
+        inode: u64,
+        size: u64,
+        checksum: u32,
+    },
+    SetReplicationFactor {
+        inode: u64,
+        replication_factor: usize,
+    },
+    SetDirReplicationFactor {
+        inode: u64,
+        replication_factor: usize,
+    },
+    UpsertBlock {
+        block_id: String,
+        inode: u64,
+        index: u64,
+        size: u64,
+        checksum: u32,
+        state: FileState,
+    },
+    UpdateBlockState {
+        block_id: String,
+        state: FileState,
+    },
+    UpsertReplica {
+        block_id: String,
+        node_id: String,
+    },
+    RemoveReplica {
+        block_id: String,
+        node_id: String,
+    },
+}
+
+/// Progress of one standby Master as seen by the active Master
+struct StandbyState {
+    addr: SocketAddr,
+    seq_next: u64,
+    seq_acked: u64,
+    last_ack: Instant,
+}
+
+/// Active Master side: stream metadata changes to registered standby Masters
+///
+/// A standby first receives a snapshot of the whole metadata, then every change in order. Each StateSync carries a
+/// sequence number and the standby acknowledges the last one it applied. Standbys which stop acknowledging within
+/// `timeout` are dropped and have to register again.
+pub struct StateSyncPublisher {
+    timeout: Duration,
+    standbys: HashMap<String, StandbyState>,
+}
+
+/// Standby Master side: apply StateSync packets in sequence order
+pub struct StateSyncSubscriber {
+    has_snapshot: bool,
+    seq_applied: u64,
+    buffer: BTreeMap<u64, Vec<StateChange>>,
+    last_sync: Option<Instant>,
+}
+
+// ================================================
+// Implementation
+// ================================================
+
+impl std::fmt::Display for StateChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateChange::UpsertNode { ip, port, role } => write!(f, "UpsertNode({}:{}, {})", ip, port, role),
+            StateChange::UpdateNodeStatus { node_id, status } => write!(f, "UpdateNodeStatus({}, {})", node_id, status),
+            StateChange::UpdateNodeRack { node_id, rack } => write!(f, "UpdateNodeRack({}, {})", node_id, rack),
+            StateChange::CreateInode {
+                inode,
+                parent,
+                name,
+                is_dir,
+            } => write!(f, "CreateInode({}, {}/{}, is_dir = {})", inode, parent, name, is_dir),
+            StateChange::MoveInode { inode, parent, name } => write!(f, "MoveInode({}, {}/{})", inode, parent, name),
+            StateChange::RemoveInode { inode } => write!(f, "RemoveInode({})", inode),
+            StateChange::UpsertFile { inode, size, state, .. } => {
+                write!(f, "UpsertFile({}, {} bytes, {})", inode, size, state)
+            }
+            StateChange::UpdateFileState { inode, state } => write!(f, "UpdateFileState({}, {})", inode, state),
+            StateChange::UpdateFileSize { inode, size, .. } => write!(f, "UpdateFileSize({}, {} bytes)", inode, size),
+            StateChange::SetReplicationFactor {
+                inode,
+                replication_factor,
+            } => write!(f, "SetReplicationFactor({}, {})", inode, replication_factor),
+            StateChange::SetDirReplicationFactor {
+                inode,
+                replication_factor,
+            } => write!(f, "SetDirReplicationFactor({}, {})", inode, replication_factor),
+            StateChange::UpsertBlock {
+                block_id, size, state, ..
+            } => write!(f, "UpsertBlock({}, {} bytes, {})", block_id, size, state),
+            StateChange::UpdateBlockState { block_id, state } => write!(f, "UpdateBlockState({}, {})", block_id, state),
+            StateChange::UpsertReplica { block_id, node_id } => write!(f, "UpsertReplica({}, {})", block_id, node_id),
+            StateChange::RemoveReplica { block_id, node_id } => write!(f, "RemoveReplica({}, {})", block_id, node_id),
+        }
+    }
+}
+
+impl StateSyncPublisher {
+    pub fn new(configs: &Configs) -> StateSyncPublisher {
+        StateSyncPublisher {
+            timeout: Duration::from_secs(configs.threshold_dead),
+            standbys: HashMap::new(),
+        }
+    }
+
+    /// Register a standby Master and return the snapshot to send it. Registering again restarts from a new snapshot.
+    pub fn register(
+        &mut self,
+        addr: SocketAddr,
+        node_info: &NodeInfoDB,
+        file_info: &FileInfoDB,
+    ) -> Result<Vec<Packet>> {
+        let snapshot = take_snapshot(node_info, file_info)?;
+
+        let node_id = addr.to_string();
+        let seq_next = self.standbys.get(&node_id).map_or(1, |standby| standby.seq_next);
+        let mut standby = StandbyState {
+            addr,
+            seq_next,
+            seq_acked: seq_next - 1,
+            last_ack: Instant::now(),
+        };
+        let packets = _create_syncs(&mut standby, &snapshot, true);
+
+        log::info!(
+            "Standby {} registered. Send snapshot of {} changes in {} packets",
+            node_id,
+            snapshot.len(),
+            packets.len()
+        );
+        self.standbys.insert(node_id, standby);
+
+        Ok(packets)
+    }
+
+    /// Create StateSync packets carrying `changes` for every standby. Empty changes are still sent as keepalive.
+    pub fn publish(&mut self, changes: &[StateChange]) -> Vec<Packet> {
+        self.standbys
+            .values_mut()
+            .flat_map(|standby| _create_syncs(standby, changes, false))
+            .collect()
+    }
+
+    /// Record that standby `node_id` applied every StateSync up to `seq`
+    pub fn on_ack(&mut self, node_id: &str, seq: u64) {
+        match self.standbys.get_mut(node_id) {
+            Some(standby) => {
+                if seq > standby.seq_acked {
+                    standby.seq_acked = seq;
+                }
+                standby.last_ack = Instant::now();
+            }
+            None => log::warn!("StateSyncAck from unregistered standby {}", node_id),
+        }
+    }
+
+    /// Drop standbys which have StateSync packets unacknowledged for longer than `timeout`
+    pub fn expire(&mut self) {
+        let timeout = self.timeout;
+        self.standbys.retain(|node_id, standby| {
+            let is_lagging = standby.seq_acked + 1 < standby.seq_next && standby.last_ack.elapsed() > timeout;
+            if is_lagging {
+                log::warn!(
+                    "Standby {} stopped acknowledging at seq {}. Drop it.",
+                    node_id,
+                    standby.seq_acked
+                );
+            }
+
+            !is_lagging
+        });
+    }
+}
+
+impl Default for StateSyncSubscriber {
+    fn default() -> Self {
+        StateSyncSubscriber::new()
+    }
+}
+
+impl StateSyncSubscriber {
+    pub fn new() -> StateSyncSubscriber {
+        StateSyncSubscriber {
+            has_snapshot: false,
+            seq_applied: 0,
+            buffer: BTreeMap::new(),
+            last_sync: None,
+        }
+    }
+
+    /// Apply a StateSync packet and every buffered one following it. Returns the seq to acknowledge.
+    pub fn on_sync(
+        &mut self,
+        seq: u64,
+        is_snapshot: bool,
+        changes: Vec<StateChange>,
+        node_info: &NodeInfoDB,
+        file_info: &FileInfoDB,
+    ) -> Result<u64> {
+        self.last_sync = Some(Instant::now());
+
+        if is_snapshot {
+            node_info.clear()?;
+            file_info.clear()?;
+            _apply_changes(&changes, node_info, file_info)?;
+
+            log::info!("Applied snapshot of {} changes at seq {}", changes.len(), seq);
+
+            self.has_snapshot = true;
+            self.seq_applied = seq;
+            self.buffer = self.buffer.split_off(&(seq + 1));
+        } else if seq > self.seq_applied {
+            if self.buffer.len() < MAX_BUFFERED_SYNCS {
+                self.buffer.insert(seq, changes);
+            } else {
+                log::warn!("Too many StateSync packets out of order. Drop seq {}", seq);
+            }
+        }
+
+        // Apply what is contiguous with the last applied one
+        if self.has_snapshot {
+            while let Some(changes) = self.buffer.remove(&(self.seq_applied + 1)) {
+                _apply_changes(&changes, node_info, file_info)?;
+                self.seq_applied += 1;
+            }
+        }
+
+        Ok(self.seq_applied)
+    }
+
+    /// Whether no StateSync arrived within `timeout`, meaning the standby should register again
+    pub fn is_stale(&self, timeout: Duration) -> bool {
+        match self.last_sync {
+            Some(last_sync) => last_sync.elapsed() > timeout,
+            None => true,
+        }
+    }
+
+    /// Record that a registration was just sent, so that the Master has `timeout` to answer before the next one
+    pub fn on_register(&mut self) {
+        self.last_sync = Some(Instant::now());
+    }
+}
+
+/// Apply a change to the metadata DBs
+pub fn apply_change(change: &StateChange, node_info: &NodeInfoDB, file_info: &FileInfoDB) -> Result<()> {
+    match change {
+        StateChange::UpsertNode { ip, port, role } => node_info.upsert(*ip, *port, *role),
+        StateChange::UpdateNodeStatus { node_id, status } => node_info.update_status(node_id, *status),
+        StateChange::UpdateNodeRack { node_id, rack } => node_info.update_rack(node_id, rack),
+        StateChange::CreateInode {
+            inode,
+            parent,
+            name,
+            is_dir,
+        } => {
+            let mut entry = InodeEntry::initialize(*parent, name.clone(), *is_dir);
+            entry.inode = *inode;
+            file_info.insert_inode(&entry).map(|_| ())
+        }
+        StateChange::MoveInode { inode, parent, name } => file_info.move_inode(*inode, *parent, name),
+        StateChange::RemoveInode { inode } => file_info.remove_inode(*inode).map(|_| ()),
+        StateChange::UpsertFile {
+            inode,
+            size,
+            checksum,
+            state,
+            replication_factor,
+        } => {
+            let mut entry = FileInfoEntry::initialize(String::new(), *size, *checksum, *replication_factor);
+            entry.inode = *inode;
+            entry.state = *state;
+            file_info.upsert(&entry)
+        }
+        StateChange::UpdateFileState { inode, state } => file_info.update_state(*inode, *state),
+        StateChange::UpdateFileSize { inode, size, checksum } => file_info.update_size(*inode, *size, *checksum),
+        StateChange::SetReplicationFactor {
+            inode,
+            replication_factor,
+        } => file_info
+            .set_replication_factor(*inode, *replication_factor)
+            .map(|_| ()),
+        StateChange::SetDirReplicationFactor {
+            inode,
+            replication_factor,
+        } => file_info.set_dir_replication_factor(*inode, *replication_factor),
+        StateChange::UpsertBlock {
+            block_id,
+            inode,
+            index,
+            size,
+            checksum,
+            state,
+        } => {
+            let mut entry = BlockInfoEntry::initialize(*inode, *index, *size);
+            entry.block_id = block_id.clone();
+            entry.checksum = *checksum;
+            entry.state = *state;
+            file_info.upsert_block(&entry)
+        }
+        StateChange::UpdateBlockState { block_id, state } => file_info.update_block_state(block_id, *state),
+        StateChange::UpsertReplica { block_id, node_id } => file_info.upsert_replica(block_id, node_id),
+        StateChange::RemoveReplica { block_id, node_id } => file_info.remove_replica(block_id, node_id),
+    }
+}
+
+fn _apply_changes(changes: &[StateChange], node_info: &NodeInfoDB, file_info: &FileInfoDB) -> Result<()> {
+    for change in changes {
+        log::debug!("Apply {}", change);
+        apply_change(change, node_info, file_info)?;
+    }
+
+    Ok(())
+}
+
+/// Describe the whole metadata as changes which rebuild it from empty DBs
+pub fn take_snapshot(node_info: &NodeInfoDB, file_info: &FileInfoDB) -> Result<Vec<StateChange>> {
+    let mut changes = Vec::<StateChange>::new();
+
+    for node in node_info.get_nodes()? {
+        let ip = match node.ip {
+            Some(ip) => ip,
+            None => {
+                log::error!("Cannot retrieve ip from node with node_id = {}", node.node_id);
+                continue;
+            }
+        };
+        changes.push(StateChange::UpsertNode {
+            ip,
+            port: node.port,
+            role: node.role,
+        });
+        if node.status != NodeStatus::Alive {
+            changes.push(StateChange::UpdateNodeStatus {
+                node_id: node.node_id.clone(),
+                status: node.status,
+            });
+        }
+        if !node.rack.is_empty() {
+            changes.push(StateChange::UpdateNodeRack {
+                node_id: node.node_id,
+                rack: node.rack,
+            });
+        }
+    }
+
+    // Directories come before what they contain
+    let mut dirs = VecDeque::from([ROOT_INODE]);
+    while let Some(dir) = dirs.pop_front() {
+        for child in file_info.get_children(dir)? {
+            changes.push(StateChange::CreateInode {
+                inode: child.inode,
+                parent: child.parent,
+                name: child.name,
+                is_dir: child.is_dir,
+            });
+            if child.is_dir {
+                if child.replication_factor > 0 {
+                    changes.push(StateChange::SetDirReplicationFactor {
+                        inode: child.inode,
+                        replication_factor: child.replication_factor,
+                    });
+                }
+                dirs.push_back(child.inode);
+            }
+        }
+    }
+    for file in file_info.get_files()? {
+        changes.push(StateChange::UpsertFile {
+            inode: file.inode,
+            size: file.size,
+            checksum: file.checksum,
+            state: file.state,
+            replication_factor: file.replication_factor,
+        });
+    }
+    // Blocks and replicas come after files since upserting a file forgets them
+    for block in file_info.get_all_blocks()? {
+        changes.push(StateChange::UpsertBlock {
+            block_id: block.block_id,
+            inode: block.inode,
+            index: block.index,
+            size: block.size,
+            checksum: block.checksum,
+            state: block.state,
+        });
+    }
+    for replica in file_info.get_all_replicas()? {
+        changes.push(StateChange::UpsertReplica {
+            block_id: replica.block_id,
+            node_id: replica.node_id,
+        });
+    }
+
+    Ok(changes)
+}
+
+/// Split `changes` into StateSync packets for one standby. Only the first packet of a snapshot is flagged so.
+fn _create_syncs(standby: &mut StandbyState, changes: &[StateChange], is_snapshot: bool) -> Vec<Packet> {
+    let mut packets = Vec::<Packet>::new();
+    let mut pos = 0;
+    loop {
+        let batch = &changes[pos..changes.len().min(pos + MAX_CHANGES_PER_SYNC)];
+        packets.push(Packet::create_state_sync(
+            standby.addr,
+            standby.seq_next,
+            is_snapshot && pos == 0,
+            batch,
+        ));
+        standby.seq_next += 1;
+
+        pos += batch.len();
+        if pos >= changes.len() {
+            break;
+        }
+    }
+
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{db::DBBackend, packets::decode_changes};
+
+    fn metadata() -> (NodeInfoDB, FileInfoDB) {
+        let file_info = FileInfoDB::initialize("file_info", &DBBackend::InMemory);
+        let node_info = NodeInfoDB::initialize_along("node_info", &file_info);
+        (node_info, file_info)
+    }
+
+    /// Store a file at `path` with one block held by Data node 7003
+    fn put_file(node_info: &NodeInfoDB, file_info: &FileInfoDB, path: &str) {
+        node_info.upsert(Ipv4Addr::LOCALHOST, 7003, Role::Data).unwrap();
+        let mut entry = FileInfoEntry::initialize(String::new(), 10, 7, 2);
+        entry.inode = file_info.create_file(path).unwrap().unwrap().0;
+        file_info.upsert(&entry).unwrap();
+        let block = BlockInfoEntry::initialize(entry.inode, 0, 10);
+        file_info.upsert_block(&block).unwrap();
+        file_info
+            .upsert_replica(&block.block_id, &"127.0.0.1:7003".to_string())
+            .unwrap();
+        file_info.update_state(entry.inode, FileState::Complete).unwrap();
+    }
+
+    /// Metadata as a comparable list of changes rebuilding it
+    fn describe(node_info: &NodeInfoDB, file_info: &FileInfoDB) -> Vec<String> {
+        take_snapshot(node_info, file_info)
+            .unwrap()
+            .iter()
+            .map(|change| change.to_string())
+            .collect()
+    }
+
+    /// Read back the seq, snapshot flag and changes carried by a StateSync
+    fn unpack(packet: &Packet) -> (u64, bool, Vec<StateChange>) {
+        let payload = packet.payload.as_ref().unwrap();
+        let seq = u64::from_be_bytes(payload[..8].try_into().unwrap());
+        (seq, payload[8] == 1, decode_changes(&payload[9..]).unwrap())
+    }
+
+    #[test]
+    fn standby_applies_snapshot_then_changes_in_sequence_order() {
+        let (node_info, file_info) = metadata();
+        put_file(&node_info, &file_info, "dir/a");
+        node_info.drain_changes();
+        file_info.drain_changes();
+        let (node_info_standby, file_info_standby) = metadata();
+        // Left over from an earlier registration, and dropped by the snapshot
+        put_file(&node_info_standby, &file_info_standby, "stale");
+
+        let addr_standby = SocketAddr::from(([127, 0, 0, 1], 7010));
+        let mut publisher = StateSyncPublisher::new(&Configs::default());
+        let mut subscriber = StateSyncSubscriber::new();
+        let packets = publisher.register(addr_standby, &node_info, &file_info).unwrap();
+        assert_eq!(packets.len(), 1);
+        let (seq, is_snapshot, changes) = unpack(&packets[0]);
+        assert!(is_snapshot);
+        let seq_acked = subscriber
+            .on_sync(seq, is_snapshot, changes, &node_info_standby, &file_info_standby)
+            .unwrap();
+        assert_eq!(seq_acked, seq);
+        assert_eq!(
+            describe(&node_info_standby, &file_info_standby),
+            describe(&node_info, &file_info)
+        );
+
+        // Two rounds of changes arriving out of order are applied once both are there
+        put_file(&node_info, &file_info, "dir/b");
+        let mut packets = publisher.publish(&file_info.drain_changes());
+        file_info
+            .set_replication_factor(file_info.resolve("dir/b").unwrap().unwrap().inode, 3)
+            .unwrap();
+        packets.extend(publisher.publish(&file_info.drain_changes()));
+        assert_eq!(packets.len(), 2);
+
+        let (seq_second, _, changes) = unpack(&packets[1]);
+        let seq_acked = subscriber
+            .on_sync(seq_second, false, changes, &node_info_standby, &file_info_standby)
+            .unwrap();
+        assert_eq!(seq_acked, seq);
+        assert!(file_info_standby.resolve("dir/b").unwrap().is_none());
+
+        let (seq_first, _, changes) = unpack(&packets[0]);
+        let seq_acked = subscriber
+            .on_sync(seq_first, false, changes, &node_info_standby, &file_info_standby)
+            .unwrap();
+        assert_eq!(seq_acked, seq_second);
+        assert_eq!(
+            describe(&node_info_standby, &file_info_standby),
+            describe(&node_info, &file_info)
+        );
+        let inode = file_info_standby.resolve("dir/b").unwrap().unwrap().inode;
+        let file = file_info_standby
+            .get_files()
+            .unwrap()
+            .into_iter()
+            .find(|file| file.inode == inode);
+        assert_eq!(file.unwrap().replication_factor, 3);
+    }
+
+    #[test]
+    fn journal_replayed_into_fresh_metadata_rebuilds_it() {
+        let (node_info, file_info) = metadata();
+        put_file(&node_info, &file_info, "dir/a");
+        put_file(&node_info, &file_info, "dir/b");
+        file_info.delete("dir/a", false).unwrap();
+        node_info
+            .update_status(&"127.0.0.1:7003".to_string(), NodeStatus::Suspect)
+            .unwrap();
+        let journal: Vec<StateChange> = node_info
+            .drain_changes()
+            .into_iter()
+            .chain(file_info.drain_changes())
+            .collect();
+
+        let (node_info_replayed, file_info_replayed) = metadata();
+        for change in &journal {
+            apply_change(change, &node_info_replayed, &file_info_replayed).unwrap();
+        }
+
+        assert_eq!(
+            describe(&node_info_replayed, &file_info_replayed),
+            describe(&node_info, &file_info)
+        );
+        assert!(file_info_replayed.resolve("dir/a").unwrap().is_none());
+        assert!(file_info_replayed.resolve("dir/b").unwrap().is_some());
+    }
+}
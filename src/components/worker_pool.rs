@@ -0,0 +1,114 @@
+use std::{
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+// ================================================
+// Definition
+// ================================================
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed set of threads running jobs handed over by thread:Processor
+///
+/// At most `n_workers` jobs run at once and at most `capacity` more wait for a free worker. Jobs submitted beyond that
+/// are refused, so that a burst of requests cannot spawn threads or pile up memory without limit. Workers stop once
+/// the pool is dropped and the jobs queued are done.
+pub struct WorkerPool {
+    name: String,
+    sender: SyncSender<Job>,
+}
+
+// ================================================
+// Implementation
+// ================================================
+
+impl WorkerPool {
+    pub fn new(name: &str, n_workers: usize, capacity: usize) -> WorkerPool {
+        let (sender, receiver) = sync_channel::<Job>(capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..n_workers.max(1) {
+            let receiver = Arc::clone(&receiver);
+            thread::spawn(move || _run_worker(receiver));
+        }
+
+        WorkerPool {
+            name: name.to_string(),
+            sender,
+        }
+    }
+
+    /// Queue `job` for the next free worker. Return false if the queue is full and the job is dropped.
+    pub fn submit(&self, job: impl FnOnce() + Send + 'static) -> bool {
+        match self.sender.try_send(Box::new(job)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                log::warn!("Workers of '{}' are busy, refuse job", self.name);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                log::error!("Workers of '{}' are gone, refuse job", self.name);
+                false
+            }
+        }
+    }
+}
+
+fn _run_worker(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // The lock is released before running the job so that other workers can take the next one
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        match job {
+            Ok(job) => job(),
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc::channel, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn jobs_run_on_workers() {
+        let pool = WorkerPool::new("test", 2, 8);
+        let (sender, receiver) = channel::<usize>();
+        for i in 0..8 {
+            let sender = sender.clone();
+            assert!(pool.submit(move || sender.send(i).unwrap()));
+        }
+
+        let mut done: Vec<usize> = (0..8)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        done.sort();
+        assert_eq!(done, (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn jobs_beyond_capacity_are_refused() {
+        let pool = WorkerPool::new("test", 1, 1);
+
+        // Hold the only worker until released, then fill the queue
+        let (sender_started, receiver_started) = channel::<()>();
+        let (sender_release, receiver_release) = channel::<()>();
+        assert!(pool.submit(move || {
+            sender_started.send(()).unwrap();
+            receiver_release.recv().unwrap();
+        }));
+        receiver_started.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(pool.submit(|| {}));
+
+        assert!(!pool.submit(|| {}));
+
+        sender_release.send(()).unwrap();
+    }
+}
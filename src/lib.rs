@@ -0,0 +1,7 @@
+pub mod components;
+
+pub use components::{
+    db::DirEntry,
+    entity::dfs_client::{DfsClient, DfsFile, DfsReader, DfsWriter},
+    errors::ClientError,
+};
@@ -1,11 +1,15 @@
-use components::{
+use std::process::ExitCode;
+
+use dfs::components::{
     configs::Configs,
-    entity::{client::Client, node_roles::Role, nodes::Node},
+    entity::{
+        cli::{Cli, EXIT_USAGE, USAGE},
+        node_roles::Role,
+        nodes::Node,
+    },
 };
 
-mod components;
-
-fn main() {
+fn main() -> ExitCode {
     // ================================================
     // Intialize configs
     // ================================================
@@ -14,23 +18,35 @@ fn main() {
     // ================================================
     // Establish server
     // ================================================
-    match configs.args[1].as_str() {
-        "master" => {
+    match configs.args.get(1).map(|mode| mode.as_str()) {
+        Some("master") => {
             let mut node = Node::new(configs, Role::Master);
             node.start();
         }
-        "data" => {
+        Some("data") => {
             let mut node = Node::new(configs, Role::Data);
             node.start();
         }
-        "dns" => {
+        Some("standby") => {
+            let mut node = Node::new(configs, Role::Standby);
+            node.start()
+        }
+        Some("dns") => {
             let mut node = Node::new(configs, Role::DNS);
             node.start()
         }
-        "client" => {
-            let client = Client::new(&configs);
-            client.ask_master_ip();
+        Some("client") => match Cli::parse(&configs.args[2..]) {
+            Ok(cli) => return cli.run(&configs),
+            Err(err) => {
+                eprintln!("dfs: {}\n\n{}", err, USAGE);
+                return ExitCode::from(EXIT_USAGE);
+            }
+        },
+        _ => {
+            eprintln!("Usage: dfs <master|standby|data|dns> [<port>] | dfs client <command>");
+            return ExitCode::from(EXIT_USAGE);
         }
-        _ => panic!("First argument must be a valid mode"),
     };
+
+    ExitCode::SUCCESS
 }